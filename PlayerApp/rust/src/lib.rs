@@ -25,11 +25,12 @@ pub unsafe extern "system" fn Java_com_playerapp_PlayerWalletModule_cli(env: JNI
 // TODO: hide passwords
 //    debug!("JNI command: {}", command);
 // 10.0.2.2 is for testing in the android studio emulator
-    let conf = libcli::Conf {
+    let conf = tglib::config::Config {
         electrum_url: format!("tcp://{}:60401", LOCALHOST),
         name_url: format!("http://{}:18420", LOCALHOST),
         arbiter_url: format!("http://{}:5000", LOCALHOST),
         exchange_url: format!("http://{}:5050", LOCALHOST),
+        ..tglib::config::Config::default()
     };
     let response = libcli::cli(command, conf);
     debug!("JNI response: {}", response);
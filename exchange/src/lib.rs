@@ -1,3 +1,4 @@
+use std::str::FromStr;
 use serde::{
     Deserialize,
     Serialize,
@@ -19,6 +20,7 @@ use tglib::{
         PublicKey,
     },
     hex,
+    funding::FundingParty,
     player::PlayerName,
     payout::Payout,
 };
@@ -45,7 +47,32 @@ pub struct ContractRecord {
     pub p2_name:        PlayerName,
     pub hex:            String,
     pub desc:           String,
+// comma separated for threshold contracts, where oracle_threshold of them have to sign a token
     pub oracle_pubkey:  String,
+    #[serde(default = "single_oracle")]
+    pub oracle_threshold: u8,
+// where each player's share of a payout goes, whatever the outcomes are
+    #[serde(default)]
+    pub p1_payout_address: String,
+    #[serde(default)]
+    pub p2_payout_address: String,
+}
+
+fn single_oracle() -> u8 {
+    1
+}
+
+impl ContractRecord {
+    pub fn oracle_pubkeys(&self) -> Result<Vec<PublicKey>> {
+        Ok(self.oracle_pubkey
+            .split(',')
+            .map(|pubkey| PublicKey::from_str(pubkey))
+            .collect::<std::result::Result<Vec<PublicKey>, _>>()?)
+    }
+
+    pub fn payout_addresses(&self) -> Result<(Address, Address)> {
+        Ok((Address::from_str(&self.p1_payout_address)?, Address::from_str(&self.p2_payout_address)?))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,26 +84,52 @@ pub struct TokenRecord {
     pub desc:           String,
 }
 
+// an outcome paying both players, amounts are each player's share of the escrow
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SplitRecord {
+    pub cxid:           String,
+    pub token:          String,
+    pub desc:           String,
+    pub p1_amount:      u64,
+    pub p2_amount:      u64,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TokenContractRecord {
     pub contract_record: ContractRecord,
-    pub p1_token: TokenRecord,
-    pub p2_token: TokenRecord,
+// the outcomes paying all of the escrow to one player, an event doesn't need to have them
+    #[serde(default)]
+    pub p1_token: Option<TokenRecord>,
+    #[serde(default)]
+    pub p2_token: Option<TokenRecord>,
+    #[serde(default)]
+    pub splits: Vec<SplitRecord>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PayoutRecord {
     pub cxid:           String,
     pub psbt:           String,
+// comma separated hex script sigs
     pub sig:            String,
 }
 
+impl PayoutRecord {
+    pub fn script_sigs(&self) -> Vec<Signature> {
+        self.sig
+            .split(',')
+            .filter_map(|sig| Signature::from_der(&hex::decode(sig).ok()?).ok())
+            .collect()
+    }
+}
+
 impl From<Payout> for PayoutRecord {
     fn from(p: Payout) -> PayoutRecord {
-        let sig = match p.script_sig {
-           Some(sig) => hex::encode(sig.serialize_der().to_vec()),
-           None => "".to_string(),
-        };
+        let sig = p.script_sigs
+            .iter()
+            .map(|sig| hex::encode(sig.serialize_der().to_vec()))
+            .collect::<Vec<String>>()
+            .join(",");
         PayoutRecord {
             cxid: hex::encode(p.contract.cxid()),
             psbt: hex::encode(consensus::serialize(&p.psbt)),
@@ -127,6 +180,13 @@ impl PlayerContractInfo {
         let hash: &[u8] = &ShaHash::from_engine(engine);
         Vec::from(hash)
     }
+
+    pub fn funding(&self) -> FundingParty {
+        FundingParty {
+            change_script: self.change_address.script_pubkey(),
+            utxos: self.utxos.iter().map(|(outpoint, value, _)| (*outpoint, *value)).collect(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,6 +42,7 @@ use tglib::{
         },
     },
     JsonResponse,
+    config::Config,
     hex,
     log::{
         debug,
@@ -51,11 +52,6 @@ use tglib::{
     rand::{self, Rng},
     player::PlayerName,
     wallet::get_namecoin_address,
-    mock::{
-        ELECTRS_SERVER,
-        NETWORK,
-        REDIS_SERVER,
-    },
 };
 
 type Response = JsonResponse<()>;
@@ -63,13 +59,12 @@ type Response = JsonResponse<()>;
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 type WebResult<T> = std::result::Result<T, Rejection>;
 
-const NAME_SERVICE_URL: &'static str = "http://nmc-id:18420";
 const AUTH_TOKEN_LIFETIME: usize = 30;
 
-async fn controls_name(pubkey: &PublicKey, player_name: &PlayerName) -> reqwest::Result<bool> {
-    match reqwest::get(&format!("{}/{}/{}", NAME_SERVICE_URL, "get-name-address", hex::encode(player_name.0.as_bytes()))).await {
+async fn controls_name(pubkey: &PublicKey, player_name: &PlayerName, config: &Config) -> reqwest::Result<bool> {
+    match reqwest::get(&format!("{}/{}/{}", config.name_url, "get-name-address", hex::encode(player_name.0.as_bytes()))).await {
         Ok(response) => match response.text().await {
-            Ok(name_address) => Ok(get_namecoin_address(pubkey, NETWORK) == name_address), 
+            Ok(name_address) => Ok(get_namecoin_address(pubkey, config.network) == name_address), 
             Err(e) => {
                 error!("{:?}", e);
                 Err(e)
@@ -82,9 +77,9 @@ async fn controls_name(pubkey: &PublicKey, player_name: &PlayerName) -> reqwest:
     }
 }
 
-async fn check_auth_token_sig(player_name: PlayerName, auth: AuthTokenSig, con: &mut Connection) -> Result<bool> {
+async fn check_auth_token_sig(player_name: PlayerName, auth: AuthTokenSig, con: &mut Connection, config: &Config) -> Result<bool> {
 
-    match controls_name(&auth.pubkey, &player_name).await {
+    match controls_name(&auth.pubkey, &player_name, config).await {
         Ok(true) => (),
         _ => return Ok(false),
     }
@@ -98,8 +93,8 @@ async fn check_auth_token_sig(player_name: PlayerName, auth: AuthTokenSig, con:
     Ok(secp.verify(&Message::from_slice(&hex::decode(token).unwrap()).unwrap(), &sig, &auth.pubkey.key).is_ok())
 }
 
-async fn set_contract_info_handler(body: SetContractInfoBody, redis_client: redis::Client) -> WebResult<impl Reply> {
-    match controls_name(&body.pubkey, &body.contract_info.name).await {
+async fn set_contract_info_handler(body: SetContractInfoBody, redis_client: redis::Client, config: Config) -> WebResult<impl Reply> {
+    match controls_name(&body.pubkey, &body.contract_info.name, &config).await {
         Ok(true) => (),
         Ok(false) => return Ok(warp::reply::json(&Response::error("pubkey doesn't control name".to_string(), None))),
         Err(e) => {
@@ -155,9 +150,9 @@ async fn send_contract_handler(body: SendContractBody, redis_client: redis::Clie
     }
 }
 
-async fn receive_contract_handler(auth: AuthTokenSig, redis_client: redis::Client) -> WebResult<impl Reply> {
+async fn receive_contract_handler(auth: AuthTokenSig, redis_client: redis::Client, config: Config) -> WebResult<impl Reply> {
     let mut con = redis_client.get_async_connection().await.unwrap();
-    match check_auth_token_sig(auth.player_name.clone(), auth.clone(), &mut con).await {
+    match check_auth_token_sig(auth.player_name.clone(), auth.clone(), &mut con, &config).await {
         Ok(true) => (),
         Ok(false) => {
             let message = "auth failed: invalid credentials";
@@ -194,9 +189,9 @@ async fn send_payout_handler(body: SendPayoutBody, redis_client: redis::Client)
     }
 }
 
-async fn receive_payout_handler(auth: AuthTokenSig, redis_client: redis::Client) -> WebResult<impl Reply> {
+async fn receive_payout_handler(auth: AuthTokenSig, redis_client: redis::Client, config: Config) -> WebResult<impl Reply> {
     let mut con = redis_client.get_async_connection().await.unwrap();
-    match check_auth_token_sig(auth.player_name.clone(), auth.clone(), &mut con).await {
+    match check_auth_token_sig(auth.player_name.clone(), auth.clone(), &mut con, &config).await {
         Ok(true) => (),
         Ok(false) => {
             let message = "auth failed: invalid credentials";
@@ -236,13 +231,13 @@ async fn auth_token_handler(player_name: String, redis_client: redis::Client) ->
     }
 }
 
-async fn remove_stale_contract_info() -> Result<()> {
+async fn remove_stale_contract_info(config: Config) -> Result<()> {
 //TODO: try using script pubkey subscription instead
-    let redis_client = redis_client();
+    let redis_client = redis_client(&config);
     let mut con = redis_client.get_async_connection().await.unwrap();
     loop {
         sleep(Duration::from_secs(10)).await;
-        let electrum_client = match Client::new(ELECTRS_SERVER) {
+        let electrum_client = match Client::new(&config.electrum_url) {
             Ok(client) => client,
             Err(e) => {
                 error!("couldn't create electrum client: {:?}",e);
@@ -302,11 +297,11 @@ async fn remove_stale_contract_info() -> Result<()> {
     }
 }
 
-fn redis_client() -> redis::Client {
-    let mut client = redis::Client::open(REDIS_SERVER);
+fn redis_client(config: &Config) -> redis::Client {
+    let mut client = redis::Client::open(config.redis_url.as_str());
     while client.is_err() {
         std::thread::sleep(Duration::from_millis(100));
-        client = redis::Client::open(REDIS_SERVER);
+        client = redis::Client::open(config.redis_url.as_str());
     }
     client.unwrap()
 }
@@ -320,14 +315,18 @@ async fn main() {
         .with_module_level("reqwest",LevelFilter::Warn)
         .init()
         .unwrap();
-    
-    let redis_client = redis_client();
+
+    let config = Config::default().load().unwrap();
+    let redis_client = redis_client(&config);
     let redis_client = warp::any().map(move || redis_client.clone());
+    let stale_config = config.clone();
+    let config = warp::any().map(move || config.clone());
 
     let set_contract_info = warp::path("set-contract-info")
         .and(warp::post())
         .and(warp::body::json())
         .and(redis_client.clone())
+        .and(config.clone())
         .and_then(set_contract_info_handler);
 
     let get_contract_info = warp::path("get-contract-info")
@@ -345,6 +344,7 @@ async fn main() {
         .and(warp::post())
         .and(warp::body::json())
         .and(redis_client.clone())
+        .and(config.clone())
         .and_then(receive_contract_handler);
 
     let send_payout = warp::path("send-payout")
@@ -357,6 +357,7 @@ async fn main() {
         .and(warp::post())
         .and(warp::body::json())
         .and(redis_client.clone())
+        .and(config.clone())
         .and_then(receive_payout_handler);
 
     let auth_token = warp::path("auth-token")
@@ -374,6 +375,6 @@ async fn main() {
 
     let _ = tokio::join!(
         warp::serve(routes).run(([0, 0, 0, 0], 5050)),
-        remove_stale_contract_info(),
+        remove_stale_contract_info(stale_config),
     );
 }
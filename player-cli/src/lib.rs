@@ -12,21 +12,30 @@ use tglib::{
         consensus,
         Address,
         Amount,
+        PublicKey,
         secp256k1::Signature,
-        util::psbt::PartiallySignedTransaction,
+        util::{
+            bip32::{
+                ExtendedPubKey,
+                Fingerprint,
+            },
+            psbt::PartiallySignedTransaction,
+        },
     },
     hex,
     secrecy::Secret,
+    config::Config,
     contract::Contract,
-    player::PlayerName,
-    wallet::{
-        TX_FEE,
-        SavedSeed,
+    payout::{
+        Payout,
+        PAYOUT_VERSION,
     },
-    mock::{
-        NETWORK,
-        SEED_NAME,
+    player::PlayerName,
+    script::{
+        TgScriptDebugger,
+        TraceStep,
     },
+    wallet::SavedSeed,
     JsonResponse,
 };
 use libexchange::{
@@ -42,7 +51,10 @@ use player_wallet::{
         WalletUI,
         SignDocumentParams,
     },
-    wallet::PlayerWallet,
+    wallet::{
+        PlayerWallet,
+        SEED_NAME,
+    },
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -75,8 +87,8 @@ impl From<&TokenContractRecord> for ContractSummary {
             p2_sig: contract.sigs.len() > 1,
             arbiter_sig: contract.sigs.len() > 2,
             txid: contract.funding_tx.extract_tx().txid().to_string(),
-            p1_token_desc: tcr.p1_token.desc.clone(),
-            p2_token_desc: tcr.p2_token.desc.clone(),
+            p1_token_desc: tcr.p1_token.as_ref().map_or(String::new(), |token| token.desc.clone()),
+            p2_token_desc: tcr.p2_token.as_ref().map_or(String::new(), |token| token.desc.clone()),
         }
     }
 }
@@ -94,6 +106,67 @@ pub struct PayoutSummary {
     pub txid:           String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptDebugSummary {
+    pub cxid:           String,
+    pub steps:          Vec<TraceStep>,
+    pub valid:          bool,
+    pub error:          Option<String>,
+}
+
+impl ScriptDebugSummary {
+// step through the payout script the same way the arbiter would evaluate it
+    fn get(player_wallet: &PlayerWallet, cxid: &str, token_sigs: Option<Vec<&str>>) -> std::result::Result<ScriptDebugSummary, String> {
+        let tcr = DocumentUI::<TokenContractRecord>::get(player_wallet, cxid).ok_or("no such contract")?;
+        let pr = DocumentUI::<PayoutRecord>::get(player_wallet, cxid).ok_or("no payout for contract")?;
+        let script_sigs = match token_sigs {
+            Some(token_sigs) => {
+                let mut script_sigs = Vec::new();
+                for sig_hex in token_sigs {
+                    script_sigs.push(Signature::from_der(&hex::decode(sig_hex).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?);
+                }
+                script_sigs
+            }
+            None => pr.script_sigs(),
+        };
+        let payout = Payout {
+            version: PAYOUT_VERSION,
+            contract: Contract::from_bytes(hex::decode(&tcr.contract_record.hex).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?,
+            psbt: consensus::deserialize(&hex::decode(&pr.psbt).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?,
+            script_sigs,
+        };
+        let mut debugger = TgScriptDebugger::for_payout(payout).map_err(|e| e.to_string())?;
+        let mut steps = Vec::new();
+        let error = loop {
+            match debugger.step() {
+                Ok(Some(step)) => steps.push(step),
+                Ok(None) => break None,
+                Err(e) => break Some(e.to_string()),
+            }
+        };
+        Ok(ScriptDebugSummary {
+            cxid: cxid.to_string(),
+            steps,
+            valid: error.is_none() && debugger.env().validity() == Some(true),
+            error,
+        })
+    }
+}
+
+impl std::fmt::Display for ScriptDebugSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for step in &self.steps {
+            writeln!(f, "{}", step)?;
+        }
+        match (&self.error, self.valid) {
+            (Some(e), _) => write!(f, "script error: {}", e),
+            (None, true) => write!(f, "payout is valid"),
+            (None, false) => write!(f, "payout is invalid"),
+        }
+    }
+}
+
 impl PayoutSummary {
         fn get(player_wallet: &PlayerWallet, pr: &PayoutRecord) -> Option<PayoutSummary> {
             let psbt: PartiallySignedTransaction = consensus::deserialize(&hex::decode(&pr.psbt).unwrap()).unwrap();
@@ -104,15 +177,17 @@ impl PayoutSummary {
 // it will always show the balance as going to the other player since
 // we aren't paying out to the escrow pubkey any more
 //            let my_script_pubkey = Address::p2wpkh(&player_wallet.get_escrow_pubkey(), NETWORK).unwrap().script_pubkey();
-            let p1_script_pubkey = Address::from_str(&tcr.p1_token.address).unwrap().script_pubkey();
-            let p1_amount = Amount::from_sat(psbt.global.unsigned_tx.output.iter().filter_map(|txout| if txout.script_pubkey == p1_script_pubkey { Some(txout.value) } else { None}).sum()).as_sat();
-            let contract_amount = contract.amount().ok()?;
+            let paid_to = |address: &str| -> Option<u64> {
+                let script_pubkey = Address::from_str(address).ok()?.script_pubkey();
+                Some(psbt.global.unsigned_tx.output.iter().filter(|txout| txout.script_pubkey == script_pubkey).map(|txout| txout.value).sum())
+            };
+            let p1_amount = paid_to(&tcr.contract_record.p1_payout_address)?;
 //            let p1_amount = if contract.p1_pubkey == player_wallet.get_escrow_pubkey() {
 //                my_amount.as_sat()
 //            } else {
 //                contract_amount.as_sat() - my_amount.as_sat() - TX_FEE
 //            };
-            let p2_amount = contract_amount.as_sat() - p1_amount - TX_FEE;
+            let p2_amount = paid_to(&tcr.contract_record.p2_payout_address)?;
 
             Some(PayoutSummary {
                 cxid: pr.cxid.clone(),
@@ -138,14 +213,27 @@ fn player_cli<'a, 'b>() -> App<'a, 'b> {
         .subcommand(SubCommand::with_name("init").about("initialize new wallet")
             .arg(Arg::with_name("password")
                 .long("password")
-                .required(true)
+                .required_unless("xpubkey")
                 .takes_value(true)
                 .help("wallet password"))
             .arg(Arg::with_name("seed-phrase")
                 .long("seed-phrase")
                 .required(false)
                 .takes_value(true)
-                .help("BIP39 seed phrase")))
+                .help("BIP39 seed phrase"))
+            .arg(Arg::with_name("xpubkey")
+                .long("xpubkey")
+                .required(false)
+                .takes_value(true)
+                .requires("fingerprint")
+                .conflicts_with_all(&["password", "seed-phrase"])
+                .help("watch-only wallet for an external signer, with its m/44'/0'/0' xpubkey"))
+            .arg(Arg::with_name("fingerprint")
+                .long("fingerprint")
+                .required(false)
+                .takes_value(true)
+                .requires("xpubkey")
+                .help("external signer's master key fingerprint")))
         .subcommand(SubCommand::with_name("balance").about("display balance (sats)"))
         .subcommand(SubCommand::with_name("deposit").about("display a deposit address"))
         .subcommand(SubCommand::with_name("fund").about("fund the wallet"))
@@ -180,14 +268,7 @@ fn player_cli<'a, 'b>() -> App<'a, 'b> {
             .long("wallet-dir"))
 }
 
-pub struct Conf {
-    pub electrum_url: String,
-    pub name_url: String,
-    pub arbiter_url: String,
-    pub exchange_url: String,
-}
-
-pub fn cli(line: String, conf: Conf) -> String {
+pub fn cli(line: String, conf: Config) -> String {
     let split_line = match shell_words::split(&line) {
         Ok(line) => line,
         Err(e) => return format!("invalid command: {:?}", e),
@@ -210,13 +291,21 @@ pub fn cli(line: String, conf: Conf) -> String {
                 }
                 Err(_) => match c {
                     "init" => {
-                        let mnemonic = match a.value_of("seed-phrase") {
-                            Some(phrase) => Some(Secret::new(phrase.to_owned())),
-                            None => None,
-                        };
-                        let new_seed = match SavedSeed::new(Secret::new(a.value_of("password").unwrap().to_owned()), mnemonic) {
-                            Ok(seed) => seed,
-                            Err(e) => return format!("{:?}", e),
+                        let new_seed = if let Some(xpubkey) = a.value_of("xpubkey") {
+                            let (fingerprint, xpubkey) = match (Fingerprint::from_str(a.value_of("fingerprint").unwrap()), ExtendedPubKey::from_str(xpubkey)) {
+                                (Ok(fingerprint), Ok(xpubkey)) => (fingerprint, xpubkey),
+                                _ => return "invalid fingerprint or xpubkey".to_string(),
+                            };
+                            SavedSeed::watch_only(fingerprint, xpubkey, conf.network)
+                        } else {
+                            let mnemonic = match a.value_of("seed-phrase") {
+                                Some(phrase) => Some(Secret::new(phrase.to_owned())),
+                                None => None,
+                            };
+                            match SavedSeed::new(Secret::new(a.value_of("password").unwrap().to_owned()), mnemonic, conf.network) {
+                                Ok(seed) => seed,
+                                Err(e) => return format!("{:?}", e),
+                            }
                         };
                         match File::create(&seed_path) {
                             Ok(mut writer) => writer.write_all(serde_json::to_string(&new_seed).unwrap().as_bytes()).unwrap(),
@@ -239,7 +328,8 @@ pub fn cli(line: String, conf: Conf) -> String {
                 }
             };
 
-            let wallet = PlayerWallet::new(wallet_dir, NETWORK, conf.electrum_url, conf.name_url, conf.arbiter_url, conf.exchange_url);
+            let wallet = PlayerWallet::new(wallet_dir, conf.network, conf.electrum_url, conf.name_url, conf.arbiter_url, conf.exchange_url)
+                .with_signer(conf.signer);
             match c {
                 "balance" => match wallet.balance() {
                     Ok(balance) => if a.is_present("json-output") {
@@ -444,9 +534,26 @@ pub fn contract_ui<'a, 'b>() -> App<'a, 'b> {
                     .takes_value(true))
                 .arg(Arg::with_name("event-payouts")
                     .index(5)
-                    .help("which player to pay for each event outcome. player order should coincide with outcome order in event")
+                    .help("which player to pay for each event outcome, or p1_amount,p2_amount to split the amount between them. order should coincide with outcome order in event")
                     .required(true)
-                    .multiple(true)),
+                    .multiple(true))
+                .arg(Arg::with_name("fee-rate")
+                    .long("fee-rate")
+                    .required(false)
+                    .takes_value(true)
+                    .help("fee rate in sat/vbyte for the funding and payout txs. defaults to the electrum estimate"))
+                .arg(Arg::with_name("oracle-pubkey")
+                    .long("oracle-pubkey")
+                    .required(false)
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("another oracle signing the event's tokens, repeat for more. makes a threshold contract"))
+                .arg(Arg::with_name("oracle-threshold")
+                    .long("oracle-threshold")
+                    .required(false)
+                    .takes_value(true)
+                    .help("how many of the oracles have to sign an outcome's token, defaults to 1")),
             SubCommand::with_name("import").about("import contract")
                 .arg(Arg::with_name("contract-value")
                     .index(1)
@@ -519,6 +626,13 @@ pub fn contract_ui<'a, 'b>() -> App<'a, 'b> {
                     .help("contract id")
                     .required(true)
                     .takes_value(true)),
+            SubCommand::with_name("state").about("check the chain for the contract's state")
+                .arg(Arg::with_name("cxid")
+                    .index(1)
+                    .value_name("CXID")
+                    .help("contract id")
+                    .required(true)
+                    .takes_value(true)),
             SubCommand::with_name("delete").about("delete contract")
                 .arg(Arg::with_name("cxid")
                     .index(1)
@@ -526,33 +640,99 @@ pub fn contract_ui<'a, 'b>() -> App<'a, 'b> {
                     .help("contract id")
                     .required(true)
                     .takes_value(true)),
+            SubCommand::with_name("debug-script").about("step through the payout script to check a payout before submitting it")
+                .arg(Arg::with_name("cxid")
+                    .index(1)
+                    .value_name("CXID")
+                    .help("contract id")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("token-sig")
+                    .long("token-sig")
+                    .required(false)
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("hex-encoded oracle token sig, repeat for threshold contracts. defaults to the payout's script sigs")),
             SubCommand::with_name("list").about("list all contracts"),
         ])
 }
 
+// an event payout is either the player taking the whole amount or p1_amount,p2_amount
+fn event_split(payout: &str, p1_name: &PlayerName, p2_name: &PlayerName, amount: Amount) -> Option<(Amount, Amount)> {
+    let nothing = Amount::from_sat(0);
+    if payout == p1_name.0 {
+        Some((amount, nothing))
+    } else if payout == p2_name.0 {
+        Some((nothing, amount))
+    } else {
+        let mut amounts = payout.splitn(2, ',').map(|amount| amount.parse::<u64>().ok().map(Amount::from_sat));
+        Some((amounts.next()??, amounts.next()??))
+    }
+}
+
 pub fn contract_subcommand(subcommand: (&str, Option<&ArgMatches>), wallet: &PlayerWallet) -> String {
     if let (c, Some(a)) = subcommand {
         match c {
-            "new" => match DocumentUI::<TokenContractRecord>::new(
-                wallet,
-                NewDocumentParams::NewContractParams {
-                    p1_name: PlayerName(a.value_of("player-1").unwrap().to_string()),
-                    p2_name: PlayerName(a.value_of("player-2").unwrap().to_string()),
-                    amount: Amount::from_sat(a.value_of("amount").unwrap().parse::<u64>().unwrap()),
-                    event: serde_json::from_str(a.value_of("event").unwrap()).unwrap(),
-                    event_payouts: a.values_of("event-payouts").unwrap().collect::<Vec<&str>>().iter().map(|player| {
-                            PlayerName(player.to_string()) 
-                        }).collect(),
-                }) {
-                Ok(tcr) => if a.is_present("json-output") {
-                    serde_json::to_string(&JsonResponse::success(Some(ContractSummary::from(&tcr)))).unwrap()
-                } else {
-                    format!("contract {} created", tcr.contract_record.cxid)
-                }
-                Err(e) => if a.is_present("json-output") {
-                    serde_json::to_string(&JsonResponse::<String>::error(e.to_string(), None)).unwrap()
-                } else {
-                    format!("{:?}", e)
+            "new" => {
+                let p1_name = PlayerName(a.value_of("player-1").unwrap().to_string());
+                let p2_name = PlayerName(a.value_of("player-2").unwrap().to_string());
+                let amount = Amount::from_sat(a.value_of("amount").unwrap().parse::<u64>().unwrap());
+                let event_splits = match a.values_of("event-payouts").unwrap().map(|payout| event_split(payout, &p1_name, &p2_name, amount)).collect::<Option<Vec<(Amount, Amount)>>>() {
+                    Some(splits) => splits,
+                    None => return if a.is_present("json-output") {
+                        serde_json::to_string(&JsonResponse::<String>::error("invalid event payout".to_string(), None)).unwrap()
+                    } else {
+                        "invalid event payout".to_string()
+                    }
+                };
+                let fee_rate = match a.value_of("fee-rate").map(|fee_rate| fee_rate.parse::<u64>()) {
+                    Some(Ok(fee_rate)) => Some(fee_rate),
+                    None => None,
+                    Some(Err(_)) => return if a.is_present("json-output") {
+                        serde_json::to_string(&JsonResponse::<String>::error("invalid fee rate".to_string(), None)).unwrap()
+                    } else {
+                        "invalid fee rate".to_string()
+                    }
+                };
+                let extra_oracle_pubkeys = match a.values_of("oracle-pubkey").map_or(Ok(Vec::new()), |pubkeys| pubkeys.map(|pubkey| PublicKey::from_str(pubkey)).collect()) {
+                    Ok(pubkeys) => pubkeys,
+                    Err(_) => return if a.is_present("json-output") {
+                        serde_json::to_string(&JsonResponse::<String>::error("invalid oracle pubkey".to_string(), None)).unwrap()
+                    } else {
+                        "invalid oracle pubkey".to_string()
+                    }
+                };
+                let oracle_threshold = match a.value_of("oracle-threshold").map_or(Ok(1), |threshold| threshold.parse::<u8>()) {
+                    Ok(threshold) => threshold,
+                    Err(_) => return if a.is_present("json-output") {
+                        serde_json::to_string(&JsonResponse::<String>::error("invalid oracle threshold".to_string(), None)).unwrap()
+                    } else {
+                        "invalid oracle threshold".to_string()
+                    }
+                };
+                match DocumentUI::<TokenContractRecord>::new(
+                    wallet,
+                    NewDocumentParams::NewContractParams {
+                        p1_name,
+                        p2_name,
+                        amount,
+                        event: serde_json::from_str(a.value_of("event").unwrap()).unwrap(),
+                        event_splits,
+                        extra_oracle_pubkeys,
+                        oracle_threshold,
+                        fee_rate,
+                    }) {
+                    Ok(tcr) => if a.is_present("json-output") {
+                        serde_json::to_string(&JsonResponse::success(Some(ContractSummary::from(&tcr)))).unwrap()
+                    } else {
+                        format!("contract {} created", tcr.contract_record.cxid)
+                    }
+                    Err(e) => if a.is_present("json-output") {
+                        serde_json::to_string(&JsonResponse::<String>::error(e.to_string(), None)).unwrap()
+                    } else {
+                        format!("{:?}", e)
+                    }
                 }
             }
             "import" => match DocumentUI::<TokenContractRecord>::import(wallet, &a.value_of("contract-value").unwrap()) {
@@ -647,6 +827,18 @@ pub fn contract_subcommand(subcommand: (&str, Option<&ArgMatches>), wallet: &Pla
                     format!("{:?}", e)
                 }
             }
+            "state" => match wallet.contract_state(a.value_of("cxid").unwrap()) {
+                Ok(state) => if a.is_present("json-output") {
+                    serde_json::to_string(&JsonResponse::success(Some(state.to_string()))).unwrap()
+                } else {
+                    state.to_string()
+                }
+                Err(e) => if a.is_present("json-output") {
+                    serde_json::to_string(&JsonResponse::<String>::error(e.to_string(), None)).unwrap()
+                } else {
+                    format!("{:?}", e)
+                }
+            }
             "broadcast" => match DocumentUI::<TokenContractRecord>::broadcast(wallet, a.value_of("cxid").unwrap()) {
                 Ok(()) => if a.is_present("json-output") {
                     serde_json::to_string(&JsonResponse::<String>::success(None)).unwrap()
@@ -671,6 +863,18 @@ pub fn contract_subcommand(subcommand: (&str, Option<&ArgMatches>), wallet: &Pla
                     format!("{:?}", e)
                 }
             }
+            "debug-script" => match ScriptDebugSummary::get(wallet, a.value_of("cxid").unwrap(), a.values_of("token-sig").map(|sigs| sigs.collect())) {
+                Ok(summary) => if a.is_present("json-output") {
+                    serde_json::to_string(&JsonResponse::success(Some(summary))).unwrap()
+                } else {
+                    summary.to_string()
+                }
+                Err(e) => if a.is_present("json-output") {
+                    serde_json::to_string(&JsonResponse::<String>::error(e, None)).unwrap()
+                } else {
+                    e
+                }
+            }
             "list" => if a.is_present("json-output") {
                 let cs: Vec<ContractSummary> = DocumentUI::<TokenContractRecord>::list(wallet).iter().map(|cr| cr.into()).collect();
                 serde_json::to_string(&JsonResponse::success(Some(cs))).unwrap()
@@ -733,7 +937,7 @@ pub fn payout_ui<'a, 'b>() -> App<'a, 'b> {
                     .help("contract id")
                     .required(true)
                     .takes_value(true)),
-            SubCommand::with_name("sign").about("sign payout tx and optionally set script sig")
+            SubCommand::with_name("sign").about("sign payout tx and optionally set script sigs")
                 .arg(Arg::with_name("cxid")
                     .index(1)
                     .help("contract id of payout to sign")
@@ -742,7 +946,8 @@ pub fn payout_ui<'a, 'b>() -> App<'a, 'b> {
                 .arg(Arg::with_name("script-sig")
                     .index(2)
                     .required(false)
-                    .help("payout script sig")
+                    .multiple(true)
+                    .help("payout script sigs, one per oracle for threshold contracts")
                     .takes_value(true))
                 .arg(Arg::with_name("password")
                     .long("password")
@@ -781,6 +986,37 @@ pub fn payout_ui<'a, 'b>() -> App<'a, 'b> {
                     .help("contract id for the payout tx")
                     .required(true)
                     .takes_value(true)),
+            SubCommand::with_name("state").about("check the chain for the payout's state")
+                .arg(Arg::with_name("cxid")
+                    .index(1)
+                    .value_name("CXID")
+                    .help("contract id for the payout")
+                    .required(true)
+                    .takes_value(true)),
+            SubCommand::with_name("bump").about("pay for a stuck payout tx with a child tx spending our output of it")
+                .arg(Arg::with_name("cxid")
+                    .index(1)
+                    .value_name("CXID")
+                    .help("contract id for the payout")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("fee-rate")
+                    .long("fee-rate")
+                    .required(false)
+                    .takes_value(true)
+                    .help("fee rate in sat/vbyte for the payout and child tx together. defaults to the electrum estimate"))
+                .arg(Arg::with_name("password")
+                    .long("password")
+                    .required(true)
+                    .takes_value(true)
+                    .help("wallet password")),
+            SubCommand::with_name("refund").about("broadcast the contract's refund tx after its lock time")
+                .arg(Arg::with_name("cxid")
+                    .index(1)
+                    .value_name("CXID")
+                    .help("contract id to refund")
+                    .required(true)
+                    .takes_value(true)),
             SubCommand::with_name("delete").about("delete payout")
                 .arg(Arg::with_name("cxid")
                     .index(1)
@@ -846,9 +1082,9 @@ pub fn payout_subcommand(subcommand: (&str, Option<&ArgMatches>), wallet: &Playe
                     wallet,
                     SignDocumentParams::SignPayoutParams {
                         cxid: a.value_of("cxid").unwrap().to_string(),
-                        script_sig: match a.value_of("script-sig") {
-                            Some(sig_hex) => Some(Signature::from_der(&hex::decode(sig_hex).unwrap()).unwrap()),
-                            None => None,
+                        script_sigs: match a.values_of("script-sig") {
+                            Some(sigs) => sigs.map(|sig_hex| Signature::from_der(&hex::decode(sig_hex).unwrap()).unwrap()).collect(),
+                            None => Vec::new(),
                         },
                     },
                     Secret::new(a.value_of("password").unwrap().to_owned())) {
@@ -916,6 +1152,53 @@ pub fn payout_subcommand(subcommand: (&str, Option<&ArgMatches>), wallet: &Playe
                     format!("{:?}", e)
                 }
             }
+            "state" => match wallet.payout_state(a.value_of("cxid").unwrap()) {
+                Ok(state) => if a.is_present("json-output") {
+                    serde_json::to_string(&JsonResponse::success(Some(state.to_string()))).unwrap()
+                } else {
+                    state.to_string()
+                }
+                Err(e) => if a.is_present("json-output") {
+                    serde_json::to_string(&JsonResponse::<String>::error(e.to_string(), None)).unwrap()
+                } else {
+                    format!("{:?}", e)
+                }
+            }
+            "bump" => {
+                let fee_rate = match a.value_of("fee-rate").map(|fee_rate| fee_rate.parse::<u64>()) {
+                    Some(Ok(fee_rate)) => Some(fee_rate),
+                    None => None,
+                    Some(Err(_)) => return if a.is_present("json-output") {
+                        serde_json::to_string(&JsonResponse::<String>::error("invalid fee rate".to_string(), None)).unwrap()
+                    } else {
+                        "invalid fee rate".to_string()
+                    }
+                };
+                match wallet.bump_payout(a.value_of("cxid").unwrap(), fee_rate, Secret::new(a.value_of("password").unwrap().to_owned())) {
+                    Ok(txid) => if a.is_present("json-output") {
+                        serde_json::to_string(&JsonResponse::success(Some(txid.to_string()))).unwrap()
+                    } else {
+                        format!("child tx {} broadcast to network", txid)
+                    }
+                    Err(e) => if a.is_present("json-output") {
+                        serde_json::to_string(&JsonResponse::<String>::error(e.to_string(), None)).unwrap()
+                    } else {
+                        format!("{:?}", e)
+                    }
+                }
+            }
+            "refund" => match wallet.refund(a.value_of("cxid").unwrap()) {
+                Ok(txid) => if a.is_present("json-output") {
+                    serde_json::to_string(&JsonResponse::success(Some(txid.to_string()))).unwrap()
+                } else {
+                    format!("refund tx {} broadcast to network", txid)
+                }
+                Err(e) => if a.is_present("json-output") {
+                    serde_json::to_string(&JsonResponse::<String>::error(e.to_string(), None)).unwrap()
+                } else {
+                    format!("{:?}", e)
+                }
+            }
             "delete" => match DocumentUI::<PayoutRecord>::delete(wallet, a.value_of("cxid").unwrap()) {
                 Ok(()) => if a.is_present("json-output") {
                     serde_json::to_string(&JsonResponse::<String>::success(None)).unwrap()
@@ -945,8 +1228,11 @@ mod test {
     
     use super::{
         cli,
-        Conf,
+        event_split,
+        Amount,
+        Config,
         ContractSummary,
+        PlayerName,
     };
     use libexchange::Event;
     use tglib::JsonResponse;
@@ -971,13 +1257,19 @@ mod test {
   \"oracle_pubkey\": \"025c571f77d693246e64f01ef740064a0b024a228813c94ae7e1e4ee73e991e0ba\"
 }";
 
-    fn conf() -> Conf {
-        Conf {
-            electrum_url: "tcp://localhost:60401".into(),
-            name_url: "http://localhost:18420".into(),
-            arbiter_url: "http://localhost:5000".into(),
-            exchange_url: "http://localhost:5050".into(),
-        }
+    #[test]
+    fn event_splits() {
+        let (p1, p2) = (PlayerName("p1".to_string()), PlayerName("p2".to_string()));
+        let amount = Amount::from_sat(1000);
+        assert_eq!(event_split("p1", &p1, &p2, amount), Some((amount, Amount::from_sat(0))));
+        assert_eq!(event_split("p2", &p1, &p2, amount), Some((Amount::from_sat(0), amount)));
+        assert_eq!(event_split("600,400", &p1, &p2, amount), Some((Amount::from_sat(600), Amount::from_sat(400))));
+        assert_eq!(event_split("p3", &p1, &p2, amount), None);
+        assert_eq!(event_split("600", &p1, &p2, amount), None);
+    }
+
+    fn conf() -> Config {
+        Config::localhost()
     }
     
     fn init_funded_wallet(wallet_dir: &str) {
@@ -1211,6 +1503,13 @@ mod test {
         println!("sign with p2 token");
         cli(format!("payout sign {} {} --wallet-dir {} --password {}", cxid, payout_script_sig, DIR_1, PW), conf());
  //       println!("{}", cli(format!("payout summary {} --wallet-dir {}", cxid, DIR_2), conf()));
+        println!("debug payout script");
+        let response: JsonResponse<ScriptDebugSummary> = serde_json::from_str(&
+            cli(format!("contract debug-script {} --wallet-dir {} --json-output", cxid, DIR_1), conf())).unwrap();
+        assert!(!response.data.unwrap().valid);
+        let response: JsonResponse<ScriptDebugSummary> = serde_json::from_str(&
+            cli(format!("contract debug-script {} --token-sig {} --wallet-dir {} --json-output", cxid, sign_token(&event.outcomes[0].token), DIR_1), conf())).unwrap();
+        assert!(response.data.unwrap().valid);
         println!("submit payout to arbiter");
         let response = cli(format!("payout submit {} --wallet-dir {}", cxid, DIR_1), conf());
         assert_eq!(response, invalid_payout_response);
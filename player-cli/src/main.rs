@@ -4,13 +4,20 @@ use std::{
 };
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
-use tglib::mock::NETWORK;
+use tglib::config::Config;
 
 fn main() {
 
+    let conf = match Config::localhost().load() {
+        Ok(conf) => conf,
+        Err(e) => {
+            println!("{}", e);
+            return
+        }
+    };
     let work_dir: PathBuf = current_dir().unwrap();
     let mut history_file = work_dir;
-    history_file.push(&NETWORK.to_string());
+    history_file.push(&conf.network.to_string());
     history_file.push("history.txt");
     let history_file = history_file.as_path();
 
@@ -24,14 +31,8 @@ fn main() {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
-                let conf = libcli::Conf {
-                    electrum_url: "tcp://localhost:60401".into(),
-                    name_url: "http://localhost:18420".into(),
-                    arbiter_url: "http://localhost:5000".into(),
-                    exchange_url: "http://localhost:5050".into(),
-                };
                 rl.add_history_entry(line.clone());
-                println!("{}", libcli::cli(line, conf));
+                println!("{}", libcli::cli(line, conf.clone()));
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -6,7 +6,6 @@ use tglib::{
         PublicKey,
         consensus,
         hash_types::Txid,
-        secp256k1::Signature,
         util::psbt::PartiallySignedTransaction,
     },
     hex,
@@ -62,7 +61,7 @@ impl ArbiterService for ArbiterClient {
         }
     }
 
-    fn submit_contract(&self, contract: &Contract) -> Result<Signature> {
+    fn submit_contract(&self, contract: &Contract) -> Result<Contract> {
         let body = SubmitContractBody { 
             contract_hex: hex::encode(contract.to_bytes()) 
         };
@@ -71,13 +70,18 @@ impl ArbiterService for ArbiterClient {
 //        Ok(sig)
         let response: JsonResponse<String> = serde_json::from_str(&response.text()?)?;
         match response.status {
-            Status::Success => if let Some(sig_hex) = response.data {
-                Ok(Signature::from_der(&hex::decode(sig_hex)?)?)
+            Status::Success => if let Some(contract_hex) = response.data {
+                Ok(Contract::from_bytes(hex::decode(contract_hex)?)?)
             } else {
-                Err(Box::new(Error::Adhoc("missing signature in response")))
+                Err(Box::new(Error::Adhoc("missing contract in response")))
             }
+// a policy rejection comes with its code as the data
             Status::Error => {
-                Err(Error::JsonResponse(response.message.unwrap_or("unknown arbiter error".to_string())).into())
+                let message = response.message.unwrap_or("unknown arbiter error".to_string());
+                Err(Error::JsonResponse(match response.data {
+                    Some(code) => format!("{} ({})", message, code),
+                    None => message,
+                }).into())
             }
         }
     }
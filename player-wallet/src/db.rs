@@ -1,15 +1,24 @@
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, types::Type, Connection, Result};
 use serde::{
     Serialize,
     Deserialize,
 };
 use libexchange::{
     ContractRecord,
+    SplitRecord,
     TokenRecord,
     TokenContractRecord,
     PayoutRecord,
 };
-use tglib::player::PlayerName;
+use tglib::{
+    contract::{
+        Contract,
+        ContractState,
+    },
+    hex,
+    payout::PayoutState,
+    player::PlayerName,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlayerRecord {
@@ -60,8 +69,81 @@ impl DB {
                     FOREIGN KEY(player) REFERENCES player(name),
                     PRIMARY KEY(cxid, token)
                 );
+                CREATE TABLE IF NOT EXISTS split (
+                    cxid            TEXT NOT NULL,
+                    token           TEXT NOT NULL,
+                    desc            TEXT NOT NULL,
+                    p1_amount       INTEGER NOT NULL,
+                    p2_amount       INTEGER NOT NULL,
+                    FOREIGN KEY(cxid) REFERENCES contract(cxid),
+                    PRIMARY KEY(cxid, token)
+                );
             COMMIT;"
-        )
+        )?;
+        self.migrate()
+    }
+
+// sqlite's user_version is the last step that ran, a new step goes at the end with the next number
+    fn migrate(&self) -> Result<()> {
+        let version: u32 = self.conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        if version < 1 {
+            self.migrate_step(1, || self.rekey_contracts())?;
+        }
+        if version < 2 {
+            self.migrate_step(2, || self.conn.execute_batch(
+                "ALTER TABLE contract ADD COLUMN state TEXT;
+                ALTER TABLE payout ADD COLUMN state TEXT;"
+            ))?;
+        }
+        if version < 5 {
+            self.migrate_step(5, || self.conn.execute_batch("ALTER TABLE contract ADD COLUMN oracle_threshold INTEGER NOT NULL DEFAULT 1;"))?;
+        }
+// payout addresses used to come from each player's winning token
+        if version < 6 {
+            self.migrate_step(6, || self.conn.execute_batch(
+                "ALTER TABLE contract ADD COLUMN p1_payout_address TEXT NOT NULL DEFAULT '';
+                ALTER TABLE contract ADD COLUMN p2_payout_address TEXT NOT NULL DEFAULT '';
+                UPDATE contract SET
+                    p1_payout_address = COALESCE((SELECT address FROM token WHERE token.cxid = contract.cxid AND token.player = contract.p1_name), ''),
+                    p2_payout_address = COALESCE((SELECT address FROM token WHERE token.cxid = contract.cxid AND token.player = contract.p2_name), '');"
+            ))?;
+        }
+        Ok(())
+    }
+
+// a step and the version it brings the db to commit together, so a failed step is all rolled back
+// and the next open picks up from there instead of redoing the steps before it
+    fn migrate_step<F: FnOnce() -> Result<()>>(&self, version: u32, step: F) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        step()?;
+        tx.pragma_update(None, "user_version", &version)?;
+        tx.commit()
+    }
+
+// contracts used to be keyed on a hash of their payout script only, see Contract::cxid
+// the old signatures don't commit to the new cxid so they're dropped and the contract has to be signed again
+// a contract that doesn't decode can't be rekeyed, so the whole migration fails rather than leave it behind
+    fn rekey_contracts(&self) -> Result<()> {
+        let mut stmt = self.conn.prepare("SELECT cxid, hex FROM contract")?;
+        let rows = stmt.query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<(String, String)>>>()?;
+// everything is decoded before anything is written
+        let mut contracts = Vec::new();
+        for (old_cxid, contract_hex) in rows {
+            match hex::decode(&contract_hex).ok().and_then(|b| Contract::from_bytes(b).ok()) {
+                Some(contract) => contracts.push((old_cxid, contract)),
+                None => return Err(rusqlite::Error::FromSqlConversionFailure(1, Type::Text, format!("contract {} doesn't decode", old_cxid).into())),
+            }
+        }
+        for (old_cxid, mut contract) in contracts {
+            contract.sigs = Vec::new();
+            let cxid = hex::encode(contract.cxid());
+            self.conn.execute("UPDATE contract SET cxid = ?1, hex = ?2 WHERE cxid = ?3", params![cxid, hex::encode(contract.to_bytes()), old_cxid])?;
+            self.conn.execute("UPDATE payout SET cxid = ?1 WHERE cxid = ?2", params![cxid, old_cxid])?;
+            self.conn.execute("UPDATE token SET cxid = ?1 WHERE cxid = ?2", params![cxid, old_cxid])?;
+            self.conn.execute("UPDATE split SET cxid = ?1 WHERE cxid = ?2", params![cxid, old_cxid])?;
+        }
+        Ok(())
     }
 
     pub fn insert_player(&self, player: PlayerRecord) -> Result<usize> {
@@ -73,9 +155,9 @@ impl DB {
 
     pub fn insert_contract(&self, contract: ContractRecord) -> Result<usize> {
         self.conn.execute(
-            "INSERT INTO contract (cxid, p1_name, p2_name, hex, oracle_pubkey, desc) VALUES (?1, ?2, ?3, ?4, ?5, ?6) 
-            ON CONFLICT (cxid) DO UPDATE SET p1_name=?2, p2_name=?3, hex=?4, oracle_pubkey=?5, desc=?6",
-            params![contract.cxid, contract.p1_name.0, contract.p2_name.0, contract.hex, contract.oracle_pubkey, contract.desc],
+            "INSERT INTO contract (cxid, p1_name, p2_name, hex, oracle_pubkey, desc, oracle_threshold, p1_payout_address, p2_payout_address) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) 
+            ON CONFLICT (cxid) DO UPDATE SET p1_name=?2, p2_name=?3, hex=?4, oracle_pubkey=?5, desc=?6, oracle_threshold=?7, p1_payout_address=?8, p2_payout_address=?9",
+            params![contract.cxid, contract.p1_name.0, contract.p2_name.0, contract.hex, contract.oracle_pubkey, contract.desc, contract.oracle_threshold, contract.p1_payout_address, contract.p2_payout_address],
         )
     }
 
@@ -111,6 +193,9 @@ impl DB {
                 hex: row.get(3)?,
                 oracle_pubkey: row.get(4)?,
                 desc: row.get(5)?,
+                oracle_threshold: row.get("oracle_threshold")?,
+                p1_payout_address: row.get("p1_payout_address")?,
+                p2_payout_address: row.get("p2_payout_address")?,
             })
         })?;
 
@@ -131,6 +216,9 @@ impl DB {
                 hex: row.get(3)?,
                 oracle_pubkey: row.get(4)?,
                 desc: row.get(5)?,
+                oracle_threshold: row.get("oracle_threshold")?,
+                p1_payout_address: row.get("p1_payout_address")?,
+                p2_payout_address: row.get("p2_payout_address")?,
             })
         }).unwrap();
         if let Some(cr) = contract_iter.next() {
@@ -155,6 +243,19 @@ impl DB {
         )
     }
 
+// last known state, see Contract::state and Contract::chain_state
+    pub fn set_contract_state(&self, cxid: &str, state: ContractState) -> Result<usize> {
+        self.conn.execute(
+            "UPDATE contract SET state = ?1 WHERE cxid = ?2",
+            params![state.to_string(), cxid],
+        )
+    }
+
+    pub fn get_contract_state(&self, cxid: &str) -> Option<ContractState> {
+        let state: Option<String> = self.conn.query_row("SELECT state FROM contract WHERE cxid = ?1", params![cxid], |row| row.get(0)).ok()?;
+        state?.parse().ok()
+    }
+
     pub fn insert_payout(&self, payout: PayoutRecord) -> Result<usize> {
         self.conn.execute(
             "INSERT INTO payout (cxid, psbt, sig) VALUES (?1, ?2, ?3) ON CONFLICT(cxid) DO UPDATE SET
@@ -196,6 +297,18 @@ impl DB {
         }
     }
 
+    pub fn set_payout_state(&self, cxid: &str, state: PayoutState) -> Result<usize> {
+        self.conn.execute(
+            "UPDATE payout SET state = ?1 WHERE cxid = ?2",
+            params![state.to_string(), cxid],
+        )
+    }
+
+    pub fn get_payout_state(&self, cxid: &str) -> Option<PayoutState> {
+        let state: Option<String> = self.conn.query_row("SELECT state FROM payout WHERE cxid = ?1", params![cxid], |row| row.get(0)).ok()?;
+        state?.parse().ok()
+    }
+
     pub fn delete_payout(&self, cxid: &str) -> Result<usize> {
         self.conn.execute(
             "DELETE FROM payout WHERE cxid = ?1",
@@ -210,24 +323,54 @@ impl DB {
         )
     }
 
+    pub fn insert_split(&self, split_record: SplitRecord) -> Result<usize> {
+        self.conn.execute(
+            "INSERT INTO split (cxid, token, desc, p1_amount, p2_amount) VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT DO NOTHING",
+            params![split_record.cxid, split_record.token, split_record.desc, split_record.p1_amount as i64, split_record.p2_amount as i64],
+        )
+    }
+
     pub fn insert_token_contract(&self, tcr: TokenContractRecord) -> Result<usize> {
-        self.insert_contract(tcr.contract_record)?;
-        self.insert_token(tcr.p1_token)?;
-        self.insert_token(tcr.p2_token)?;
-        Ok(3)
+        let mut inserted = self.insert_contract(tcr.contract_record)?;
+        for token in tcr.p1_token.into_iter().chain(tcr.p2_token) {
+            inserted += self.insert_token(token)?;
+        }
+        for split in tcr.splits {
+            inserted += self.insert_split(split)?;
+        }
+        Ok(inserted)
     }
 
     pub fn get_token_contract(&self, cxid: &str) -> Result<TokenContractRecord> {
         let mut stmt = self.conn.prepare(&format!("{} WHERE contract.cxid = ?1", DB::select_token_contract_stmt()))?;
-        stmt.query_row(params![cxid], |row| { DB::tcr_from_row(row) })
+        let mut tcr = stmt.query_row(params![cxid], |row| { DB::tcr_from_row(row) })?;
+        tcr.splits = self.get_splits(cxid)?;
+        Ok(tcr)
     }
 
     pub fn all_token_contracts(&self) -> Result<Vec<TokenContractRecord>> {
         let mut stmt = self.conn.prepare(&DB::select_token_contract_stmt())?;
         let records = stmt.query_map(params![], |row| {
             DB::tcr_from_row(row)
-        })?.collect::<Vec<Result<TokenContractRecord>>>().into_iter().collect();
-        records
+        })?.collect::<Result<Vec<TokenContractRecord>>>()?;
+        records.into_iter().map(|mut tcr| {
+            tcr.splits = self.get_splits(&tcr.contract_record.cxid)?;
+            Ok(tcr)
+        }).collect()
+    }
+
+    pub fn get_splits(&self, cxid: &str) -> Result<Vec<SplitRecord>> {
+        let mut stmt = self.conn.prepare("SELECT cxid, token, desc, p1_amount, p2_amount FROM split WHERE cxid = ?1")?;
+        let splits = stmt.query_map(params![cxid], |row| {
+            Ok(SplitRecord {
+                cxid: row.get(0)?,
+                token: row.get(1)?,
+                desc: row.get(2)?,
+                p1_amount: row.get::<_, i64>(3)? as u64,
+                p2_amount: row.get::<_, i64>(4)? as u64,
+            })
+        })?.collect();
+        splits
     }
 
     fn select_token_contract_stmt() -> String {
@@ -237,6 +380,9 @@ impl DB {
                 contract.p2_name, 
                 contract.hex, 
                 contract.oracle_pubkey, 
+                contract.oracle_threshold, 
+                contract.p1_payout_address, 
+                contract.p2_payout_address, 
                 contract.desc, 
                 p1token.token AS p1_token, 
                 p1token.address AS p1_address, 
@@ -245,8 +391,8 @@ impl DB {
                 p2token.address AS p2_address, 
                 p2token.desc AS p2_token_desc
             FROM contract
-            JOIN token AS p1token ON contract.cxid = p1token.cxid AND contract.p1_name = p1token.player
-            JOIN token AS p2token ON contract.cxid = p2token.cxid AND contract.p2_name = p2token.player".to_string()
+            LEFT JOIN token AS p1token ON contract.cxid = p1token.cxid AND contract.p1_name = p1token.player
+            LEFT JOIN token AS p2token ON contract.cxid = p2token.cxid AND contract.p2_name = p2token.player".to_string()
     }
 
     fn tcr_from_row(row: &rusqlite::Row) -> Result<TokenContractRecord> {
@@ -257,24 +403,31 @@ impl DB {
                 p2_name: PlayerName(row.get("p2_name")?),
                 hex: row.get("hex")?,
                 oracle_pubkey: row.get("oracle_pubkey")?,
+                oracle_threshold: row.get("oracle_threshold")?,
+                p1_payout_address: row.get("p1_payout_address")?,
+                p2_payout_address: row.get("p2_payout_address")?,
                 desc: row.get("desc")?,
             },
-            p1_token: TokenRecord {
-                cxid: row.get("cxid")?,
-                token: row.get("p1_token")?,
-                player: PlayerName(row.get("p1_name")?),
-                address: row.get("p1_address")?,
-                desc: row.get("p1_token_desc")?,
-            },
-            p2_token: TokenRecord {
-                cxid: row.get("cxid")?,
-                token: row.get("p2_token")?,
-                player: PlayerName(row.get("p2_name")?),
-                address: row.get("p2_address")?,
-                desc: row.get("p2_token_desc")?,
-            },
+            p1_token: DB::token_from_row(row, "p1")?,
+            p2_token: DB::token_from_row(row, "p2")?,
+            splits: Vec::new(),
         })
     }
+
+// null when the player has no winner-takes-all outcome
+    fn token_from_row(row: &rusqlite::Row, player: &str) -> Result<Option<TokenRecord>> {
+        let token: Option<String> = row.get(format!("{}_token", player).as_str())?;
+        match token {
+            Some(token) => Ok(Some(TokenRecord {
+                cxid: row.get("cxid")?,
+                token,
+                player: PlayerName(row.get(format!("{}_name", player).as_str())?),
+                address: row.get(format!("{}_address", player).as_str())?,
+                desc: row.get(format!("{}_token_desc", player).as_str())?,
+            })),
+            None => Ok(None),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -286,6 +439,22 @@ mod test {
         fs::remove_file,
         path::PathBuf,
     };
+    use tglib::bdk::bitcoin::{
+        Network,
+        OutPoint,
+        PublicKey,
+        Script,
+        Transaction,
+        TxIn,
+        TxOut,
+        secp256k1::{
+            self,
+            Message,
+            Secp256k1,
+            SecretKey,
+        },
+        util::psbt::PartiallySignedTransaction,
+    };
 
     #[test]
     fn test_create_tables() -> Result<()> {
@@ -300,4 +469,241 @@ mod test {
         remove_file(db_path).unwrap();
         Ok(())
     }
+
+    fn test_contract() -> Contract {
+        let secp = Secp256k1::new();
+        let pubkeys: Vec<PublicKey> = (1..4u8).map(|i| {
+            let key = SecretKey::from_slice(&[i; 32]).unwrap();
+            PublicKey { compressed: true, key: secp256k1::PublicKey::from_secret_key(&secp, &key) }
+        }).collect();
+        let funding_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec!(TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0,
+                witness: Vec::new(),
+            }),
+            output: vec!(TxOut { value: 1_000_000, script_pubkey: Script::new() }),
+        };
+        let mut contract = Contract::new(
+            pubkeys[0],
+            pubkeys[1],
+            pubkeys[2],
+            PartiallySignedTransaction::from_unsigned_tx(funding_tx).unwrap(),
+            "PUSHDATA1 0xff PUSHTXID EQUAL VALIDATE".parse().unwrap(),
+            1,
+            Network::Regtest,
+        );
+        contract.sigs.push(secp.sign(&Message::from_slice(&[1; 32]).unwrap(), &SecretKey::from_slice(&[1; 32]).unwrap()));
+        contract
+    }
+
+    #[test]
+    fn test_rekey_contracts() -> Result<()> {
+        let mut db_path: PathBuf = current_dir().unwrap();
+        db_path.push("test_rekey_contracts.db");
+        let db = DB::new(&db_path)?;
+        db.create_tables()?;
+
+        let contract = test_contract();
+        db.insert_player(PlayerRecord { name: PlayerName("p1".to_string()) })?;
+        db.insert_player(PlayerRecord { name: PlayerName("p2".to_string()) })?;
+        db.insert_contract(ContractRecord {
+            cxid: "old".to_string(),
+            p1_name: PlayerName("p1".to_string()),
+            p2_name: PlayerName("p2".to_string()),
+            hex: hex::encode(contract.to_bytes()),
+            oracle_pubkey: String::new(),
+            oracle_threshold: 1,
+            p1_payout_address: String::new(),
+            p2_payout_address: String::new(),
+            desc: String::new(),
+        })?;
+        db.insert_token(TokenRecord {
+            cxid: "old".to_string(),
+            token: "00".to_string(),
+            player: PlayerName("p1".to_string()),
+            address: String::new(),
+            desc: String::new(),
+        })?;
+        db.rekey_contracts()?;
+
+        let cxid = hex::encode(contract.cxid());
+        assert!(db.get_contract("old").is_none());
+        let cr = db.get_contract(&cxid).unwrap();
+        assert!(Contract::from_bytes(hex::decode(cr.hex).unwrap()).unwrap().sigs.is_empty());
+        let token_cxid: String = db.conn.query_row("SELECT cxid FROM token", params![], |row| row.get(0))?;
+        assert_eq!(token_cxid, cxid);
+
+        assert_eq!(db.get_contract_state(&cxid), None);
+        db.set_contract_state(&cxid, ContractState::Live)?;
+        assert_eq!(db.get_contract_state(&cxid), Some(ContractState::Live));
+
+        db.conn.close().unwrap();
+        remove_file(db_path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_bad_contract() -> Result<()> {
+        let mut db_path: PathBuf = current_dir().unwrap();
+        db_path.push("test_rekey_bad_contract.db");
+        let db = DB::new(&db_path)?;
+        db.create_tables()?;
+
+        let contract = test_contract();
+        db.insert_player(PlayerRecord { name: PlayerName("p1".to_string()) })?;
+        db.insert_player(PlayerRecord { name: PlayerName("p2".to_string()) })?;
+        for (cxid, hex) in vec![("old", hex::encode(contract.to_bytes())), ("bad", "00".to_string())] {
+            db.insert_contract(ContractRecord {
+                cxid: cxid.to_string(),
+                p1_name: PlayerName("p1".to_string()),
+                p2_name: PlayerName("p2".to_string()),
+                hex,
+                oracle_pubkey: String::new(),
+                oracle_threshold: 1,
+                p1_payout_address: String::new(),
+                p2_payout_address: String::new(),
+                desc: String::new(),
+            })?;
+        }
+        assert!(db.rekey_contracts().is_err());
+// nothing was rekeyed
+        assert!(db.get_contract("old").is_some());
+        assert!(db.get_contract("bad").is_some());
+
+        db.conn.close().unwrap();
+        remove_file(db_path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_migration() -> Result<()> {
+        let mut db_path: PathBuf = current_dir().unwrap();
+        db_path.push("test_failed_migration.db");
+        let db = DB::new(&db_path)?;
+// a version 0 db that already has a column the last step adds, so that step fails
+        db.conn.execute_batch(
+            "CREATE TABLE contract (
+                cxid            TEXT PRIMARY KEY,
+                p1_name         TEXT NOT NULL,
+                p2_name         TEXT NOT NULL,
+                hex             TEXT NOT NULL,
+                oracle_pubkey   TEXT NOT NULL,
+                desc            TEXT,
+                p2_payout_address TEXT
+            );"
+        )?;
+        assert!(db.create_tables().is_err());
+// the earlier steps stuck, none of the failed one did
+        let version: u32 = db.conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        assert_eq!(version, 5);
+        assert!(db.conn.execute_batch("SELECT state, oracle_threshold FROM contract").is_ok());
+        assert!(db.conn.execute_batch("SELECT p1_payout_address FROM contract").is_err());
+        assert!(db.migrate().is_err());
+        let version: u32 = db.conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+        assert_eq!(version, 5);
+
+        db.conn.close().unwrap();
+        remove_file(db_path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_contract_splits() -> Result<()> {
+        let mut db_path: PathBuf = current_dir().unwrap();
+        db_path.push("test_token_contract_splits.db");
+        let db = DB::new(&db_path)?;
+        db.create_tables()?;
+
+        let cxid = hex::encode(test_contract().cxid());
+        let token = |token: &str, player: &str| TokenRecord {
+            cxid: cxid.clone(),
+            token: token.to_string(),
+            player: PlayerName(player.to_string()),
+            address: String::new(),
+            desc: String::new(),
+        };
+        db.insert_player(PlayerRecord { name: PlayerName("p1".to_string()) })?;
+        db.insert_player(PlayerRecord { name: PlayerName("p2".to_string()) })?;
+        db.insert_token_contract(TokenContractRecord {
+            contract_record: ContractRecord {
+                cxid: cxid.clone(),
+                p1_name: PlayerName("p1".to_string()),
+                p2_name: PlayerName("p2".to_string()),
+                hex: hex::encode(test_contract().to_bytes()),
+                oracle_pubkey: String::new(),
+                oracle_threshold: 1,
+                p1_payout_address: String::new(),
+                p2_payout_address: String::new(),
+                desc: String::new(),
+            },
+            p1_token: Some(token("01", "p1")),
+            p2_token: Some(token("02", "p2")),
+            splits: vec![SplitRecord {
+                cxid: cxid.clone(),
+                token: "03".to_string(),
+                desc: "draw".to_string(),
+                p1_amount: 500_000,
+                p2_amount: 500_000,
+            }],
+        })?;
+
+        let tcr = db.get_token_contract(&cxid)?;
+        assert_eq!(tcr.p1_token.unwrap().token, "01");
+        assert_eq!(tcr.splits.len(), 1);
+        assert_eq!(tcr.splits[0].p2_amount, 500_000);
+        assert_eq!(db.all_token_contracts()?.len(), 1);
+
+        db.conn.close().unwrap();
+        remove_file(db_path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_only_contract() -> Result<()> {
+        let mut db_path: PathBuf = current_dir().unwrap();
+        db_path.push("test_split_only_contract.db");
+        let db = DB::new(&db_path)?;
+        db.create_tables()?;
+
+        let cxid = hex::encode(test_contract().cxid());
+        db.insert_player(PlayerRecord { name: PlayerName("p1".to_string()) })?;
+        db.insert_player(PlayerRecord { name: PlayerName("p2".to_string()) })?;
+        db.insert_token_contract(TokenContractRecord {
+            contract_record: ContractRecord {
+                cxid: cxid.clone(),
+                p1_name: PlayerName("p1".to_string()),
+                p2_name: PlayerName("p2".to_string()),
+                hex: hex::encode(test_contract().to_bytes()),
+                oracle_pubkey: String::new(),
+                oracle_threshold: 1,
+                p1_payout_address: "p1 address".to_string(),
+                p2_payout_address: "p2 address".to_string(),
+                desc: String::new(),
+            },
+            p1_token: None,
+            p2_token: None,
+            splits: vec![SplitRecord {
+                cxid: cxid.clone(),
+                token: "03".to_string(),
+                desc: "draw".to_string(),
+                p1_amount: 500_000,
+                p2_amount: 500_000,
+            }],
+        })?;
+
+        let tcr = db.get_token_contract(&cxid)?;
+        assert!(tcr.p1_token.is_none());
+        assert!(tcr.p2_token.is_none());
+        assert_eq!(tcr.contract_record.p2_payout_address, "p2 address");
+        assert_eq!(tcr.splits.len(), 1);
+        assert_eq!(db.all_token_contracts()?.len(), 1);
+
+        db.conn.close().unwrap();
+        remove_file(db_path).unwrap();
+        Ok(())
+    }
 }
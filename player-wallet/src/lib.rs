@@ -95,12 +95,14 @@ mod tests {
         mock::{
             get_referee_signature,
             ARBITER_MNEMONIC,
-            ESCROW_KIX,
-            ESCROW_SUBACCOUNT,
             NETWORK,
             PLAYER_1_MNEMONIC,
             PLAYER_2_MNEMONIC,
         },
+        wallet::{
+            ESCROW_KIX,
+            ESCROW_SUBACCOUNT,
+        },
     };
 
     const ELECTRUM_URL: &'static str = "tcp://localhost:60401";
@@ -135,7 +137,7 @@ mod tests {
         payout.psbt = sign_payout_psbt(&wallet, &mut payout).unwrap();
         let arbiter_wallet = Trezor::new(Mnemonic::parse(ARBITER_MNEMONIC).unwrap());
         let msg = Message::from_slice(&payout.psbt.clone().extract_tx().txid()).unwrap();
-        payout.script_sig = Some(get_referee_signature(msg));
+        payout.script_sigs = vec![get_referee_signature(msg)];
 
         assert!(arbiter_wallet.validate_payout(&payout).is_ok())
     }
@@ -151,7 +153,7 @@ mod tests {
         payout.psbt = sign_payout_psbt(&wallet, &mut payout).unwrap();
         let arbiter_wallet = Trezor::new(Mnemonic::parse(ARBITER_MNEMONIC).unwrap());
         let msg = Message::from_slice(&payout.psbt.clone().extract_tx().txid()).unwrap();
-        payout.script_sig = Some(get_referee_signature(msg));
+        payout.script_sigs = vec![get_referee_signature(msg)];
 
         let r = arbiter_wallet.validate_payout(&payout);
         assert!(r.is_ok())
@@ -167,7 +169,7 @@ mod tests {
         payout.psbt = sign_payout_psbt(&wallet, &mut payout).unwrap();
         let arbiter_wallet = Trezor::new(Mnemonic::parse(ARBITER_MNEMONIC).unwrap());
         let msg = Message::from_slice(&payout.psbt.clone().extract_tx().txid()).unwrap();
-        payout.script_sig = Some(get_referee_signature(msg));
+        payout.script_sigs = vec![get_referee_signature(msg)];
 
         assert!(arbiter_wallet.validate_payout(&payout).is_err())
     }
@@ -197,8 +199,8 @@ mod tests {
         payout.psbt = sign_payout_psbt(&wallet, &mut payout).unwrap();
         let arbiter_wallet = Trezor::new(Mnemonic::parse(ARBITER_MNEMONIC).unwrap());
 // signing with the player's wallet incorrectly
-        payout.script_sig = Some(wallet.sign_message(Message::from_slice(&payout.psbt.clone().extract_tx().txid()).unwrap(), 
-                DerivationPath::from_str(&format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX)).unwrap()).unwrap());
+        payout.script_sigs = vec![wallet.sign_message(Message::from_slice(&payout.psbt.clone().extract_tx().txid()).unwrap(), 
+                DerivationPath::from_str(&format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX)).unwrap()).unwrap()];
 
         assert!(arbiter_wallet.validate_payout(&payout).is_err())
     }
@@ -213,7 +215,7 @@ mod tests {
 
         let arbiter_wallet = Trezor::new(Mnemonic::parse(ARBITER_MNEMONIC).unwrap());
         let msg = Message::from_slice(&payout.psbt.clone().extract_tx().txid()).unwrap();
-        payout.script_sig = Some(get_referee_signature(msg));
+        payout.script_sigs = vec![get_referee_signature(msg)];
         assert!(arbiter_wallet.validate_payout(&payout).is_err())
 
     }
@@ -229,7 +231,7 @@ mod tests {
         payout.psbt = sign_payout_psbt(&wallet, &mut payout).unwrap();
         let arbiter_wallet = Trezor::new(Mnemonic::parse(ARBITER_MNEMONIC).unwrap());
         let msg = Message::from_slice(&payout.psbt.clone().extract_tx().txid()).unwrap();
-        payout.script_sig = Some(get_referee_signature(msg));
+        payout.script_sigs = vec![get_referee_signature(msg)];
         assert!(arbiter_wallet.validate_payout(&payout).is_err())
     }
 }
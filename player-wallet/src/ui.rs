@@ -14,6 +14,7 @@ use tglib::{
         bitcoin::{
             Address,
             Amount,
+            PublicKey,
             consensus,
             hash_types::Txid,
             hashes::{
@@ -35,8 +36,15 @@ use tglib::{
     hex,
     secrecy::Secret,
     arbiter::ArbiterService,
-    contract::Contract,
-    payout::Payout,
+    contract::{
+        Contract,
+        ContractState,
+    },
+    payout::{
+        Payout,
+        PayoutState,
+        PAYOUT_VERSION,
+    },
     player::{
         PlayerName,
         PlayerNameService,
@@ -49,7 +57,6 @@ use tglib::{
         NAME_SUBACCOUNT,
         NAME_KIX,
     },
-    mock::PAYOUT_VERSION,
 };
 use crate::{
     Error,
@@ -85,6 +92,65 @@ impl PlayerWallet {
             sig_hex,
         })
     }
+
+// broadcast the contract's pre-signed refund tx once its lock time has passed
+    pub fn refund(&self, cxid: &str) -> Result<Txid> {
+        let cr = self.db().get_contract(&cxid).ok_or(Error::Adhoc("unknown contract"))?;
+        let contract = Contract::from_bytes(hex::decode(cr.hex)?)?;
+        let refund_tx = contract.refund_tx.ok_or(Error::Adhoc("contract has no refund tx"))?;
+        if refund_tx.inputs.iter().any(|input| input.final_script_witness.is_none()) {
+            return Err(Error::Adhoc("refund tx isn't fully signed").into())
+        }
+        let tx = refund_tx.extract_tx();
+        let wallet = self.wallet()?;
+        if wallet.client().get_height()? < tx.lock_time {
+            return Err(Error::Adhoc("refund tx is still timelocked").into())
+        }
+        wallet.broadcast(tx.clone())?;
+        Ok(tx.txid())
+    }
+
+// check the chain for the contract's state and remember it
+    pub fn contract_state(&self, cxid: &str) -> Result<ContractState> {
+        let cr = self.db().get_contract(&cxid).ok_or(Error::Adhoc("unknown contract"))?;
+        let contract = Contract::from_bytes(hex::decode(cr.hex)?)?;
+        let state = contract.chain_state(&self.electrum_client()?)?;
+        self.db().set_contract_state(&cxid, state)?;
+        Ok(state)
+    }
+
+    pub fn payout_state(&self, cxid: &str) -> Result<PayoutState> {
+        let payout = self.get_payout(cxid)?;
+        let state = payout.chain_state(&self.electrum_client()?)?;
+        self.db().set_payout_state(&cxid, state)?;
+        Ok(state)
+    }
+
+// cpfp for a payout tx stuck in the mempool, at fee_rate or the electrum estimate
+    pub fn bump_payout(&self, cxid: &str, fee_rate: Option<u64>, pw: Secret<String>) -> Result<Txid> {
+        let payout = self.get_payout(cxid)?;
+        if self.payout_state(cxid)? != PayoutState::Live {
+            return Err(Error::Adhoc("payout tx isn't waiting to confirm").into())
+        }
+        let fee_rate = match fee_rate {
+            Some(fee_rate) => fee_rate,
+            None => self.estimate_fee_rate()?,
+        };
+        let tx = self.create_bump_tx(&payout, fee_rate, pw)?;
+        self.wallet()?.broadcast(tx.clone())?;
+        Ok(tx.txid())
+    }
+
+    fn get_payout(&self, cxid: &str) -> Result<Payout> {
+        let cr = self.db().get_contract(&cxid).ok_or(Error::Adhoc("unknown contract"))?;
+        let pr = self.db().get_payout(&cxid).ok_or(Error::Adhoc("unknown payout"))?;
+        Ok(Payout {
+            version: PAYOUT_VERSION,
+            contract: Contract::from_bytes(hex::decode(cr.hex)?)?,
+            psbt: consensus::deserialize(&hex::decode(&pr.psbt)?)?,
+            script_sigs: pr.script_sigs(),
+        })
+    }
 }
 
 // players
@@ -119,7 +185,14 @@ pub enum NewDocumentParams {
         p2_name: PlayerName, 
         amount: Amount, 
         event: Event,
-        event_payouts: Vec<PlayerName>,
+// (p1 amount, p2 amount) for each event outcome, in outcome order
+        event_splits: Vec<(Amount, Amount)>,
+// oracles signing the outcome tokens along with the event's, for a threshold contract
+// where oracle_threshold of them have to sign. none and 1 for the usual single oracle
+        extra_oracle_pubkeys: Vec<PublicKey>,
+        oracle_threshold: u8,
+// sat/vbyte for the funding and payout txs, estimated from electrum if not given
+        fee_rate: Option<u64>,
     },
     NewPayoutParams { 
         cxid: String, 
@@ -130,7 +203,7 @@ pub enum NewDocumentParams {
 
 pub enum SignDocumentParams {
     SignContractParams { cxid: String, sign_funding_tx: bool },
-    SignPayoutParams { cxid: String, script_sig: Option<Signature> },
+    SignPayoutParams { cxid: String, script_sigs: Vec<Signature> },
 }
 
 impl WalletUI for PlayerWallet {
@@ -235,8 +308,8 @@ impl PlayerUI for PlayerWallet {
 
 impl DocumentUI<TokenContractRecord> for PlayerWallet {
     fn new(&self, params: NewDocumentParams) -> Result<TokenContractRecord> {
-        let (p1_name, p2_name, amount, event, event_payouts) = match params {
-            NewDocumentParams::NewContractParams { p1_name, p2_name, amount, event, event_payouts } => (p1_name, p2_name, amount, event, event_payouts),
+        let (p1_name, p2_name, amount, event, event_splits, extra_oracle_pubkeys, oracle_threshold, fee_rate) = match params {
+            NewDocumentParams::NewContractParams { p1_name, p2_name, amount, event, event_splits, extra_oracle_pubkeys, oracle_threshold, fee_rate } => (p1_name, p2_name, amount, event, event_splits, extra_oracle_pubkeys, oracle_threshold, fee_rate),
             _ => return Err(Error::Adhoc("invalid params").into()),
         };
 
@@ -246,6 +319,12 @@ impl DocumentUI<TokenContractRecord> for PlayerWallet {
 
 // TODO: check if p2_name is registered, or just let the future contract info check fail
 // if p2 isn't registered, they couldn't have posted contract info
+        let p1_contract_info = match self.exchange_client().get_contract_info(p1_name.clone())? {
+            Some(info) => info,
+            None => {
+                return Err(Error::Adhoc("can't create contract: couldn't fetch p1 contract info").into())
+            }
+        };
         let p2_contract_info = match self.exchange_client().get_contract_info(p2_name.clone())? {
             Some(info) => info,
             None => {
@@ -259,15 +338,27 @@ impl DocumentUI<TokenContractRecord> for PlayerWallet {
 //            Err(_) => return Err(Error::Adhoc("can't create contract: couldn't get arbiter pubkey").into())
 //        };
 
+        let fee_rate = match fee_rate {
+            Some(fee_rate) => fee_rate,
+            None => self.estimate_fee_rate()?,
+        };
+
+        let mut oracle_pubkeys = vec![event.oracle_pubkey];
+        oracle_pubkeys.extend(extra_oracle_pubkeys);
+
 // TODO: could fail if amount is too large
-        let (contract, token_records) = self.create_event_contract(
+        let (contract, (p1_payout_address, p2_payout_address), token_records, split_records) = self.create_event_contract(
             &p1_name, 
             &p2_name, 
+            p1_contract_info, 
             p2_contract_info, 
             amount, 
             arbiter_pubkey, 
             &event, 
-            &event_payouts
+            &event_splits,
+            &oracle_pubkeys,
+            oracle_threshold,
+            fee_rate,
         )?;
 
         let contract_record = ContractRecord {
@@ -275,20 +366,28 @@ impl DocumentUI<TokenContractRecord> for PlayerWallet {
             p1_name: p1_name.clone(),
             p2_name: p2_name.clone(),
             hex: hex::encode(contract.to_bytes()),
-            oracle_pubkey: event.oracle_pubkey.to_string(),
+            oracle_pubkey: oracle_pubkeys.iter().map(|pubkey| pubkey.to_string()).collect::<Vec<String>>().join(","),
+            oracle_threshold,
+            p1_payout_address: p1_payout_address.to_string(),
+            p2_payout_address: p2_payout_address.to_string(),
             desc: event.desc.clone(),
         };
 
         self.db().insert_contract(contract_record.clone())?;
+        self.db().set_contract_state(&contract_record.cxid, contract.state())?;
 
         for record in token_records.iter().cloned() {
             self.db().insert_token(record)?;
         }
+        for record in split_records.iter().cloned() {
+            self.db().insert_split(record)?;
+        }
 
         Ok(TokenContractRecord {
             contract_record,
-            p1_token: token_records.iter().cloned().find(|record| record.player == p1_name).unwrap().clone(),
-            p2_token: token_records.iter().cloned().find(|record| record.player == p2_name).unwrap().clone(),
+            p1_token: token_records.iter().cloned().find(|record| record.player == p1_name),
+            p2_token: token_records.iter().cloned().find(|record| record.player == p2_name),
+            splits: split_records,
         })
     }
 
@@ -343,14 +442,21 @@ impl DocumentUI<TokenContractRecord> for PlayerWallet {
         };
         if let Some(contract_record) = self.db().get_contract(&cxid) {
             let mut contract = Contract::from_bytes(hex::decode(contract_record.hex.clone()).unwrap()).unwrap();
+            self.validate_contract(&contract)?;
+            contract.validate_refund_lock_time(self.wallet()?.client().get_height()?)?;
+            self.validate_funding(&contract, &contract_record)?;
             let sig = sign_contract(self, &contract, pw.clone()).unwrap();
             if !contract.sigs.contains(&sig) {
                 contract.sigs.push(sig);
             }
+            if let Some(refund_tx) = contract.refund_tx.clone() {
+                contract.refund_tx = Some(self.sign_escrow_psbt(&contract, refund_tx, pw.clone())?);
+            }
             if sign_funding_tx {
                 contract.funding_tx = self.sign_tx(contract.funding_tx.clone(), None, pw).unwrap();
             }
-            let _r = self.db().add_signature(contract_record.cxid, hex::encode(contract.to_bytes()));
+            let _r = self.db().add_signature(contract_record.cxid.clone(), hex::encode(contract.to_bytes()));
+            self.db().set_contract_state(&contract_record.cxid, contract.state())?;
             Ok(())
         } else {
             Err(Error::Adhoc("unknown contract").into())
@@ -381,10 +487,13 @@ impl DocumentUI<TokenContractRecord> for PlayerWallet {
 
     fn submit(&self, cxid: &str) -> Result<()> {
         if let Some(cr) = self.db().get_contract(&cxid) {
-            let mut contract = Contract::from_bytes(hex::decode(cr.hex)?)?;
-            if let Ok(sig) = self.arbiter_client().submit_contract(&contract) {
-               contract.sigs.push(sig);
-               let _r = self.db().add_signature(cr.cxid, hex::encode(contract.to_bytes()));
+            let contract = Contract::from_bytes(hex::decode(cr.hex)?)?;
+            if let Ok(contract) = self.arbiter_client().submit_contract(&contract) {
+               if hex::encode(contract.cxid()) != cr.cxid {
+                   return Err(Error::Adhoc("arbiter returned a different contract").into())
+               }
+               let _r = self.db().add_signature(cr.cxid.clone(), hex::encode(contract.to_bytes()));
+               self.db().set_contract_state(&cr.cxid, contract.state())?;
                Ok(())
             }
             else {
@@ -426,8 +535,10 @@ impl DocumentUI<PayoutRecord> for PlayerWallet {
         };
         let tcr = self.db().get_token_contract(&cxid).unwrap();
         let payout = self.create_payout(&tcr, p1_amount, p2_amount)?;
+        let state = payout.state();
         let payout_record = PayoutRecord::from(payout);
         let _r = self.db().insert_payout(payout_record.clone());
+        self.db().set_payout_state(&cxid, state)?;
         Ok(payout_record)
     }
 
@@ -448,7 +559,7 @@ impl DocumentUI<PayoutRecord> for PlayerWallet {
             version: PAYOUT_VERSION,
             contract: Contract::from_bytes(hex::decode(cr.hex).unwrap()).unwrap(),
             psbt: consensus::deserialize(&hex::decode(pr.psbt).unwrap()).unwrap(),
-            script_sig: Signature::from_der(&hex::decode(pr.sig).unwrap()).ok()
+            script_sigs: pr.script_sigs(),
         };
         Some(hex::encode(p.to_bytes()))
     }
@@ -458,25 +569,23 @@ impl DocumentUI<PayoutRecord> for PlayerWallet {
     }
 
     fn sign(&self, params: SignDocumentParams, pw: Secret<String>) -> Result<()> {
-        let (cxid, script_sig) = match params {
-            SignDocumentParams::SignPayoutParams { cxid, script_sig } => (cxid, script_sig),
+        let (cxid, script_sigs) = match params {
+            SignDocumentParams::SignPayoutParams { cxid, script_sigs } => (cxid, script_sigs),
             _ => return Err(Error::Adhoc("invalid params").into()),
         };
         let pr = self.db().get_payout(&cxid).ok_or(Error::Adhoc("unknown payout"))?;
         let tcr = DocumentUI::<TokenContractRecord>::get(self, &cxid).ok_or(Error::Adhoc("unknown contract"))?;
         let contract = Contract::from_bytes(hex::decode(tcr.contract_record.hex)?)?;
         let psbt: PartiallySignedTransaction = consensus::deserialize(&hex::decode(pr.psbt)?)?;
-        let payout = Payout::new(contract, psbt);
-        let psbt = self.sign_payout(payout, pw)?;
-        let psbt = hex::encode(consensus::serialize(&psbt));
+        let mut payout = Payout::new(contract, psbt);
+        payout.psbt = self.sign_payout(payout.clone(), pw)?;
+        let psbt = hex::encode(consensus::serialize(&payout.psbt));
         self.db().insert_payout(PayoutRecord {
-            cxid: pr.cxid, 
+            cxid: pr.cxid.clone(), 
             psbt,
-            sig: match script_sig {
-                Some(sig) => hex::encode(sig.serialize_der()),
-                None => String::default(),
-            }
+            sig: script_sigs.iter().map(|sig| hex::encode(sig.serialize_der())).collect::<Vec<String>>().join(","),
         })?;
+        self.db().set_payout_state(&pr.cxid, payout.state())?;
         Ok(())
     }
 
@@ -508,11 +617,13 @@ impl DocumentUI<PayoutRecord> for PlayerWallet {
 // TODO: poster child for serde hell
             contract: Contract::from_bytes(hex::decode(cr.hex).unwrap()).unwrap(),
             psbt: consensus::deserialize(&hex::decode(pr.psbt).unwrap()).unwrap(),
-            script_sig: Signature::from_der(&hex::decode(pr.sig).unwrap()).ok()
+            script_sigs: pr.script_sigs(),
         };
         let psbt = self.arbiter_client().submit_payout(&p)?; 
         p.psbt = psbt; 
+        let state = p.state();
         self.db().insert_payout(PayoutRecord::from(p)).unwrap();
+        self.db().set_payout_state(&cxid, state)?;
         Ok(())
     }
 
@@ -5,6 +5,8 @@ use std::{
 };
 use libexchange::{
     ContractRecord,
+    ExchangeService,
+    SplitRecord,
     TokenRecord,
     TokenContractRecord,
     PlayerContractInfo,
@@ -16,12 +18,12 @@ use tglib::{
             Address,
             Amount,
             Network,
+            OutPoint,
             PublicKey,
             Script,
             Transaction,
             TxIn,
             TxOut,
-            blockdata::transaction::OutPoint,
             secp256k1::{
                 Message,
                 Secp256k1,
@@ -37,11 +39,15 @@ use tglib::{
         },
         blockchain::{
             noop_progress,
+            Blockchain,
             ElectrumBlockchain,
         },
         electrum_client::Client as ElectrumClient,
+        miniscript::DescriptorTrait,
         signer::TransactionSigner,
         signer::SignOptions,
+        FeeRate,
+        TransactionDetails,
         Wallet,
         wallet::AddressIndex::New,
     },
@@ -49,34 +55,44 @@ use tglib::{
     Result as TgResult,
     Error as TgError,
     arbiter::ArbiterService,
+    chain::estimate_fee_rate,
     contract::Contract,
+    funding::funding_report_with_chain,
     payout::Payout,
     player::PlayerName,
+    script::ScriptAnalysis,
+    signer::{
+        external_signing_wallet,
+        ExternalSigner,
+        ExternalSigningWallet,
+    },
     wallet::{
-        create_escrow_address,
-        create_escrow_script,
+        contract_payout_tx,
         create_payout_script,
         create_payout_tx,
+        create_refund,
+        create_split_payout,
+        create_split_payout_tx,
+        create_threshold_token_script,
         create_token_pair_script,
         derive_account_xprivkey,
+        escrow_descriptor,
+        payout_fee_shortfall,
+        escrow_signing_descriptor,
+        estimated_vsize,
         EscrowWallet,
         NameWallet,
         SavedSeed,
         SigningWallet,
         BITCOIN_ACCOUNT_PATH,
-        NAME_SUBACCOUNT,
-        NAME_KIX,
-        TX_FEE,
-    },
-    mock::{
-        referee_pubkey,
-        DB_NAME,
         ESCROW_SUBACCOUNT,
         ESCROW_KIX,
-        NETWORK,
-        SEED_NAME,
-        WALLET_DB_NAME,
-        WALLET_TREE_NAME,
+        NAME_SUBACCOUNT,
+        NAME_KIX,
+        MAX_FEE_RATE,
+        MIN_FEE_RATE,
+        P2WPKH_SATISFACTION_WEIGHT,
+        REFUND_DELAY,
     },
 };
 use crate::{
@@ -89,6 +105,11 @@ use crate::{
     ui::PlayerUI,
 };
 
+pub const DB_NAME: &'static str = "dev-app.db";
+pub const WALLET_DB_NAME: &'static str = "dev-wallet";
+pub const WALLET_TREE_NAME: &'static str = "wallet";
+pub const SEED_NAME: &'static str = "dev-seed.json";
+
 pub struct PlayerWallet {
     wallet_dir: PathBuf,
     network: Network,
@@ -96,6 +117,7 @@ pub struct PlayerWallet {
     pub name_url: String,
     pub arbiter_url: String,
     pub exchange_url: String,
+    signer: String,
 }
 
 impl PlayerWallet {
@@ -107,21 +129,27 @@ impl PlayerWallet {
             name_url,
             arbiter_url,
             exchange_url,
+            signer: String::new(),
         }
     }
 
+// sign with something other than the saved seed, see tglib::signer::external_signing_wallet
+    pub fn with_signer(mut self, signer: String) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    fn external_signer(&self) -> TgResult<Option<ExternalSigningWallet<Box<dyn ExternalSigner>>>> {
+        let saved_seed = self.saved_seed().map_err(|_| TgError::Adhoc("couldn't load seed"))?;
+        external_signing_wallet(&self.signer, &saved_seed)
+    }
+
     fn saved_seed(&self) -> Result<SavedSeed> {
         let mut seed_path = self.wallet_dir.clone();
         seed_path.push(SEED_NAME);
         let reader = File::open(&seed_path)?;
-        let mut seed: SavedSeed = serde_json::from_reader(reader).unwrap();
-// serde json bug ? where regtest keys load as testnet
-// maybe because they have the same string form
-        if seed.xpubkey.network == Network::Testnet 
-        && self.network == Network::Regtest {
-            seed.xpubkey.network = Network::Regtest;
-        }
-        Ok(seed)
+        let seed: SavedSeed = serde_json::from_reader(reader).unwrap();
+        Ok(seed.for_network(self.network)?)
     }
 
     pub fn wallet(&self) -> Result<Wallet<ElectrumBlockchain, sled::Tree>> {
@@ -133,12 +161,16 @@ impl PlayerWallet {
             Some(&self.internal_descriptor(&descriptor_key)),
             self.network,
             self.wallet_db(),
-            ElectrumBlockchain::from(ElectrumClient::new(&self.electrum_url)?)
+            ElectrumBlockchain::from(self.electrum_client()?)
         ).unwrap();
         w.sync(noop_progress(), None).unwrap();
         Ok(w)
     }
 
+    pub fn electrum_client(&self) -> Result<ElectrumClient> {
+        Ok(ElectrumClient::new(&self.electrum_url)?)
+    }
+
     pub fn offline_wallet(&self) -> Wallet<(), sled::Tree> {
         let saved_seed = self.saved_seed().unwrap();
         let descriptor_key = format!("[{}/{}]{}", saved_seed.fingerprint, BITCOIN_ACCOUNT_PATH, saved_seed.xpubkey);
@@ -201,71 +233,93 @@ impl PlayerWallet {
         db
     }
 
-    pub fn create_event_contract(&self, p1_name: &PlayerName, p2_name: &PlayerName, p2_contract_info: PlayerContractInfo, amount: Amount, arbiter_pubkey: PublicKey, event: &Event, event_payouts: &Vec<PlayerName>) -> Result<(Contract, Vec<TokenRecord>)> {
-        if event.outcomes.len() != event_payouts.len() {
+// each outcome pays (p1 amount, p2 amount) out of the escrow, in the same order as the event outcomes
+// oracle_threshold of the oracle_pubkeys have to sign an outcome's token, the event's oracle alone for 1 of 1
+    pub fn create_event_contract(&self, p1_name: &PlayerName, p2_name: &PlayerName, p1_contract_info: PlayerContractInfo, p2_contract_info: PlayerContractInfo, amount: Amount, arbiter_pubkey: PublicKey, event: &Event, event_splits: &Vec<(Amount, Amount)>, oracle_pubkeys: &[PublicKey], oracle_threshold: u8, fee_rate: u64) -> Result<(Contract, (Address, Address), Vec<TokenRecord>, Vec<SplitRecord>)> {
+        if event.outcomes.len() != event_splits.len() {
             return Err(Error::Adhoc("not enough payouts specified for event"))
         }
+        if oracle_threshold == 0 || oracle_threshold as usize > oracle_pubkeys.len() {
+            return Err(Error::Adhoc("oracle threshold out of range"))
+        }
+        if fee_rate < MIN_FEE_RATE || fee_rate > MAX_FEE_RATE {
+            return Err(Error::Adhoc("fee rate out of range"))
+        }
         let p1_pubkey = self.get_escrow_pubkey();
-        let escrow_address = create_escrow_address(&p1_pubkey, &p2_contract_info.escrow_pubkey, &arbiter_pubkey, self.network).unwrap();
-        let funding_tx = self.create_funding_tx(&p2_contract_info, amount, &escrow_address)?;
-        let payout_addresses: std::collections::HashMap<&PlayerName, Address> = 
-            [
-                (p1_name, self.offline_wallet().get_address(New)?.address),
-                (p2_name, p2_contract_info.payout_address),
-            ].iter().cloned().collect();
-        let payout_txs: std::collections::HashMap<&PlayerName, Transaction> = 
-            [
-                (p1_name, create_payout_tx(&funding_tx.clone().extract_tx(), &escrow_address, &payout_addresses.get(p1_name).unwrap()).unwrap()),
-                (p2_name, create_payout_tx(&funding_tx.clone().extract_tx(), &escrow_address, &payout_addresses.get(p2_name).unwrap()).unwrap()),
-            ].iter().cloned().collect();
-
-        let tx_token_pairs: Vec<(tglib::bdk::bitcoin::Txid, Vec<u8>)> = event.outcomes.iter().enumerate().map(|(i, outcome)| {
-            let txid = payout_txs.get(event_payouts.get(i).unwrap()).unwrap().txid();
-            let token_bytes = tglib::hex::decode(&outcome.token).unwrap();
-            (txid, token_bytes)
-        }).collect();
-        let tx_token_script = create_token_pair_script(&event.oracle_pubkey, tx_token_pairs);
-        let contract = Contract::new(
+        let escrow = escrow_descriptor(&p1_pubkey, &p2_contract_info.escrow_pubkey, &arbiter_pubkey)?;
+        let funding_tx = self.create_funding_tx(&p1_contract_info, &p2_contract_info, amount, &escrow.script_pubkey(), fee_rate)?;
+        let p1_payout_address = self.offline_wallet().get_address(New)?.address;
+        let p2_payout_address = p2_contract_info.payout_address;
+
+        let mut tx_token_pairs: Vec<(tglib::bdk::bitcoin::Txid, Vec<u8>)> = Vec::new();
+        for (outcome, (p1_amount, p2_amount)) in event.outcomes.iter().zip(event_splits) {
+            let payout_tx = create_split_payout_tx(&funding_tx.clone().extract_tx(), &escrow, &p1_payout_address, &p2_payout_address, p1_amount.as_sat(), p2_amount.as_sat(), fee_rate)?;
+            let token_bytes = tglib::hex::decode(&outcome.token).map_err(|_| Error::Adhoc("invalid outcome token"))?;
+            tx_token_pairs.push((payout_tx.txid(), token_bytes));
+        }
+        let tx_token_script = match oracle_pubkeys {
+            [oracle_pubkey] => create_token_pair_script(oracle_pubkey, tx_token_pairs),
+            _ => create_threshold_token_script(oracle_pubkeys, oracle_threshold, tx_token_pairs),
+        };
+        let mut contract = Contract::new(
             p1_pubkey,
             p2_contract_info.escrow_pubkey,
             arbiter_pubkey,
             funding_tx,
             tx_token_script,
+            fee_rate,
+            self.network,
         );
+        let lock_time = self.wallet()?.client().get_height()? + REFUND_DELAY;
+        contract.refund_tx = Some(create_refund(&contract, &p1_payout_address, &p2_payout_address, lock_time)?);
 
         let cxid = tglib::hex::encode(contract.cxid());
 
-        let token_records = event_payouts.iter().cloned().enumerate().map(|(i, player)| {
-            let outcome = event.outcomes.get(i).unwrap();
-            
-            TokenRecord {
+        let nothing = Amount::from_sat(0);
+        let mut token_records = Vec::new();
+        let mut split_records = Vec::new();
+        for (outcome, (p1_amount, p2_amount)) in event.outcomes.iter().zip(event_splits) {
+            let (player, address) = if *p2_amount == nothing {
+                (p1_name, &p1_payout_address)
+            } else if *p1_amount == nothing {
+                (p2_name, &p2_payout_address)
+            } else {
+                split_records.push(SplitRecord {
+                    cxid: cxid.clone(),
+                    token: outcome.token.clone(),
+                    desc: outcome.desc.clone(),
+                    p1_amount: p1_amount.as_sat(),
+                    p2_amount: p2_amount.as_sat(),
+                });
+                continue
+            };
+            token_records.push(TokenRecord {
                 cxid: cxid.clone(),
                 token: outcome.token.clone(),
                 player: player.clone(),
-                address: payout_addresses.get(&player).unwrap().to_string(),
+                address: address.to_string(),
                 desc: outcome.desc.clone(),
-            }
-        }).collect();
+            });
+        }
 
-        Ok((contract, token_records))
+        Ok((contract, (p1_payout_address, p2_payout_address), token_records, split_records))
     }
 
-    pub fn create_contract(&self, p2_contract_info: PlayerContractInfo, amount: Amount, arbiter_pubkey: PublicKey ) -> Result<Contract> {
+    pub fn create_contract(&self, p1_contract_info: PlayerContractInfo, p2_contract_info: PlayerContractInfo, amount: Amount, arbiter_pubkey: PublicKey, oracle_pubkey: PublicKey, fee_rate: u64) -> Result<Contract> {
 
         let p1_pubkey = self.get_escrow_pubkey();
-        let escrow_address = create_escrow_address(&p1_pubkey, &p2_contract_info.escrow_pubkey, &arbiter_pubkey, self.network).unwrap();
-        let funding_tx = self.create_funding_tx(&p2_contract_info, amount, &escrow_address)?;
+        let escrow = escrow_descriptor(&p1_pubkey, &p2_contract_info.escrow_pubkey, &arbiter_pubkey)?;
+        let funding_tx = self.create_funding_tx(&p1_contract_info, &p2_contract_info, amount, &escrow.script_pubkey(), fee_rate)?;
         let p1_payout_address = self.offline_wallet().get_address(New)?;
 // need the oracle tokens here
-        let p1_payout_tx = create_payout_tx(&funding_tx.clone().extract_tx(), &escrow_address, &p1_payout_address).unwrap();
-        let p2_payout_tx = create_payout_tx(&funding_tx.clone().extract_tx(), &escrow_address, &p2_contract_info.payout_address).unwrap();
+        let p1_payout_tx = create_payout_tx(&funding_tx.clone().extract_tx(), &escrow, &p1_payout_address, fee_rate).unwrap();
+        let p2_payout_tx = create_payout_tx(&funding_tx.clone().extract_tx(), &escrow, &p2_contract_info.payout_address, fee_rate).unwrap();
         let tx_token_pairs = vec![
             (p1_payout_tx.txid(), p1_payout_tx.txid().to_vec()),
             (p2_payout_tx.txid(), p2_payout_tx.txid().to_vec()),
         ];
-        let oracle_pubkey = referee_pubkey();
         let tx_token_script = create_token_pair_script(&oracle_pubkey, tx_token_pairs);
-        let payout_script = create_payout_script(&escrow_address, &p1_payout_address, &p2_contract_info.payout_address, &funding_tx.clone().extract_tx());
+        let payout_script = create_payout_script(&oracle_pubkey, &escrow, &p1_payout_address, &p2_contract_info.payout_address, &funding_tx.clone().extract_tx(), fee_rate);
 
         assert_eq!(tx_token_script, payout_script);
 
@@ -278,87 +332,74 @@ impl PlayerWallet {
 //            p2_contract_info.payout_address,
             funding_tx,
             payout_script,
+            fee_rate,
+            self.network,
         ))
     }
 
-    fn create_funding_tx(&self, p2_contract_info: &PlayerContractInfo, amount: Amount, escrow_address: &Address) -> Result<PartiallySignedTransaction> {
-        let mut input = Vec::new();
-        let mut psbt_inputs = Vec::new();
-        let arbiter_fee = amount.as_sat()/100;
-        let sats_per_player = (amount.as_sat() + arbiter_fee + TX_FEE)/2;
-// electrum default is 20000 sats
-        let mut total: u64 = 0;
-
-        for (outpoint, value, psbt_input) in &p2_contract_info.utxos {
-            if total > sats_per_player {
-                break
-            } else {
-                total += value;
-                input.push(TxIn{
-                    previous_output: *outpoint,
-                    script_sig: Script::new(),
-                    sequence: 0,
-                    witness: Vec::new(),
-                });
-                psbt_inputs.push(psbt_input.clone());
-            }
-        }
-        if total < sats_per_player {
-            return Err(Error::Adhoc("p2 has insufficient funds"))
-        }
-        let p2_change = total - sats_per_player;
-        let wallet = self.wallet()?;
-        assert!(wallet.sync(noop_progress(), None).is_ok());
-        for utxo in wallet.list_unspent().unwrap() {
-            if total > 2 * sats_per_player + p2_change {
-                break
-            }
-            else {
-                total += utxo.txout.value;
-                input.push(TxIn{
-                    previous_output: utxo.outpoint,
-                    script_sig: Script::new(),
-                    sequence: 0,
-                    witness: Vec::new(),
-                });
-                psbt_inputs.push(wallet.get_psbt_input(utxo, None, false).unwrap());
-            }
-        }
-        assert!(total > 2 * sats_per_player + p2_change);
-        let p1_change = total - 2 * sats_per_player - p2_change;
+// what it takes to confirm within about an hour
+    pub fn estimate_fee_rate(&self) -> Result<u64> {
+        Ok(estimate_fee_rate(&self.electrum_client()?, 6)?)
+    }
 
+    fn create_funding_tx(&self, p1_contract_info: &PlayerContractInfo, p2_contract_info: &PlayerContractInfo, amount: Amount, escrow_script: &Script, fee_rate: u64) -> Result<PartiallySignedTransaction> {
+        let arbiter_fee = amount.as_sat()/100;
         let arbiter_client = self.arbiter_client();
         let fee_address = arbiter_client.get_fee_address().map_err(|_| Error::Adhoc("couldn't get fee address"))?;
 
-        let output = vec!(
-            TxOut {
-                value: amount.as_sat(),
-                script_pubkey: escrow_address.script_pubkey(),
-            },
-            TxOut {
-                value: arbiter_fee,
-                script_pubkey: fee_address.script_pubkey(),
-            },
-            TxOut {
-                value: p2_change,
-                script_pubkey: p2_contract_info.change_address.script_pubkey(),
-            },
-            TxOut {
-                value: p1_change,
-                script_pubkey: wallet.get_address(New).unwrap().script_pubkey(),
-            },
-        );
+// the fee depends on how many utxos it takes to cover it, so go until the selection stops growing
+        let mut fee = 0;
+        loop {
+            let sats_per_player = (amount.as_sat() + arbiter_fee + fee)/2;
+// both players fund their half from the utxos they posted, see tglib::funding
+            let (p2_input, p2_psbt_inputs, p2_change) = select_posted_utxos(p2_contract_info, sats_per_player).ok_or(Error::Adhoc("p2 has insufficient funds"))?;
+            let (p1_input, p1_psbt_inputs, p1_change) = select_posted_utxos(p1_contract_info, sats_per_player).ok_or(Error::Adhoc("p1 has insufficient funds"))?;
+
+            let output = vec!(
+                TxOut {
+                    value: amount.as_sat(),
+                    script_pubkey: escrow_script.clone(),
+                },
+                TxOut {
+                    value: arbiter_fee,
+                    script_pubkey: fee_address.script_pubkey(),
+                },
+                TxOut {
+                    value: p2_change,
+                    script_pubkey: p2_contract_info.change_address.script_pubkey(),
+                },
+                TxOut {
+                    value: p1_change,
+                    script_pubkey: p1_contract_info.change_address.script_pubkey(),
+                },
+            );
+
+            let tx = Transaction {
+                version: 1,
+                lock_time: 0,
+                input: p2_input.into_iter().chain(p1_input).collect(),
+                output,
+            };
+            let tx_fee = fee_rate * estimated_vsize(&tx, &vec![P2WPKH_SATISFACTION_WEIGHT; tx.input.len()]);
+            if tx_fee > fee {
+                fee = tx_fee;
+                continue
+            }
 
-        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(Transaction {
-            version: 1,
-            lock_time: 0,
-            input,
-            output,
-        }).unwrap();
+            let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+            psbt.inputs = p2_psbt_inputs.into_iter().chain(p1_psbt_inputs).collect();
 
-        psbt.inputs = psbt_inputs;
+            return Ok(psbt)
+        }
+    }
 
-        Ok(psbt)
+// the funding tx has to spend what both players posted and give them the right change
+    pub fn validate_funding(&self, contract: &Contract, contract_record: &ContractRecord) -> Result<()> {
+        let exchange_client = self.exchange_client();
+        let p1_contract_info = exchange_client.get_contract_info(contract_record.p1_name.clone()).ok().flatten().ok_or(Error::Adhoc("couldn't get p1 contract info"))?;
+        let p2_contract_info = exchange_client.get_contract_info(contract_record.p2_name.clone()).ok().flatten().ok_or(Error::Adhoc("couldn't get p2 contract info"))?;
+        let report = funding_report_with_chain(contract, &p1_contract_info.funding(), &p2_contract_info.funding(), &self.electrum_client()?)?;
+        Ok(report.into_result()?)
     }
 
     pub fn get_other_player_name(&self, contract_record: &ContractRecord) -> Result<PlayerName> {
@@ -376,78 +417,82 @@ impl PlayerWallet {
     // and p2 gets the difference between p1_amount and the contract amount
     pub fn create_payout(&self, tcr: &TokenContractRecord, p1_amount: Amount, p2_amount: Amount) -> Result<Payout> {
         let contract = Contract::from_bytes(tglib::hex::decode(tcr.contract_record.hex.clone()).unwrap()).unwrap();
-        let contract_amount = contract.amount()?;
-        if p1_amount + p2_amount != contract_amount {
-            return Err(Error::Adhoc("payout amounts don't sum to contract amount"));
+        let (p1_address, p2_address) = tcr.contract_record.payout_addresses().map_err(|_| Error::Adhoc("invalid payout address"))?;
+// has to be built the same way as the outcome txs in the payout script, see create_event_contract
+        Ok(create_split_payout(&contract, &p1_address, &p2_address, p1_amount.as_sat(), p2_amount.as_sat())?)
+    }
+
+// child pays for parent: spends our own payout output so the pair pays fee_rate
+    pub fn create_bump_tx(&self, payout: &Payout, fee_rate: u64, pw: Secret<String>) -> Result<Transaction> {
+        let payout_tx = payout.psbt.global.unsigned_tx.clone();
+// the child pays for itself plus whatever the payout pays short of fee_rate
+        let shortfall = payout_fee_shortfall(payout, fee_rate)?;
+        if shortfall == 0 {
+            return Err(Error::Adhoc("payout tx already pays that fee rate"))
         }
-        let escrow_address = create_escrow_address(
-            &contract.p1_pubkey,
-            &contract.p2_pubkey,
-            &contract.arbiter_pubkey,
-            NETWORK,
-            ).unwrap();
-        let funding_tx = contract.clone().funding_tx.extract_tx();
-        let (escrow_vout, escrow_txout) = funding_tx.output.iter().enumerate().find(|(_vout, txout)| txout.script_pubkey == escrow_address.script_pubkey()).unwrap();
-        let wallet = self.offline_wallet();
-        let mut builder = wallet.build_tx();
-        builder.only_witness_utxo();
-        let psbt_input = Input {
-            witness_utxo: Some(escrow_txout.clone()),
-            witness_script: Some(create_escrow_script(&contract.p1_pubkey, &contract.p2_pubkey, &contract.arbiter_pubkey)),
-            ..Default::default()
-        };
-// TODO: set satisfaction weight correctly and include tx fee
-        let p1_tx_fee: u64 = Amount::from_btc(Amount::from_sat(TX_FEE).as_btc() * (p1_amount.as_btc() as f64 / contract_amount.as_btc())).unwrap().as_sat();
-        let p2_tx_fee: u64 = TX_FEE - p1_tx_fee;
-        builder.add_foreign_utxo(OutPoint { vout: escrow_vout as u32, txid: funding_tx.txid()}, psbt_input, 0).unwrap();
-        if p1_amount.as_sat() != 0 {
-//            builder.add_recipient(contract.p1_payout_address.script_pubkey(), p1_amount.as_sat() - p1_tx_fee);
-            builder.add_recipient(Address::from_str(&tcr.p1_token.address).unwrap().script_pubkey(), p1_amount.as_sat() - p1_tx_fee);
+
+        let wallet = self.wallet()?;
+        let mut outpoint = None;
+        for (vout, txout) in payout_tx.output.iter().enumerate() {
+            if wallet.is_mine(&txout.script_pubkey)? {
+                outpoint = Some(OutPoint::new(payout_tx.txid(), vout as u32));
+                break
+            }
         }
-        if p2_amount.as_sat() != 0 {
-//            builder.add_recipient(contract.p2_payout_address.script_pubkey(), p2_amount.as_sat() - p2_tx_fee);
-            builder.add_recipient(Address::from_str(&tcr.p2_token.address).unwrap().script_pubkey(), p2_amount.as_sat() - p2_tx_fee);
+        let outpoint = outpoint.ok_or(Error::Adhoc("payout tx doesn't pay us"))?;
+// all of it, less the fees, comes back to us
+        let address = wallet.get_address(New)?.address;
+        let build = |fee: Option<u64>| -> Result<(PartiallySignedTransaction, TransactionDetails)> {
+            let mut builder = wallet.build_tx();
+            builder.add_utxo(outpoint)?
+                .manually_selected_only()
+                .set_single_recipient(address.script_pubkey());
+            match fee {
+                Some(fee) => builder.fee_absolute(fee),
+                None => builder.fee_rate(FeeRate::from_sat_per_vb(fee_rate as f32)),
+            };
+            Ok(builder.finish()?)
+        };
+        let (_psbt, details) = build(None)?;
+        let (psbt, _details) = build(Some(details.fees + shortfall))?;
+// signing opens the wallet db again
+        drop(wallet);
+
+        let psbt = self.sign_tx(psbt, None, pw)?;
+        if psbt.inputs.iter().any(|input| input.final_script_witness.is_none()) {
+            return Err(Error::Adhoc("couldn't sign bump tx"))
         }
-        builder.manually_selected_only();
-        builder.fee_absolute(TX_FEE);
-        let (psbt, _details) = builder.finish().unwrap();
-        Ok(Payout::new(contract.clone(), psbt))
+        Ok(psbt.extract_tx())
     }
 
-    pub fn sign_payout(&self, mut payout: Payout, pw: Secret<String>) -> Result<PartiallySignedTransaction> {
+    pub fn sign_payout(&self, payout: Payout, pw: Secret<String>) -> Result<PartiallySignedTransaction> {
+        self.sign_escrow_psbt(&payout.contract, payout.psbt, pw)
+    }
+
+// sign a tx spending the contract's escrow output, e.g. a payout or refund
+    pub fn sign_escrow_psbt(&self, contract: &Contract, mut psbt: PartiallySignedTransaction, pw: Secret<String>) -> Result<PartiallySignedTransaction> {
 // derive escrow private key
         let path = DerivationPath::from_str(&format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX)).unwrap();
+        if let Some(signer) = self.external_signer()? {
+            return Ok(signer.sign_tx(psbt, Some(path), pw)?)
+        }
         let seed = self.saved_seed().unwrap().get_seed(pw)?;
         let account_key = derive_account_xprivkey(seed, self.network);
         let secp = Secp256k1::new();
         let escrow_privkey = account_key.derive_priv(&secp, &path).unwrap().private_key;
-// check if we are p1 or p2
-        let escrow_pubkey = PublicKey::from_private_key(&secp, &escrow_privkey);
-// create escrow descriptor wallet
-        let desc = if escrow_pubkey == payout.contract.p1_pubkey {
-            format!("wsh(multi(2,{},{},{}))", 
-                escrow_privkey.to_wif(), 
-                payout.contract.p2_pubkey.to_string(), 
-                payout.contract.arbiter_pubkey.to_string())
-        } else if escrow_pubkey == payout.contract.p2_pubkey {
-            format!("wsh(multi(2,{},{},{}))", 
-                payout.contract.p1_pubkey.to_string(), 
-                escrow_privkey.to_wif(), 
-                payout.contract.arbiter_pubkey.to_string())
-        } else {
-            return Err(Error::Adhoc("can't sign payout - not party to this payout"))
-        };
-        
-        let wallet = tglib::bdk::Wallet::new_offline(&desc, None, NETWORK, tglib::bdk::database::MemoryDatabase::default()).unwrap();
+// one-off escrow descriptor wallet
+        let desc = escrow_signing_descriptor(contract, &escrow_privkey)?;
+
+        let wallet = tglib::bdk::Wallet::new_offline(&desc, None, self.network, tglib::bdk::database::MemoryDatabase::default()).unwrap();
 
         let options = SignOptions {
             trust_witness_utxo: true,
             ..SignOptions::default()
         };
 
-        let _finalized = wallet.sign(&mut payout.psbt, options).unwrap();
+        let _finalized = wallet.sign(&mut psbt, options).unwrap();
         
-        Ok(payout.psbt)
+        Ok(psbt)
     }
 }
 
@@ -460,6 +505,30 @@ impl NameWallet for PlayerWallet {
     }
 }
 
+// posted utxos in order until they cover the amount, with the change left over
+fn select_posted_utxos(contract_info: &PlayerContractInfo, amount: u64) -> Option<(Vec<TxIn>, Vec<Input>, u64)> {
+    let mut input = Vec::new();
+    let mut psbt_inputs = Vec::new();
+    let mut total: u64 = 0;
+    for (outpoint, value, psbt_input) in &contract_info.utxos {
+        if total >= amount {
+            break
+        }
+        total += value;
+        input.push(TxIn{
+            previous_output: *outpoint,
+            script_sig: Script::new(),
+            sequence: 0,
+            witness: Vec::new(),
+        });
+        psbt_inputs.push(psbt_input.clone());
+    }
+    if total < amount {
+        return None
+    }
+    Some((input, psbt_inputs, total - amount))
+}
+
 impl EscrowWallet for PlayerWallet {
     fn get_escrow_pubkey(&self) -> PublicKey {
         let secp = Secp256k1::new();
@@ -469,6 +538,7 @@ impl EscrowWallet for PlayerWallet {
     }
 
     fn validate_contract(&self, contract: &Contract) -> TgResult<()> {
+        contract.validate_network(self.network)?;
         let player_pubkey = self.get_escrow_pubkey();
         if contract.p1_pubkey != player_pubkey && contract.p2_pubkey != player_pubkey {
             return Err(TgError::Adhoc("contract doesn't contain our pubkey"));
@@ -478,13 +548,61 @@ impl EscrowWallet for PlayerWallet {
         if contract.arbiter_pubkey != arbiter_pubkey {
             return Err(TgError::Adhoc("unexpected arbiter pubkey"));
         }
-        contract.validate()
+        contract.validate()?;
+        let tcr = self.db().get_token_contract(&tglib::hex::encode(contract.cxid())).map_err(|_| TgError::Adhoc("unknown contract tokens"))?;
+        let (p1_address, p2_address) = tcr.contract_record.payout_addresses().map_err(|_| TgError::Adhoc("invalid payout address"))?;
+        let payout_address = if contract.p1_pubkey == player_pubkey { &p1_address } else { &p2_address };
+        let oracle_pubkeys: Vec<Vec<u8>> = tcr.contract_record.oracle_pubkeys().map_err(|_| TgError::Adhoc("invalid oracle pubkey"))?
+            .iter()
+            .map(|pubkey| pubkey.to_bytes())
+            .collect();
+// rebuild every outcome tx we agreed to from the event and split table, see create_event_contract
+        let amount = contract.amount()?.as_sat();
+        let mut outcomes = Vec::new();
+        for (token, address) in tcr.p1_token.iter().map(|token| (token, &p1_address)).chain(tcr.p2_token.iter().map(|token| (token, &p2_address))) {
+            outcomes.push((contract_payout_tx(contract, address, address, amount, 0)?.txid(), &token.token));
+        }
+        for split in &tcr.splits {
+            let split_tx = contract_payout_tx(contract, &p1_address, &p2_address, split.p1_amount, split.p2_amount)?;
+            outcomes.push((split_tx.txid(), &split.token));
+        }
+// each of them only with the oracles' sigs on its token, and nothing else
+        let analysis = ScriptAnalysis::new(&contract.payout_script)?;
+        for branch in analysis.accepting() {
+            let agreed = outcomes.iter().any(|(txid, token)| {
+                branch.payout_txid.as_deref() == Some(txid.as_ref()) && branch.message == tglib::hex::decode(token).ok()
+            });
+            if !agreed || branch.signers != oracle_pubkeys || branch.threshold != tcr.contract_record.oracle_threshold as usize {
+                return Err(TgError::InvalidContract("payout script pays out an outcome we didn't agree to"));
+            }
+        }
+        for (txid, _token) in &outcomes {
+            if analysis.branch_for(txid.as_ref()).is_none() {
+                return Err(TgError::InvalidContract("payout script is missing an outcome"));
+            }
+        }
+// the refund has to return our stake to us
+        if let Some(refund_tx) = &contract.refund_tx {
+            if !refund_tx.global.unsigned_tx.output.iter().any(|txout| txout.script_pubkey == payout_address.script_pubkey()) {
+                return Err(TgError::InvalidContract("refund tx doesn't pay us"));
+            }
+        }
+        Ok(())
     }
 }
 
 impl SigningWallet for PlayerWallet {
 
     fn sign_tx(&self, mut psbt: PartiallySignedTransaction, path: Option<DerivationPath>, pw: Secret<String>) -> TgResult<PartiallySignedTransaction> {
+        if let Some(signer) = self.external_signer()? {
+            let finalize = path.is_none();
+            let mut psbt = signer.sign_tx(psbt, path, pw)?;
+// bdk finalizes the wallet's own inputs when it signs them, external signers only add partial sigs
+            if finalize {
+                let _finalized = self.offline_wallet().finalize_psbt(&mut psbt, SignOptions::default())?;
+            }
+            return Ok(psbt)
+        }
         match path {
             Some(path) => {
                 let seed = self.saved_seed().unwrap().get_seed(pw)?;
@@ -505,6 +623,9 @@ impl SigningWallet for PlayerWallet {
     }
 
     fn sign_message(&self, msg: Message, path: DerivationPath, pw: Secret<String>) -> TgResult<Signature> {
+        if let Some(signer) = self.external_signer()? {
+            return signer.sign_message(msg, path, pw)
+        }
         let seed = self.saved_seed().unwrap().get_seed(pw)?;
         let account_key = derive_account_xprivkey(seed, self.network);
         let secp = Secp256k1::new();
@@ -0,0 +1,173 @@
+use std::str::FromStr;
+use clap::{
+    App,
+    AppSettings,
+    Arg,
+    ArgMatches,
+    SubCommand,
+};
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+};
+use tglib::{
+    Error,
+    JsonResponse,
+    Status,
+    arbiter::{
+        ContractDecision,
+        ContractDetails,
+        Decision,
+        PayoutDecision,
+        ReviewBody,
+    },
+    config::Config,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// rbtr-public's admin api, authenticated with the arbiter_admin_token from the config
+struct AdminClient {
+    url: String,
+    token: String,
+}
+
+impl AdminClient {
+    fn new(config: &Config) -> Self {
+        AdminClient {
+            url: config.arbiter_url.clone(),
+            token: config.arbiter_admin_token.clone(),
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let response = reqwest::blocking::Client::new().get(&format!("{}/admin/{}", self.url, path))
+            .bearer_auth(&self.token)
+            .send()?;
+        data(response)
+    }
+
+    fn post<T: DeserializeOwned, B: Serialize>(&self, path: &str, body: &B) -> Result<T> {
+        let response = reqwest::blocking::Client::new().post(&format!("{}/admin/{}", self.url, path))
+            .bearer_auth(&self.token)
+            .json(body)
+            .send()?;
+        data(response)
+    }
+
+    fn contracts(&self, decision: Option<Decision>) -> Result<Vec<ContractDecision>> {
+        self.get(&format!("contracts{}", query(decision)))
+    }
+
+    fn contract(&self, cxid: &str) -> Result<ContractDetails> {
+        self.get(&format!("contracts/{}", cxid))
+    }
+
+    fn payouts(&self, decision: Option<Decision>) -> Result<Vec<PayoutDecision>> {
+        self.get(&format!("payouts{}", query(decision)))
+    }
+
+    fn review(&self, request_id: &str, approve: bool, reason: String) -> Result<PayoutDecision> {
+        let action = if approve { "approve" } else { "reject" };
+        self.post(&format!("payouts/{}/{}", request_id, action), &ReviewBody { reason })
+    }
+}
+
+fn query(decision: Option<Decision>) -> String {
+    match decision {
+        Some(decision) => format!("?decision={}", decision.as_str()),
+        None => String::new(),
+    }
+}
+
+fn data<T: DeserializeOwned>(response: reqwest::blocking::Response) -> Result<T> {
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(Box::new(Error::Adhoc("unauthorized, check arbiter_admin_token")))
+    }
+    let response: JsonResponse<T> = serde_json::from_str(&response.text()?)?;
+    match response.status {
+        Status::Success => response.data.ok_or(Box::new(Error::Adhoc("missing data in response"))),
+        Status::Error => Err(Error::JsonResponse(response.message.unwrap_or("unknown arbiter error".to_string())).into()),
+    }
+}
+
+fn rbtr_admin<'a, 'b>() -> App<'a, 'b> {
+    let decision = Arg::with_name("decision")
+        .long("decision")
+        .required(false)
+        .takes_value(true)
+        .possible_values(&["approved", "rejected", "pending"])
+        .help("only show requests with this decision");
+    App::new("rbtr-admin")
+        .version(option_env!("CARGO_PKG_VERSION").unwrap_or("unknown"))
+        .author(option_env!("CARGO_PKG_AUTHORS").unwrap_or(""))
+        .about("review what the arbiter signed and decide flagged payouts")
+        .settings(&[AppSettings::SubcommandRequiredElseHelp, AppSettings::VersionlessSubcommands])
+        .subcommand(SubCommand::with_name("contracts").about("list contract signing requests")
+            .arg(decision.clone()))
+        .subcommand(SubCommand::with_name("contract").about("show a decoded contract and its requests")
+            .arg(Arg::with_name("cxid")
+                .index(1)
+                .required(true)))
+        .subcommand(SubCommand::with_name("payouts").about("list payout signing requests")
+            .arg(decision))
+        .subcommand(SubCommand::with_name("approve").about("sign a payout flagged for review")
+            .arg(Arg::with_name("request-id")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("reason")
+                .long("reason")
+                .required(false)
+                .takes_value(true)
+                .help("recorded in the arbiter's ledger")))
+        .subcommand(SubCommand::with_name("reject").about("refuse a payout flagged for review")
+            .arg(Arg::with_name("request-id")
+                .index(1)
+                .required(true))
+            .arg(Arg::with_name("reason")
+                .long("reason")
+                .required(true)
+                .takes_value(true)
+                .help("recorded in the arbiter's ledger and shown to the player")))
+}
+
+fn decision(matches: &ArgMatches) -> Result<Option<Decision>> {
+    Ok(match matches.value_of("decision") {
+        Some(decision) => Some(Decision::from_str(decision)?),
+        None => None,
+    })
+}
+
+fn print_payout(payout: &PayoutDecision) {
+    println!("{} cxid {} txid {} {}{} {}", payout.request_id, payout.cxid, payout.txid, payout.decision.as_str(), if payout.reviewed { " (reviewed)" } else { "" }, payout.reason.as_deref().unwrap_or(""));
+}
+
+fn run(matches: ArgMatches, client: &AdminClient) -> Result<()> {
+    match matches.subcommand() {
+        ("contracts", Some(args)) => for contract in client.contracts(decision(args)?)? {
+            println!("{} cxid {} {} {}", contract.request_id, contract.cxid, contract.decision.as_str(), contract.reason.as_deref().unwrap_or(""));
+        }
+        ("contract", Some(args)) => println!("{}", serde_json::to_string_pretty(&client.contract(args.value_of("cxid").unwrap())?)?),
+        ("payouts", Some(args)) => for payout in client.payouts(decision(args)?)? {
+            print_payout(&payout);
+        }
+        ("approve", Some(args)) => print_payout(&client.review(args.value_of("request-id").unwrap(), true, args.value_of("reason").unwrap_or("approved by operator").to_string())?),
+        ("reject", Some(args)) => print_payout(&client.review(args.value_of("request-id").unwrap(), false, args.value_of("reason").unwrap().to_string())?),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn main() {
+    let config = match Config::localhost().load() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return
+        }
+    };
+    if let Err(e) = run(rbtr_admin().get_matches(), &AdminClient::new(&config)) {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+}
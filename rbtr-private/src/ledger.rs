@@ -0,0 +1,371 @@
+use std::{
+    path::Path,
+    str::FromStr,
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use rusqlite::{
+    params,
+    Connection,
+    OptionalExtension,
+    Row,
+};
+use tglib::{
+    bdk::bitcoin::{
+        OutPoint,
+        PublicKey,
+        Txid,
+    },
+    hex,
+    Error,
+    arbiter::{
+        ContractDecision,
+        Decision,
+        PayoutDecision,
+    },
+    contract::Contract,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// bumped whenever existing rows need migrating, stored in sqlite's user_version
+const LEDGER_VERSION: u32 = 2;
+
+// everything the arbiter was asked to sign and what it did about it
+pub struct Ledger {
+    conn: Connection,
+}
+
+impl Ledger {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ledger::new(Connection::open(path)?)
+    }
+
+    fn new(conn: Connection) -> Result<Self> {
+        let ledger = Ledger { conn };
+        ledger.create_tables()?;
+        Ok(ledger)
+    }
+
+    fn create_tables(&self) -> Result<()> {
+        self.conn.execute_batch(
+// signed_payout is the one payout tx we'll ever sign for an escrow outpoint.
+// the refund tx spends the escrow too, but it's co-signed with the contract and timelocked
+            "BEGIN;
+                CREATE TABLE IF NOT EXISTS contract_request (
+                    request_id      TEXT PRIMARY KEY,
+                    cxid            TEXT NOT NULL,
+                    contract_hex    TEXT NOT NULL,
+                    decision        TEXT NOT NULL,
+                    reason          TEXT,
+                    sig             TEXT,
+                    refund_psbt     TEXT,
+                    created_at      INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS payout_request (
+                    request_id      TEXT PRIMARY KEY,
+                    cxid            TEXT NOT NULL,
+                    escrow_outpoint TEXT NOT NULL,
+                    txid            TEXT NOT NULL,
+                    payout_hex      TEXT NOT NULL,
+                    decision        TEXT NOT NULL,
+                    reason          TEXT,
+                    psbt            TEXT,
+                    created_at      INTEGER NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS signed_payout (
+                    escrow_outpoint TEXT PRIMARY KEY,
+                    cxid            TEXT NOT NULL,
+                    txid            TEXT NOT NULL,
+                    psbt            TEXT NOT NULL,
+                    request_id      TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS contract_request_cxid ON contract_request(cxid);
+                CREATE INDEX IF NOT EXISTS payout_request_cxid ON payout_request(cxid);
+            COMMIT;"
+        )?;
+        self.migrate()
+    }
+
+    fn migrate(&self) -> Result<()> {
+        let version: u32 = self.conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+// payouts can be flagged for an operator to decide
+        if version < 2 {
+            self.conn.execute_batch("ALTER TABLE payout_request ADD COLUMN reviewed INTEGER NOT NULL DEFAULT 0;")?;
+        }
+        self.conn.execute_batch(&format!("PRAGMA user_version = {};", LEDGER_VERSION))?;
+        Ok(())
+    }
+
+// a retried request replaces the earlier decision
+    pub fn record_contract(&self, decision: &ContractDecision) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO contract_request (request_id, cxid, contract_hex, decision, reason, sig, refund_psbt, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![decision.request_id, decision.cxid, decision.contract_hex, decision.decision.as_str(), decision.reason, decision.sig, decision.refund_psbt, decision.created_at],
+        )?;
+        Ok(())
+    }
+
+    pub fn record_payout(&self, decision: &PayoutDecision) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO payout_request (request_id, cxid, escrow_outpoint, txid, payout_hex, decision, reason, psbt, reviewed, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![decision.request_id, decision.cxid, decision.escrow_outpoint, decision.txid, decision.payout_hex, decision.decision.as_str(), decision.reason, decision.psbt, decision.reviewed, decision.created_at],
+        )?;
+        Ok(())
+    }
+
+// the payout tx already signed for the escrow and the psbt we returned
+    pub fn signed_payout(&self, escrow_outpoint: &OutPoint) -> Result<Option<(Txid, String)>> {
+        let signed: Option<(String, String)> = self.conn.query_row(
+            "SELECT txid, psbt FROM signed_payout WHERE escrow_outpoint = ?1",
+            params![escrow_outpoint.to_string()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?;
+        match signed {
+            Some((txid, psbt)) => Ok(Some((Txid::from_str(&txid)?, psbt))),
+            None => Ok(None),
+        }
+    }
+
+// call before the psbt leaves rbtr-private. the primary key refuses a second payout for the escrow
+    pub fn insert_signed_payout(&self, escrow_outpoint: &OutPoint, cxid: &str, txid: &Txid, psbt: &str, request_id: &str) -> Result<()> {
+        match self.signed_payout(escrow_outpoint)? {
+            Some((signed_txid, _psbt)) if signed_txid == *txid => return Ok(()),
+            Some(_) => return Err(Box::new(Error::InvalidPayout("escrow already paid out by a different payout"))),
+            None => (),
+        }
+        self.conn.execute(
+            "INSERT INTO signed_payout (escrow_outpoint, cxid, txid, psbt, request_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![escrow_outpoint.to_string(), cxid, txid.to_string(), psbt, request_id],
+        )?;
+        Ok(())
+    }
+
+// filters are optional, null matches everything
+    pub fn contracts(&self, decision: Option<Decision>, cxid: Option<&str>) -> Result<Vec<ContractDecision>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT request_id, cxid, contract_hex, decision, reason, sig, refund_psbt, created_at FROM contract_request
+                WHERE (?1 IS NULL OR decision = ?1) AND (?2 IS NULL OR cxid = ?2) ORDER BY created_at"
+        )?;
+        let rows = stmt.query_map(params![decision.map(|d| d.as_str()), cxid], contract_decision)?.collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    pub fn payouts(&self, decision: Option<Decision>, cxid: Option<&str>) -> Result<Vec<PayoutDecision>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT request_id, cxid, escrow_outpoint, txid, payout_hex, decision, reason, psbt, reviewed, created_at FROM payout_request
+                WHERE (?1 IS NULL OR decision = ?1) AND (?2 IS NULL OR cxid = ?2) ORDER BY created_at"
+        )?;
+        let rows = stmt.query_map(params![decision.map(|d| d.as_str()), cxid], payout_decision)?.collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+// escrow amounts of the approved contracts with this player that the arbiter hasn't paid out, leaving out cxid
+// rbtr-private can't see the chain, so contracts the players settled between themselves still count.
+// once height reaches a contract's refund lock time it was either never funded or the players can take
+// their stakes back, so it stops counting
+    pub fn exposure(&self, pubkey: &PublicKey, cxid: &str, height: u32) -> Result<u64> {
+        let mut stmt = self.conn.prepare(
+            "SELECT contract_hex FROM contract_request
+                WHERE decision = 'approved' AND cxid != ?1 AND cxid NOT IN (SELECT cxid FROM signed_payout) GROUP BY cxid"
+        )?;
+        let contracts = stmt.query_map(params![cxid], |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut exposure = 0;
+        for contract_hex in contracts {
+            let contract = Contract::from_bytes(hex::decode(contract_hex)?)?;
+            if let Some(refund_tx) = &contract.refund_tx {
+                if refund_tx.global.unsigned_tx.lock_time <= height {
+                    continue
+                }
+            }
+            if contract.p1_pubkey == *pubkey || contract.p2_pubkey == *pubkey {
+                exposure += contract.amount()?.as_sat();
+            }
+        }
+        Ok(exposure)
+    }
+
+    pub fn payout(&self, request_id: &str) -> Result<Option<PayoutDecision>> {
+        Ok(self.conn.query_row(
+            "SELECT request_id, cxid, escrow_outpoint, txid, payout_hex, decision, reason, psbt, reviewed, created_at FROM payout_request WHERE request_id = ?1",
+            params![request_id],
+            payout_decision,
+        ).optional()?)
+    }
+}
+
+fn decision(row: &Row, i: usize) -> rusqlite::Result<Decision> {
+    let decision: String = row.get(i)?;
+    Decision::from_str(&decision).map_err(|_| rusqlite::Error::InvalidColumnType(i, "decision".to_string(), rusqlite::types::Type::Text))
+}
+
+fn contract_decision(row: &Row) -> rusqlite::Result<ContractDecision> {
+    Ok(ContractDecision {
+        request_id: row.get(0)?,
+        cxid: row.get(1)?,
+        contract_hex: row.get(2)?,
+        decision: decision(row, 3)?,
+        reason: row.get(4)?,
+        sig: row.get(5)?,
+        refund_psbt: row.get(6)?,
+        created_at: row.get(7)?,
+    })
+}
+
+fn payout_decision(row: &Row) -> rusqlite::Result<PayoutDecision> {
+    Ok(PayoutDecision {
+        request_id: row.get(0)?,
+        cxid: row.get(1)?,
+        escrow_outpoint: row.get(2)?,
+        txid: row.get(3)?,
+        payout_hex: row.get(4)?,
+        decision: decision(row, 5)?,
+        reason: row.get(6)?,
+        psbt: row.get(7)?,
+        reviewed: row.get(8)?,
+        created_at: row.get(9)?,
+    })
+}
+
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use tglib::{
+        bdk::bitcoin::{
+            secp256k1::{
+                Secp256k1,
+                SecretKey,
+            },
+            Network,
+            Script,
+            Transaction,
+            TxIn,
+            TxOut,
+            hashes::Hash,
+            util::psbt::PartiallySignedTransaction,
+        },
+        script::TgScript,
+        wallet::create_escrow_address,
+    };
+
+    fn ledger() -> Ledger {
+        Ledger::new(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn txid(i: u8) -> Txid {
+        Txid::from_slice(&[i; 32]).unwrap()
+    }
+
+    fn key(i: u8) -> PublicKey {
+        let secp = Secp256k1::new();
+        PublicKey { compressed: true, key: tglib::bdk::bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[i; 32]).unwrap()) }
+    }
+
+    fn approve(ledger: &Ledger, cxid: &str, amount: u64, refund_lock_time: u32) {
+        let escrow_address = create_escrow_address(&key(1), &key(2), &key(3), Network::Regtest).unwrap();
+        let tx = |lock_time, value| Transaction {
+            version: 1,
+            lock_time,
+            input: vec![TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xFFFFFFFE, witness: Vec::new() }],
+            output: vec![TxOut { value, script_pubkey: escrow_address.script_pubkey() }],
+        };
+        let mut contract = Contract::new(key(1), key(2), key(3), PartiallySignedTransaction::from_unsigned_tx(tx(0, amount)).unwrap(), TgScript(vec![]), 2, Network::Regtest);
+        contract.refund_tx = Some(PartiallySignedTransaction::from_unsigned_tx(tx(refund_lock_time, amount)).unwrap());
+        ledger.record_contract(&ContractDecision {
+            request_id: cxid.to_string(),
+            cxid: cxid.to_string(),
+            contract_hex: hex::encode(contract.to_bytes()),
+            decision: Decision::Approved,
+            reason: None,
+            sig: None,
+            refund_psbt: None,
+            created_at: 1,
+        }).unwrap();
+    }
+
+    #[test]
+    fn records() {
+        let ledger = ledger();
+        let mut contract = ContractDecision {
+            request_id: "r1".to_string(),
+            cxid: "c1".to_string(),
+            contract_hex: "00".to_string(),
+            decision: Decision::Rejected,
+            reason: Some("bad contract".to_string()),
+            sig: None,
+            refund_psbt: None,
+            created_at: 1,
+        };
+        ledger.record_contract(&contract).unwrap();
+// retried and approved
+        contract.decision = Decision::Approved;
+        contract.reason = None;
+        contract.sig = Some("3044".to_string());
+        ledger.record_contract(&contract).unwrap();
+        assert_eq!(ledger.contracts(None, None).unwrap(), vec![contract.clone()]);
+        assert_eq!(ledger.contracts(Some(Decision::Rejected), None).unwrap(), vec![]);
+        assert_eq!(ledger.contracts(Some(Decision::Approved), Some("c1")).unwrap(), vec![contract]);
+
+        let payout = PayoutDecision {
+            request_id: "r2".to_string(),
+            cxid: "c1".to_string(),
+            escrow_outpoint: OutPoint::new(txid(1), 0).to_string(),
+            txid: txid(2).to_string(),
+            payout_hex: "00".to_string(),
+            decision: Decision::Approved,
+            reason: None,
+            psbt: Some("70736274".to_string()),
+            reviewed: false,
+            created_at: 2,
+        };
+        ledger.record_payout(&payout).unwrap();
+        assert_eq!(ledger.payouts(None, None).unwrap(), vec![payout.clone()]);
+        assert_eq!(ledger.payouts(Some(Decision::Approved), Some("c1")).unwrap(), vec![payout.clone()]);
+        assert_eq!(ledger.payouts(Some(Decision::Pending), None).unwrap(), vec![]);
+        assert_eq!(ledger.payouts(None, Some("c2")).unwrap(), vec![]);
+        assert_eq!(ledger.payout("r2").unwrap(), Some(payout));
+        assert_eq!(ledger.payout("r3").unwrap(), None);
+    }
+
+    #[test]
+    fn one_payout_per_escrow() {
+        let ledger = ledger();
+        let escrow = OutPoint::new(txid(1), 0);
+        assert_eq!(ledger.signed_payout(&escrow).unwrap(), None);
+        ledger.insert_signed_payout(&escrow, "c1", &txid(2), "psbt", "r1").unwrap();
+// the same payout again is fine, another one isn't
+        ledger.insert_signed_payout(&escrow, "c1", &txid(2), "psbt", "r2").unwrap();
+        assert!(ledger.insert_signed_payout(&escrow, "c1", &txid(3), "psbt", "r3").is_err());
+        assert_eq!(ledger.signed_payout(&escrow).unwrap(), Some((txid(2), "psbt".to_string())));
+// even going around the check
+        assert!(ledger.conn.execute(
+            "INSERT INTO signed_payout (escrow_outpoint, cxid, txid, psbt, request_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![escrow.to_string(), "c1", txid(3).to_string(), "psbt", "r3"],
+        ).is_err());
+        ledger.insert_signed_payout(&OutPoint::new(txid(1), 1), "c2", &txid(3), "psbt", "r3").unwrap();
+    }
+
+    #[test]
+    fn exposure() {
+        let ledger = ledger();
+        approve(&ledger, "c1", 100_000, 1000);
+        approve(&ledger, "c2", 200_000, 2000);
+        assert_eq!(ledger.exposure(&key(1), "c3", 0).unwrap(), 300_000);
+        assert_eq!(ledger.exposure(&key(2), "c1", 0).unwrap(), 200_000);
+        assert_eq!(ledger.exposure(&key(4), "c3", 0).unwrap(), 0);
+// refundable, so funded or not it's no longer on the arbiter
+        assert_eq!(ledger.exposure(&key(1), "c3", 1000).unwrap(), 200_000);
+        assert_eq!(ledger.exposure(&key(1), "c3", 2000).unwrap(), 0);
+        ledger.insert_signed_payout(&OutPoint::new(txid(1), 0), "c2", &txid(2), "psbt", "r1").unwrap();
+        assert_eq!(ledger.exposure(&key(1), "c3", 0).unwrap(), 100_000);
+    }
+}
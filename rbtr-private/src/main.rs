@@ -1,97 +1,368 @@
 use std::{
+    env,
+    path::Path,
     thread::sleep,
     time::Duration,
 };
-use redis::{
-    self,
-    aio::Connection,
-    AsyncCommands,
+use clap::{
+    App,
+    AppSettings,
+    Arg,
+    SubCommand,
 };
 use simple_logger::SimpleLogger;
 use tglib::{
     bdk::bitcoin::{
-       consensus,
-        secp256k1::Signature,
-        util::psbt::PartiallySignedTransaction,
+        consensus,
+        OutPoint,
     },
+    bip39::Mnemonic,
     hex,
+    rand::Rng,
     log::{
         LevelFilter,
-        debug,
         error,
     },
     secrecy::Secret,
+    config::Config,
+    Error,
+    arbiter::{
+        serve_next,
+        ContractDecision,
+        Decision,
+        PayoutDecision,
+        SigningRequest,
+        SigningResponse,
+    },
     contract::Contract,
     payout::Payout,
     wallet::{
-        sign_contract,
+        escrow_pubkey,
+        validate_payout_script,
+        validate_sighash_all,
         EscrowWallet,
+        SavedSeed,
     },
-    mock::REDIS_SERVER,
 };
 
+mod ledger;
+mod policy;
+mod signing;
 mod wallet;
+use ledger::Ledger;
+use policy::{
+    Policy,
+    PolicyViolation,
+};
+use signing::RedisSigningServer;
 use wallet::Wallet;
 
-const ARBITER_PW: &'static str = "somebogusarbiterpwweeee4j14hrkqj3htlkj";
+// the seed file's password, kept out of the config so it doesn't end up in a toml file
+const SEED_PASSWORD_VAR: &'static str = "RBTR_SEED_PASSWORD";
+// how long to block waiting for a request before checking the connection again
+const POLL_TIMEOUT: Duration = Duration::from_secs(5);
+// longest wait between attempts to reconnect to redis
+const MAX_WAITING_TIME: Duration = Duration::from_secs(60);
 
-async fn maybe_sign_contract(con: &mut Connection, wallet: &Wallet) {
-    if let Some(contract) = next_contract(con).await {
-        if wallet.validate_contract(&contract).is_ok() {
-            if let Ok(sig) = sign_contract(wallet, &contract, Secret::new(ARBITER_PW.to_owned())) {
-                let _r = set_contract_signature(con, contract, sig).await;
-            }
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+fn handle_request(wallet: &Wallet, ledger: &Ledger, policy: &Policy, request: &SigningRequest) -> SigningResponse {
+    let id = request.id();
+    let handled = match request {
+        SigningRequest::Contract { contract_hex, height } => handle_contract(wallet, ledger, policy, &id, contract_hex, *height),
+        SigningRequest::Payout { payout_hex } => handle_payout(wallet, ledger, &id, payout_hex),
+        SigningRequest::Contracts { decision, cxid } => ledger.contracts(*decision, cxid.as_deref()).map(|contracts| SigningResponse::Contracts { contracts }),
+        SigningRequest::Payouts { decision, cxid } => ledger.payouts(*decision, cxid.as_deref()).map(|payouts| SigningResponse::Payouts { payouts }),
+        SigningRequest::Review { request_id, approve, reason } => handle_review(wallet, ledger, request_id, *approve, reason),
+    };
+// nothing goes out unless it made it into the ledger
+    match handled {
+        Ok(response) => response,
+        Err(e) => {
+            error!("couldn't handle request {}: {}", id, e);
+            SigningResponse::Rejected { reason: e.to_string(), code: None }
         }
     }
 }
 
-async fn next_contract(con: &mut Connection) -> Option<Contract> {
-    let r: redis::RedisResult<String> = con.lpop("contracts").await;
-    Contract::from_bytes(hex::decode(r.ok()?).ok()?).ok()
+fn handle_contract(wallet: &Wallet, ledger: &Ledger, policy: &Policy, request_id: &str, contract_hex: &str, height: u32) -> Result<SigningResponse> {
+    let contract = Contract::from_bytes(hex::decode(contract_hex)?)?;
+    let mut decision = ContractDecision {
+        request_id: request_id.to_string(),
+        cxid: hex::encode(contract.cxid()),
+        contract_hex: contract_hex.to_string(),
+        decision: Decision::Rejected,
+        reason: None,
+        sig: None,
+        refund_psbt: None,
+        created_at: ledger::now(),
+    };
+    let response = match sign_contract_request(wallet, ledger, policy, contract, height) {
+        Ok(signed) => {
+            decision.decision = Decision::Approved;
+            decision.sig = signed.sigs.last().map(|sig| hex::encode(sig.serialize_der()));
+            decision.refund_psbt = signed.refund_tx.as_ref().map(|psbt| hex::encode(consensus::serialize(psbt)));
+            SigningResponse::Approved { hex: hex::encode(signed.to_bytes()) }
+        }
+        Err(e) => {
+            error!("rejected contract {}: {}", decision.cxid, e);
+            decision.reason = Some(e.to_string());
+            let code = e.downcast_ref::<PolicyViolation>().map(|violation| violation.code.to_string());
+            SigningResponse::Rejected { reason: e.to_string(), code }
+        }
+    };
+    ledger.record_contract(&decision)?;
+    Ok(response)
 }
 
-async fn set_contract_signature(con: &mut Connection, contract: Contract, sig: Signature) -> redis::RedisResult<String> {
-    con.set(hex::encode(contract.cxid()), hex::encode(sig.serialize_der())).await
+// the contract with our sig added and the refund tx co-signed
+fn sign_contract_request(wallet: &Wallet, ledger: &Ledger, policy: &Policy, mut contract: Contract, height: u32) -> Result<Contract> {
+    let cxid = hex::encode(contract.cxid());
+// older escrow keys are only for paying out the contracts made with them
+    if contract.arbiter_pubkey != wallet.get_escrow_pubkey() {
+        return Err(Box::new(Error::InvalidContract("arbiter escrow key has been rotated")))
+    }
+// the policy only lets through payout scripts it recognizes, so nothing else reaches the script analysis
+    policy.check(&contract, ledger.exposure(&contract.p1_pubkey, &cxid, height)?, ledger.exposure(&contract.p2_pubkey, &cxid, height)?)?;
+    wallet.validate_contract(&contract)?;
+    contract.validate_refund_lock_time(height)?;
+    if let Some(refund_tx) = contract.refund_tx.clone() {
+        contract.refund_tx = Some(wallet.sign_escrow_psbt(&contract, refund_tx)?);
+    }
+    let sig = wallet.sign_contract(&contract)?;
+    contract.sigs.push(sig);
+    Ok(contract)
 }
 
-async fn maybe_sign_payout(con: &mut Connection, wallet: &Wallet) {
-    if let Some(payout) = next_payout(con).await {
-        if wallet.validate_payout(&payout).is_ok() {
-            debug!("payout validation succeeded");
-            if let Ok(psbt) = wallet.sign_payout(payout.clone(), Secret::new(ARBITER_PW.to_owned())) {
-                let _r = set_payout_psbt(con, payout, psbt).await;
+fn handle_payout(wallet: &Wallet, ledger: &Ledger, request_id: &str, payout_hex: &str) -> Result<SigningResponse> {
+// flagged payouts wait for an operator, and what the operator decided sticks
+    if let Some(earlier) = ledger.payout(request_id)? {
+        if earlier.reviewed || earlier.decision == Decision::Pending {
+            return Ok(payout_response(&earlier))
+        }
+    }
+    let payout = Payout::from_bytes(hex::decode(payout_hex)?)?;
+    let (escrow_outpoint, _escrow_script) = payout.contract.escrow_outpoint()?;
+    let mut decision = PayoutDecision {
+        request_id: request_id.to_string(),
+        cxid: hex::encode(payout.contract.cxid()),
+        escrow_outpoint: escrow_outpoint.to_string(),
+        txid: payout.psbt.global.unsigned_tx.txid().to_string(),
+        payout_hex: payout_hex.to_string(),
+        decision: Decision::Rejected,
+        reason: None,
+        psbt: None,
+        reviewed: false,
+        created_at: ledger::now(),
+    };
+// a payout that's fine apart from its script is a dispute, e.g. no token or the wrong one
+    let checked = wallet.validate_payout_tx(&payout)
+        .map(|()| validate_payout_script(&payout).map_err(|e| format!("flagged for review: {}", e)));
+    match checked {
+        Ok(Ok(())) => match sign_payout(wallet, ledger, &payout, &escrow_outpoint, request_id) {
+            Ok(psbt) => {
+                decision.decision = Decision::Approved;
+                decision.psbt = Some(psbt);
             }
+            Err(e) => decision.reason = Some(e.to_string()),
+        }
+        Ok(Err(flagged)) => {
+            decision.decision = Decision::Pending;
+            decision.reason = Some(flagged);
         }
+        Err(e) => decision.reason = Some(e.to_string()),
     }
+    if decision.decision != Decision::Approved {
+        error!("payout {} {}: {}", decision.txid, decision.decision.as_str(), decision.reason.clone().unwrap_or_default());
+    }
+    ledger.record_payout(&decision)?;
+    Ok(payout_response(&decision))
+}
+
+fn handle_review(wallet: &Wallet, ledger: &Ledger, request_id: &str, approve: bool, reason: &str) -> Result<SigningResponse> {
+    let mut decision = ledger.payout(request_id)?.ok_or(Error::Adhoc("unknown payout request"))?;
+// the operator gets the updated record back, and the same review again is a no-op
+    if decision.reviewed && (decision.decision == Decision::Approved) == approve {
+        return Ok(SigningResponse::Payouts { payouts: vec![decision] })
+    }
+    if decision.decision != Decision::Pending {
+        return Err(Box::new(Error::Adhoc("payout isn't waiting for review")))
+    }
+    if approve {
+        let payout = Payout::from_bytes(hex::decode(&decision.payout_hex)?)?;
+        wallet.validate_payout_tx(&payout)?;
+        let (escrow_outpoint, _escrow_script) = payout.contract.escrow_outpoint()?;
+        decision.psbt = Some(sign_payout(wallet, ledger, &payout, &escrow_outpoint, request_id)?);
+        decision.decision = Decision::Approved;
+    } else {
+        decision.decision = Decision::Rejected;
+    }
+    decision.reason = Some(reason.to_string());
+    decision.reviewed = true;
+    ledger.record_payout(&decision)?;
+    Ok(SigningResponse::Payouts { payouts: vec![decision] })
 }
 
-async fn next_payout(con: &mut Connection) -> Option<Payout> {
-    let r: redis::RedisResult<String> = con.lpop("payouts").await;
-    Payout::from_bytes(hex::decode(r.ok()?).ok()?).ok()
+fn payout_response(decision: &PayoutDecision) -> SigningResponse {
+    let reason = decision.reason.clone().unwrap_or_default();
+    match decision.decision {
+        Decision::Approved => SigningResponse::Approved { hex: decision.psbt.clone().unwrap_or_default() },
+        Decision::Rejected => SigningResponse::Rejected { reason, code: None },
+        Decision::Pending => SigningResponse::Pending { reason },
+    }
 }
 
-async fn set_payout_psbt(con: &mut Connection, payout: Payout, psbt: PartiallySignedTransaction) -> redis::RedisResult<String> {
-    con.set(hex::encode(payout.contract.cxid()), hex::encode(consensus::serialize(&psbt))).await
+// the payout we already signed for this escrow gets the same psbt again, any other is refused
+// the txid only pins down what we signed if the sig commits to all of it
+fn sign_payout(wallet: &Wallet, ledger: &Ledger, payout: &Payout, escrow_outpoint: &OutPoint, request_id: &str) -> Result<String> {
+    validate_sighash_all(&payout.psbt)?;
+    let txid = payout.psbt.global.unsigned_tx.txid();
+    if let Some((signed_txid, psbt)) = ledger.signed_payout(escrow_outpoint)? {
+        if signed_txid == txid {
+            return Ok(psbt)
+        }
+        return Err(Box::new(Error::InvalidPayout("escrow already paid out by a different payout")))
+    }
+    let psbt = wallet.sign_payout(payout.clone())?;
+    let psbt = hex::encode(consensus::serialize(&psbt));
+    ledger.insert_signed_payout(escrow_outpoint, &hex::encode(payout.contract.cxid()), &txid, &psbt, request_id)?;
+    Ok(psbt)
 }
 
-#[tokio::main]
-async fn main() {
-    SimpleLogger::new().with_level(LevelFilter::Debug).init().unwrap();
-    let redis_client = redis::Client::open(REDIS_SERVER).unwrap();
-    let wallet = Wallet::new(Secret::new(ARBITER_PW.to_owned()));
+fn seed_password() -> Result<Secret<String>> {
+    match env::var(SEED_PASSWORD_VAR) {
+        Ok(pw) if !pw.is_empty() => Ok(Secret::new(pw)),
+        _ => Err(format!("set {} to the arbiter seed's password", SEED_PASSWORD_VAR).into()),
+    }
+}
+
+// a toml fragment for rbtr-public's config
+fn print_xpub(saved_seed: &SavedSeed, config: &Config) {
+    println!("arbiter_fingerprint = \"{}\"", saved_seed.fingerprint);
+    println!("arbiter_xpubkey = \"{}\"", saved_seed.xpubkey);
+    println!("arbiter_escrow_kix = {}", config.arbiter_escrow_kix);
+    println!("# escrow pubkey {}", escrow_pubkey(&saved_seed.xpubkey, config.arbiter_escrow_kix));
+}
+
+// a new seed, or one restored from its mnemonic. the mnemonic is only ever shown here
+fn keygen(config: &Config, mnemonic: Option<&str>) -> Result<()> {
+    let (mnemonic, generated) = match mnemonic {
+        Some(mnemonic) => (mnemonic.to_string(), false),
+        None => (Mnemonic::from_entropy(&tglib::rand::thread_rng().gen::<[u8; 32]>()).map_err(|_| Error::Adhoc("couldn't generate mnemonic"))?.to_string(), true),
+    };
+    let saved_seed = SavedSeed::new(seed_password()?, Some(Secret::new(mnemonic.clone())), config.network)?;
+    wallet::write_seed(Path::new(&config.arbiter_seed_path), &saved_seed)?;
+    if generated {
+        eprintln!("write down the arbiter mnemonic, it's the only backup of the seed:\n{}\n", mnemonic);
+    }
+    print_xpub(&saved_seed, config);
+    Ok(())
+}
+
+fn serve(config: Config) -> Result<()> {
+    let redis_client = redis::Client::open(config.redis_url.as_str())?;
+    let wallet = Wallet::load(Path::new(&config.arbiter_seed_path), seed_password()?, config.arbiter_escrow_kix, config.network)?;
+    let ledger = Ledger::open(Path::new(&config.arbiter_ledger_path))?;
+    let policy = Policy::load(&config.arbiter_policy_path)?;
     let mut waiting_time = Duration::from_secs(1);
     loop {
-        match redis_client.get_async_connection().await {
-            Ok(mut con) => {
-                maybe_sign_contract(&mut con, &wallet).await;
-                maybe_sign_payout(&mut con, &wallet).await;
+        match RedisSigningServer::new(redis_client.clone(), "rbtr-private") {
+            Ok(server) => loop {
+                if let Err(e) = serve_next(&server, POLL_TIMEOUT, |request| handle_request(&wallet, &ledger, &policy, request)) {
+                    error!("{}", e);
+                    break
+                }
                 waiting_time = Duration::from_secs(1);
             }
-            Err(e) => {
-                error!("{}",e);
-                waiting_time = waiting_time * 2;
-            }
+            Err(e) => error!("{}", e),
         }
         sleep(waiting_time);
+        waiting_time = (waiting_time * 2).min(MAX_WAITING_TIME);
+    }
+}
+
+fn main() {
+    let matches = App::new("rbtr-private")
+        .version(option_env!("CARGO_PKG_VERSION").unwrap_or("unknown"))
+        .author(option_env!("CARGO_PKG_AUTHORS").unwrap_or(""))
+        .about("arbiter signing service, serves signing requests without a subcommand")
+        .settings(&[AppSettings::VersionlessSubcommands])
+        .subcommand(SubCommand::with_name("keygen").about("create the arbiter seed at arbiter_seed_path and print its xpub")
+            .arg(Arg::with_name("mnemonic")
+                .long("mnemonic")
+                .required(false)
+                .takes_value(true)
+                .help("restore the seed from its BIP39 mnemonic instead of generating one")))
+        .subcommand(SubCommand::with_name("export-xpub").about("print the arbiter's fingerprint and xpub for rbtr-public's config"))
+        .get_matches();
+
+    SimpleLogger::new().with_level(LevelFilter::Debug).init().unwrap();
+    let config = Config::default().load().unwrap();
+    let result = match matches.subcommand() {
+        ("keygen", Some(args)) => keygen(&config, args.value_of("mnemonic")),
+        ("export-xpub", _) => wallet::read_seed(Path::new(&config.arbiter_seed_path), config.network)
+            .map(|saved_seed| print_xpub(&saved_seed, &config))
+            .map_err(Into::into),
+        _ => serve(config),
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+    use tglib::{
+        bdk::bitcoin::{
+            Network,
+            Script,
+            SigHashType,
+            Transaction,
+            TxIn,
+            TxOut,
+            util::psbt::PartiallySignedTransaction,
+        },
+        script::TgScript,
+        wallet::create_escrow_address,
+    };
+
+    fn test_wallet() -> Wallet {
+        let path = env::temp_dir().join(format!("rbtr-private-seed-{}", hex::encode(tglib::rand::random::<[u8; 8]>())));
+        wallet::write_seed(&path, &SavedSeed::new(Secret::new("pw".to_string()), None, Network::Regtest).unwrap()).unwrap();
+        let wallet = Wallet::load(&path, Secret::new("pw".to_string()), 0, Network::Regtest).unwrap();
+        fs::remove_file(&path).unwrap();
+        wallet
+    }
+
+    #[test]
+    fn payout_sighash_all() {
+        let wallet = test_wallet();
+        let ledger = Ledger::open(Path::new(":memory:")).unwrap();
+        let arbiter_pubkey = wallet.get_escrow_pubkey();
+        let escrow_address = create_escrow_address(&arbiter_pubkey, &arbiter_pubkey, &arbiter_pubkey, Network::Regtest).unwrap();
+        let funding_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xFFFFFFFF, witness: Vec::new() }],
+            output: vec![TxOut { value: 100_000, script_pubkey: escrow_address.script_pubkey() }],
+        };
+        let contract = Contract::new(arbiter_pubkey, arbiter_pubkey, arbiter_pubkey, PartiallySignedTransaction::from_unsigned_tx(funding_tx).unwrap(), TgScript(vec![]), 2, Network::Regtest);
+        let (escrow_outpoint, _escrow_script) = contract.escrow_outpoint().unwrap();
+        let payout_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn { previous_output: escrow_outpoint, script_sig: Script::new(), sequence: 0xFFFFFFFF, witness: Vec::new() }],
+            output: vec![TxOut { value: 90_000, script_pubkey: escrow_address.script_pubkey() }],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(payout_tx).unwrap();
+// a sig that leaves the outputs open
+        psbt.inputs[0].sighash_type = Some(SigHashType::None);
+        let payout = Payout::new(contract, psbt);
+        assert!(sign_payout(&wallet, &ledger, &payout, &escrow_outpoint, "r1").is_err());
+        assert_eq!(ledger.signed_payout(&escrow_outpoint).unwrap(), None);
     }
 }
@@ -0,0 +1,232 @@
+use std::{
+    fmt,
+    fs,
+    str::FromStr,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tglib::{
+    bdk::bitcoin::PublicKey,
+    Error,
+    contract::Contract,
+    wallet::PayoutTemplate,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const PAYOUT_TEMPLATES: [&'static str; 3] = ["token-pair", "token-preimage", "threshold-token"];
+
+// what the arbiter is willing to sign on top of the contract being valid
+// loaded from the toml file at arbiter_policy_path, leaving out a field keeps its default
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+// escrow amount in sats
+    pub min_amount: u64,
+    pub max_amount: Option<u64>,
+// hex pubkeys, empty allows any oracle
+    pub oracle_pubkeys: Vec<String>,
+// see PayoutTemplate::name, scripts that aren't one of these are refused
+    pub payout_templates: Vec<String>,
+// sats in escrows the arbiter approved with the player and hasn't paid out, this one included
+    pub max_player_exposure: Option<u64>,
+// sat/vbyte, on top of the range every contract has to be in
+    pub min_fee_rate: Option<u64>,
+    pub max_fee_rate: Option<u64>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            min_amount: 0,
+            max_amount: None,
+            oracle_pubkeys: Vec::new(),
+            payout_templates: PAYOUT_TEMPLATES.iter().map(|name| name.to_string()).collect(),
+            max_player_exposure: None,
+            min_fee_rate: None,
+            max_fee_rate: None,
+        }
+    }
+}
+
+// code is what the player's software matches on, reason is for people
+#[derive(Debug, PartialEq)]
+pub struct PolicyViolation {
+    pub code: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "policy {}: {}", self.code, self.reason)
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+fn violation(code: &'static str, reason: String) -> Box<dyn std::error::Error> {
+    Box::new(PolicyViolation { code, reason })
+}
+
+impl Policy {
+// an empty path is the default policy
+    pub fn load(path: &str) -> Result<Self> {
+        if path.is_empty() {
+            return Ok(Policy::default())
+        }
+        let file = fs::read_to_string(path).map_err(|e| Error::Config(format!("couldn't read {}: {}", path, e)))?;
+        Policy::from_toml(&file)
+    }
+
+    fn from_toml(file: &str) -> Result<Self> {
+        let policy: Policy = toml::from_str(file).map_err(|e| Error::Config(e.to_string()))?;
+// a typo would otherwise lock out an oracle or a kind of contract without saying why
+        for pubkey in &policy.oracle_pubkeys {
+            PublicKey::from_str(pubkey).map_err(|_| Error::Config(format!("invalid oracle pubkey {}", pubkey)))?;
+        }
+        for name in &policy.payout_templates {
+            if !PAYOUT_TEMPLATES.contains(&name.as_str()) {
+                return Err(Box::new(Error::Config(format!("unknown payout template {}", name))))
+            }
+        }
+        Ok(policy)
+    }
+
+// exposure is what each player already has with the arbiter, see Ledger::exposure
+    pub fn check(&self, contract: &Contract, p1_exposure: u64, p2_exposure: u64) -> Result<()> {
+        let template = match PayoutTemplate::recognize(&contract.payout_script) {
+            Some(template) if self.payout_templates.iter().any(|name| name == template.name()) => template,
+            Some(template) => return Err(violation("payout_script_not_allowed", format!("{} payout scripts aren't accepted", template.name()))),
+            None => return Err(violation("payout_script_not_allowed", "payout script isn't a known template".to_string())),
+        };
+        if !self.oracle_pubkeys.is_empty() {
+            let oracle_pubkeys = template.oracle_pubkeys();
+// a preimage script doesn't say who the oracle is
+            if oracle_pubkeys.is_empty() {
+                return Err(violation("oracle_not_allowed", "payout script doesn't name an oracle".to_string()))
+            }
+            for oracle_pubkey in oracle_pubkeys {
+                if !self.oracle_pubkeys.iter().any(|allowed| PublicKey::from_str(allowed).ok() == Some(oracle_pubkey)) {
+                    return Err(violation("oracle_not_allowed", format!("oracle {} isn't accepted", oracle_pubkey)))
+                }
+            }
+        }
+        let amount = contract.amount()?.as_sat();
+        if amount < self.min_amount {
+            return Err(violation("amount_below_minimum", format!("escrow amount {} is below the minimum {}", amount, self.min_amount)))
+        }
+        if let Some(max_amount) = self.max_amount {
+            if amount > max_amount {
+                return Err(violation("amount_above_maximum", format!("escrow amount {} is above the maximum {}", amount, max_amount)))
+            }
+        }
+        if let Some(min_fee_rate) = self.min_fee_rate {
+            if contract.fee_rate < min_fee_rate {
+                return Err(violation("fee_rate_below_minimum", format!("fee rate {} is below the minimum {}", contract.fee_rate, min_fee_rate)))
+            }
+        }
+        if let Some(max_fee_rate) = self.max_fee_rate {
+            if contract.fee_rate > max_fee_rate {
+                return Err(violation("fee_rate_above_maximum", format!("fee rate {} is above the maximum {}", contract.fee_rate, max_fee_rate)))
+            }
+        }
+        if let Some(max_player_exposure) = self.max_player_exposure {
+            for (pubkey, exposure) in &[(contract.p1_pubkey, p1_exposure), (contract.p2_pubkey, p2_exposure)] {
+                if exposure + amount > max_player_exposure {
+                    return Err(violation("player_exposure_limit", format!("player {} would have {} in escrow, above the limit {}", pubkey, exposure + amount, max_player_exposure)))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use tglib::{
+        bdk::bitcoin::{
+            secp256k1::{
+                Secp256k1,
+                SecretKey,
+            },
+            Address,
+            Network,
+            OutPoint,
+            Script,
+            Transaction,
+            TxIn,
+            TxOut,
+            Txid,
+            hashes::Hash,
+            util::psbt::PartiallySignedTransaction,
+        },
+        wallet::{
+            create_escrow_address,
+            create_threshold_token_script,
+            create_token_pair_script,
+        },
+    };
+
+    fn key(i: u8) -> PublicKey {
+        let secp = Secp256k1::new();
+        PublicKey { compressed: true, key: tglib::bdk::bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[i; 32]).unwrap()) }
+    }
+
+    fn contract(amount: u64, fee_rate: u64, oracle: PublicKey) -> Contract {
+        let escrow_address = create_escrow_address(&key(1), &key(2), &key(3), Network::Regtest).unwrap();
+        let fee_address = Address::p2wpkh(&key(4), Network::Regtest).unwrap();
+        let funding_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xFFFFFFFF, witness: Vec::new() }],
+            output: vec![
+                TxOut { value: amount, script_pubkey: escrow_address.script_pubkey() },
+                TxOut { value: amount / 100, script_pubkey: fee_address.script_pubkey() },
+            ],
+        };
+        let pairs = vec![(Txid::from_slice(&[1; 32]).unwrap(), vec![1; 32]), (Txid::from_slice(&[2; 32]).unwrap(), vec![2; 32])];
+        Contract::new(key(1), key(2), key(3), PartiallySignedTransaction::from_unsigned_tx(funding_tx).unwrap(), create_token_pair_script(&oracle, pairs), fee_rate, Network::Regtest)
+    }
+
+    fn code(result: Result<()>) -> Option<&'static str> {
+        result.err().map(|e| e.downcast_ref::<PolicyViolation>().unwrap().code)
+    }
+
+    #[test]
+    fn default_policy() {
+        assert_eq!(Policy::load("").unwrap(), Policy::default());
+        assert_eq!(Policy::from_toml("").unwrap(), Policy::default());
+        assert!(Policy::default().check(&contract(1_000_000, 2, key(5)), 0, 0).is_ok());
+        let mut other_script = contract(1_000_000, 2, key(5));
+        other_script.payout_script.0.pop();
+        assert_eq!(code(Policy::default().check(&other_script, 0, 0)), Some("payout_script_not_allowed"));
+    }
+
+    #[test]
+    fn policy_checks() {
+        let file = format!("min_amount = 100000\nmax_amount = 2000000\noracle_pubkeys = [\"{}\"]\npayout_templates = [\"token-pair\"]\nmax_player_exposure = 3000000\nmin_fee_rate = 2\nmax_fee_rate = 50\n", key(5));
+        let policy = Policy::from_toml(&file).unwrap();
+        assert!(policy.check(&contract(1_000_000, 2, key(5)), 0, 0).is_ok());
+        assert_eq!(code(policy.check(&contract(10_000, 2, key(5)), 0, 0)), Some("amount_below_minimum"));
+        assert_eq!(code(policy.check(&contract(5_000_000, 2, key(5)), 0, 0)), Some("amount_above_maximum"));
+        assert_eq!(code(policy.check(&contract(1_000_000, 2, key(6)), 0, 0)), Some("oracle_not_allowed"));
+        assert_eq!(code(policy.check(&contract(1_000_000, 1, key(5)), 0, 0)), Some("fee_rate_below_minimum"));
+        assert_eq!(code(policy.check(&contract(1_000_000, 51, key(5)), 0, 0)), Some("fee_rate_above_maximum"));
+        assert!(policy.check(&contract(1_000_000, 2, key(5)), 2_000_000, 0).is_ok());
+        assert_eq!(code(policy.check(&contract(1_000_000, 2, key(5)), 0, 2_000_001)), Some("player_exposure_limit"));
+        let mut threshold = contract(1_000_000, 2, key(5));
+        threshold.payout_script = create_threshold_token_script(&[key(5), key(6)], 1, vec![(Txid::from_slice(&[1; 32]).unwrap(), vec![1; 32])]);
+        assert_eq!(code(policy.check(&threshold, 0, 0)), Some("payout_script_not_allowed"));
+    }
+
+    #[test]
+    fn bad_policy() {
+        assert!(Policy::from_toml("max_amont = 1").is_err());
+        assert!(Policy::from_toml("oracle_pubkeys = [\"02abc\"]").is_err());
+        assert!(Policy::from_toml("payout_templates = [\"winner-takes-all\"]").is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+use redis::{
+    self,
+    Commands,
+    streams::StreamReadReply,
+};
+use tglib::{
+    Error,
+    arbiter::{
+        reply_key,
+        Result,
+        SigningRequest,
+        SigningResponse,
+        SigningServer,
+        SIGNING_GROUP,
+        SIGNING_REQUESTS_STREAM,
+        SIGNING_RESPONSE_PREFIX,
+    },
+};
+
+// approvals are kept this long for retries
+const APPROVAL_TTL: usize = 24 * 60 * 60;
+// replies nobody waited for
+const REPLY_TTL: usize = 60;
+
+// requests come in on a redis stream through a consumer group, replies go out on a list per attempt
+pub struct RedisSigningServer {
+    client: redis::Client,
+    consumer: String,
+}
+
+impl RedisSigningServer {
+    pub fn new(client: redis::Client, consumer: &str) -> Result<Self> {
+        let mut con = client.get_connection()?;
+// fails with BUSYGROUP once the group exists
+        let _r: redis::RedisResult<String> = redis::cmd("XGROUP")
+            .arg("CREATE").arg(SIGNING_REQUESTS_STREAM).arg(SIGNING_GROUP).arg("0").arg("MKSTREAM")
+            .query(&mut con);
+        Ok(RedisSigningServer {
+            client,
+            consumer: consumer.to_string(),
+        })
+    }
+}
+
+impl SigningServer for RedisSigningServer {
+    fn next_request(&self, timeout: Duration) -> Result<Option<(String, String, SigningRequest)>> {
+        let mut con = self.client.get_connection()?;
+        let reply: Option<StreamReadReply> = redis::cmd("XREADGROUP")
+            .arg("GROUP").arg(SIGNING_GROUP).arg(&self.consumer)
+            .arg("COUNT").arg(1)
+            .arg("BLOCK").arg(timeout.as_millis() as u64)
+            .arg("STREAMS").arg(SIGNING_REQUESTS_STREAM).arg(">")
+            .query(&mut con)?;
+        let entry = match reply.and_then(|reply| reply.keys.into_iter().next()).and_then(|key| key.ids.into_iter().next()) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+// acked and deleted on read. if we die before answering, the requester times out and retries with the same id
+        let _acked: u64 = redis::cmd("XACK").arg(SIGNING_REQUESTS_STREAM).arg(SIGNING_GROUP).arg(&entry.id).query(&mut con)?;
+        let _deleted: u64 = redis::cmd("XDEL").arg(SIGNING_REQUESTS_STREAM).arg(&entry.id).query(&mut con)?;
+        let id: String = entry.get("id").ok_or(Error::Adhoc("signing request has no id"))?;
+        let attempt: String = entry.get("attempt").ok_or(Error::Adhoc("signing request has no attempt"))?;
+        let request: String = entry.get("request").ok_or(Error::Adhoc("signing request has no request"))?;
+        Ok(Some((id, attempt, serde_json::from_str(&request)?)))
+    }
+
+    fn respond(&self, id: &str, attempt: &str, response: &SigningResponse) -> Result<()> {
+        let mut con = self.client.get_connection()?;
+        let json = serde_json::to_string(response)?;
+        if let SigningResponse::Approved { .. } = response {
+            let _: () = con.set_ex(format!("{}{}", SIGNING_RESPONSE_PREFIX, id), &json, APPROVAL_TTL)?;
+        }
+        let key = reply_key(id, attempt);
+        let _: () = con.rpush(&key, &json)?;
+        let _: () = con.expire(&key, REPLY_TTL)?;
+        Ok(())
+    }
+
+    fn approved(&self, id: &str) -> Result<Option<SigningResponse>> {
+        let mut con = self.client.get_connection()?;
+        let json: Option<String> = con.get(format!("{}{}", SIGNING_RESPONSE_PREFIX, id))?;
+        match json {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+}
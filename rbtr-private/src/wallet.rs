@@ -1,8 +1,21 @@
-use std::str::FromStr;
+use std::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::{
+        BufReader,
+        BufWriter,
+        Write,
+    },
+    path::Path,
+    str::FromStr,
+};
 use tglib::{
     bdk::{
         bitcoin::{
             Address,
+            Network,
             PublicKey,
             secp256k1::{
                 Message,
@@ -18,67 +31,121 @@ use tglib::{
         signer::SignOptions,
     },
     log::error,
-    secrecy::Secret,
+    secrecy::{
+        ExposeSecret,
+        Secret,
+    },
     Result,
     Error,
     contract::Contract,
     payout::Payout,
     wallet::{
         derive_account_xprivkey,
+        escrow_path,
+        escrow_pubkey,
+        escrow_signing_descriptor,
+        validate_escrow_kix,
+        validate_sighash_all,
         EscrowWallet,
         SavedSeed,
         SigningWallet,
     },
-    mock::{
-        ARBITER_MNEMONIC,
-        ESCROW_KIX,
-        ESCROW_SUBACCOUNT,
-        NETWORK,
-    }
 };
 
+// the public parts are readable without the password, e.g. for export-xpub
+pub fn read_seed(path: &Path, network: Network) -> Result<SavedSeed> {
+    let reader = BufReader::new(File::open(path).map_err(|e| Error::Config(format!("couldn't open arbiter seed {}: {}", path.display(), e)))?);
+    let saved_seed: SavedSeed = serde_json::from_reader(reader).map_err(|e| Error::Config(format!("couldn't read arbiter seed: {}", e)))?;
+    saved_seed.for_network(network)
+}
+
+// never replaces an existing seed, there may be escrows only it can sign for
+pub fn write_seed(path: &Path, saved_seed: &SavedSeed) -> Result<()> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path).map_err(|e| Error::Config(format!("couldn't create arbiter seed {}: {}", path.display(), e)))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, saved_seed).map_err(|e| Error::Config(format!("couldn't write arbiter seed: {}", e)))?;
+    writer.flush().map_err(|e| Error::Config(format!("couldn't write arbiter seed: {}", e)))
+}
+
 pub struct Wallet {
     saved_seed: SavedSeed,
+    network: Network,
+// new contracts get the escrow key at this index
+    escrow_kix: u32,
+// every escrow key up to escrow_kix, indexed by kix, so requests don't derive them again
+    escrow_pubkeys: Vec<PublicKey>,
+    pw: Secret<String>,
 }
 
 impl Wallet {
-    pub fn new(pw: Secret<String>) -> Self {
-       Wallet {
-           saved_seed: SavedSeed::new(pw, Some(Secret::new(ARBITER_MNEMONIC.to_owned()))).unwrap()
-       } 
+// checks the password up front so a typo doesn't surface as failed signing requests
+    pub fn load(path: &Path, pw: Secret<String>, escrow_kix: u32, network: Network) -> Result<Self> {
+        validate_escrow_kix(escrow_kix)?;
+        let saved_seed = read_seed(path, network)?;
+        saved_seed.get_seed(Secret::new(pw.expose_secret().clone()))?;
+        let escrow_pubkeys = (0..=escrow_kix).map(|kix| escrow_pubkey(&saved_seed.xpubkey, kix)).collect();
+        Ok(Wallet {
+            saved_seed,
+            network,
+            escrow_kix,
+            escrow_pubkeys,
+            pw,
+        })
+    }
+
+    fn pw(&self) -> Secret<String> {
+        Secret::new(self.pw.expose_secret().clone())
     }
 
     pub fn get_fee_address(&self) -> Address {
         let a = self.saved_seed.xpubkey.derive_pub(&Secp256k1::new(), &DerivationPath::from_str("m/0/0").unwrap()).unwrap();
-        Address::p2wpkh(&a.public_key, NETWORK).unwrap()
+        Address::p2wpkh(&a.public_key, self.network).unwrap()
+    }
+
+// which of our escrow keys made pubkey, newest first. see tglib::wallet::escrow_kix
+    fn escrow_kix(&self, pubkey: &PublicKey) -> Option<u32> {
+        self.escrow_pubkeys.iter().rposition(|escrow_pubkey| escrow_pubkey == pubkey).map(|kix| kix as u32)
+    }
+
+// the path of whichever of our escrow keys the contract was made with
+    fn contract_escrow_path(&self, contract: &Contract) -> Result<DerivationPath> {
+        let kix = self.escrow_kix(&contract.arbiter_pubkey).ok_or(Error::InvalidContract("unknown arbiter pubkey"))?;
+        Ok(escrow_path(kix))
     }
 
-    pub fn sign_payout(&self, mut payout: Payout, pw: Secret<String>) -> Result<PartiallySignedTransaction> {
+    pub fn sign_contract(&self, contract: &Contract) -> Result<Signature> {
+        self.sign_message(Message::from_slice(&contract.cxid()).unwrap(), self.contract_escrow_path(contract)?, self.pw())
+    }
+
+    pub fn sign_payout(&self, payout: Payout) -> Result<PartiallySignedTransaction> {
+        self.sign_escrow_psbt(&payout.contract, payout.psbt)
+    }
+
+// sign a tx spending the contract's escrow output, e.g. a payout or refund
+    pub fn sign_escrow_psbt(&self, contract: &Contract, mut psbt: PartiallySignedTransaction) -> Result<PartiallySignedTransaction> {
+        validate_sighash_all(&psbt)?;
 // derive escrow private key
-        let path = DerivationPath::from_str(&format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX)).unwrap();
-        let seed = self.saved_seed.get_seed(pw)?;
-        let account_key = derive_account_xprivkey(seed, NETWORK);
+        let path = self.contract_escrow_path(contract)?;
+        let seed = self.saved_seed.get_seed(self.pw())?;
+        let account_key = derive_account_xprivkey(seed, self.network);
         let secp = Secp256k1::new();
-        let escrow_privkey = account_key.derive_priv(&secp, &path).unwrap().private_key;
+        let escrow_privkey = account_key.derive_priv(&secp, &path).map_err(|e| Error::Signer(format!("couldn't derive escrow key: {}", e)))?.private_key;
 // create escrow descriptor wallet
-        let desc = format!("wsh(multi(2,{},{},{}))", 
-                payout.contract.p1_pubkey.to_string(), 
-                payout.contract.p2_pubkey.to_string(), 
-                escrow_privkey.to_wif());
-        let wallet = tglib::bdk::Wallet::new_offline(&desc, None, NETWORK, tglib::bdk::database::MemoryDatabase::default()).unwrap();
-
-        let _finalized = wallet.sign(&mut payout.psbt, SignOptions::default()).unwrap();
-        
-        Ok(payout.psbt)
+        let desc = escrow_signing_descriptor(contract, &escrow_privkey)?;
+        let wallet = tglib::bdk::Wallet::new_offline(&desc, None, self.network, tglib::bdk::database::MemoryDatabase::default())?;
+// the psbt is the player's, e.g. an input without its witness utxo fails here
+        let _finalized = wallet.sign(&mut psbt, SignOptions::default())?;
+
+        Ok(psbt)
     }
 }
 
 impl SigningWallet for Wallet {
-    fn sign_tx(&self, psbt: PartiallySignedTransaction, _path: Option<DerivationPath>, pw: Secret<String>) -> Result<PartiallySignedTransaction> {
+    fn sign_tx(&self, psbt: PartiallySignedTransaction, path: Option<DerivationPath>, pw: Secret<String>) -> Result<PartiallySignedTransaction> {
         let secp = Secp256k1::new();
-        let path = DerivationPath::from_str(&String::from(format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX))).unwrap();
-        let account_key = derive_account_xprivkey(self.saved_seed.get_seed(pw)?, NETWORK);
-        let escrow_key = account_key.derive_priv(&secp, &path).unwrap();
+        let path = path.unwrap_or_else(|| escrow_path(self.escrow_kix));
+        let account_key = derive_account_xprivkey(self.saved_seed.get_seed(pw)?, self.network);
+        let escrow_key = account_key.derive_priv(&secp, &path).map_err(|e| Error::Signer(format!("couldn't derive escrow key: {}", e)))?;
         let mut maybe_signed = psbt.clone();
         match &escrow_key.private_key.sign_tx(&mut maybe_signed, &secp) {
             Ok(()) => {
@@ -92,23 +159,23 @@ impl SigningWallet for Wallet {
     }
 
     fn sign_message(&self, msg: Message, path: DerivationPath, pw: Secret<String>) -> Result<Signature> {
-        let account_key = derive_account_xprivkey(self.saved_seed.get_seed(pw)?, NETWORK);
+        let account_key = derive_account_xprivkey(self.saved_seed.get_seed(pw)?, self.network);
         let secp = Secp256k1::new();
-        let signing_key = account_key.derive_priv(&secp, &path).unwrap();
+        let signing_key = account_key.derive_priv(&secp, &path).map_err(|e| Error::Signer(format!("couldn't derive signing key: {}", e)))?;
         Ok(secp.sign(&msg, &signing_key.private_key.key))
     }
 }
 
 impl EscrowWallet for Wallet {
     fn get_escrow_pubkey(&self) -> PublicKey {
-        let path = DerivationPath::from_str(&String::from(format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX))).unwrap();
-        let escrow_pubkey = self.saved_seed.xpubkey.derive_pub(&Secp256k1::new(), &path).unwrap();
-        escrow_pubkey.public_key
+        self.escrow_pubkeys[self.escrow_kix as usize]
     }
 
+// contracts made with an escrow key we've since rotated away from are still ours to pay out
     fn validate_contract(&self, contract: &Contract) -> Result<()> {
+        contract.validate_network(self.network)?;
 // TODO: better fee validation
-        if contract.arbiter_pubkey != self.get_escrow_pubkey() {
+        if self.escrow_kix(&contract.arbiter_pubkey).is_none() {
             error!("incorrect arbiter pubkey");
             return Err(Error::Adhoc("incorrect arbiter pubkey"));
         }
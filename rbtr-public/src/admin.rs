@@ -0,0 +1,201 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use warp::{
+    http::StatusCode,
+    Filter,
+    Reply,
+    Rejection,
+};
+use tglib::{
+    hex,
+    log::error,
+    Error,
+    JsonResponse,
+    arbiter::{
+        ContractDecision,
+        ContractDetails,
+        Decision,
+        PayoutDecision,
+        ReviewBody,
+        SigningClient,
+        SigningRequest,
+        SigningResponse,
+        SIGNING_TIMEOUT,
+    },
+    contract::Contract,
+};
+use crate::signing::RedisSigningClient;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+type WebResult<T> = std::result::Result<T, Rejection>;
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug, Deserialize)]
+struct DecisionQuery {
+    decision: Option<Decision>,
+}
+
+// everything under admin/ needs "Authorization: Bearer <arbiter_admin_token>"
+pub fn routes(signing_client: RedisSigningClient, token: String) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let signing_client = warp::any().map(move || signing_client.clone());
+    let authorized = warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let ok = check_token(header.as_deref(), &token);
+            async move {
+                if ok {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one();
+
+    let contracts = warp::path!("admin" / "contracts")
+        .and(warp::get())
+        .and(authorized.clone())
+        .and(warp::query::<DecisionQuery>())
+        .and(signing_client.clone())
+        .and_then(contracts_handler);
+
+    let contract = warp::path!("admin" / "contracts" / String)
+        .and(warp::get())
+        .and(authorized.clone())
+        .and(signing_client.clone())
+        .and_then(contract_handler);
+
+    let payouts = warp::path!("admin" / "payouts")
+        .and(warp::get())
+        .and(authorized.clone())
+        .and(warp::query::<DecisionQuery>())
+        .and(signing_client.clone())
+        .and_then(payouts_handler);
+
+    let approve = warp::path!("admin" / "payouts" / String / "approve")
+        .and(warp::post())
+        .and(authorized.clone())
+        .and(warp::body::json())
+        .and(signing_client.clone())
+        .and_then(|request_id: String, body: ReviewBody, signing_client: RedisSigningClient| review_handler(request_id, true, body, signing_client));
+
+    let reject = warp::path!("admin" / "payouts" / String / "reject")
+        .and(warp::post())
+        .and(authorized)
+        .and(warp::body::json())
+        .and(signing_client)
+        .and_then(|request_id: String, body: ReviewBody, signing_client: RedisSigningClient| review_handler(request_id, false, body, signing_client));
+
+    contracts
+        .or(contract)
+        .or(payouts)
+        .or(approve)
+        .or(reject)
+}
+
+// only answers for the admin routes, anything else falls through to warp's own rejections
+pub async fn recover(rejection: Rejection) -> WebResult<impl Reply> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_status(warp::reply::json(&JsonResponse::<()>::error("unauthorized".into(), None)), StatusCode::UNAUTHORIZED))
+    } else {
+        Err(rejection)
+    }
+}
+
+// an empty token turns the admin api off. compares every byte so the time taken doesn't leak the token
+fn check_token(header: Option<&str>, token: &str) -> bool {
+    let presented = match header.and_then(|header| header.strip_prefix("Bearer ")) {
+        Some(presented) => presented,
+        None => return false,
+    };
+    if token.is_empty() || presented.len() != token.len() {
+        return false
+    }
+    presented.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+async fn admin_request<C: SigningClient>(signing_client: &C, request: SigningRequest) -> Result<SigningResponse> {
+    match tokio::task::block_in_place(|| signing_client.request(&request, SIGNING_TIMEOUT))? {
+        Some(SigningResponse::Rejected { reason, .. }) => Err(reason.into()),
+        Some(response) => Ok(response),
+        None => Err("arbiter didn't answer in time, try again".into()),
+    }
+}
+
+async fn contracts<C: SigningClient>(signing_client: &C, decision: Option<Decision>, cxid: Option<String>) -> Result<Vec<ContractDecision>> {
+    match admin_request(signing_client, SigningRequest::Contracts { decision, cxid }).await? {
+        SigningResponse::Contracts { contracts } => Ok(contracts),
+        _ => Err(Box::new(Error::Adhoc("unexpected response from arbiter"))),
+    }
+}
+
+async fn payouts<C: SigningClient>(signing_client: &C, decision: Option<Decision>, cxid: Option<String>) -> Result<Vec<PayoutDecision>> {
+    match admin_request(signing_client, SigningRequest::Payouts { decision, cxid }).await? {
+        SigningResponse::Payouts { payouts } => Ok(payouts),
+        _ => Err(Box::new(Error::Adhoc("unexpected response from arbiter"))),
+    }
+}
+
+// decoded from the latest submission of the contract
+async fn contract_details<C: SigningClient>(signing_client: &C, cxid: String) -> Result<ContractDetails> {
+    let decisions = contracts(signing_client, None, Some(cxid.clone())).await?;
+    let latest = decisions.last().ok_or(Error::Adhoc("unknown contract"))?;
+    let contract = Contract::from_bytes(hex::decode(&latest.contract_hex)?)?;
+    let payouts = payouts(signing_client, None, Some(cxid)).await?;
+    Ok(ContractDetails::new(&contract, decisions, payouts)?)
+}
+
+async fn review<C: SigningClient>(signing_client: &C, request_id: String, approve: bool, reason: String) -> Result<PayoutDecision> {
+    match admin_request(signing_client, SigningRequest::Review { request_id, approve, reason }).await? {
+        SigningResponse::Payouts { mut payouts } if payouts.len() == 1 => Ok(payouts.remove(0)),
+        _ => Err(Box::new(Error::Adhoc("unexpected response from arbiter"))),
+    }
+}
+
+fn reply<T: Serialize>(result: Result<T>) -> warp::reply::Json {
+    match result {
+        Ok(data) => warp::reply::json(&JsonResponse::success(Some(data))),
+        Err(e) => {
+            error!("{:?}", e);
+            warp::reply::json(&JsonResponse::<T>::error(e.to_string(), None))
+        }
+    }
+}
+
+async fn contracts_handler(query: DecisionQuery, signing_client: RedisSigningClient) -> WebResult<impl Reply> {
+    Ok(reply(contracts(&signing_client, query.decision, None).await))
+}
+
+async fn contract_handler(cxid: String, signing_client: RedisSigningClient) -> WebResult<impl Reply> {
+    Ok(reply(contract_details(&signing_client, cxid).await))
+}
+
+async fn payouts_handler(query: DecisionQuery, signing_client: RedisSigningClient) -> WebResult<impl Reply> {
+    Ok(reply(payouts(&signing_client, query.decision, None).await))
+}
+
+async fn review_handler(request_id: String, approve: bool, body: ReviewBody, signing_client: RedisSigningClient) -> WebResult<impl Reply> {
+    Ok(reply(review(&signing_client, request_id, approve, body.reason).await))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn admin_token() {
+        assert!(check_token(Some("Bearer s3cret"), "s3cret"));
+        assert!(!check_token(Some("Bearer s3cres"), "s3cret"));
+        assert!(!check_token(Some("Bearer s3cret2"), "s3cret"));
+        assert!(!check_token(Some("s3cret"), "s3cret"));
+        assert!(!check_token(None, "s3cret"));
+// disabled
+        assert!(!check_token(Some("Bearer "), ""));
+    }
+}
@@ -1,15 +1,10 @@
 use std::{
+    fmt,
     str::FromStr,
+    sync::Arc,
     time::Duration,
 };
-use redis::{
-    self,
-    AsyncCommands,
-    RedisResult,
-    aio::Connection,
-};
 use simple_logger::SimpleLogger;
-use tokio::time::sleep;
 use warp::{
     Filter,
     Reply,
@@ -38,10 +33,7 @@ use tglib::{
                 },
                 psbt::PartiallySignedTransaction,
             },
-            secp256k1::Signature,
         },
-        blockchain::ElectrumBlockchain,
-        database::MemoryDatabase,
         electrum_client::Client,
     },
     hex,
@@ -51,129 +43,175 @@ use tglib::{
     },
     Error,
     JsonResponse,
+    config::Config,
+    chain::ChainSource,
     arbiter::{
+        SigningClient,
+        SigningRequest,
+        SigningResponse,
         SubmitContractBody,
         SubmitPayoutBody,
+        SIGNING_TIMEOUT,
+    },
+    contract::{
+        Contract,
+        ContractState,
     },
-    contract::Contract,
     payout::Payout,
     wallet::EscrowWallet,
-    mock::{                  
-        ARBITER_FINGERPRINT,
-        ARBITER_XPUBKEY,
-        ELECTRS_SERVER,
-        NETWORK,             
-        REDIS_SERVER,
-    },
 };
+mod admin;
+mod signing;
 mod wallet;
+use signing::RedisSigningClient;
 use wallet::Wallet;
 
 type Response = JsonResponse<String>;
 
-const BITCOIN_RPC_URL: &'static str = "http://electrs:18443";
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 type WebResult<T> = std::result::Result<T, Rejection>;
 
-fn wallet() -> Wallet<ElectrumBlockchain, MemoryDatabase> {
-    let mut client = Client::new(ELECTRS_SERVER);
+// rbtr-private's refusal, the code goes back to the player as the response data
+#[derive(Debug)]
+struct SigningRejection {
+    reason: String,
+    code: Option<String>,
+}
+
+impl fmt::Display for SigningRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "arbiter rejected request: {}", self.reason)
+    }
+}
+
+impl std::error::Error for SigningRejection {}
+
+fn error_response(e: &(dyn std::error::Error + 'static)) -> Response {
+    let code = e.downcast_ref::<SigningRejection>().and_then(|rejection| rejection.code.clone());
+    Response::error(e.to_string(), code)
+}
+
+fn electrum_client(config: &Config) -> Client {
+    let mut client = Client::new(&config.electrum_url);
     while client.is_err() {
         std::thread::sleep(Duration::from_secs(1));
-        client = Client::new(ELECTRS_SERVER);
+        client = Client::new(&config.electrum_url);
     }
-//TODO: should this database be persisted?
-    Wallet::<ElectrumBlockchain, MemoryDatabase>::new(Fingerprint::from_str(ARBITER_FINGERPRINT).unwrap(), ExtendedPubKey::from_str(ARBITER_XPUBKEY).unwrap(), ElectrumBlockchain::from(client.unwrap()), NETWORK)
+    client.unwrap()
 }
 
-fn get_escrow_pubkey() -> PublicKey {
-    EscrowWallet::get_escrow_pubkey(&wallet())
+// what rbtr-private export-xpub printed, checked once at startup
+fn arbiter_key(config: &Config) -> Result<(Fingerprint, ExtendedPubKey)> {
+    if config.arbiter_fingerprint.is_empty() || config.arbiter_xpubkey.is_empty() {
+        return Err(Box::new(Error::Config("arbiter_fingerprint and arbiter_xpubkey aren't set, see rbtr-private export-xpub".to_string())))
+    }
+    let fingerprint = Fingerprint::from_str(&config.arbiter_fingerprint).map_err(|_| Error::Config("invalid arbiter_fingerprint".to_string()))?;
+    let xpubkey = ExtendedPubKey::from_str(&config.arbiter_xpubkey).map_err(|_| Error::Config("invalid arbiter_xpubkey".to_string()))?;
+    Ok((fingerprint, xpubkey))
 }
 
-// TODO: more functions like these for the other redis ops
-async fn push_contract(con: &mut Connection, hex: &str) -> RedisResult<String> {
-    con.rpush("contracts", hex).await?;
-    Ok(String::from(hex))
+// rejections come back with rbtr-private's reason, timeouts can be retried with the same request
+// and so can flagged payouts once an operator has reviewed them
+async fn request_signature<C: SigningClient>(signing_client: &C, request: SigningRequest) -> Result<String> {
+    match tokio::task::block_in_place(|| signing_client.request(&request, SIGNING_TIMEOUT))? {
+        Some(SigningResponse::Approved { hex }) => Ok(hex),
+        Some(SigningResponse::Rejected { reason, code }) => Err(Box::new(SigningRejection { reason, code })),
+        Some(SigningResponse::Pending { reason }) => Err(format!("payout waiting for arbiter review: {}", reason).into()),
+        Some(_) => Err(Box::new(Error::Adhoc("unexpected response from arbiter"))),
+        None => Err("arbiter didn't answer in time, try again".into()),
+    }
 }
 
-async fn push_payout(con: &mut Connection, hex: &str) -> RedisResult<String> {
-    con.rpush("payouts", hex).await?;
-    Ok(String::from(hex))
+async fn submit_contract<C: SigningClient>(signing_client: &C, wallet: &Wallet, contract: &Contract, config: &Config) -> Result<Contract> {
+// the script analysis can take a while, keep it off the async workers
+    tokio::task::block_in_place(|| wallet.validate_contract(&contract))?;
+// new contracts have to use the current escrow key from escrow-pubkey
+    if contract.arbiter_pubkey != wallet.get_escrow_pubkey() {
+        let e = Error::InvalidContract("arbiter escrow key has been rotated, get the current one from escrow-pubkey");
+        error!("{:?}", e);
+        return Err(Box::new(e))
+    }
+// rbtr-private checks the refund lock time against it
+    let height = tokio::task::block_in_place(|| electrum_client(config).tip_height())?;
+    let signed = Contract::from_bytes(hex::decode(request_signature(signing_client, SigningRequest::contract(contract, height)).await?)?)?;
+    if signed.cxid() != contract.cxid() {
+        let e = Error::InvalidContract("arbiter signed a different contract");
+        error!("{:?}", e);
+        return Err(Box::new(e))
+    }
+    Ok(signed)
 }
 
-async fn submit_contract(con: &mut Connection, contract: &Contract) -> Result<Signature> {
-    wallet().validate_contract(&contract)?;
-
-    let _r = push_contract(con, &hex::encode(contract.to_bytes())).await.unwrap();
-    let cxid = hex::encode(contract.cxid());
-    for _ in 1..15 as u32 {
-        sleep(Duration::from_secs(1)).await;
-        let r: RedisResult<String> = con.get(cxid.clone()).await;
-        if let Ok(sig_hex) = r {
-            let _r : RedisResult<u64> = con.del(cxid).await;
-            return Ok(Signature::from_der(&hex::decode(sig_hex).unwrap()).unwrap())
-        }
+async fn submit_payout<C: SigningClient>(signing_client: &C, wallet: &Wallet, payout: &Payout, config: &Config) -> Result<PartiallySignedTransaction> {
+// a payout with a script the arbiter doesn't recognize still goes through, rbtr-private flags it for review
+    tokio::task::block_in_place(|| wallet.validate_payout_tx(&payout))?;
+// only pay out contracts whose funding tx has confirmed and whose escrow is still unspent
+    if tokio::task::block_in_place(|| payout.contract.chain_state(&electrum_client(config)))? != ContractState::Live {
+        let e = Error::InvalidPayout("contract isn't live");
+        error!("{:?}", e);
+        return Err(Box::new(e))
+    }
+    let psbt: PartiallySignedTransaction = consensus::deserialize(&hex::decode(request_signature(signing_client, SigningRequest::payout(payout)).await?)?)?;
+    if psbt.global.unsigned_tx != payout.psbt.global.unsigned_tx {
+        let e = Error::InvalidPayout("arbiter signed a different payout");
+        error!("{:?}", e);
+        return Err(Box::new(e))
     }
-    let e = Error::InvalidContract("arbiter rejected contract");
-    error!("{:?}", e);
-    Err(Box::new(e))
+    Ok(psbt)
 }
 
-async fn submit_payout(con: &mut Connection, payout: &Payout) -> Result<PartiallySignedTransaction> {
-    wallet().validate_payout(&payout)?;
-    let _r = push_payout(con, &hex::encode(payout.to_bytes())).await.unwrap();
-    let cxid = hex::encode(payout.contract.cxid());
-    for _ in 1..15 as u32 {
-        sleep(Duration::from_secs(1)).await;
-        let r: RedisResult<String> = con.get(cxid.clone()).await;
-        if let Ok(tx) = r {
-            let _r : RedisResult<u64> = con.del(cxid).await;
-            return Ok(consensus::deserialize::<PartiallySignedTransaction>(&hex::decode(tx).unwrap()).unwrap())
-        }
-    }
-    let e = Error::InvalidPayout("arbiter rejected payout");
-    error!("{:?}", e);
-    Err(Box::new(e))
+// request bodies are untrusted so decoding errors go back to the client
+fn decode_contract(contract_hex: &str) -> Result<Contract> {
+    Ok(Contract::from_bytes(hex::decode(contract_hex)?)?)
+}
+
+fn decode_payout(payout_hex: &str) -> Result<Payout> {
+    Ok(Payout::from_bytes(hex::decode(payout_hex)?)?)
 }
 
-async fn submit_contract_handler(body: SubmitContractBody, redis_client: redis::Client) -> WebResult<impl Reply> {
-    let mut con = redis_client.get_async_connection().await.unwrap();
-    let contract = Contract::from_bytes(hex::decode(body.contract_hex).unwrap()).unwrap();
-    match submit_contract(&mut con, &contract).await {
-        Ok(sig) => Ok(warp::reply::json(&Response::success(Some(hex::encode(sig.serialize_der()))))),
+async fn submit_contract_handler(body: SubmitContractBody, signing_client: RedisSigningClient, wallet: Arc<Wallet>, config: Config) -> WebResult<impl Reply> {
+    let contract = match decode_contract(&body.contract_hex) {
+        Ok(contract) => contract,
+        Err(e) => return Ok(warp::reply::json(&Response::error(e.to_string(), None))),
+    };
+    match submit_contract(&signing_client, &wallet, &contract, &config).await {
+        Ok(contract) => Ok(warp::reply::json(&Response::success(Some(hex::encode(contract.to_bytes()))))),
         Err(e) => {
             error!("{:?}", e);
-            Ok(warp::reply::json(&Response::error(e.to_string(),None)))
+            Ok(warp::reply::json(&error_response(e.as_ref())))
         }
     }
 }
 
 // TODO: somehow break out of serialize(decode( hell
-async fn submit_payout_handler(body: SubmitPayoutBody, redis_client: redis::Client) -> WebResult<impl Reply> {
-    let mut con = redis_client.get_async_connection().await.unwrap();
-    let payout = Payout::from_bytes(hex::decode(body.payout_hex).unwrap()).unwrap();
-    match submit_payout(&mut con, &payout).await {
+async fn submit_payout_handler(body: SubmitPayoutBody, signing_client: RedisSigningClient, wallet: Arc<Wallet>, config: Config) -> WebResult<impl Reply> {
+    let payout = match decode_payout(&body.payout_hex) {
+        Ok(payout) => payout,
+        Err(e) => return Ok(warp::reply::json(&Response::error(e.to_string(), None))),
+    };
+    match submit_payout(&signing_client, &wallet, &payout, &config).await {
         Ok(tx) => Ok(warp::reply::json(&Response::success(Some(hex::encode(consensus::serialize(&tx)))))),
         Err(e) => {
             error!("{:?}", e);
-            Ok(warp::reply::json(&Response::error(e.to_string(),None)))
+            Ok(warp::reply::json(&error_response(e.as_ref())))
         }
     }
 }
 
-async fn fund_address_handler(address: String) -> WebResult<impl Reply> { 
+async fn fund_address_handler(address: String, config: Config) -> WebResult<impl Reply> { 
     let address = RpcAddress::from_str(&address).unwrap();
-    let bitcoin_rpc_client = RpcClient::new(BITCOIN_RPC_URL.to_string(), Auth::UserPass("admin".to_string(), "passw".to_string())).unwrap();
+    let bitcoin_rpc_client = RpcClient::new(config.bitcoin_rpc_url.clone(), Auth::UserPass(config.bitcoin_rpc_user.clone(), config.bitcoin_rpc_password.clone())).unwrap();
     let coinbase_addr = bitcoin_rpc_client.get_new_address(None, None).unwrap();
     let txid = bitcoin_rpc_client.send_to_address(&address, Amount::ONE_BTC, None, None, None, None, None, None).unwrap();
     let _blockhashes = bitcoin_rpc_client.generate_to_address(150, &coinbase_addr).unwrap();
     Ok(txid.to_hex())
 }
 
-fn redis_client() -> redis::Client {
-    let mut client = redis::Client::open(REDIS_SERVER);
+fn redis_client(config: &Config) -> redis::Client {
+    let mut client = redis::Client::open(config.redis_url.as_str());
     while client.is_err() {
         std::thread::sleep(Duration::from_millis(100));
-        client = redis::Client::open(REDIS_SERVER);
+        client = redis::Client::open(config.redis_url.as_str());
     }
     client.unwrap()
 }
@@ -188,13 +226,24 @@ async fn main() {
         .with_module_level("reqwest",LevelFilter::Warn)
         .init()
         .unwrap();
-    
-    let escrow_pubkey = get_escrow_pubkey();
+
+    let config = Config::default().load().unwrap();
+    let wallet = match arbiter_key(&config).and_then(|(_fingerprint, xpubkey)| Ok(Wallet::new(xpubkey, config.arbiter_escrow_kix, config.network)?)) {
+        Ok(wallet) => Arc::new(wallet),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let escrow_pubkey = wallet.get_escrow_pubkey();
     let escrow_pubkey = warp::any().map(move || escrow_pubkey.clone());
-    let fee_address = wallet().get_fee_address();
+    let fee_address = wallet.get_fee_address();
     let fee_address = warp::any().map(move || fee_address.clone());
-    let redis_client = redis_client();
-    let redis_client = warp::any().map(move || redis_client.clone());
+    let wallet = warp::any().map(move || wallet.clone());
+    let signing_client = RedisSigningClient::new(redis_client(&config));
+    let admin = admin::routes(signing_client.clone(), config.arbiter_admin_token.clone());
+    let signing_client = warp::any().map(move || signing_client.clone());
+    let config = warp::any().map(move || config.clone());
 
     let get_escrow_pubkey = warp::path("escrow-pubkey")
         .and(escrow_pubkey)
@@ -207,24 +256,31 @@ async fn main() {
     let submit_contract = warp::path("submit-contract")
         .and(warp::post())
         .and(warp::body::json())
-        .and(redis_client.clone())
+        .and(signing_client.clone())
+        .and(wallet.clone())
+        .and(config.clone())
         .and_then(submit_contract_handler);
 
     let submit_payout = warp::path("submit-payout")
         .and(warp::post())
         .and(warp::body::json())
-        .and(redis_client.clone())
+        .and(signing_client.clone())
+        .and(wallet.clone())
+        .and(config.clone())
         .and_then(submit_payout_handler);
 
 // TODO: can add validation filters for path params?
     let fund_address = warp::path("fund-address")
         .and(warp::path::param::<String>())
+        .and(config.clone())
         .and_then(fund_address_handler);
 
     let routes = get_escrow_pubkey
         .or(get_fee_address)
         .or(submit_contract)
         .or(submit_payout)
-        .or(fund_address);
+        .or(fund_address)
+        .or(admin)
+        .recover(admin::recover);
     warp::serve(routes).run(([0, 0, 0, 0], 5000)).await;
 }
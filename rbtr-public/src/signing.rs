@@ -0,0 +1,49 @@
+use std::time::Duration;
+use redis::{
+    self,
+    Commands,
+};
+use tglib::arbiter::{
+    new_attempt,
+    reply_key,
+    Result,
+    SigningClient,
+    SigningRequest,
+    SigningResponse,
+    SIGNING_REQUESTS_STREAM,
+    SIGNING_STREAM_MAXLEN,
+};
+
+// puts requests on the stream rbtr-private reads and blocks on the reply list for this attempt
+#[derive(Clone)]
+pub struct RedisSigningClient {
+    client: redis::Client,
+}
+
+impl RedisSigningClient {
+    pub fn new(client: redis::Client) -> Self {
+        RedisSigningClient { client }
+    }
+}
+
+impl SigningClient for RedisSigningClient {
+    fn request(&self, request: &SigningRequest, timeout: Duration) -> Result<Option<SigningResponse>> {
+        let mut con = self.client.get_connection()?;
+        let id = request.id();
+        let attempt = new_attempt();
+        let _entry_id: String = redis::cmd("XADD")
+            .arg(SIGNING_REQUESTS_STREAM)
+            .arg("MAXLEN").arg("~").arg(SIGNING_STREAM_MAXLEN)
+            .arg("*")
+            .arg("id").arg(&id)
+            .arg("attempt").arg(&attempt)
+            .arg("request").arg(serde_json::to_string(request)?)
+            .query(&mut con)?;
+// 0 would block forever
+        let reply: Option<(String, String)> = con.blpop(reply_key(&id, &attempt), timeout.as_secs().max(1) as usize)?;
+        match reply {
+            Some((_key, json)) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -1,111 +1,69 @@
 use std::str::FromStr;
 use tglib::{
-    bdk::{
-        Wallet as BdkWallet,
-        database::BatchDatabase,
-        bitcoin::{
-            Address,
-            Network,
-            PublicKey,
-            secp256k1::Secp256k1,
-            util::bip32::{
-                ExtendedPubKey,
-                DerivationPath,
-                Fingerprint,
-            }
+    bdk::bitcoin::{
+        Address,
+        Network,
+        PublicKey,
+        secp256k1::Secp256k1,
+        util::bip32::{
+            ExtendedPubKey,
+            DerivationPath,
         },
-        blockchain::Blockchain,
     },
     log::error,
     Result,
     Error,
     contract::Contract,
     wallet::{
+        escrow_pubkey,
+        validate_escrow_kix,
         EscrowWallet,
-        BITCOIN_ACCOUNT_PATH,
-        ESCROW_SUBACCOUNT,
     },
 };
 
-const ESCROW_KIX: u64 = 0;
-
-pub struct Wallet<B, D> where D: BatchDatabase {
+// the arbiter's public keys, built once at startup and shared by every request
+pub struct Wallet {
     pub xpubkey: ExtendedPubKey,
     pub network: Network,
-    pub wallet: BdkWallet<B, D>,
+// see Config::arbiter_escrow_kix
+    pub escrow_kix: u32,
+// every escrow key up to escrow_kix, indexed by kix, so requests don't derive them again
+    escrow_pubkeys: Vec<PublicKey>,
 }
 
-impl<B, D> Wallet<B, D> 
-where 
-    B: Blockchain,
-    D: BatchDatabase + Default,
-{
-    pub fn new(fingerprint: Fingerprint, xpubkey: ExtendedPubKey, client: B, network: Network) -> Self {
-        let descriptor_key = format!("[{}/{}]{}", fingerprint, BITCOIN_ACCOUNT_PATH, xpubkey);
-        let external_descriptor = format!("wpkh({}/0/*)", descriptor_key);
-        let internal_descriptor = format!("wpkh({}/1/*)", descriptor_key);
-
-        Wallet {
+impl Wallet {
+    pub fn new(xpubkey: ExtendedPubKey, escrow_kix: u32, network: Network) -> Result<Self> {
+        validate_escrow_kix(escrow_kix)?;
+        let escrow_pubkeys = (0..=escrow_kix).map(|kix| escrow_pubkey(&xpubkey, kix)).collect();
+        Ok(Wallet {
             xpubkey,
             network,
-            wallet: BdkWallet::new(
-                &external_descriptor,
-                Some(&internal_descriptor),
-                network,
-//                sled::open("wallet-db").unwrap().open_tree("wallet-db").unwrap(),
-                D::default(),
-                client,
-            ).unwrap(),
-        }
+            escrow_kix,
+            escrow_pubkeys,
+        })
     }
-}
 
-impl<B, D> Wallet<B, D>
-where
-    D: BatchDatabase + Default, 
-{
     pub fn get_fee_address(&self) -> Address {
 // TODO: this should come from a separate wallet and the address hosted statically
         let a = self.xpubkey.derive_pub(&Secp256k1::new(), &DerivationPath::from_str("m/0/0").unwrap()).unwrap();
         Address::p2wpkh(&a.public_key, self.network).unwrap()
     }
-}
 
-impl<D> Wallet<(),D>
-where
-    D: BatchDatabase + Default 
-{
-    #[allow(dead_code)]
-    pub fn new_offline(fingerprint: Fingerprint, xpubkey: ExtendedPubKey, network: Network) -> Self {
-        let descriptor_key = format!("[{}/{}]{}", fingerprint, BITCOIN_ACCOUNT_PATH, xpubkey);
-        let external_descriptor = format!("wpkh({}/0/*)", descriptor_key);
-        let internal_descriptor = format!("wpkh({}/1/*)", descriptor_key);
-
-        Wallet {
-            xpubkey,
-            network,
-            wallet: BdkWallet::new_offline(
-                &external_descriptor,
-                Some(&internal_descriptor),
-                network,
-                D::default(),
-            ).unwrap(),
-        }
+// which of the arbiter's escrow keys made pubkey, newest first. see tglib::wallet::escrow_kix
+    fn escrow_kix(&self, pubkey: &PublicKey) -> Option<u32> {
+        self.escrow_pubkeys.iter().rposition(|escrow_pubkey| escrow_pubkey == pubkey).map(|kix| kix as u32)
     }
 }
 
-impl<B, D> EscrowWallet for Wallet<B, D>
-where 
-    D: BatchDatabase + Default,
-{
+impl EscrowWallet for Wallet {
     fn get_escrow_pubkey(&self) -> PublicKey {
-        let path = DerivationPath::from_str(&String::from(format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX))).unwrap();
-        let escrow_pubkey = self.xpubkey.derive_pub(&Secp256k1::new(), &path).unwrap();
-        escrow_pubkey.public_key
+        self.escrow_pubkeys[self.escrow_kix as usize]
     }
 
+// any of the arbiter's escrow keys so far, payouts of contracts made before a rotation still go through
     fn validate_contract(&self, contract: &Contract) -> Result<()> {
-        if contract.arbiter_pubkey != self.get_escrow_pubkey() {
+        contract.validate_network(self.network)?;
+        if self.escrow_kix(&contract.arbiter_pubkey).is_none() {
             let e = Error::Adhoc("unexpected arbiter pubkey");
             error!("{}", e);
             return Err(e);
@@ -11,9 +11,11 @@ use tglib::{
         },
     },
     hex,
-    mock::REFEREE_PRIVKEY,
 };
 
+// the referee is a dev oracle, this is the same key as tglib::mock::REFEREE_PRIVKEY
+pub const REFEREE_PRIVKEY: &'static str = "L52hw8to1fdBj9eP8HESBNrfcbehxvKU1vsqWjmHJavxNEi9q91i";
+
 fn main() {
     let matches = App::new("referee-signer")
         .version("1.0")
@@ -1,12 +1,31 @@
+use std::{
+    str::FromStr,
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    sync::{
+        Arc,
+        Condvar,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
 use serde::{
     Deserialize,
     Serialize,
 };
 use bdk::bitcoin::{
     hash_types::Txid,
+    hashes::{
+        sha256,
+        Hash,
+    },
     Address,
     PublicKey,
-    secp256k1::Signature,
     util::psbt::PartiallySignedTransaction,
 };
 use crate::{
@@ -19,7 +38,8 @@ pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 pub trait ArbiterService {
     fn get_escrow_pubkey(&self) -> Result<PublicKey>;
     fn get_fee_address(&self) -> Result<Address>;
-    fn submit_contract(&self, contract: &Contract) -> Result<Signature>;
+// returns the contract with the arbiter's sig added and its refund tx co-signed
+    fn submit_contract(&self, contract: &Contract) -> Result<Contract>;
     fn submit_payout(&self, payout: &Payout) -> Result<PartiallySignedTransaction>;
 // testnet
     fn fund_address(&self, address: Address) -> Result<Txid>;
@@ -34,3 +54,342 @@ pub struct SubmitContractBody {
 pub struct SubmitPayoutBody {
     pub payout_hex: String,
 }
+
+// how long rbtr-public waits on rbtr-private before telling the player to try again
+pub const SIGNING_TIMEOUT: Duration = Duration::from_secs(15);
+// redis keys shared by rbtr-public and rbtr-private
+pub const SIGNING_REQUESTS_STREAM: &'static str = "signing-requests";
+pub const SIGNING_GROUP: &'static str = "rbtr-private";
+pub const SIGNING_REPLY_PREFIX: &'static str = "signing-reply-";
+// the stream is trimmed to about this many entries, rbtr-private also deletes the ones it read
+pub const SIGNING_STREAM_MAXLEN: usize = 10000;
+pub const SIGNING_RESPONSE_PREFIX: &'static str = "signing-response-";
+
+// what rbtr-public asks rbtr-private to sign, and the admin api's questions about what it signed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SigningRequest {
+// height is the chain tip as rbtr-public saw it, rbtr-private can't see the chain itself
+    Contract { contract_hex: String, #[serde(default)] height: u32 },
+    Payout { payout_hex: String },
+    Contracts { decision: Option<Decision>, cxid: Option<String> },
+    Payouts { decision: Option<Decision>, cxid: Option<String> },
+// an operator's call on a payout flagged for review
+    Review { request_id: String, approve: bool, reason: String },
+}
+
+impl SigningRequest {
+    pub fn contract(contract: &Contract, height: u32) -> Self {
+        SigningRequest::Contract { contract_hex: hex::encode(contract.to_bytes()), height }
+    }
+
+    pub fn payout(payout: &Payout) -> Self {
+        SigningRequest::Payout { payout_hex: hex::encode(payout.to_bytes()) }
+    }
+
+// the same request always gets the same id, so a retry picks up the earlier answer
+// and concurrent submissions of one contract don't step on each other, even a block apart
+    pub fn id(&self) -> String {
+        let preimage = match self {
+            SigningRequest::Contract { contract_hex, .. } => format!("contract:{}", contract_hex),
+            SigningRequest::Payout { payout_hex } => format!("payout:{}", payout_hex),
+            request => serde_json::to_string(request).unwrap(),
+        };
+        hex::encode(sha256::Hash::hash(preimage.as_bytes()))
+    }
+}
+
+// approved contracts come back with the arbiter sig and co-signed refund, payouts as the signed psbt
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum SigningResponse {
+    Approved { hex: String },
+// code is a stable name for the rule that rejected it, e.g. a policy check in rbtr-private
+    Rejected { reason: String, #[serde(default)] code: Option<String> },
+// waiting on an operator, see SigningRequest::Review
+    Pending { reason: String },
+// answers to the admin api, a review answers with the payout it updated
+    Contracts { contracts: Vec<ContractDecision> },
+    Payouts { payouts: Vec<PayoutDecision> },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Approved,
+    Rejected,
+    Pending,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Approved => "approved",
+            Decision::Rejected => "rejected",
+            Decision::Pending => "pending",
+        }
+    }
+}
+
+impl FromStr for Decision {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "approved" => Ok(Decision::Approved),
+            "rejected" => Ok(Decision::Rejected),
+            "pending" => Ok(Decision::Pending),
+            _ => Err(crate::Error::Adhoc("unknown decision")),
+        }
+    }
+}
+
+// the arbiter's records of what it was asked to sign and what it did, kept by rbtr-private
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ContractDecision {
+    pub request_id: String,
+    pub cxid: String,
+    pub contract_hex: String,
+    pub decision: Decision,
+    pub reason: Option<String>,
+// der hex
+    pub sig: Option<String>,
+    pub refund_psbt: Option<String>,
+// unix time
+    pub created_at: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PayoutDecision {
+    pub request_id: String,
+    pub cxid: String,
+    pub escrow_outpoint: String,
+    pub txid: String,
+    pub payout_hex: String,
+    pub decision: Decision,
+    pub reason: Option<String>,
+    pub psbt: Option<String>,
+// decided by an operator rather than by the arbiter's checks
+    pub reviewed: bool,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReviewBody {
+    pub reason: String,
+}
+
+// a contract decoded for an operator, with everything the arbiter did about it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContractDetails {
+    pub cxid: String,
+    pub network: String,
+    pub state: String,
+    pub p1_pubkey: String,
+    pub p2_pubkey: String,
+    pub arbiter_pubkey: String,
+    pub amount: u64,
+    pub fee_rate: u64,
+    pub funding_txid: String,
+    pub escrow_outpoint: String,
+    pub refund_txid: Option<String>,
+    pub payout_script: String,
+    pub decisions: Vec<ContractDecision>,
+    pub payouts: Vec<PayoutDecision>,
+}
+
+impl ContractDetails {
+    pub fn new(contract: &Contract, decisions: Vec<ContractDecision>, payouts: Vec<PayoutDecision>) -> crate::Result<Self> {
+        Ok(ContractDetails {
+            cxid: hex::encode(contract.cxid()),
+            network: contract.network.to_string(),
+            state: contract.state().to_string(),
+            p1_pubkey: contract.p1_pubkey.to_string(),
+            p2_pubkey: contract.p2_pubkey.to_string(),
+            arbiter_pubkey: contract.arbiter_pubkey.to_string(),
+            amount: contract.amount()?.as_sat(),
+            fee_rate: contract.fee_rate,
+            funding_txid: contract.funding_tx.global.unsigned_tx.txid().to_string(),
+            escrow_outpoint: contract.escrow_outpoint()?.0.to_string(),
+            refund_txid: contract.refund_tx.as_ref().map(|psbt| psbt.global.unsigned_tx.txid().to_string()),
+            payout_script: contract.payout_script.to_string(),
+            decisions,
+            payouts,
+        })
+    }
+}
+
+// each attempt gets its own reply list, so a late answer to one that timed out isn't taken for the next one's
+pub fn reply_key(id: &str, attempt: &str) -> String {
+    format!("{}{}-{}", SIGNING_REPLY_PREFIX, id, attempt)
+}
+
+pub fn new_attempt() -> String {
+    hex::encode(rand::random::<[u8; 8]>())
+}
+
+// rbtr-public's end. None if nobody answered in time
+pub trait SigningClient {
+    fn request(&self, request: &SigningRequest, timeout: Duration) -> Result<Option<SigningResponse>>;
+}
+
+// rbtr-private's end. requests come with their id and the attempt to answer
+pub trait SigningServer {
+    fn next_request(&self, timeout: Duration) -> Result<Option<(String, String, SigningRequest)>>;
+    fn respond(&self, id: &str, attempt: &str, response: &SigningResponse) -> Result<()>;
+// an earlier approval for the request, if any
+    fn approved(&self, id: &str) -> Result<Option<SigningResponse>>;
+}
+
+// answer the next request with handle, or the approval it already got. false if there wasn't one
+// rejections aren't reused since whatever caused them may be fixed by the retry
+pub fn serve_next<S, F>(server: &S, timeout: Duration, handle: F) -> Result<bool>
+where S: SigningServer, F: FnOnce(&SigningRequest) -> SigningResponse {
+    let (id, attempt, request) = match server.next_request(timeout)? {
+        Some(next) => next,
+        None => return Ok(false),
+    };
+    let response = match server.approved(&id)? {
+        Some(response) => response,
+        None => handle(&request),
+    };
+    server.respond(&id, &attempt, &response)?;
+    Ok(true)
+}
+
+// both ends in one process, for tests
+#[derive(Clone, Default)]
+pub struct LocalSigningChannel {
+    state: Arc<(Mutex<LocalSigningState>, Condvar)>,
+}
+
+#[derive(Default)]
+struct LocalSigningState {
+    requests: VecDeque<(String, String, SigningRequest)>,
+    replies: HashMap<String, VecDeque<SigningResponse>>,
+    approved: HashMap<String, SigningResponse>,
+}
+
+impl SigningClient for LocalSigningChannel {
+    fn request(&self, request: &SigningRequest, timeout: Duration) -> Result<Option<SigningResponse>> {
+        let (lock, cvar) = &*self.state;
+        let id = request.id();
+        let attempt = new_attempt();
+        let key = reply_key(&id, &attempt);
+        let deadline = Instant::now() + timeout;
+        let mut state = lock.lock().unwrap();
+        state.requests.push_back((id, attempt, request.clone()));
+        cvar.notify_all();
+        loop {
+            if let Some(response) = state.replies.get_mut(&key).and_then(|replies| replies.pop_front()) {
+                return Ok(Some(response))
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None)
+            }
+            state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+impl SigningServer for LocalSigningChannel {
+    fn next_request(&self, timeout: Duration) -> Result<Option<(String, String, SigningRequest)>> {
+        let (lock, cvar) = &*self.state;
+        let deadline = Instant::now() + timeout;
+        let mut state = lock.lock().unwrap();
+        loop {
+            if let Some(next) = state.requests.pop_front() {
+                return Ok(Some(next))
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None)
+            }
+            state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    fn respond(&self, id: &str, attempt: &str, response: &SigningResponse) -> Result<()> {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if let SigningResponse::Approved { .. } = response {
+            state.approved.insert(id.to_string(), response.clone());
+        }
+        state.replies.entry(reply_key(id, attempt)).or_default().push_back(response.clone());
+        cvar.notify_all();
+        Ok(())
+    }
+
+    fn approved(&self, id: &str) -> Result<Option<SigningResponse>> {
+        Ok(self.state.0.lock().unwrap().approved.get(id).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::thread;
+
+    fn request(hex: &str) -> SigningRequest {
+        SigningRequest::Contract { contract_hex: hex.to_string(), height: 0 }
+    }
+
+    #[test]
+    fn request_ids() {
+        assert_eq!(request("00").id(), request("00").id());
+        assert_eq!(request("00").id(), SigningRequest::Contract { contract_hex: "00".to_string(), height: 1 }.id());
+        assert_ne!(request("00").id(), request("01").id());
+        assert_ne!(request("00").id(), SigningRequest::Payout { payout_hex: "00".to_string() }.id());
+        assert_ne!(SigningRequest::Contracts { decision: None, cxid: None }.id(), SigningRequest::Payouts { decision: None, cxid: None }.id());
+        assert_ne!(SigningRequest::Payouts { decision: None, cxid: None }.id(), SigningRequest::Payouts { decision: Some(Decision::Pending), cxid: None }.id());
+    }
+
+    #[test]
+    fn approve_and_reject() {
+        let channel = LocalSigningChannel::default();
+        let server = channel.clone();
+        let signer = thread::spawn(move || {
+            let mut signed = 0;
+            for _ in 0..3 {
+                assert!(serve_next(&server, Duration::from_secs(10), |request| match request {
+                    SigningRequest::Contract { contract_hex, .. } if contract_hex == "00" => {
+                        signed += 1;
+                        SigningResponse::Approved { hex: "0000".to_string() }
+                    }
+                    _ => SigningResponse::Rejected { reason: "bad contract".to_string(), code: None },
+                }).unwrap());
+            }
+            signed
+        });
+        let approved = SigningResponse::Approved { hex: "0000".to_string() };
+        assert_eq!(channel.request(&request("00"), Duration::from_secs(10)).unwrap(), Some(approved.clone()));
+        assert_eq!(channel.request(&request("01"), Duration::from_secs(10)).unwrap(), Some(SigningResponse::Rejected { reason: "bad contract".to_string(), code: None }));
+// a retry gets the same answer without signing again
+        assert_eq!(channel.request(&request("00"), Duration::from_secs(10)).unwrap(), Some(approved));
+        assert_eq!(signer.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn timeout() {
+        let channel = LocalSigningChannel::default();
+        assert!(!serve_next(&channel, Duration::from_millis(10), |_| unreachable!()).unwrap());
+        assert_eq!(channel.request(&request("00"), Duration::from_millis(10)).unwrap(), None);
+    }
+
+    #[test]
+    fn late_reply() {
+        let channel = LocalSigningChannel::default();
+        assert_eq!(channel.request(&request("01"), Duration::from_millis(10)).unwrap(), None);
+// answered after the requester gave up
+        let late = SigningResponse::Rejected { reason: "late".to_string(), code: None };
+        assert!(serve_next(&channel, Duration::from_millis(10), |_| late).unwrap());
+        let server = channel.clone();
+        let signer = thread::spawn(move || {
+            serve_next(&server, Duration::from_secs(10), |_| SigningResponse::Rejected { reason: "retry".to_string(), code: None }).unwrap()
+        });
+        assert_eq!(channel.request(&request("01"), Duration::from_secs(10)).unwrap(), Some(SigningResponse::Rejected { reason: "retry".to_string(), code: None }));
+        assert!(signer.join().unwrap());
+    }
+}
@@ -0,0 +1,116 @@
+use bdk::{
+    bitcoin::{
+        OutPoint,
+        Script,
+        Transaction,
+        Txid,
+    },
+    electrum_client::{
+        Client,
+        ElectrumApi,
+    },
+};
+use crate::{
+    Result,
+    wallet::{
+        MAX_FEE_RATE,
+        MIN_FEE_RATE,
+    },
+};
+
+// what contract and payout states need to know about the chain
+// electrum-style since that's what the wallets talk to
+pub trait ChainSource {
+// txids touching the script with their confirmation height, 0 while unconfirmed
+    fn script_history(&self, script: &Script) -> Result<Vec<(Txid, u32)>>;
+    fn transaction(&self, txid: &Txid) -> Result<Transaction>;
+    fn tip_height(&self) -> Result<u32>;
+
+    fn confirmed(&self, txid: &Txid, script: &Script) -> Result<bool> {
+        Ok(self.script_history(script)?.iter().any(|(t, height)| t == txid && *height > 0))
+    }
+
+// the tx spending the outpoint, if any. the outpoint has to pay to script
+    fn spending_tx(&self, outpoint: &OutPoint, script: &Script) -> Result<Option<(Txid, u32)>> {
+        for (txid, height) in self.script_history(script)? {
+            if txid == outpoint.txid {
+                continue
+            }
+            if self.transaction(&txid)?.input.iter().any(|txin| txin.previous_output == *outpoint) {
+                return Ok(Some((txid, height)))
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl ChainSource for Client {
+    fn script_history(&self, script: &Script) -> Result<Vec<(Txid, u32)>> {
+        let history = self.script_get_history(script).map_err(bdk::Error::Electrum)?;
+// electrum uses 0 and -1 for mempool txs
+        Ok(history.into_iter().map(|h| (h.tx_hash, if h.height > 0 { h.height as u32 } else { 0 })).collect())
+    }
+
+    fn transaction(&self, txid: &Txid) -> Result<Transaction> {
+        Ok(self.transaction_get(txid).map_err(bdk::Error::Electrum)?)
+    }
+
+    fn tip_height(&self) -> Result<u32> {
+        Ok(self.block_headers_subscribe().map_err(bdk::Error::Electrum)?.height as u32)
+    }
+}
+
+// sat/vbyte to confirm within target_blocks, clamped to what contracts accept
+pub fn estimate_fee_rate<E: ElectrumApi>(client: &E, target_blocks: usize) -> Result<u64> {
+    let btc_per_kb = client.estimate_fee(target_blocks).map_err(bdk::Error::Electrum)?;
+    Ok(fee_rate_from_btc_per_kb(btc_per_kb))
+}
+
+// electrum returns -1 when it has no estimate, e.g. on regtest
+fn fee_rate_from_btc_per_kb(btc_per_kb: f64) -> u64 {
+    if btc_per_kb.is_nan() || btc_per_kb <= 0.0 {
+        return MIN_FEE_RATE
+    }
+    let sat_per_vbyte = (btc_per_kb * 100_000_000.0 / 1000.0).ceil() as u64;
+    sat_per_vbyte.clamp(MIN_FEE_RATE, MAX_FEE_RATE)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+    use super::*;
+    use crate::Error;
+
+// every tx is in the history of every script, which is all the state checks need
+    pub(crate) struct MockChain {
+        txs: Vec<(Transaction, u32)>,
+    }
+
+    impl MockChain {
+        pub(crate) fn new(txs: Vec<(Transaction, u32)>) -> Self {
+            MockChain { txs }
+        }
+    }
+
+    impl ChainSource for MockChain {
+        fn script_history(&self, _script: &Script) -> Result<Vec<(Txid, u32)>> {
+            Ok(self.txs.iter().map(|(tx, height)| (tx.txid(), *height)).collect())
+        }
+
+        fn transaction(&self, txid: &Txid) -> Result<Transaction> {
+            self.txs.iter().find(|(tx, _)| tx.txid() == *txid).map(|(tx, _)| tx.clone()).ok_or(Error::Adhoc("unknown tx"))
+        }
+
+        fn tip_height(&self) -> Result<u32> {
+            Ok(self.txs.iter().map(|(_, height)| *height).max().unwrap_or(0))
+        }
+    }
+
+    #[test]
+    fn fee_rate_conversion() {
+        assert_eq!(fee_rate_from_btc_per_kb(0.0001), 10);
+        assert_eq!(fee_rate_from_btc_per_kb(0.000015), 2);
+        assert_eq!(fee_rate_from_btc_per_kb(-1.0), MIN_FEE_RATE);
+        assert_eq!(fee_rate_from_btc_per_kb(1.0), MAX_FEE_RATE);
+    }
+}
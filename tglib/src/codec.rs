@@ -0,0 +1,466 @@
+use std::fmt;
+use nom::{
+    self,
+    IResult,
+    bytes::complete::take,
+    error::{
+        ErrorKind,
+        ParseError,
+    },
+    multi::{
+        length_data,
+        many0,
+    },
+    number::complete::{
+        be_u8,
+        be_u32,
+    },
+};
+use bdk::bitcoin::{
+    Network,
+    PublicKey,
+    consensus,
+    secp256k1::Signature,
+    util::psbt::PartiallySignedTransaction,
+};
+use crate::script::{
+    parser::tg_script,
+    TgScript,
+};
+
+// binary encoding shared by Contract and Payout
+// every field is either fixed length or length-prefixed so decoding never has to guess
+// decoding errors are Failures so they aren't swallowed by many0
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecError {
+    Truncated,
+    TrailingBytes(usize),
+    UnsupportedVersion(u8),
+    UnknownNetwork(u32),
+    InvalidPubkey,
+    InvalidTx,
+    InvalidScript,
+    InvalidSignature,
+    Nom(ErrorKind),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::Truncated => write!(f, "Truncated"),
+            CodecError::TrailingBytes(n) => write!(f, "TrailingBytes({})", n),
+            CodecError::UnsupportedVersion(version) => write!(f, "UnsupportedVersion({})", version),
+            CodecError::UnknownNetwork(magic) => write!(f, "UnknownNetwork({:#x})", magic),
+            CodecError::InvalidPubkey => write!(f, "InvalidPubkey"),
+            CodecError::InvalidTx => write!(f, "InvalidTx"),
+            CodecError::InvalidScript => write!(f, "InvalidScript"),
+            CodecError::InvalidSignature => write!(f, "InvalidSignature"),
+            CodecError::Nom(kind) => write!(f, "Nom({:?})", kind),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl<'a> ParseError<&'a [u8]> for CodecError {
+    fn from_error_kind(_input: &'a [u8], kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Eof => CodecError::Truncated,
+            kind => CodecError::Nom(kind),
+        }
+    }
+
+    fn append(_input: &'a [u8], _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+pub type CodecResult<'a, T> = IResult<&'a [u8], T, CodecError>;
+
+fn fail<'a, T>(e: CodecError) -> CodecResult<'a, T> {
+    Err(nom::Err::Failure(e))
+}
+
+// run a parser over the whole input
+pub fn decode<'a, T, F>(bytes: &'a [u8], parser: F) -> Result<T, CodecError>
+where F: Fn(&'a [u8]) -> CodecResult<'a, T> {
+    match parser(bytes) {
+        Ok((rest, _)) if !rest.is_empty() => Err(CodecError::TrailingBytes(rest.len())),
+        Ok((_, t)) => Ok(t),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(e),
+        Err(nom::Err::Incomplete(_)) => Err(CodecError::Truncated),
+    }
+}
+
+// versions from 1 up to current are understood, older ones are migrated by the caller
+pub fn version<'a>(current: u8) -> impl Fn(&'a [u8]) -> CodecResult<'a, u8> {
+    move |input: &'a [u8]| {
+        let (input, version) = be_u8(input)?;
+        if version == 0 || version > current {
+            return fail(CodecError::UnsupportedVersion(version))
+        }
+        Ok((input, version))
+    }
+}
+
+// networks are written as their magic
+pub fn network(input: &[u8]) -> CodecResult<Network> {
+    let (input, magic) = be_u32(input)?;
+    match Network::from_magic(magic) {
+        Some(network) => Ok((input, network)),
+        None => fail(CodecError::UnknownNetwork(magic)),
+    }
+}
+
+pub fn pubkey(input: &[u8]) -> CodecResult<PublicKey> {
+    let (input, b) = take(33u8)(input)?;
+    match PublicKey::from_slice(b) {
+        Ok(key) => Ok((input, key)),
+        Err(_) => fail(CodecError::InvalidPubkey),
+    }
+}
+
+pub fn psbt(input: &[u8]) -> CodecResult<PartiallySignedTransaction> {
+    let (input, b) = length_data(be_u32)(input)?;
+    match consensus::deserialize(b) {
+        Ok(psbt) => Ok((input, psbt)),
+        Err(_) => fail(CodecError::InvalidTx),
+    }
+}
+
+// zero length for none
+pub fn optional_psbt(input: &[u8]) -> CodecResult<Option<PartiallySignedTransaction>> {
+    let (rest, b) = length_data(be_u32)(input)?;
+    if b.is_empty() {
+        return Ok((rest, None))
+    }
+    let (rest, psbt) = psbt(input)?;
+    Ok((rest, Some(psbt)))
+}
+
+pub fn script(input: &[u8]) -> CodecResult<TgScript> {
+    let (input, b) = length_data(be_u32)(input)?;
+    match tg_script(b) {
+        Ok((rest, script)) if rest.is_empty() => Ok((input, script)),
+        _ => fail(CodecError::InvalidScript),
+    }
+}
+
+// der-encoded signature with its length
+pub fn signature(input: &[u8]) -> CodecResult<Signature> {
+    let (input, b) = length_data(be_u8)(input)?;
+    match Signature::from_der(b) {
+        Ok(sig) => Ok((input, sig)),
+        Err(_) => fail(CodecError::InvalidSignature),
+    }
+}
+
+// signatures run to the end of the input
+pub fn signatures(input: &[u8]) -> CodecResult<Vec<Signature>> {
+    let (input, sigs) = many0(signature)(input)?;
+// a partial signature at the end stops many0 without an error
+    if !input.is_empty() {
+        return fail(CodecError::Truncated)
+    }
+    Ok((input, sigs))
+}
+
+pub fn write_signature(v: &mut Vec<u8>, sig: &Signature) {
+    let sig_bytes = sig.serialize_der().to_vec();
+// der signatures are at most 72 bytes
+    v.push(sig_bytes.len() as u8);
+    v.extend(sig_bytes);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+    use super::*;
+    use rand::Rng;
+    use bdk::bitcoin::{
+        Address,
+        OutPoint,
+        Script,
+        Transaction,
+        TxIn,
+        TxOut,
+        secp256k1::{
+            self,
+            Message,
+            Secp256k1,
+            SecretKey,
+        },
+    };
+    use crate::{
+        Error,
+        contract::{
+            Contract,
+            ContractState,
+            CONTRACT_VERSION,
+        },
+        payout::{
+            Payout,
+            PAYOUT_VERSION,
+        },
+        wallet::{
+            create_escrow_address,
+            create_payout,
+            create_refund,
+            LEGACY_NETWORK,
+            MIN_FEE_RATE,
+        },
+        mock::NETWORK,
+    };
+
+    pub(crate) fn keys() -> Vec<(SecretKey, PublicKey)> {
+        let secp = Secp256k1::new();
+        (1..4u8).map(|i| {
+            let key = SecretKey::from_slice(&[i; 32]).unwrap();
+            (key, PublicKey { compressed: true, key: secp256k1::PublicKey::from_secret_key(&secp, &key) })
+        }).collect()
+    }
+
+    pub(crate) fn test_contract() -> Contract {
+        let keys = keys();
+        let escrow_address = create_escrow_address(&keys[0].1, &keys[1].1, &keys[2].1, NETWORK).unwrap();
+        let funding_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec!(TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0,
+                witness: Vec::new(),
+            }),
+            output: vec!(TxOut { value: 1_000_000, script_pubkey: escrow_address.script_pubkey() }),
+        };
+        let mut contract = Contract::new(
+            keys[0].1,
+            keys[1].1,
+            keys[2].1,
+            PartiallySignedTransaction::from_unsigned_tx(funding_tx).unwrap(),
+            "PUSHDATA1 0xff PUSHTXID EQUAL VALIDATE".parse().unwrap(),
+            MIN_FEE_RATE,
+            NETWORK,
+        );
+        let address = Address::p2wpkh(&keys[0].1, NETWORK).unwrap();
+        contract.refund_tx = Some(create_refund(&contract, &address, &address, 700).unwrap());
+        let secp = Secp256k1::new();
+        let msg = Message::from_slice(&contract.cxid()).unwrap();
+        contract.sigs = keys.iter().map(|(key, _)| secp.sign(&msg, key)).collect();
+        contract
+    }
+
+    pub(crate) fn test_payout() -> Payout {
+        let contract = test_contract();
+        let mut payout = create_payout(&contract, &Address::p2wpkh(&contract.p2_pubkey, NETWORK).unwrap());
+        payout.script_sigs = contract.sigs[..2].to_vec();
+        payout
+    }
+
+    #[test]
+    fn contract_round_trip() {
+        let contract = test_contract();
+        let bytes = contract.to_bytes();
+        let decoded = Contract::from_bytes(bytes.clone()).unwrap();
+        assert_eq!(decoded.version, CONTRACT_VERSION);
+        assert_eq!(decoded.p1_pubkey, contract.p1_pubkey);
+        assert_eq!(decoded.p2_pubkey, contract.p2_pubkey);
+        assert_eq!(decoded.arbiter_pubkey, contract.arbiter_pubkey);
+        assert_eq!(decoded.funding_tx, contract.funding_tx);
+        assert_eq!(decoded.payout_script, contract.payout_script);
+        assert_eq!(decoded.refund_tx, contract.refund_tx);
+        assert_eq!(decoded.fee_rate, contract.fee_rate);
+        assert_eq!(decoded.network, contract.network);
+        assert_eq!(decoded.sigs, contract.sigs);
+        assert_eq!(decoded.to_bytes(), bytes);
+
+        let mut contract = test_contract();
+        contract.refund_tx = None;
+        contract.sigs = Vec::new();
+        let decoded = Contract::from_bytes(contract.to_bytes()).unwrap();
+        assert_eq!(decoded.refund_tx, None);
+        assert!(decoded.sigs.is_empty());
+    }
+
+    #[test]
+    fn payout_round_trip() {
+        let payout = test_payout();
+        let bytes = payout.to_bytes();
+        let decoded = Payout::from_bytes(bytes.clone()).unwrap();
+        assert_eq!(decoded.version, PAYOUT_VERSION);
+        assert_eq!(decoded.contract.to_bytes(), payout.contract.to_bytes());
+        assert_eq!(decoded.psbt, payout.psbt);
+        assert_eq!(decoded.script_sigs, payout.script_sigs);
+        assert_eq!(decoded.to_bytes(), bytes);
+    }
+
+    fn resign(mut contract: Contract) -> Contract {
+        let secp = Secp256k1::new();
+        let msg = Message::from_slice(&contract.cxid()).unwrap();
+        contract.sigs = keys().iter().map(|(key, _)| secp.sign(&msg, key)).collect();
+        contract
+    }
+
+// older contracts decode as they were signed, if they were signed over the cxid
+    #[test]
+    fn migrate_v1_contract() {
+        let mut contract = test_contract();
+        contract.version = 1;
+        contract.refund_tx = None;
+        let contract = resign(contract);
+        let decoded = Contract::from_bytes(contract.to_bytes()).unwrap();
+        assert_eq!(decoded.version, 1);
+        assert_eq!(decoded.payout_script, contract.payout_script);
+        assert_eq!(decoded.fee_rate, MIN_FEE_RATE);
+        assert_eq!(decoded.sigs, contract.sigs);
+        assert_eq!(decoded.cxid(), contract.cxid());
+        assert_eq!(decoded.state(), ContractState::ArbiterSigned);
+        assert_eq!(decoded.to_bytes(), contract.to_bytes());
+    }
+
+// as the first version wrote them: no refund, fee rate or network, and signed over a hash of the payout script
+    #[test]
+    fn baseline_v1_contract() {
+        use bdk::bitcoin::hashes::{sha256, Hash};
+        use byteorder::{BigEndian, WriteBytesExt};
+        let contract = test_contract();
+        let secp = Secp256k1::new();
+        let payout_script = Vec::from(contract.payout_script.clone());
+        let script_hash = sha256::Hash::hash(&payout_script);
+        let msg = Message::from_slice(&script_hash).unwrap();
+        let sigs: Vec<secp256k1::Signature> = keys().iter().map(|(key, _)| secp.sign(&msg, key)).collect();
+        let mut bytes = vec![1];
+        for pubkey in &[contract.p1_pubkey, contract.p2_pubkey, contract.arbiter_pubkey] {
+            bytes.extend(pubkey.to_bytes());
+        }
+        let funding_tx = consensus::serialize(&contract.funding_tx);
+        bytes.write_u32::<BigEndian>(funding_tx.len() as u32).unwrap();
+        bytes.extend(funding_tx);
+        bytes.write_u32::<BigEndian>(payout_script.len() as u32).unwrap();
+        bytes.extend(payout_script);
+        for sig in &sigs {
+            let sig = sig.serialize_der();
+            bytes.push(sig.len() as u8);
+            bytes.extend(sig.to_vec());
+        }
+        let decoded = Contract::from_bytes(bytes.clone()).unwrap();
+        assert_eq!(decoded.version, 1);
+        assert_eq!(decoded.funding_tx, contract.funding_tx);
+        assert_eq!(decoded.payout_script, contract.payout_script);
+        assert_eq!(decoded.refund_tx, None);
+        assert_eq!(decoded.to_bytes(), bytes);
+// the signatures decode but the cxid isn't what they signed, so they don't verify
+        assert_eq!(decoded.sigs, sigs);
+        assert_ne!(decoded.cxid(), script_hash.into_inner().to_vec());
+        assert_eq!(decoded.state(), ContractState::Invalid);
+        assert!(decoded.validate().is_err());
+        let mut unsigned = decoded.clone();
+        unsigned.sigs = Vec::new();
+        assert_eq!(unsigned.state(), ContractState::Unsigned);
+    }
+
+    #[test]
+    fn migrate_v2_contract() {
+        let mut contract = test_contract();
+        contract.version = 2;
+        contract.fee_rate = 10;
+        let contract = resign(contract);
+        let decoded = Contract::from_bytes(contract.to_bytes()).unwrap();
+        assert_eq!(decoded.version, 2);
+        assert_eq!(decoded.refund_tx, contract.refund_tx);
+// version 2 contracts don't carry a fee rate, or commit to one
+        assert_eq!(decoded.fee_rate, MIN_FEE_RATE);
+        assert_eq!(decoded.cxid(), contract.cxid());
+        assert_eq!(decoded.state(), ContractState::ArbiterSigned);
+    }
+
+    #[test]
+    fn migrate_v3_contract() {
+        let mut contract = test_contract();
+        contract.version = 3;
+        contract.network = Network::Signet;
+        let contract = resign(contract);
+        let decoded = Contract::from_bytes(contract.to_bytes()).unwrap();
+        assert_eq!(decoded.version, 3);
+        assert_eq!(decoded.fee_rate, contract.fee_rate);
+// version 3 contracts don't carry a network, or commit to one
+        assert_eq!(decoded.network, LEGACY_NETWORK);
+        assert_eq!(decoded.cxid(), contract.cxid());
+        assert_eq!(decoded.state(), ContractState::ArbiterSigned);
+    }
+
+    #[test]
+    fn contract_network() {
+        for network in &[Network::Bitcoin, Network::Testnet, Network::Signet, Network::Regtest] {
+            let mut contract = test_contract();
+            contract.network = *network;
+            assert_eq!(Contract::from_bytes(contract.to_bytes()).unwrap().network, *network);
+        }
+// without signatures the magic is the last field
+        let mut contract = test_contract();
+        contract.sigs = Vec::new();
+        let mut bytes = contract.to_bytes();
+        let len = bytes.len();
+        bytes[len - 4..].copy_from_slice(&0xdeadbeefu32.to_be_bytes());
+        assert!(matches!(Contract::from_bytes(bytes), Err(Error::Codec(CodecError::UnknownNetwork(0xdeadbeef)))));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        for version in &[0, CONTRACT_VERSION + 1] {
+            let mut bytes = test_contract().to_bytes();
+            bytes[0] = *version;
+            match Contract::from_bytes(bytes) {
+                Err(Error::Codec(CodecError::UnsupportedVersion(v))) => assert_eq!(v, *version),
+                r => panic!("unexpected result: {:?}", r.map(|c| c.version)),
+            }
+        }
+        let mut bytes = test_payout().to_bytes();
+        bytes[0] = PAYOUT_VERSION + 1;
+        assert!(matches!(Payout::from_bytes(bytes), Err(Error::Codec(CodecError::UnsupportedVersion(_)))));
+    }
+
+    #[test]
+    fn invalid_fields() {
+        let bytes = test_contract().to_bytes();
+        let mut bad_pubkey = bytes.clone();
+        bad_pubkey[1] = 0x05;
+        assert!(matches!(Contract::from_bytes(bad_pubkey), Err(Error::Codec(CodecError::InvalidPubkey))));
+
+        let mut bad_sig = bytes.clone();
+        let last = bad_sig.len() - 1;
+        bad_sig[last - 70] = 0xff;
+        assert!(matches!(Contract::from_bytes(bad_sig), Err(Error::Codec(_))));
+
+        assert!(matches!(Contract::from_bytes(bytes[..50].to_vec()), Err(Error::Codec(CodecError::Truncated))));
+        assert!(matches!(Contract::from_bytes(Vec::new()), Err(Error::Codec(CodecError::Truncated))));
+        assert!(matches!(Payout::from_bytes(vec![PAYOUT_VERSION]), Err(Error::Codec(CodecError::Truncated))));
+    }
+
+// malformed input must never panic, whatever it is
+    #[test]
+    fn malformed() {
+        use rand::{SeedableRng, rngs::StdRng};
+// shown when the test fails, TG_FUZZ_SEED=<seed> reruns the same cases
+        let seed = std::env::var("TG_FUZZ_SEED").ok().and_then(|seed| seed.parse().ok()).unwrap_or_else(rand::random::<u64>);
+        println!("fuzz seed {}", seed);
+        let mut rng = StdRng::seed_from_u64(seed);
+        for bytes in vec![test_contract().to_bytes(), test_payout().to_bytes()] {
+            for _ in 0..2000 {
+                let mut b = bytes.clone();
+                match rng.gen_range(0..3) {
+                    0 => b.truncate(rng.gen_range(0..bytes.len())),
+                    1 => {
+                        let i = rng.gen_range(0..b.len());
+                        b[i] = rng.gen();
+                    }
+                    _ => b.extend((0..rng.gen_range(1..8)).map(|_| rng.gen::<u8>())),
+                }
+                let _ = Contract::from_bytes(b.clone());
+                let _ = Payout::from_bytes(b);
+            }
+        }
+    }
+}
@@ -1,15 +1,19 @@
-//use std::str::FromStr;
+use std::{
+    fmt,
+    str::FromStr,
+};
 use byteorder::{BigEndian, WriteBytesExt};
 use serde::{Serialize, Deserialize,};
 use bdk::{
     bitcoin::{
         Address,
         Amount,
+        Network,
+        OutPoint,
         PublicKey,
-        consensus::{
-            self,
-            encode::Decodable,
-        },
+        Script,
+        TxOut,
+        consensus,
         hashes::{
             Hash as BitcoinHash,
             HashEngine,
@@ -25,30 +29,44 @@ use bdk::{
     },
 };
 use nom::{
-    self,
-    IResult,
-    bytes::complete::take,
-    number::complete::{be_u8, be_u32},
-//    branch::alt,
-    multi::{many0, length_data, length_value},
-//    combinator::opt,
+    number::complete::be_u64,
     sequence::tuple,
 };
 
 use crate::{
     Result,
     Error,
+    chain::ChainSource,
+    codec::{
+        decode,
+        network,
+        optional_psbt,
+        psbt,
+        pubkey,
+        script,
+        signatures,
+        version,
+        write_signature,
+        CodecResult,
+    },
     script::{
-        parser::tg_script,
+        ScriptAnalysis,
         TgScript,
     },
-    wallet::create_escrow_address,
-    mock::{
-        NETWORK,
-        CONTRACT_VERSION,
-    }
+    wallet::{
+        create_escrow_script,
+        escrow_tx_fee_rate,
+        LEGACY_NETWORK,
+        MAX_FEE_RATE,
+        MIN_REFUND_DELAY,
+        MIN_FEE_RATE,
+    },
 };
 
+pub const CONTRACT_VERSION: u8 = 5;
+// nLockTime values below this are block heights
+pub const LOCK_TIME_THRESHOLD: u32 = 500_000_000;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Contract {
     pub p1_pubkey:          PublicKey,
@@ -56,12 +74,18 @@ pub struct Contract {
     pub arbiter_pubkey:     PublicKey,
     pub funding_tx:         PartiallySignedTransaction,
     pub payout_script:      TgScript,
+// pre-signed refund for when the oracle never resolves the contract, see wallet::create_refund
+    pub refund_tx:          Option<PartiallySignedTransaction>,
+// sat/vbyte the payout and refund txs are built with
+    pub fee_rate:           u64,
+// addresses don't tell regtest, testnet and signet apart, so the contract says which it's for
+    pub network:            Network,
     pub sigs:               Vec<Signature>, 
     pub version:            u8,
 }
 
 impl Contract {
-    pub fn new(p1_pubkey: PublicKey, p2_pubkey: PublicKey, arbiter_pubkey: PublicKey, funding_tx: PartiallySignedTransaction, payout_script: TgScript) -> Self {
+    pub fn new(p1_pubkey: PublicKey, p2_pubkey: PublicKey, arbiter_pubkey: PublicKey, funding_tx: PartiallySignedTransaction, payout_script: TgScript, fee_rate: u64, network: Network) -> Self {
         Contract {
             version: CONTRACT_VERSION,
             p1_pubkey,
@@ -69,19 +93,91 @@ impl Contract {
             arbiter_pubkey,
             funding_tx,
             payout_script,
+            refund_tx: None,
+            fee_rate,
+            network,
             sigs: Vec::new(),
         }
     }
 
+// commits to every field except signatures, the txs without their witness data
+// so signing the funding or refund tx doesn't change the id
     pub fn cxid(&self) -> Vec<u8> {
         let mut engine = ShaHashEngine::default();
-        engine.input(&Vec::from(self.payout_script.clone()));
+        engine.input(&self.commitment());
         let hash: &[u8] = &ShaHash::from_engine(engine);
         hash.to_vec()
     }
 
+    fn commitment(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        let _ = v.write_u8(self.version);
+        v.extend(self.p1_pubkey.to_bytes());
+        v.extend(self.p2_pubkey.to_bytes());
+        v.extend(self.arbiter_pubkey.to_bytes());
+        let funding_tx = consensus::serialize(&self.funding_tx.global.unsigned_tx);
+        v.write_u32::<BigEndian>(funding_tx.len() as u32).unwrap();
+        v.extend(funding_tx);
+        let payout_script = Vec::from(self.payout_script.clone());
+        v.write_u32::<BigEndian>(payout_script.len() as u32).unwrap();
+        v.extend(payout_script);
+        let refund_tx = match &self.refund_tx {
+            Some(refund_tx) => consensus::serialize(&refund_tx.global.unsigned_tx),
+            None => Vec::new(),
+        };
+        v.write_u32::<BigEndian>(refund_tx.len() as u32).unwrap();
+        v.extend(refund_tx);
+// only the fields the version has, so contracts from version 2 on keep their cxid and signatures.
+// version 1 contracts signed before cxids committed to the contract signed a hash of the payout script,
+// those signatures don't verify against this and player-wallet drops them, see DB::rekey_contracts
+        if self.version >= 3 {
+            v.write_u64::<BigEndian>(self.fee_rate).unwrap();
+        }
+        if self.version >= 4 {
+            v.write_u32::<BigEndian>(self.network.magic()).unwrap();
+        }
+        v
+    }
+
+// signatures are added in order: p1, p2, arbiter
     pub fn state(&self) -> ContractState {
-        return ContractState::Invalid
+        if self.validate_sigs().is_err() {
+            return ContractState::Invalid
+        }
+        match self.sigs.len() {
+            0 => ContractState::Unsigned,
+            1 => ContractState::P1Signed,
+            2 => ContractState::P2Signed,
+            _ => ContractState::ArbiterSigned,
+        }
+    }
+
+// a fully signed contract is live once the funding tx confirms and resolved once the escrow output is spent
+    pub fn chain_state<C: ChainSource>(&self, chain: &C) -> Result<ContractState> {
+        let state = self.state();
+        if state != ContractState::ArbiterSigned {
+            return Ok(state)
+        }
+        let (outpoint, script) = self.escrow_outpoint()?;
+        if !chain.confirmed(&outpoint.txid, &script)? {
+            return Ok(state)
+        }
+        match chain.spending_tx(&outpoint, &script)? {
+            Some((_txid, height)) if height > 0 => Ok(ContractState::Resolved),
+            _ => Ok(ContractState::Live),
+        }
+    }
+
+// the escrow output's script pubkey, which is the same on every network
+    fn escrow_script_pubkey(&self) -> Result<Script> {
+        Ok(create_escrow_script(&self.p1_pubkey, &self.p2_pubkey, &self.arbiter_pubkey)?.to_v0_p2wsh())
+    }
+
+    pub fn escrow_outpoint(&self) -> Result<(OutPoint, Script)> {
+        let escrow_script = self.escrow_script_pubkey()?;
+        let funding_tx = &self.funding_tx.global.unsigned_tx;
+        let vout = funding_tx.output.iter().position(|txout| txout.script_pubkey == escrow_script).ok_or(Error::Adhoc("funding tx has no escrow output"))?;
+        Ok((OutPoint::new(funding_tx.txid(), vout as u32), escrow_script))
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -110,38 +206,56 @@ impl Contract {
         let payout_script = Vec::from(self.payout_script.clone());
         v.write_u32::<BigEndian>(payout_script.len() as u32).unwrap();
         v.extend(payout_script);
+// refund tx length + bytes, zero length if there isn't one. version 1 contracts don't have it
+        if self.version >= 2 {
+            let refund_tx = match &self.refund_tx {
+                Some(refund_tx) => consensus::serialize(refund_tx),
+                None => Vec::new(),
+            };
+            v.write_u32::<BigEndian>(refund_tx.len() as u32).unwrap();
+            v.extend(refund_tx);
+        }
+// payout fee rate, from version 3
+        if self.version >= 3 {
+            v.write_u64::<BigEndian>(self.fee_rate).unwrap();
+        }
+// network magic, from version 4
+        if self.version >= 4 {
+            v.write_u32::<BigEndian>(self.network.magic()).unwrap();
+        }
         for sig in &self.sigs {
-// der-encoded signatures with their lengths
-            let sig_bytes = sig.serialize_der().to_vec();
-// this is guaranteed to fit, right?
-            v.write_u8(sig_bytes.len() as u8).unwrap();
-            v.extend(sig_bytes);
+            write_signature(&mut v, sig);
         }
         v
     }
 
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Contract> {
-        let (i, c) = contract(&bytes).unwrap();
-        if i.len() == 0 {
-            Ok(c)
-        }
-        else {
-            Err(Error::Adhoc("couldn't parse contract"))
-        }
+        Ok(decode(&bytes, contract)?)
     }
 
     pub fn amount(&self) -> Result<Amount> {
-        let escrow_address = create_escrow_address(&self.p1_pubkey, &self.p2_pubkey, &self.arbiter_pubkey, NETWORK).unwrap();
+        let escrow_script_pubkey = self.escrow_script_pubkey()?;
         let funding_tx = self.funding_tx.clone().extract_tx();
-        let escrow_txout = funding_tx.output.iter().find(|txout| txout.script_pubkey == escrow_address.script_pubkey()).ok_or(Error::Adhoc("couldn't determine amount"))?;
+        let escrow_txout = funding_tx.output.iter().find(|txout| txout.script_pubkey == escrow_script_pubkey).ok_or(Error::Adhoc("couldn't determine amount"))?;
         Ok(Amount::from_sat(escrow_txout.value))
     }
 
-    pub fn fee_address(&self) -> Result<Address> {
-        let fee_amount = self.amount().unwrap().as_sat()/100;
+    fn fee_txout(&self) -> Result<TxOut> {
+        let fee_amount = self.amount()?.as_sat()/100;
         let funding_tx = self.funding_tx.clone().extract_tx();
-        let fee_txout = funding_tx.output.iter().find(|txout| txout.value == fee_amount).ok_or(Error::Adhoc("fee not found"))?;
-        Ok(Address::from_script(&fee_txout.script_pubkey, NETWORK).unwrap())
+        funding_tx.output.into_iter().find(|txout| txout.value == fee_amount).ok_or(Error::Adhoc("fee not found"))
+    }
+
+    pub fn fee_address(&self) -> Result<Address> {
+        Address::from_script(&self.fee_txout()?.script_pubkey, self.network).ok_or(Error::Adhoc("fee output has no address"))
+    }
+
+// wallets check this before anything else so e.g. a signet wallet never signs a regtest contract
+    pub fn validate_network(&self, network: Network) -> Result<()> {
+        if self.network != network {
+            return Err(Error::InvalidContract("contract is for a different network"))
+        }
+        Ok(())
     }
 
     pub fn validate(&self) -> Result<()> {
@@ -153,13 +267,64 @@ impl Contract {
 // therefore validation in this sense is not always possible without
 // data from another party and such validation should occur elsewhere
 //        self.validate_payout_script()?;
+// what can be checked without that data is the structure of the script
+        ScriptAnalysis::new(&self.payout_script)?.validate()?;
+        if self.fee_rate < MIN_FEE_RATE || self.fee_rate > MAX_FEE_RATE {
+            return Err(Error::InvalidContract("fee rate out of range"))
+        }
+        self.validate_refund_tx()?;
         self.validate_sigs()?;
         Ok(())
     }
 
     fn validate_funding_tx(&self) -> Result<()> {
         self.amount()?;
-        self.fee_address()?;
+        self.fee_txout()?;
+        Ok(())
+    }
+
+// the refund tx spends the whole escrow output and can't be broadcast right away
+// which addresses it pays is up to the players to check. only version 1 contracts go without one
+    fn validate_refund_tx(&self) -> Result<()> {
+        let refund_tx = match &self.refund_tx {
+            Some(psbt) => psbt.clone().extract_tx(),
+            None if self.version < 2 => return Ok(()),
+            None => return Err(Error::InvalidContract("contract has no refund tx")),
+        };
+        let escrow_script_pubkey = self.escrow_script_pubkey()?;
+        let funding_tx = self.funding_tx.clone().extract_tx();
+        let spends_escrow = refund_tx.input.len() == 1 && 
+            refund_tx.input[0].previous_output.txid == funding_tx.txid() &&
+            funding_tx.output.get(refund_tx.input[0].previous_output.vout as usize).map(|txout| &txout.script_pubkey) == Some(&escrow_script_pubkey);
+        if !spends_escrow {
+            return Err(Error::InvalidContract("refund tx doesn't spend the escrow output"))
+        }
+        if refund_tx.lock_time == 0 || refund_tx.input[0].sequence == 0xFFFFFFFF {
+            return Err(Error::InvalidContract("refund tx isn't timelocked"))
+        }
+// lock times from here up are unix times, see validate_refund_lock_time
+        if refund_tx.lock_time >= LOCK_TIME_THRESHOLD {
+            return Err(Error::InvalidContract("refund tx isn't locked to a block height"))
+        }
+        if refund_tx.output.len() != 2 || refund_tx.output[0].value != refund_tx.output[1].value {
+            return Err(Error::InvalidContract("refund tx doesn't return each player's stake"))
+        }
+// at least the agreed rate so it confirms, but not so much that the stakes go to miners
+        let fee_rate = escrow_tx_fee_rate(self, &refund_tx)?;
+        if fee_rate < self.fee_rate || fee_rate > MAX_FEE_RATE {
+            return Err(Error::InvalidContract("refund tx fee rate doesn't match the contract's"))
+        }
+        Ok(())
+    }
+
+// checked by whoever signs the refund tx, against the chain height at the time
+// the contract itself can't say when it was made, so validate can't check this
+    pub fn validate_refund_lock_time(&self, height: u32) -> Result<()> {
+        if let Some(refund_tx) = &self.refund_tx {
+            if refund_tx.global.unsigned_tx.lock_time < height.saturating_add(MIN_REFUND_DELAY) {
+                return Err(Error::InvalidContract("refund tx unlocks too soon"))
+            }
+        }
         Ok(())
     }
 
@@ -197,7 +362,7 @@ impl Contract {
     }
 //
 }
-pub fn contract(input: &[u8]) ->IResult<&[u8], Contract> {
+pub fn contract(input: &[u8]) -> CodecResult<Contract> {
     let (input, (
         version,
         p1_pubkey, 
@@ -208,11 +373,29 @@ pub fn contract(input: &[u8]) ->IResult<&[u8], Contract> {
 //        p2_payout_address,
         funding_tx, 
         payout_script, 
-        sigs
 //    )) = tuple((version, pubkey, pubkey, pubkey, pubkey, address, address, funding_tx, payout_script, sigs))(input)?; 
-    )) = tuple((version, pubkey, pubkey, pubkey, funding_tx, payout_script, sigs))(input)?; 
+    )) = tuple((version(CONTRACT_VERSION), pubkey, pubkey, pubkey, psbt, script))(input)?; 
+// version 1 contracts don't have a refund tx
+    let (input, refund_tx) = if version >= 2 {
+        optional_psbt(input)?
+    } else {
+        (input, None)
+    };
+// version 2 contracts were built with a fixed fee, which pays at least the minimum rate
+    let (input, fee_rate) = if version >= 3 {
+        be_u64(input)?
+    } else {
+        (input, MIN_FEE_RATE)
+    };
+    let (input, network) = if version >= 4 {
+        network(input)?
+    } else {
+        (input, LEGACY_NETWORK)
+    };
+    let (input, sigs) = signatures(input)?;
 
     let c = Contract {
+// older versions stay as they are, their payout txs are built the old way, see wallet::contract_payout_tx
         version,
         p1_pubkey,
         p2_pubkey,
@@ -222,49 +405,22 @@ pub fn contract(input: &[u8]) ->IResult<&[u8], Contract> {
 //        p2_payout_address,
         funding_tx,
         payout_script,
+        refund_tx,
+        fee_rate,
+        network,
         sigs,
     };
 
     Ok((input, c))
 }
 
-pub fn version(input: &[u8]) -> IResult<&[u8], u8> {
-    be_u8(input)
-}
-
-fn pubkey(input: &[u8]) -> IResult<&[u8], PublicKey> {
-    let (input, b) = take(33u8)(input)?;
-    let key = PublicKey::from_slice(&b).unwrap();
-    Ok((input, key))
-}
-
 //fn address(input: &[u8]) -> IResult<&[u8], Address> {
 //    let (input, b) = length_data(be_u32)(input)?;
 //    let address = Address::from_str(&String::from_utf8(b.to_vec()).unwrap()).unwrap();
 //    Ok((input, address))
 //}
 
-fn funding_tx(input: &[u8]) -> IResult<&[u8], PartiallySignedTransaction> {
-    let (input, b) = length_data(be_u32)(input)?;
-    let tx = PartiallySignedTransaction::consensus_decode(b).unwrap();
-    Ok((input, tx))
-}
-
-fn payout_script(input: &[u8]) -> IResult<&[u8], TgScript> {
-    length_value(be_u32, tg_script)(input)
-}
-
-fn sigs(input: &[u8]) -> IResult<&[u8], Vec<Signature>> {
-    many0(signature)(input)
-}
-
-pub fn signature(input: &[u8]) -> IResult<&[u8], Signature> {
-    let (input, b) = length_data(be_u8)(input)?;
-    let sig = Signature::from_der(b).unwrap();
-    Ok((input, sig))
-}
-
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ContractState {
     Unsigned,
     P1Signed,
@@ -274,3 +430,147 @@ pub enum ContractState {
     Resolved,
     Invalid,
 }
+
+impl fmt::Display for ContractState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for ContractState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Unsigned" => Ok(ContractState::Unsigned),
+            "P1Signed" => Ok(ContractState::P1Signed),
+            "P2Signed" => Ok(ContractState::P2Signed),
+            "ArbiterSigned" => Ok(ContractState::ArbiterSigned),
+            "Live" => Ok(ContractState::Live),
+            "Resolved" => Ok(ContractState::Resolved),
+            "Invalid" => Ok(ContractState::Invalid),
+            _ => Err(Error::Adhoc("unknown contract state")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        chain::tests::MockChain,
+        codec::tests::{
+            keys,
+            test_contract,
+        },
+    };
+
+    #[test]
+    fn cxid_commits_to_contract() {
+        let contract = test_contract();
+        let cxid = contract.cxid();
+        assert!(contract.validate_sigs().is_ok());
+
+        let mut c = contract.clone();
+        c.sigs = Vec::new();
+        assert_eq!(c.cxid(), cxid);
+
+        let mut c = contract.clone();
+        c.arbiter_pubkey = keys()[0].1;
+        assert_ne!(c.cxid(), cxid);
+        assert!(c.validate_sigs().is_err());
+
+        let mut c = contract.clone();
+        c.funding_tx.global.unsigned_tx.output[0].value += 1;
+        assert_ne!(c.cxid(), cxid);
+        assert!(c.validate_sigs().is_err());
+
+        let mut c = contract.clone();
+        c.refund_tx.as_mut().unwrap().global.unsigned_tx.lock_time += 1;
+        assert_ne!(c.cxid(), cxid);
+
+        let mut c = contract.clone();
+        c.fee_rate += 1;
+        assert_ne!(c.cxid(), cxid);
+
+        let mut c = contract.clone();
+        c.network = Network::Signet;
+        assert_ne!(c.cxid(), cxid);
+        assert!(c.validate_sigs().is_err());
+
+        let mut c = contract.clone();
+        c.version = 1;
+        assert_ne!(c.cxid(), cxid);
+    }
+
+    #[test]
+    fn contract_network() {
+        let contract = test_contract();
+        assert!(contract.validate_network(Network::Regtest).is_ok());
+        assert!(matches!(contract.validate_network(Network::Signet), Err(Error::InvalidContract(_))));
+        assert!(matches!(contract.validate_network(Network::Testnet), Err(Error::InvalidContract(_))));
+    }
+
+    #[test]
+    fn refund_fee_rate() {
+        let contract = test_contract();
+        assert!(contract.validate_refund_tx().is_ok());
+        let mut c = contract.clone();
+        c.fee_rate += 1;
+        assert!(matches!(c.validate_refund_tx(), Err(Error::InvalidContract(_))));
+        let mut c = contract.clone();
+        c.refund_tx.as_mut().unwrap().global.unsigned_tx.output[1].value -= 1;
+        assert!(matches!(c.validate_refund_tx(), Err(Error::InvalidContract(_))));
+        let mut c = contract.clone();
+        c.refund_tx = None;
+        assert!(matches!(c.validate_refund_tx(), Err(Error::InvalidContract(_))));
+        c.version = 1;
+        assert!(c.validate_refund_tx().is_ok());
+    }
+
+    #[test]
+    fn refund_lock_time() {
+        let mut contract = test_contract();
+        contract.refund_tx.as_mut().unwrap().global.unsigned_tx.lock_time = 1000 + MIN_REFUND_DELAY;
+        assert!(contract.validate_refund_lock_time(1000).is_ok());
+        assert!(matches!(contract.validate_refund_lock_time(1001), Err(Error::InvalidContract(_))));
+        assert!(contract.validate_refund_lock_time(u32::MAX).is_err());
+        let mut c = contract.clone();
+        c.refund_tx.as_mut().unwrap().global.unsigned_tx.lock_time = LOCK_TIME_THRESHOLD;
+        assert!(matches!(c.validate_refund_tx(), Err(Error::InvalidContract(_))));
+    }
+
+    #[test]
+    fn state() {
+        let contract = test_contract();
+        assert_eq!(contract.state(), ContractState::ArbiterSigned);
+        let mut c = contract.clone();
+        c.sigs.truncate(2);
+        assert_eq!(c.state(), ContractState::P2Signed);
+        c.sigs.truncate(1);
+        assert_eq!(c.state(), ContractState::P1Signed);
+        c.sigs.clear();
+        assert_eq!(c.state(), ContractState::Unsigned);
+        let mut c = contract.clone();
+        c.sigs.swap(0, 1);
+        assert_eq!(c.state(), ContractState::Invalid);
+    }
+
+    #[test]
+    fn chain_state() {
+        let contract = test_contract();
+        let funding_tx = contract.funding_tx.global.unsigned_tx.clone();
+        let refund_tx = contract.refund_tx.clone().unwrap().global.unsigned_tx;
+
+        assert_eq!(contract.chain_state(&MockChain::new(Vec::new())).unwrap(), ContractState::ArbiterSigned);
+        assert_eq!(contract.chain_state(&MockChain::new(vec![(funding_tx.clone(), 0)])).unwrap(), ContractState::ArbiterSigned);
+        assert_eq!(contract.chain_state(&MockChain::new(vec![(funding_tx.clone(), 100)])).unwrap(), ContractState::Live);
+        assert_eq!(contract.chain_state(&MockChain::new(vec![(funding_tx.clone(), 100), (refund_tx.clone(), 0)])).unwrap(), ContractState::Live);
+        assert_eq!(contract.chain_state(&MockChain::new(vec![(funding_tx.clone(), 100), (refund_tx, 1108)])).unwrap(), ContractState::Resolved);
+
+        let mut c = contract.clone();
+        c.sigs.pop();
+        assert_eq!(c.chain_state(&MockChain::new(vec![(funding_tx, 100)])).unwrap(), ContractState::P2Signed);
+    }
+}
@@ -0,0 +1,345 @@
+use std::fmt;
+use bdk::bitcoin::{
+    OutPoint,
+    Script,
+};
+use crate::{
+    Result,
+    Error,
+    chain::ChainSource,
+    contract::Contract,
+    wallet::{
+        estimated_vsize,
+        P2WPKH_SATISFACTION_WEIGHT,
+    },
+};
+
+// checks the funding tx against what each player posted, see wallet::create_funding_tx in player-wallet
+// each player puts in half of the escrow amount, arbiter fee and miner fee and gets the rest back as change
+
+// anything above the contract's fee rate is more than the players agreed to pay miners
+// posted utxos are p2wpkh, see PlayerContractInfo in libexchange
+pub fn max_funding_fee(contract: &Contract) -> u64 {
+    let funding_tx = &contract.funding_tx.global.unsigned_tx;
+    contract.fee_rate * estimated_vsize(funding_tx, &vec![P2WPKH_SATISFACTION_WEIGHT; funding_tx.input.len()])
+}
+
+// what a player offered to fund a contract with, from their posted contract info
+#[derive(Debug, Clone)]
+pub struct FundingParty {
+    pub change_script: Script,
+    pub utxos: Vec<(OutPoint, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FundingIssue {
+// the player pays more than their share, i.e. they're short-changed
+    Overpaid { expected: u64, actual: u64 },
+// the player pays less than their share, so the other player covers it
+    Underpaid { expected: u64, actual: u64 },
+    SpentInput(OutPoint),
+    UnknownInput(OutPoint),
+    UnexpectedOutput(Script),
+    MissingEscrowOutput,
+    MissingArbiterFee,
+    ExcessiveFee(u64),
+}
+
+impl fmt::Display for FundingIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FundingIssue::Overpaid { expected, actual } => write!(f, "Overpaid(expected {}, actual {})", expected, actual),
+            FundingIssue::Underpaid { expected, actual } => write!(f, "Underpaid(expected {}, actual {})", expected, actual),
+            FundingIssue::SpentInput(outpoint) => write!(f, "SpentInput({})", outpoint),
+            FundingIssue::UnknownInput(outpoint) => write!(f, "UnknownInput({})", outpoint),
+            FundingIssue::UnexpectedOutput(script) => write!(f, "UnexpectedOutput({:x})", script),
+            FundingIssue::MissingEscrowOutput => write!(f, "MissingEscrowOutput"),
+            FundingIssue::MissingArbiterFee => write!(f, "MissingArbiterFee"),
+            FundingIssue::ExcessiveFee(fee) => write!(f, "ExcessiveFee({})", fee),
+        }
+    }
+}
+
+// issues for each player's side of the tx and for the tx as a whole
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FundingReport {
+    pub p1: Vec<FundingIssue>,
+    pub p2: Vec<FundingIssue>,
+    pub tx: Vec<FundingIssue>,
+}
+
+impl FundingReport {
+    pub fn is_ok(&self) -> bool {
+        self.p1.is_empty() && self.p2.is_empty() && self.tx.is_empty()
+    }
+
+    pub fn p1_short_changed(&self) -> bool {
+        self.p1.iter().any(|issue| matches!(issue, FundingIssue::Overpaid { .. }))
+    }
+
+    pub fn p2_short_changed(&self) -> bool {
+        self.p2.iter().any(|issue| matches!(issue, FundingIssue::Overpaid { .. }))
+    }
+
+    pub fn into_result(self) -> Result<()> {
+        if self.is_ok() {
+            Ok(())
+        }
+        else {
+            Err(Error::InvalidFunding(self))
+        }
+    }
+}
+
+impl fmt::Display for FundingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let issues = |issues: &Vec<FundingIssue>| issues.iter().map(|issue| issue.to_string()).collect::<Vec<String>>().join(", ");
+        write!(f, "p1: [{}], p2: [{}], tx: [{}]", issues(&self.p1), issues(&self.p2), issues(&self.tx))
+    }
+}
+
+pub fn funding_report(contract: &Contract, p1: &FundingParty, p2: &FundingParty) -> FundingReport {
+    let mut report = FundingReport::default();
+    let funding_tx = &contract.funding_tx.global.unsigned_tx;
+    let escrow = match contract.escrow_outpoint() {
+        Ok((outpoint, script)) => Some((funding_tx.output[outpoint.vout as usize].value, script)),
+        Err(_) => None,
+    };
+    let (amount, escrow_script) = match escrow {
+        Some(escrow) => escrow,
+        None => {
+            report.tx.push(FundingIssue::MissingEscrowOutput);
+            return report
+        }
+    };
+    let arbiter_fee = amount / 100;
+
+    let mut inputs_total = 0;
+    let mut contributions = [0u64; 2];
+    for txin in &funding_tx.input {
+        let owner = [p1, p2].iter().position(|party| party.utxos.iter().any(|(outpoint, _)| *outpoint == txin.previous_output));
+        match owner {
+            Some(i) => {
+                let value = [p1, p2][i].utxos.iter().find(|(outpoint, _)| *outpoint == txin.previous_output).unwrap().1;
+                contributions[i] += value;
+                inputs_total += value;
+            }
+            None => report.tx.push(FundingIssue::UnknownInput(txin.previous_output)),
+        }
+    }
+
+    let mut outputs_total = 0;
+    let mut change = [0u64; 2];
+    let mut found_fee = false;
+    let mut found_escrow = false;
+    for txout in &funding_tx.output {
+        outputs_total += txout.value;
+        if txout.script_pubkey == escrow_script && !found_escrow {
+            found_escrow = true;
+        }
+        else if txout.script_pubkey == p1.change_script {
+            change[0] += txout.value;
+        }
+        else if txout.script_pubkey == p2.change_script {
+            change[1] += txout.value;
+        }
+// the arbiter's fee address isn't known here, see wallet::validate_contract in rbtr-private
+        else if txout.value == arbiter_fee && !found_fee {
+            found_fee = true;
+        }
+        else {
+            report.tx.push(FundingIssue::UnexpectedOutput(txout.script_pubkey.clone()));
+        }
+    }
+    if !found_fee {
+        report.tx.push(FundingIssue::MissingArbiterFee);
+    }
+
+// an odd sat from splitting the fee goes to miners
+    let fee = inputs_total.saturating_sub(outputs_total);
+    let share = (amount + arbiter_fee + fee) / 2;
+    for (i, issues) in [&mut report.p1, &mut report.p2].iter_mut().enumerate() {
+        let actual = contributions[i].saturating_sub(change[i]);
+        if actual > share {
+            issues.push(FundingIssue::Overpaid { expected: share, actual });
+        }
+        else if actual < share {
+            issues.push(FundingIssue::Underpaid { expected: share, actual });
+        }
+    }
+
+    if fee > max_funding_fee(contract) {
+        report.tx.push(FundingIssue::ExcessiveFee(fee));
+    }
+    report
+}
+
+// also checks that none of the inputs have been spent already
+// inputs are looked up by the script in their psbt input, so ones without a utxo can't be checked
+pub fn funding_report_with_chain<C: ChainSource>(contract: &Contract, p1: &FundingParty, p2: &FundingParty, chain: &C) -> Result<FundingReport> {
+    let mut report = funding_report(contract, p1, p2);
+    let funding_tx = &contract.funding_tx.global.unsigned_tx;
+    for (txin, psbt_input) in funding_tx.input.iter().zip(contract.funding_tx.inputs.iter()) {
+        let outpoint = txin.previous_output;
+        let script = match (&psbt_input.witness_utxo, &psbt_input.non_witness_utxo) {
+            (Some(txout), _) => txout.script_pubkey.clone(),
+            (None, Some(tx)) => match tx.output.get(outpoint.vout as usize) {
+                Some(txout) => txout.script_pubkey.clone(),
+                None => continue,
+            },
+            (None, None) => continue,
+        };
+// the funding tx itself spending them is fine
+        match chain.spending_tx(&outpoint, &script)? {
+            Some((txid, _)) if txid != funding_tx.txid() => (),
+            _ => continue,
+        }
+        let issue = FundingIssue::SpentInput(outpoint);
+        if p1.utxos.iter().any(|(o, _)| *o == outpoint) {
+            report.p1.push(issue);
+        }
+        else if p2.utxos.iter().any(|(o, _)| *o == outpoint) {
+            report.p2.push(issue);
+        }
+        else {
+            report.tx.push(issue);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use bdk::bitcoin::{
+        Address,
+        Transaction,
+        TxIn,
+        TxOut,
+        Txid,
+        hashes::Hash,
+        util::psbt::PartiallySignedTransaction,
+    };
+    use crate::{
+        chain::tests::MockChain,
+        codec::tests::keys,
+        mock::NETWORK,
+        wallet::create_escrow_address,
+    };
+
+    fn txin(outpoint: OutPoint) -> TxIn {
+        TxIn {
+            previous_output: outpoint,
+            script_sig: Script::new(),
+            sequence: 0,
+            witness: Vec::new(),
+        }
+    }
+
+// amount 1_000_000, fee 20_000, share (1_000_000 + 10_000 + 20_000) / 2 = 515_000
+// at 100 sat/vbyte the fee can be up to about 35_000
+    fn setup() -> (Contract, FundingParty, FundingParty) {
+        let keys = keys();
+        let script = |i: usize| Address::p2wpkh(&keys[i].1, NETWORK).unwrap().script_pubkey();
+        let p1 = FundingParty {
+            change_script: script(0),
+            utxos: vec![(OutPoint::new(Txid::hash(b"p1"), 0), 600_000)],
+        };
+        let p2 = FundingParty {
+            change_script: script(1),
+            utxos: vec![(OutPoint::new(Txid::hash(b"p2"), 0), 300_000), (OutPoint::new(Txid::hash(b"p2"), 1), 300_000)],
+        };
+        let escrow_address = create_escrow_address(&keys[0].1, &keys[1].1, &keys[2].1, NETWORK).unwrap();
+        let funding_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: p1.utxos.iter().chain(p2.utxos.iter()).map(|(outpoint, _)| txin(*outpoint)).collect(),
+            output: vec!(
+                TxOut { value: 1_000_000, script_pubkey: escrow_address.script_pubkey() },
+                TxOut { value: 10_000, script_pubkey: script(2) },
+                TxOut { value: 85_000, script_pubkey: p2.change_script.clone() },
+                TxOut { value: 85_000, script_pubkey: p1.change_script.clone() },
+            ),
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(funding_tx).unwrap();
+        for (input, (_, value)) in psbt.inputs.iter_mut().zip(p1.utxos.iter().chain(p2.utxos.iter())) {
+            input.witness_utxo = Some(TxOut { value: *value, script_pubkey: script(0) });
+        }
+        let contract = Contract::new(keys[0].1, keys[1].1, keys[2].1, psbt, "1 VALIDATE".parse().unwrap(), 100, NETWORK);
+        (contract, p1, p2)
+    }
+
+    #[test]
+    fn valid() {
+        let (contract, p1, p2) = setup();
+        let report = funding_report(&contract, &p1, &p2);
+        assert!(report.is_ok(), "{}", report);
+        assert!(report.into_result().is_ok());
+    }
+
+    #[test]
+    fn short_changed() {
+        let (mut contract, p1, p2) = setup();
+        contract.funding_tx.global.unsigned_tx.output[2].value -= 1_000;
+        let report = funding_report(&contract, &p1, &p2);
+        assert!(report.p2_short_changed());
+        assert!(!report.p1_short_changed());
+// the missing change goes to the fee, which both players pay half of
+        assert_eq!(report.p2, vec![FundingIssue::Overpaid { expected: 515_500, actual: 516_000 }]);
+
+        let (mut contract, p1, p2) = setup();
+        contract.funding_tx.global.unsigned_tx.output[3].script_pubkey = Script::new();
+        let report = funding_report(&contract, &p1, &p2);
+        assert!(report.p1_short_changed());
+        assert_eq!(report.tx, vec![FundingIssue::UnexpectedOutput(Script::new())]);
+
+        let (mut contract, p1, p2) = setup();
+        contract.funding_tx.global.unsigned_tx.output[2].value += 1_000;
+        contract.funding_tx.global.unsigned_tx.output[3].value -= 1_000;
+        let report = funding_report(&contract, &p1, &p2);
+        assert_eq!(report.p2, vec![FundingIssue::Underpaid { expected: 515_000, actual: 514_000 }]);
+        assert!(report.p1_short_changed());
+        assert!(matches!(report.into_result(), Err(Error::InvalidFunding(_))));
+    }
+
+    #[test]
+    fn tx_issues() {
+        let (mut contract, p1, p2) = setup();
+        let unknown = OutPoint::new(Txid::hash(b"unknown"), 0);
+        contract.funding_tx.global.unsigned_tx.input.push(txin(unknown));
+        contract.funding_tx.global.unsigned_tx.output[1].value = 9_999;
+        let report = funding_report(&contract, &p1, &p2);
+        assert!(report.tx.contains(&FundingIssue::UnknownInput(unknown)));
+        assert!(report.tx.contains(&FundingIssue::MissingArbiterFee));
+
+        let (mut contract, p1, p2) = setup();
+        contract.funding_tx.global.unsigned_tx.output[2].value -= 30_000;
+        contract.funding_tx.global.unsigned_tx.output[3].value -= 30_000;
+        let report = funding_report(&contract, &p1, &p2);
+        assert!(report.tx.contains(&FundingIssue::ExcessiveFee(80_000)));
+        contract.fee_rate = 250;
+        assert!(funding_report(&contract, &p1, &p2).is_ok());
+
+        let (mut contract, p1, p2) = setup();
+        contract.funding_tx.global.unsigned_tx.output.remove(0);
+        assert_eq!(funding_report(&contract, &p1, &p2).tx, vec![FundingIssue::MissingEscrowOutput]);
+    }
+
+    #[test]
+    fn spent_input() {
+        let (contract, p1, p2) = setup();
+        let spending_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![txin(p2.utxos[1].0)],
+            output: vec![TxOut { value: 290_000, script_pubkey: Script::new() }],
+        };
+        let chain = MockChain::new(vec![(contract.funding_tx.global.unsigned_tx.clone(), 0)]);
+        assert!(funding_report_with_chain(&contract, &p1, &p2, &chain).unwrap().is_ok());
+        let chain = MockChain::new(vec![(spending_tx, 0)]);
+        let report = funding_report_with_chain(&contract, &p1, &p2, &chain).unwrap();
+        assert_eq!(report.p2, vec![FundingIssue::SpentInput(p2.utxos[1].0)]);
+        assert!(report.p1.is_empty() && report.tx.is_empty());
+    }
+}
@@ -9,13 +9,19 @@ pub use nom;
 pub use rand;
 pub use secrecy;
 
+#[cfg(any(test, feature = "mock"))]
 pub mod mock;
 
 pub mod player;
 pub mod arbiter;
+pub mod chain;
+pub mod codec;
+pub mod config;
 pub mod contract;
+pub mod funding;
 pub mod payout;
 pub mod script;
+pub mod signer;
 pub mod wallet;
 
 use std::fmt;
@@ -29,22 +35,32 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     Adhoc(&'static str),
+    Config(String),
+    Signer(String),
     JsonResponse(String),
     Bdk(bdk::Error),
     WrongPassword,
     InvalidContract(&'static str),
     InvalidPayout(&'static str),
+    Script(script::ScriptError),
+    Codec(codec::CodecError),
+    InvalidFunding(funding::FundingReport),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Adhoc(message) => write!(f, "Adhoc({})", message),
+            Error::Config(message) => write!(f, "Config({})", message),
+            Error::Signer(message) => write!(f, "Signer({})", message),
             Error::JsonResponse(message) => write!(f, "JsonResponse({})", message),
             Error::Bdk(error) => write!(f, "Bdk({})", error),
             Error::WrongPassword => write!(f, "WrongPassword"),
             Error::InvalidContract(message) => write!(f, "InvalidContract({})", message),
             Error::InvalidPayout(message) => write!(f, "InvalidPayout({})", message),
+            Error::Script(error) => write!(f, "Script({})", error),
+            Error::Codec(error) => write!(f, "Codec({})", error),
+            Error::InvalidFunding(report) => write!(f, "InvalidFunding({})", report),
         }
     }
 }
@@ -53,11 +69,16 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::Adhoc(_) => None,
+            Error::Config(_) => None,
+            Error::Signer(_) => None,
             Error::JsonResponse(_) => None,
             Error::Bdk(e) => Some(e),
             Error::WrongPassword => None,
             Error::InvalidPayout(_) => None,
             Error::InvalidContract(_) => None,
+            Error::Script(e) => Some(e),
+            Error::Codec(e) => Some(e),
+            Error::InvalidFunding(_) => None,
         }
     }
 }
@@ -68,6 +89,18 @@ impl From<bdk::Error> for Error {
     }
 }
 
+impl From<script::ScriptError> for Error {
+    fn from(error: script::ScriptError) -> Self {
+        Error::Script(error)
+    }
+}
+
+impl From<codec::CodecError> for Error {
+    fn from(error: codec::CodecError) -> Self {
+        Error::Codec(error)
+    }
+}
+
 impl From<bdk::wallet::signer::SignerError> for Error {
     fn from(error: bdk::wallet::signer::SignerError) -> Self {
         Error::Bdk(error.into())
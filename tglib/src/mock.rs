@@ -21,17 +21,7 @@ pub use crate::{
     },
 };
 
-pub const DB_NAME: &'static str = "dev-app.db";
-pub const WALLET_DB_NAME: &'static str = "dev-wallet";
-pub const WALLET_TREE_NAME: &'static str = "wallet";
-pub const SEED_NAME: &'static str = "dev-seed.json";
 pub const NETWORK: Network = Network::Regtest;
-pub const ELECTRS_SERVER: &'static str = "tcp://electrs:60401";
-pub const REDIS_SERVER: &'static str = "redis://redis/";
-
-pub const CONTRACT_VERSION: u8 = 1;
-pub const PAYOUT_VERSION: u8 = 1;
-pub const ESCROW_KIX: &'static str = "0";
 
 // TODO: i think all of this can be moved into tests
 pub const PLAYER_1_MNEMONIC: &'static str = "deny income tiger glove special recycle cup surface unusual sleep speed scene enroll finger protect dice powder unit";
@@ -42,11 +32,6 @@ pub const ARBITER_MNEMONIC: &'static str = "meadow found language where fringe c
 pub const ARBITER_FINGERPRINT: &'static str = "1af44eee";
 pub const ARBITER_XPUBKEY: &'static str = "tpubDCoCzmZtfuft3oM8Y5RnaT5GFq27NR7iYLbj5r1HZyfbgMAT1AAeAxCoyMnKGQ67GAeZDcekJgsaSMTb7SpmRJ3vGbPXZxDToKHTRa3mBS2";
 
-pub const ELECTRUM_PORT: u32 = 60401;
-pub const ARBITER_PORT: u32 = 5000;
-pub const NAME_SERVICE_PORT: u32 = 18420;
-pub const ARBITER_PUBLIC_URL: &'static str = "http://localhost:5000";
-pub const NAME_SERVICE_URL: &'static str = "http://localhost:18420";
 pub const REFEREE_PRIVKEY: &'static str = "L52hw8to1fdBj9eP8HESBNrfcbehxvKU1vsqWjmHJavxNEi9q91i";
 
 pub fn referee_pubkey() -> PublicKey {
@@ -1,48 +1,52 @@
+use std::{
+    fmt,
+    str::FromStr,
+};
 use serde::{
     Serialize,
     Deserialize,
 };
 use byteorder::{BigEndian, WriteBytesExt};
 use nom::{
-    IResult,
-    combinator::opt,
-    multi::{
-        length_data,
-        length_value,
-    },
+    multi::length_data,
     number::complete::be_u32,
     sequence::tuple,
 };
 use bdk::bitcoin::{
     Address,
-    consensus::{
-        self,
-        Decodable,
-    },
+    consensus,
     util::psbt::PartiallySignedTransaction,
     secp256k1::Signature,
 };
 use crate::{
     Result,
     Error,
+    chain::ChainSource,
+    codec::{
+        decode,
+        psbt,
+        signatures,
+        version,
+        write_signature,
+        CodecResult,
+    },
     contract::{
         Contract,
+        ContractState,
         contract,
-        signature,
-        version,
     },
-    mock::{
-        NETWORK,
-        PAYOUT_VERSION,
-    }
+    wallet::anchor_script,
 };
 
+pub const PAYOUT_VERSION: u8 = 1;
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Payout {
     pub version:         u8,
     pub contract:        Contract,
     pub psbt:            PartiallySignedTransaction,
-    pub script_sig:      Option<Signature>,
+// one per oracle for threshold contracts, pushed in order before the payout script runs
+    pub script_sigs:     Vec<Signature>,
 }
 
 impl Payout {
@@ -52,7 +56,7 @@ impl Payout {
             version: PAYOUT_VERSION,
             contract,
             psbt,
-            script_sig: None,
+            script_sigs: Vec::new(),
         }
     }
 
@@ -67,80 +71,105 @@ impl Payout {
         let payout_tx = consensus::serialize(&self.psbt);
         v.write_u32::<BigEndian>(payout_tx.len() as u32).unwrap();
         v.extend(payout_tx);
-// payout script sig
-        if let Some(sig) = self.script_sig {
-            let sig_bytes = sig.serialize_der().to_vec();
-            v.write_u8(sig_bytes.len() as u8).unwrap();
-            v.extend(sig_bytes);
+// payout script sigs
+        for sig in &self.script_sigs {
+            write_signature(&mut v, sig);
         }
         v
 
     }
 
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Payout> {
-        let (i, p) = payout(&bytes).unwrap();
-        if i.len() == 0 {
-            return Ok(p)
+        Ok(decode(&bytes, payout)?)
+    }
+
+// only the escrow input matters, player signs first then the arbiter
+    pub fn state(&self) -> PayoutState {
+        let spends_escrow = match self.contract.escrow_outpoint() {
+            Ok((outpoint, _)) => self.psbt.global.unsigned_tx.input.iter().any(|txin| txin.previous_output == outpoint),
+            Err(_) => false,
+        };
+        if !spends_escrow || self.psbt.inputs.is_empty() || self.contract.state() != ContractState::ArbiterSigned {
+            return PayoutState::Invalid
+        }
+        let input = &self.psbt.inputs[0];
+        if input.final_script_witness.is_some() {
+            return PayoutState::ArbiterSigned
         }
-        else {
-            return Err(Error::Adhoc("couldn't parse payout"))
+        match input.partial_sigs.len() {
+            0 => PayoutState::Unsigned,
+            1 => PayoutState::PlayerSigned,
+            _ => PayoutState::ArbiterSigned,
         }
     }
 
+// live once the payout tx is broadcast and resolved once it confirms
+// if something else spent the escrow output, e.g. the refund tx, the payout can't happen anymore
+    pub fn chain_state<C: ChainSource>(&self, chain: &C) -> Result<PayoutState> {
+        let state = self.state();
+        if state == PayoutState::Invalid {
+            return Ok(state)
+        }
+        let (outpoint, script) = self.contract.escrow_outpoint()?;
+        match chain.spending_tx(&outpoint, &script)? {
+            Some((txid, height)) if txid == self.psbt.global.unsigned_tx.txid() => {
+                if height > 0 {
+                    Ok(PayoutState::Resolved)
+                }
+                else {
+                    Ok(PayoutState::Live)
+                }
+            }
+            Some(_) => Ok(PayoutState::Invalid),
+            None => Ok(state),
+        }
+    }
+
+// the address getting the whole escrow less the fee and anchor, so not for split payouts
     pub fn address(&self) -> Result<Address> {
-        let amount = self.contract.amount()?.as_sat() - crate::wallet::TX_FEE;
+        let anchor_script_pubkey = anchor_script().to_v0_p2wsh();
         let tx = self.psbt.clone().extract_tx();
-        for txout in tx.output {
-            if txout.value == amount {
-                return Ok(Address::from_script(&txout.script_pubkey, NETWORK).unwrap())
-            }
-        };
-        Err(Error::Adhoc("couldn't determine payout address"))
+        let payouts = tx.output.iter().filter(|txout| txout.script_pubkey != anchor_script_pubkey).collect::<Vec<_>>();
+        match payouts.as_slice() {
+            [txout] => Address::from_script(&txout.script_pubkey, self.contract.network).ok_or(Error::Adhoc("couldn't determine payout address")),
+            _ => Err(Error::Adhoc("couldn't determine payout address")),
+        }
     }
 }
 
-fn payout(input: &[u8]) ->IResult<&[u8], Payout> {
+fn payout(input: &[u8]) -> CodecResult<Payout> {
     let (input, (
         version,
         contract,
         psbt,
-        script_sig,
+        script_sigs,
     )) = tuple((
-        version, 
-        length_value(be_u32, contract), 
-        payout_psbt, 
-        opt(signature)
+        version(PAYOUT_VERSION), 
+        payout_contract, 
+        psbt, 
+        signatures,
     ))(input)?;
-//    let (input, version) = version(input).unwrap();
-//    let (input, contract) = length_value(be_u32, contract)(input).unwrap();
-//    let (input, psbt) = length_value(be_u32, payout_psbt)(input).unwrap();
-//    let (input, script_sig) = opt(signature)(input).unwrap();
-//        contract,
-//        psbt,
-//        script_sig,
-//    )) = tuple((
-//        version, 
-//        length_value(be_u32, contract), 
-//        length_value(be_u32, payout_psbt), 
-//        opt(signature)
-//    ))(input)?;
 
     let p = Payout {
         version,
         contract,
         psbt,
-        script_sig,
+        script_sigs,
     };
 
     Ok((input, p))
 }
 
-fn payout_psbt(input: &[u8]) ->IResult<&[u8], PartiallySignedTransaction> {
+// the contract is length-prefixed and has to fill its whole length
+fn payout_contract(input: &[u8]) -> CodecResult<Contract> {
     let (input, b) = length_data(be_u32)(input)?;
-    let psbt = PartiallySignedTransaction::consensus_decode(b).unwrap();
-    Ok((input, psbt))
+    match decode(b, contract) {
+        Ok(contract) => Ok((input, contract)),
+        Err(e) => Err(nom::Err::Failure(e)),
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum PayoutState {
     Unsigned,
     PlayerSigned,
@@ -149,3 +178,75 @@ pub enum PayoutState {
     Resolved,
     Invalid,
 }
+
+impl fmt::Display for PayoutState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for PayoutState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Unsigned" => Ok(PayoutState::Unsigned),
+            "PlayerSigned" => Ok(PayoutState::PlayerSigned),
+            "ArbiterSigned" => Ok(PayoutState::ArbiterSigned),
+            "Live" => Ok(PayoutState::Live),
+            "Resolved" => Ok(PayoutState::Resolved),
+            "Invalid" => Ok(PayoutState::Invalid),
+            _ => Err(Error::Adhoc("unknown payout state")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        chain::tests::MockChain,
+        codec::tests::test_payout,
+    };
+
+    #[test]
+    fn address() {
+        let payout = test_payout();
+        let address = payout.address().unwrap();
+        assert_eq!(address.script_pubkey(), Address::p2wpkh(&payout.contract.p2_pubkey, payout.contract.network).unwrap().script_pubkey());
+        assert_eq!(address.network, payout.contract.network);
+    }
+
+    #[test]
+    fn state() {
+        let payout = test_payout();
+        assert_eq!(payout.state(), PayoutState::Unsigned);
+
+        let mut p = payout.clone();
+        p.contract.sigs.pop();
+        assert_eq!(p.state(), PayoutState::Invalid);
+
+        let mut p = payout.clone();
+        p.psbt.global.unsigned_tx.input[0].previous_output.vout = 1;
+        assert_eq!(p.state(), PayoutState::Invalid);
+    }
+
+    #[test]
+    fn chain_state() {
+        let payout = test_payout();
+        let funding_tx = payout.contract.funding_tx.global.unsigned_tx.clone();
+        let payout_tx = payout.psbt.global.unsigned_tx.clone();
+
+        let chain = MockChain::new(vec![(funding_tx.clone(), 100)]);
+        assert_eq!(payout.chain_state(&chain).unwrap(), PayoutState::Unsigned);
+        let chain = MockChain::new(vec![(funding_tx.clone(), 100), (payout_tx.clone(), 0)]);
+        assert_eq!(payout.chain_state(&chain).unwrap(), PayoutState::Live);
+        let chain = MockChain::new(vec![(funding_tx.clone(), 100), (payout_tx, 101)]);
+        assert_eq!(payout.chain_state(&chain).unwrap(), PayoutState::Resolved);
+
+        let refund_tx = payout.contract.refund_tx.clone().unwrap().global.unsigned_tx;
+        let chain = MockChain::new(vec![(funding_tx, 100), (refund_tx, 101)]);
+        assert_eq!(payout.chain_state(&chain).unwrap(), PayoutState::Invalid);
+    }
+}
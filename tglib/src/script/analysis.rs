@@ -0,0 +1,621 @@
+use bdk::bitcoin::{
+    util::key::PublicKey,
+    hashes::{
+        Hash,
+        sha256,
+        hash160,
+    },
+    secp256k1::Message,
+};
+use std::collections::HashSet;
+use crate::{
+    Result,
+    Error,
+    script::{
+        interpreter::{
+            MAX_MULTISIG_PUBKEYS,
+            ScriptError,
+            ScriptResult,
+        },
+        lib::{
+            TgScript,
+            TgOpcode,
+            TgOpcode::*,
+        },
+        limits::ScriptLimits,
+    },
+};
+
+// static analysis of a payout script
+// every OP_IF is followed both ways, so each branch is one path through the script as it
+// would run under TgScriptEnv::validate_payout, with the script sigs as the only stack input
+// and under the same ScriptLimits, so a branch the interpreter would abort fails here too
+
+// ops stepped over all paths. each OP_IF on an unknown value doubles the paths, so a small
+// script can still be too expensive to explore
+pub const MAX_ANALYSIS_OPS: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BranchOutcome {
+// validates given the right script sig
+    Accepts,
+    Rejects,
+    NoValidate,
+    Fails(ScriptError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptBranch {
+// which way each OP_IF went to reach this branch
+    pub path: Vec<bool>,
+// the payout txid this branch is restricted to, if any
+    pub payout_txid: Option<Vec<u8>>,
+// pubkeys and message checked by OP_VERIFYSIG or OP_CHECKMULTISIG
+// threshold is how many of the signers have to sign the message
+    pub signers: Vec<Vec<u8>>,
+    pub threshold: usize,
+    pub message: Option<Vec<u8>>,
+    pub outcome: BranchOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptAnalysis {
+    pub branches: Vec<ScriptBranch>,
+// paths to OP_IF branches that can never be taken
+    pub unreachable: Vec<Vec<bool>>,
+// OP_IF branches by position in the script, see State::site
+    reached: HashSet<Vec<(usize, bool)>>,
+    excluded: Vec<(Vec<(usize, bool)>, Vec<bool>)>,
+    ops: usize,
+    limits: ScriptLimits,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Data(Vec<u8>),
+    ScriptSig,
+    PayoutTxid,
+    TxidEquals(Vec<u8>),
+    SigCheck,
+// a hash of something unknown, only its length is known
+    Digest(usize),
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    frames: Vec<Vec<TgOpcode>>,
+// ops taken from each frame so far
+    pcs: Vec<usize>,
+// the OP_IF position and branch that opened each frame above the script itself
+// unlike path this is the same for every path reaching the branch
+    site: Vec<(usize, bool)>,
+    stack: Vec<Value>,
+// ops executed on this path, counted as TgScriptEnv::exec does
+    ops: usize,
+    path: Vec<bool>,
+    txid: Option<Vec<u8>>,
+    not_txids: Vec<Vec<u8>>,
+    signers: Vec<Vec<u8>>,
+    threshold: usize,
+    message: Option<Vec<u8>>,
+    validity: Option<bool>,
+}
+
+impl State {
+    fn pop(&mut self) -> std::result::Result<Value, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+    fn push_bool(&mut self, b: bool) {
+        self.stack.push(Value::Data(vec![if b { OP_1.bytecode() } else { OP_0.bytecode() }]));
+    }
+
+// the payout txid is known inside a branch that compared it to a constant
+    fn resolve(&self, value: Value) -> Value {
+        match (value, &self.txid) {
+            (Value::PayoutTxid, Some(txid)) => Value::Data(txid.clone()),
+            (value, _) => value,
+        }
+    }
+
+    fn txid_can_equal(&self, txid: &[u8]) -> bool {
+        self.txid.as_ref().map_or(true, |t| t == txid) && !self.not_txids.iter().any(|t| t == txid)
+    }
+
+    fn equals(&self, a: Value, b: Value) -> Value {
+        match (self.resolve(a), self.resolve(b)) {
+            (Value::Data(a), Value::Data(b)) => Value::Data(vec![if a == b { OP_1.bytecode() } else { OP_0.bytecode() }]),
+            (Value::PayoutTxid, Value::PayoutTxid) => Value::Data(vec![OP_1.bytecode()]),
+            (Value::PayoutTxid, Value::Data(txid)) |
+            (Value::Data(txid), Value::PayoutTxid) => Value::TxidEquals(txid),
+            (Value::Digest(n), Value::Data(d)) |
+            (Value::Data(d), Value::Digest(n)) if d.len() != n => Value::Data(vec![OP_0.bytecode()]),
+            _ => Value::Unknown,
+        }
+    }
+}
+
+impl ScriptAnalysis {
+    pub fn new(script: &TgScript) -> ScriptResult<Self> {
+        Self::with_limits(script, ScriptLimits::default())
+    }
+
+    pub fn with_limits(script: &TgScript, limits: ScriptLimits) -> ScriptResult<Self> {
+        let size = Vec::<u8>::from(script.clone()).len();
+        if size > limits.max_script_bytes {
+            return Err(ScriptError::ScriptTooLarge(size))
+        }
+        let mut analysis = ScriptAnalysis {
+            branches: Vec::new(),
+            unreachable: Vec::new(),
+            reached: HashSet::new(),
+            excluded: Vec::new(),
+            ops: 0,
+            limits,
+        };
+        analysis.explore(State {
+            frames: vec![script.0.clone()],
+            pcs: vec![0],
+            site: Vec::new(),
+            stack: vec![Value::ScriptSig],
+            ops: 0,
+            path: Vec::new(),
+            txid: None,
+            not_txids: Vec::new(),
+            signers: Vec::new(),
+            threshold: 0,
+            message: None,
+            validity: None,
+        })?;
+// a branch ruled out on one path can still be taken on another, e.g. every branch
+// after the matching one when each payout txid is checked in turn
+        let ScriptAnalysis { reached, excluded, .. } = &analysis;
+        let mut unreachable: Vec<Vec<bool>> = excluded.iter()
+            .filter(|(site, _path)| !reached.contains(site))
+            .map(|(_site, path)| path.clone())
+            .collect();
+        unreachable.dedup();
+        analysis.unreachable = unreachable;
+        Ok(analysis)
+    }
+
+    pub fn accepting(&self) -> impl Iterator<Item = &ScriptBranch> {
+        self.branches.iter().filter(|branch| branch.outcome == BranchOutcome::Accepts)
+    }
+
+// the branch that would accept a payout with this txid
+    pub fn branch_for(&self, txid: &[u8]) -> Option<&ScriptBranch> {
+        self.accepting().find(|branch| branch.payout_txid.as_deref() == Some(txid))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for branch in &self.branches {
+            match branch.outcome {
+                BranchOutcome::Fails(ScriptError::StackUnderflow) => return Err(Error::InvalidContract("payout script stack underflow")),
+                BranchOutcome::Fails(_) => return Err(Error::InvalidContract("payout script fails")),
+                BranchOutcome::NoValidate => return Err(Error::InvalidContract("payout script branch never validates")),
+                BranchOutcome::Accepts if branch.payout_txid.is_none() => return Err(Error::InvalidContract("payout script accepts any payout tx")),
+                _ => (),
+            }
+        }
+        if !self.unreachable.is_empty() {
+            return Err(Error::InvalidContract("payout script has unreachable branches"))
+        }
+        if self.accepting().next().is_none() {
+            return Err(Error::InvalidContract("payout script never accepts a payout"))
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, state: State, outcome: BranchOutcome) {
+        self.branches.push(ScriptBranch {
+            path: state.path,
+            payout_txid: state.txid,
+            signers: state.signers,
+            threshold: state.threshold,
+            message: state.message,
+            outcome,
+        });
+    }
+
+    fn explore(&mut self, mut state: State) -> ScriptResult<()> {
+        loop {
+            while state.frames.last().map_or(false, |frame| frame.is_empty()) {
+                state.frames.pop();
+                state.pcs.pop();
+                state.site.truncate(state.frames.len().saturating_sub(1));
+            }
+            let depth = state.frames.len() as u8;
+            let op = match state.frames.last_mut() {
+                Some(frame) => {
+                    *state.pcs.last_mut().unwrap() += 1;
+                    frame.remove(0)
+                }
+                None => {
+                    let outcome = match state.validity {
+                        Some(true) => BranchOutcome::Accepts,
+                        Some(false) => BranchOutcome::Rejects,
+                        None => BranchOutcome::NoValidate,
+                    };
+                    self.finish(state, outcome);
+                    return Ok(())
+                }
+            };
+            self.ops += 1;
+            if self.ops > MAX_ANALYSIS_OPS {
+                return Err(ScriptError::AnalysisLimitExceeded)
+            }
+            state.ops += 1;
+            if state.ops > self.limits.max_ops {
+                self.finish(state, BranchOutcome::Fails(ScriptError::OpLimitExceeded));
+                return Ok(())
+            }
+            let result = match op {
+                OP_IF(true_branch, false_branch) => return self.fork(state, depth, true_branch, false_branch),
+                op => Self::step(&mut state, op, &self.limits),
+            };
+            let result = result.and_then(|_| match state.stack.len() > self.limits.max_stack_depth {
+                true => Err(ScriptError::StackDepthExceeded),
+                false => Ok(()),
+            });
+            if let Err(e) = result {
+                self.finish(state, BranchOutcome::Fails(e));
+                return Ok(())
+            }
+        }
+    }
+
+    fn fork(&mut self, mut state: State, depth: u8, true_branch: TgScript, false_branch: Option<TgScript>) -> ScriptResult<()> {
+        let pc = *state.pcs.last().unwrap() - 1;
+        let condition = match state.pop() {
+            Ok(condition) => state.resolve(condition),
+            Err(e) => {
+                self.finish(state, BranchOutcome::Fails(e));
+                return Ok(())
+            }
+        };
+        let (can_be_true, can_be_false) = match &condition {
+            Value::Data(d) => (d != &vec![OP_0.bytecode()], d == &vec![OP_0.bytecode()]),
+            Value::TxidEquals(txid) => (state.txid_can_equal(txid), state.txid.as_ref() != Some(txid)),
+            _ => (true, true),
+        };
+        for (taken, reachable, branch) in vec![(true, can_be_true, Some(true_branch)), (false, can_be_false, false_branch)] {
+            let mut path = state.path.clone();
+            path.push(taken);
+            let mut site = state.site.clone();
+            site.push((pc, taken));
+            if !reachable {
+                if branch.is_some() {
+                    self.excluded.push((site, path));
+                }
+                continue
+            }
+            let mut next = state.clone();
+            next.path = path;
+            if let Value::TxidEquals(txid) = &condition {
+                if taken {
+                    next.txid = Some(txid.clone());
+                }
+                else {
+                    next.not_txids.push(txid.clone());
+                }
+            }
+            if let Some(branch) = branch {
+                self.reached.insert(site.clone());
+                if depth == self.limits.max_eval_depth {
+                    self.finish(next, BranchOutcome::Fails(ScriptError::EvalDepthExceeded));
+                    continue
+                }
+                next.frames.push(branch.0);
+                next.pcs.push(0);
+                next.site = site;
+            }
+            self.explore(next)?;
+        }
+        Ok(())
+    }
+
+    fn step(state: &mut State, op: TgOpcode, limits: &ScriptLimits) -> std::result::Result<(), ScriptError> {
+        match op {
+            OP_0                                =>  state.push_bool(false),
+            OP_1                                =>  state.push_bool(true),
+            OP_PUSHDATA1(n, bytes)              =>  push_data(state, n as usize, bytes, limits)?,
+            OP_PUSHDATA2(n, bytes)              =>  push_data(state, n as usize, bytes, limits)?,
+            OP_PUSHDATA4(n, bytes)              =>  push_data(state, n as usize, bytes, limits)?,
+            OP_PUSHTXID                         =>  state.stack.push(Value::PayoutTxid),
+            OP_DUP                              =>  {
+                let top = state.stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                state.stack.push(top);
+            }
+            OP_2DUP                             =>  {
+                let len = state.stack.len();
+                if len < 2 {
+                    return Err(ScriptError::StackUnderflow)
+                }
+                state.stack.push(state.stack[len - 2].clone());
+                state.stack.push(state.stack[len - 1].clone());
+            }
+            OP_DROP                             =>  { state.pop()?; }
+            OP_EQUAL                            =>  {
+                let (a, b) = (state.pop()?, state.pop()?);
+                let equal = state.equals(a, b);
+                state.stack.push(equal);
+            }
+            OP_EQUALVERIFY                      =>  {
+                let (a, b) = (state.pop()?, state.pop()?);
+                match state.equals(a, b) {
+                    Value::Data(d) if d == vec![OP_0.bytecode()] => return Err(ScriptError::VerifyFailed),
+                    Value::TxidEquals(txid) => {
+                        if !state.txid_can_equal(&txid) {
+                            return Err(ScriptError::VerifyFailed)
+                        }
+                        state.txid = Some(txid);
+                    }
+                    _ => (),
+                }
+            }
+            OP_VERIFYSIG                        =>  {
+                let msg = state.pop()?;
+                let pubkey = state.pop()?;
+                let _sig = state.pop()?;
+                let msg = state.resolve(msg);
+// a constant that can't be a message or pubkey always fails, as in the interpreter
+                let bad_msg = matches!(&msg, Value::Data(m) if Message::from_slice(m).is_err());
+                let bad_pubkey = matches!(&pubkey, Value::Data(p) if PublicKey::from_slice(p).is_err());
+                if bad_msg || bad_pubkey {
+                    state.push_bool(false);
+                }
+                else {
+                    if let Value::Data(p) = pubkey {
+                        state.signers = vec![p];
+                        state.threshold = 1;
+                    }
+                    if let Value::Data(m) = msg {
+                        state.message = Some(m);
+                    }
+                    state.stack.push(Value::SigCheck);
+                }
+            }
+            OP_CHECKMULTISIG                    =>  {
+                let msg = state.pop()?;
+                let msg = state.resolve(msg);
+                let n = pop_count(state)?;
+                let mut pubkeys = Vec::with_capacity(n);
+                for _ in 0..n {
+                    pubkeys.push(state.pop()?);
+                }
+                let k = pop_count(state)?;
+                if k == 0 || k > n {
+                    return Err(ScriptError::InvalidThreshold)
+                }
+// sigs below the counts come from the payout, which can carry as many as it needs
+                for _ in 0..k {
+                    state.stack.pop();
+                }
+                let bad_msg = matches!(&msg, Value::Data(m) if Message::from_slice(m).is_err());
+                let usable = pubkeys.iter().filter(|pk| !matches!(pk, Value::Data(p) if PublicKey::from_slice(p).is_err())).count();
+                if bad_msg || usable < k {
+                    state.push_bool(false);
+                }
+                else {
+                    state.signers = pubkeys.into_iter().rev().filter_map(|pk| match pk {
+                        Value::Data(p) => Some(p),
+                        _ => None,
+                    }).collect();
+                    state.threshold = k;
+                    if let Value::Data(m) = msg {
+                        state.message = Some(m);
+                    }
+                    state.stack.push(Value::SigCheck);
+                }
+            }
+            OP_SHA256                           =>  {
+                let top = state.pop()?;
+                state.stack.push(match state.resolve(top) {
+                    Value::Data(d) => Value::Data(sha256::Hash::hash(&d).to_vec()),
+                    _ => Value::Digest(sha256::Hash::LEN),
+                });
+            }
+            OP_HASH160                          =>  {
+                let top = state.pop()?;
+                state.stack.push(match state.resolve(top) {
+                    Value::Data(d) => Value::Data(hash160::Hash::hash(&d).to_vec()),
+                    _ => Value::Digest(hash160::Hash::LEN),
+                });
+            }
+            OP_VALIDATE                         =>  {
+                let top = state.pop()?;
+                state.validity = Some(match state.resolve(top) {
+                    Value::Data(d) => d == vec![OP_1.bytecode()],
+                    _ => true,
+                });
+            }
+            OP_IF(..)                           =>  unreachable!(),
+            op @ OP_ELSE(_) | op @ OP_ENDIF     =>  return Err(ScriptError::UnexpectedOpcode(op)),
+        }
+        Ok(())
+    }
+}
+
+// OP_CHECKMULTISIG counts have to be constants to know how many items to take
+fn pop_count(state: &mut State) -> std::result::Result<usize, ScriptError> {
+    match state.pop()? {
+        Value::Data(d) if d.len() == 1 && d[0] as usize <= MAX_MULTISIG_PUBKEYS => Ok(d[0] as usize),
+        _ => Err(ScriptError::InvalidThreshold),
+    }
+}
+
+fn push_data(state: &mut State, n: usize, bytes: Vec<u8>, limits: &ScriptLimits) -> std::result::Result<(), ScriptError> {
+    if bytes.len() != n {
+        return Err(ScriptError::BadPushdataLength { expected: n, actual: bytes.len() })
+    }
+    if n > limits.max_item_bytes {
+        return Err(ScriptError::ItemTooLarge(n))
+    }
+    state.stack.push(Value::Data(bytes));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const PUBKEY: &'static str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn txid(b: u8) -> Vec<u8> {
+        vec![b; 32]
+    }
+
+    fn token(b: u8) -> Vec<u8> {
+        vec![b; 32]
+    }
+
+// nested in the previous OP_ELSE, as wallet::create_token_pair_script used to
+    fn token_pair_script(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> TgScript {
+        let mut script = format!("PUSHDATA1 0x{}", PUBKEY);
+        for (txid, token) in &pairs {
+            script.push_str(&format!(" PUSHDATA1 0x{} PUSHTXID EQUAL IF PUSHDATA1 0x{} ELSE", hex::encode(txid), hex::encode(token)));
+        }
+        script.push_str(" 0");
+        script.push_str(&" ENDIF".repeat(pairs.len()));
+        script.push_str(" VERIFYSIG VALIDATE");
+        script.parse().unwrap()
+    }
+
+// same shape as wallet::create_token_pair_script
+    fn token_dispatch_script(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> TgScript {
+        let mut script = format!("PUSHDATA1 0x{} 0", PUBKEY);
+        for (txid, token) in &pairs {
+            script.push_str(&format!(" PUSHDATA1 0x{} PUSHTXID EQUAL IF DROP PUSHDATA1 0x{} ENDIF", hex::encode(txid), hex::encode(token)));
+        }
+        script.push_str(" VERIFYSIG VALIDATE");
+        script.parse().unwrap()
+    }
+
+    #[test]
+    fn token_pairs() {
+        let script = token_pair_script(vec![(txid(1), token(0xa1)), (txid(2), token(0xa2))]);
+        let analysis = ScriptAnalysis::new(&script).unwrap();
+        assert_eq!(analysis.branches.len(), 3);
+        assert!(analysis.unreachable.is_empty());
+        assert!(analysis.validate().is_ok());
+        let branch = analysis.branch_for(&txid(2)).unwrap();
+        assert_eq!(branch.path, vec![false, true]);
+        assert_eq!(branch.signers, vec![hex::decode(PUBKEY).unwrap()]);
+        assert_eq!(branch.threshold, 1);
+        assert_eq!(branch.message, Some(token(0xa2)));
+        assert_eq!(analysis.branches[2].outcome, BranchOutcome::Rejects);
+        assert!(analysis.branch_for(&txid(3)).is_none());
+    }
+
+    #[test]
+    fn unreachable() {
+// the second branch can never be taken since the txid would have matched the first
+        let script = token_pair_script(vec![(txid(1), token(0xa1)), (txid(1), token(0xa2))]);
+        let analysis = ScriptAnalysis::new(&script).unwrap();
+        assert_eq!(analysis.unreachable, vec![vec![false, true]]);
+        assert!(analysis.validate().is_err());
+
+        let analysis = ScriptAnalysis::new(&"0 IF 1 ELSE 1 ENDIF VALIDATE".parse().unwrap()).unwrap();
+        assert_eq!(analysis.unreachable, vec![vec![true]]);
+    }
+
+    #[test]
+    fn token_dispatch() {
+// later pairs can't match once one has, but each is still reachable on its own
+        let script = token_dispatch_script(vec![(txid(1), token(0xa1)), (txid(2), token(0xa2)), (txid(3), token(0xa3))]);
+        let analysis = ScriptAnalysis::new(&script).unwrap();
+        assert_eq!(analysis.branches.len(), 4);
+        assert!(analysis.unreachable.is_empty());
+        assert!(analysis.validate().is_ok());
+        assert_eq!(analysis.branch_for(&txid(3)).unwrap().message, Some(token(0xa3)));
+        assert_eq!(analysis.branches[3].outcome, BranchOutcome::Rejects);
+    }
+
+    #[test]
+    fn underflow() {
+        let analysis = ScriptAnalysis::new(&"PUSHTXID PUSHDATA1 0x01 EQUAL IF DROP DROP ENDIF 1 VALIDATE".parse().unwrap()).unwrap();
+        assert_eq!(analysis.branches[0].outcome, BranchOutcome::Fails(ScriptError::StackUnderflow));
+        assert_eq!(analysis.branches[1].outcome, BranchOutcome::Accepts);
+        assert!(analysis.validate().is_err());
+    }
+
+    #[test]
+    fn no_validate() {
+        let script: TgScript = format!("PUSHDATA1 0x{} PUSHTXID EQUAL IF 1 VALIDATE ENDIF", hex::encode(txid(1))).parse().unwrap();
+        let analysis = ScriptAnalysis::new(&script).unwrap();
+        assert_eq!(analysis.branches[0].outcome, BranchOutcome::Accepts);
+        assert_eq!(analysis.branches[1].outcome, BranchOutcome::NoValidate);
+        assert!(analysis.validate().is_err());
+    }
+
+    #[test]
+    fn threshold() {
+        let pubkey_2 = "03c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+        let script: TgScript = format!(
+            "PUSHDATA1 0x02 PUSHDATA1 0x{} PUSHDATA1 0x{} PUSHDATA1 0x02 PUSHDATA1 0x{} PUSHTXID EQUAL IF PUSHDATA1 0x{} ELSE 0 ENDIF CHECKMULTISIG VALIDATE",
+            PUBKEY, pubkey_2, hex::encode(txid(1)), hex::encode(token(0xa1)),
+        ).parse().unwrap();
+        let analysis = ScriptAnalysis::new(&script).unwrap();
+        assert!(analysis.validate().is_ok());
+        let branch = analysis.branch_for(&txid(1)).unwrap();
+        assert_eq!(branch.signers, vec![hex::decode(PUBKEY).unwrap(), hex::decode(pubkey_2).unwrap()]);
+        assert_eq!(branch.threshold, 2);
+        assert_eq!(branch.message, Some(token(0xa1)));
+
+// only one usable pubkey for a threshold of two
+        let script: TgScript = format!("PUSHDATA1 0x02 PUSHDATA1 0x{} PUSHDATA1 0xff PUSHDATA1 0x02 PUSHDATA1 0x{} CHECKMULTISIG VALIDATE", PUBKEY, hex::encode(token(0xa1))).parse().unwrap();
+        assert_eq!(ScriptAnalysis::new(&script).unwrap().branches[0].outcome, BranchOutcome::Rejects);
+
+        let script: TgScript = format!("PUSHDATA1 0x03 PUSHDATA1 0x{} PUSHDATA1 0x01 PUSHDATA1 0x{} CHECKMULTISIG VALIDATE", PUBKEY, hex::encode(token(0xa1))).parse().unwrap();
+        assert_eq!(ScriptAnalysis::new(&script).unwrap().branches[0].outcome, BranchOutcome::Fails(ScriptError::InvalidThreshold));
+    }
+
+    #[test]
+    fn preimage() {
+// the script sig's hash can't equal the OP_0 pushed when no txid matches
+        let script: TgScript = format!("SHA256 0 PUSHDATA1 0x{} PUSHTXID EQUAL IF DROP PUSHDATA1 0x{} ENDIF EQUAL VALIDATE", hex::encode(txid(1)), hex::encode(token(0xa1))).parse().unwrap();
+        let analysis = ScriptAnalysis::new(&script).unwrap();
+        assert!(analysis.validate().is_ok());
+        assert_eq!(analysis.branch_for(&txid(1)).unwrap().signers, Vec::<Vec<u8>>::new());
+        assert_eq!(analysis.branches[1].outcome, BranchOutcome::Rejects);
+    }
+
+    #[test]
+    fn analysis_limit() {
+// every OP_IF on the script sig forks, so the paths double with each one
+        let script: TgScript = vec!["DUP IF 1 DROP ENDIF"; 40].join(" ").parse().unwrap();
+        assert_eq!(ScriptAnalysis::new(&script), Err(ScriptError::AnalysisLimitExceeded));
+    }
+
+    #[test]
+    fn limits() {
+        let limits = ScriptLimits::default();
+        let script: TgScript = format!("PUSHDATA1 0x{} PUSHTXID EQUAL IF 1 1 DROP ENDIF 0 VALIDATE", hex::encode(txid(1))).parse().unwrap();
+        let analysis = ScriptAnalysis::with_limits(&script, ScriptLimits { max_ops: 8, ..limits }).unwrap();
+        assert_eq!(analysis.branches[0].outcome, BranchOutcome::Fails(ScriptError::OpLimitExceeded));
+        assert_eq!(analysis.branches[1].outcome, BranchOutcome::Rejects);
+        assert!(analysis.validate().is_err());
+
+        let analysis = ScriptAnalysis::with_limits(&"1 1 1 DROP DROP VALIDATE".parse().unwrap(), ScriptLimits { max_stack_depth: 3, ..limits }).unwrap();
+        assert_eq!(analysis.branches[0].outcome, BranchOutcome::Fails(ScriptError::StackDepthExceeded));
+
+        let analysis = ScriptAnalysis::with_limits(&"PUSHDATA1 0xffff DROP 1 VALIDATE".parse().unwrap(), ScriptLimits { max_item_bytes: 1, ..limits }).unwrap();
+        assert_eq!(analysis.branches[0].outcome, BranchOutcome::Fails(ScriptError::ItemTooLarge(2)));
+
+        let script: TgScript = "1 VALIDATE".parse().unwrap();
+        assert_eq!(ScriptAnalysis::with_limits(&script, ScriptLimits { max_script_bytes: 1, ..limits }), Err(ScriptError::ScriptTooLarge(2)));
+    }
+
+    #[test]
+    fn accepts_any_payout() {
+// checks the oracle sig but not which tx it pays for
+        let script: TgScript = format!("PUSHDATA1 0x{} PUSHDATA1 0x{} VERIFYSIG VALIDATE", PUBKEY, hex::encode(token(0xa1))).parse().unwrap();
+        let analysis = ScriptAnalysis::new(&script).unwrap();
+        assert_eq!(analysis.branches.len(), 1);
+        assert_eq!(analysis.branches[0].payout_txid, None);
+        assert!(analysis.validate().is_err());
+    }
+}
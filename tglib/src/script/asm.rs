@@ -0,0 +1,177 @@
+use std::convert::TryInto;
+use nom::{
+    self,
+    IResult,
+    bytes::complete::take_while1,
+    character::complete::multispace0,
+    combinator::{all_consuming, opt},
+    multi::many1,
+    sequence::{pair, preceded, terminated},
+};
+use crate::{
+    script::{
+        lib::{
+            TgOpcode,
+            TgScript,
+        },
+        limits::ScriptLimits,
+    },
+};
+
+// human-readable assembly for tg scripts, e.g.
+// PUSHDATA1 0x02ab.. PUSHTXID EQUAL IF PUSHDATA1 0x.. ELSE 0 ENDIF VERIFYSIG VALIDATE
+// opcode names may optionally be written with the OP_ prefix
+// push data is given as 0x-prefixed hex and the length prefix is computed from it
+
+fn token(input: &str) -> IResult<&str, &str> {
+    preceded(
+        multispace0,
+        take_while1(|c: char| !c.is_whitespace()),
+    )(input)
+}
+
+fn opcode_name(word: &str) -> &str {
+    word.strip_prefix("OP_").unwrap_or(word)
+}
+
+fn keyword(name: &'static str) -> impl Fn(&str) -> IResult<&str, &str> {
+    move |input: &str| {
+        let (rest, word) = token(input)?;
+        if opcode_name(word) == name {
+            Ok((rest, word))
+        }
+        else {
+            Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag)))
+        }
+    }
+}
+
+fn hex_data(input: &str) -> IResult<&str, Vec<u8>> {
+    let (rest, word) = token(input)?;
+    let data = word.strip_prefix("0x")
+        .and_then(|h| hex::decode(h).ok())
+        .ok_or(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::HexDigit)))?;
+    Ok((rest, data))
+}
+
+fn too_large(input: &str) -> nom::Err<nom::error::Error<&str>> {
+    nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::TooLarge))
+}
+
+fn op_else(input: &str, limits: ScriptLimits, depth: u8) -> IResult<&str, TgScript> {
+    preceded(keyword("ELSE"), |i| asm_script(i, limits, depth))(input)
+}
+
+// depth is the nesting of the script containing this op, bounded the same as the bytecode parser's op_if
+fn op_if(input: &str, limits: ScriptLimits, depth: u8) -> IResult<&str, TgOpcode> {
+    let (rest, _) = keyword("IF")(input)?;
+    if depth + 2 > limits.max_eval_depth {
+        return Err(too_large(rest))
+    }
+    let (rest, (true_branch, false_branch)) = terminated(
+        pair(|i| asm_script(i, limits, depth + 1), opt(|i| op_else(i, limits, depth + 1))),
+        keyword("ENDIF"),
+    )(rest)?;
+    Ok((rest, TgOpcode::OP_IF(true_branch, false_branch)))
+}
+
+fn op(input: &str, limits: ScriptLimits, depth: u8) -> IResult<&str, TgOpcode> {
+    use TgOpcode::*;
+    let (rest, word) = token(input)?;
+    let op = match opcode_name(word) {
+        "0"             => OP_0,
+        "1"             => OP_1,
+        "DROP"          => OP_DROP,
+        "DUP"           => OP_DUP,
+        "2DUP"          => OP_2DUP,
+        "EQUAL"         => OP_EQUAL,
+        "EQUALVERIFY"   => OP_EQUALVERIFY,
+        "VERIFYSIG"     => OP_VERIFYSIG,
+        "CHECKMULTISIG" => OP_CHECKMULTISIG,
+        "SHA256"        => OP_SHA256,
+        "HASH160"       => OP_HASH160,
+        "VALIDATE"      => OP_VALIDATE,
+        "PUSHTXID"      => OP_PUSHTXID,
+        "IF"            => return op_if(input, limits, depth),
+        "PUSHDATA1"     => {
+            let (rest, data) = hex_data(rest)?;
+            let n: u8 = data.len().try_into().map_err(|_| too_large(rest))?;
+            return Ok((rest, OP_PUSHDATA1(n, data)))
+        }
+        "PUSHDATA2"     => {
+            let (rest, data) = hex_data(rest)?;
+            let n: u16 = data.len().try_into().map_err(|_| too_large(rest))?;
+            return Ok((rest, OP_PUSHDATA2(n, data)))
+        }
+        "PUSHDATA4"     => {
+            let (rest, data) = hex_data(rest)?;
+            let n: u32 = data.len().try_into().map_err(|_| too_large(rest))?;
+            return Ok((rest, OP_PUSHDATA4(n, data)))
+        }
+// ELSE and ENDIF only make sense while parsing IF, same as the bytecode parser
+        _ => return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag))),
+    };
+    Ok((rest, op))
+}
+
+fn asm_script(input: &str, limits: ScriptLimits, depth: u8) -> IResult<&str, TgScript> {
+    let (input, ops) = many1(|i| op(i, limits, depth))(input)?;
+    Ok((input, TgScript(ops)))
+}
+
+pub fn tg_asm(input: &str) -> IResult<&str, TgScript> {
+    all_consuming(terminated(|i| asm_script(i, ScriptLimits::default(), 0), multispace0))(input)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::script::parser::tg_script;
+
+    const CONDITIONAL_SCRIPT_TRUE: &'static[u8] = &[0x01,0xF1,0x01,0xF2,0x00,0xF3,0xF1,0x00,0xF3];
+    const NESTED_SCRIPT: &'static str = "PUSHDATA1 0x02ab PUSHTXID EQUAL IF PUSHDATA1 0xff ELSE PUSHDATA1 0x01 PUSHTXID EQUAL IF 1 ELSE 0 ENDIF ENDIF VERIFYSIG VALIDATE";
+
+    #[test]
+    fn disassemble() {
+        let (_, script) = tg_script(&CONDITIONAL_SCRIPT_TRUE).unwrap();
+        assert_eq!(script.to_string(), "1 IF 1 ELSE 0 ENDIF IF 0 ENDIF");
+    }
+
+    #[test]
+    fn assemble() {
+        let (_, script) = tg_asm(NESTED_SCRIPT).unwrap();
+        assert_eq!(script.to_string(), NESTED_SCRIPT);
+        let (_, prefixed) = tg_asm("OP_1 OP_IF OP_PUSHDATA2 0x0102 OP_ENDIF").unwrap();
+        assert_eq!(prefixed.to_string(), "1 IF PUSHDATA2 0x0102 ENDIF");
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_, script) = tg_asm(NESTED_SCRIPT).unwrap();
+        let bytes = Vec::<u8>::from(script.clone());
+        let (_, parsed) = tg_script(&bytes).unwrap();
+        assert_eq!(parsed, script);
+        let (_, reassembled) = tg_asm(&parsed.to_string()).unwrap();
+        assert_eq!(reassembled, script);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(tg_asm("").is_err());
+        assert!(tg_asm("FOO").is_err());
+        assert!(tg_asm("1 ELSE 0 ENDIF").is_err());
+        assert!(tg_asm("1 IF 0").is_err());
+        assert!(tg_asm("PUSHDATA1 ab").is_err());
+        assert!(tg_asm("PUSHDATA1 0xabc").is_err());
+        assert!(tg_asm(&format!("PUSHDATA1 0x{}", "00".repeat(256))).is_err());
+    }
+
+// nesting is bounded before recursing, like the bytecode parser, so this can't overflow the stack
+    #[test]
+    fn nesting() {
+        assert!(matches!(tg_asm(&"1 IF ".repeat(100_000)), Err(nom::Err::Failure(_))));
+        assert!(tg_asm("1 IF 1 IF 1 ENDIF ENDIF").is_ok());
+        assert!(matches!(tg_asm("1 IF 1 IF 1 IF 1 ENDIF ENDIF ENDIF"), Err(nom::Err::Failure(_))));
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{
+    payout::Payout,
+    script::{
+        interpreter::{
+            ScriptError,
+            ScriptResult,
+            TgScriptEnv,
+            TraceStep,
+        },
+        lib::{
+            TgScript,
+            TgOpcode::*,
+        },
+    },
+};
+
+// executes a script one op at a time
+// the interpreter evaluates OP_IF branches recursively, so the debugger keeps its own
+// stack of frames instead and pushes the chosen branch as a new frame
+pub struct TgScriptDebugger {
+    env: TgScriptEnv,
+    frames: Vec<TgScript>,
+}
+
+impl TgScriptDebugger {
+    pub fn new(env: TgScriptEnv, script: TgScript) -> Self {
+        TgScriptDebugger {
+            env,
+            frames: vec![script],
+        }
+    }
+
+// same setup as TgScriptEnv::validate_payout
+    pub fn for_payout(payout: Payout) -> ScriptResult<Self> {
+        let mut env = TgScriptEnv::new(payout);
+        let script = env.load_payout()?;
+        Ok(TgScriptDebugger::new(env, script))
+    }
+
+    pub fn env(&self) -> &TgScriptEnv {
+        &self.env
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.frames.iter().all(|frame| frame.0.is_empty())
+    }
+
+// execute the next op. returns None when the script is finished
+// after an error the script is finished
+    pub fn step(&mut self) -> ScriptResult<Option<TraceStep>> {
+        while let Some(frame) = self.frames.last() {
+            if frame.0.is_empty() {
+                self.frames.pop();
+            }
+            else {
+                break
+            }
+        }
+        let depth = self.frames.len() as u8;
+        let op = match self.frames.last_mut() {
+            Some(frame) => frame.0.remove(0),
+            None => return Ok(None),
+        };
+        let mut step = TraceStep::new(op.clone(), depth, self.env.stack());
+        let result = match op {
+            OP_IF(true_branch, false_branch) => self.op_if(depth, true_branch, false_branch),
+            op => self.env.exec(op),
+        };
+        step.stack_after = self.env.stack().to_vec();
+        match result {
+            Ok(()) => Ok(Some(step)),
+            Err(e) => {
+                self.frames.clear();
+                Err(e)
+            }
+        }
+    }
+
+    pub fn run(&mut self) -> ScriptResult<Vec<TraceStep>> {
+        let mut steps = Vec::new();
+        while let Some(step) = self.step()? {
+            steps.push(step);
+        }
+        Ok(steps)
+    }
+
+    fn op_if(&mut self, depth: u8, true_branch: TgScript, false_branch: Option<TgScript>) -> ScriptResult<()> {
+        let branch = if self.env.pop()? != vec![OP_0.bytecode()] {
+            Some(true_branch)
+        }
+        else {
+            false_branch
+        };
+        if let Some(branch) = branch {
+            if depth == self.env.limits().max_eval_depth {
+                return Err(ScriptError::EvalDepthExceeded)
+            }
+            self.frames.push(branch);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::script::interpreter::TgScriptInterpreter;
+
+    const NESTED_SCRIPT: &'static str = "PUSHDATA1 0x02ab PUSHDATA1 0x02ab EQUAL IF PUSHDATA1 0xff ELSE PUSHDATA1 0x01 1 IF 1 ELSE 0 ENDIF ENDIF DUP DROP VALIDATE";
+
+    #[test]
+    fn matches_eval_trace() {
+        let script: TgScript = NESTED_SCRIPT.parse().unwrap();
+        let mut env = TgScriptEnv::default().with_trace();
+        env.eval(script.clone()).unwrap();
+
+        let mut debugger = TgScriptDebugger::new(TgScriptEnv::default(), script);
+        let steps = debugger.run().unwrap();
+        assert!(debugger.is_done());
+        assert_eq!(env.trace().unwrap(), &steps[..]);
+        assert_eq!(debugger.env().validity(), env.validity());
+        assert_eq!(steps[3].branch, Some(true));
+        assert_eq!(steps[4].depth, 2);
+    }
+
+    #[test]
+    fn step() {
+        let script: TgScript = "0 IF 1 ELSE 0 ENDIF VALIDATE".parse().unwrap();
+        let mut debugger = TgScriptDebugger::new(TgScriptEnv::default(), script);
+        let step = debugger.step().unwrap().unwrap();
+        assert_eq!(step.op, OP_0);
+        assert_eq!(step.stack_after, vec![vec![0x00]]);
+        let step = debugger.step().unwrap().unwrap();
+        assert_eq!(step.branch, Some(false));
+        assert!(step.stack_after.is_empty());
+        let step = debugger.step().unwrap().unwrap();
+        assert_eq!((step.op, step.depth), (OP_0, 2));
+        let step = debugger.step().unwrap().unwrap();
+        assert_eq!((step.op, step.depth), (OP_VALIDATE, 1));
+        assert_eq!(debugger.step(), Ok(None));
+        assert_eq!(debugger.env().validity(), Some(false));
+    }
+
+    #[test]
+    fn errors() {
+        let mut debugger = TgScriptDebugger::new(TgScriptEnv::default(), "1 DROP DROP 1".parse().unwrap());
+        assert!(debugger.step().is_ok());
+        assert!(debugger.step().is_ok());
+        assert_eq!(debugger.step(), Err(ScriptError::StackUnderflow));
+        assert!(debugger.is_done());
+        assert_eq!(debugger.step(), Ok(None));
+
+// deeper than the parsers accept, so it's built directly
+        let script = (0..3).fold(TgScript(vec![OP_1]), |inner, _| TgScript(vec![OP_1, OP_IF(inner, None)]));
+        assert_eq!(script.to_string(), "1 IF 1 IF 1 IF 1 ENDIF ENDIF ENDIF");
+        let mut debugger = TgScriptDebugger::new(TgScriptEnv::default(), script.clone());
+        let mut env = TgScriptEnv::default();
+        assert_eq!(debugger.run(), Err(ScriptError::EvalDepthExceeded));
+        assert_eq!(env.eval(script), Err(ScriptError::EvalDepthExceeded));
+    }
+}
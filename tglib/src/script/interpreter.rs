@@ -1,6 +1,15 @@
-use std::convert::TryInto;
+use std::fmt;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use bdk::bitcoin::{
     util::key::PublicKey,
+    hashes::{
+        Hash,
+        sha256,
+        hash160,
+    },
     secp256k1::{
         Secp256k1,
         Message,
@@ -12,19 +21,114 @@ use crate::{
     Result,
     Error,
     payout::Payout,
-    script::lib::{
-        TgScript,
-        TgOpcode::*,
+    script::{
+        lib::{
+            TgScript,
+            TgOpcode,
+            TgOpcode::*,
+        },
+        limits::ScriptLimits,
     },
 };
 
-const EVAL_DEPTH_LIMIT : u8 = 3;
+pub(crate) const EVAL_DEPTH_LIMIT : u8 = 3;
+pub(crate) const MAX_MULTISIG_PUBKEYS : usize = 20;
+
+pub type ScriptResult<T> = std::result::Result<T, ScriptError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    StackUnderflow,
+    EvalDepthExceeded,
+    NoPayout,
+    NoScriptSig,
+    BadPushdataLength { expected: usize, actual: usize },
+    UnexpectedOpcode(TgOpcode),
+    VerifyFailed,
+    ScriptTooLarge(usize),
+    OpLimitExceeded,
+    ItemTooLarge(usize),
+    StackDepthExceeded,
+    InvalidThreshold,
+    AnalysisLimitExceeded,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::StackUnderflow => write!(f, "StackUnderflow"),
+            ScriptError::EvalDepthExceeded => write!(f, "EvalDepthExceeded"),
+            ScriptError::NoPayout => write!(f, "NoPayout"),
+            ScriptError::NoScriptSig => write!(f, "NoScriptSig"),
+            ScriptError::BadPushdataLength { expected, actual } => write!(f, "BadPushdataLength({}, {})", expected, actual),
+            ScriptError::UnexpectedOpcode(op) => write!(f, "UnexpectedOpcode({:?})", op),
+            ScriptError::VerifyFailed => write!(f, "VerifyFailed"),
+            ScriptError::ScriptTooLarge(size) => write!(f, "ScriptTooLarge({})", size),
+            ScriptError::OpLimitExceeded => write!(f, "OpLimitExceeded"),
+            ScriptError::ItemTooLarge(size) => write!(f, "ItemTooLarge({})", size),
+            ScriptError::StackDepthExceeded => write!(f, "StackDepthExceeded"),
+            ScriptError::InvalidThreshold => write!(f, "InvalidThreshold"),
+            ScriptError::AnalysisLimitExceeded => write!(f, "AnalysisLimitExceeded"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+// one executed op. for OP_IF, branch records whether the condition was true
+// and the ops of the chosen branch follow at depth + 1
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceStep {
+    pub op: TgOpcode,
+    pub depth: u8,
+    pub branch: Option<bool>,
+    pub stack_before: Vec<Vec<u8>>,
+    pub stack_after: Vec<Vec<u8>>,
+}
+
+impl TraceStep {
+    pub(crate) fn new(op: TgOpcode, depth: u8, stack: &[Vec<u8>]) -> Self {
+        let branch = match op {
+            OP_IF(..) => stack.last().map(|top| top != &vec![OP_0.bytecode()]),
+            _ => None,
+        };
+// OP_IF only pops its condition, the branch's own ops are traced after it
+        let stack_after = match branch {
+            Some(_) => stack[..stack.len() - 1].to_vec(),
+            None => stack.to_vec(),
+        };
+        TraceStep {
+            op,
+            depth,
+            branch,
+            stack_before: stack.to_vec(),
+            stack_after,
+        }
+    }
+}
+
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+// don't print the whole nested script for OP_IF, its ops are traced separately
+        let op = match (&self.op, self.branch) {
+            (OP_IF(..), Some(true))     => "IF (true)".to_string(),
+            (OP_IF(..), Some(false))    => "IF (false)".to_string(),
+            (OP_IF(..), None)           => "IF".to_string(),
+            (op, _)                     => op.to_string(),
+        };
+        let stack: Vec<String> = self.stack_after.iter().map(hex::encode).collect();
+        write!(f, "{}{} [{}]", "  ".repeat(self.depth.saturating_sub(1) as usize), op, stack.join(" "))
+    }
+}
 
 pub struct TgScriptEnv {
     pub payout: Option<Payout>,
     stack: Vec<Vec<u8>>,
     eval_depth: u8,
     validity: Option<bool>,
+    trace: Option<Vec<TraceStep>>,
+    limits: ScriptLimits,
+    ops: usize,
     secp: Secp256k1<All>,
 }
 
@@ -36,34 +140,48 @@ impl TgScriptEnv {
             stack: Vec::new(),
             eval_depth: 0,
             validity: None,
+            trace: None,
+            limits: ScriptLimits::default(),
+            ops: 0,
             secp: Secp256k1::new(),
         }
     }
 
+// record every executed op, see trace()
+    pub fn with_trace(mut self) -> Self {
+        self.trace = Some(Vec::new());
+        self
+    }
+
+    pub fn trace(&self) -> Option<&[TraceStep]> {
+        self.trace.as_deref()
+    }
+
+    pub fn with_limits(mut self, limits: ScriptLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &ScriptLimits {
+        &self.limits
+    }
+
+    pub fn validity(&self) -> Option<bool> {
+        self.validity
+    }
+
+    pub fn stack(&self) -> &[Vec<u8>] {
+        &self.stack
+    }
+
 //todo: probably move this out of the interpreter
     pub fn validate_payout(&mut self) -> Result<()> {
 
-        if self.payout.is_none() {
-            return Err(Error::Adhoc("cannot validate payout request - payout request is None"));
-        }
-
 // TODO: ensure payout_tx is signed by the same player making the request
 // TODO: ensure payout_tx is not already in the blockchain (but then who cares?)
 
-        let payout = self.payout.clone().ok_or(Error::Adhoc("no payout"))?;
-//confirm payout script hash sigs on contract
-// TODO: check funding_tx is signed by the correct parties and in the blockchain
-//        if payout.contract.state() != ContractState::Live {
-//        if payout.contract.state() != ContractState::ArbiterSigned {
-//            return Err(Error::Adhoc("invalid payout request - contract is uncertified"))
-//        }
-//push script sig to stack then evaluate the payout script
-        self.stack.push(payout
-            .script_sig.ok_or(Error::Adhoc("no script sig"))?
-            .serialize_der()
-            .to_vec());
-
-        let _result = self.eval(payout.contract.payout_script.clone());
+        let script = self.load_payout()?;
+        self.eval(script)?;
 
 // TODO: this is weird
         match self.validity {
@@ -72,13 +190,99 @@ impl TgScriptEnv {
         }
     }
 
-    pub fn push_input(&mut self, bytes: Vec<u8>) {
-        self.pushdata(bytes.len(), bytes);
+// push the payout script sigs and return the contract's payout script for evaluation
+    pub(crate) fn load_payout(&mut self) -> ScriptResult<TgScript> {
+        let payout = self.payout.clone().ok_or(ScriptError::NoPayout)?;
+// the contract has to be live, the arbiter checks that against the chain before it gets here
+// see Contract::chain_state
+//push script sigs to stack in order then evaluate the payout script
+        if payout.script_sigs.is_empty() {
+            return Err(ScriptError::NoScriptSig)
+        }
+        for sig in &payout.script_sigs {
+            self.push_input(sig.serialize_der().to_vec())?;
+        }
+
+        Ok(payout.contract.payout_script)
+    }
+
+    pub fn push_input(&mut self, bytes: Vec<u8>) -> ScriptResult<()> {
+        self.pushdata(bytes.len(), bytes)
+    }
+
+    fn pushdata(&mut self, n: usize, bytes: Vec<u8>) -> ScriptResult<()> {
+        if bytes.len() != n {
+            return Err(ScriptError::BadPushdataLength { expected: n, actual: bytes.len() })
+        }
+        if n > self.limits.max_item_bytes {
+            return Err(ScriptError::ItemTooLarge(n))
+        }
+        self.stack.push(bytes);
+        Ok(())
+    }
+
+    pub(crate) fn pop(&mut self) -> ScriptResult<Vec<u8>> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+// counts for OP_CHECKMULTISIG are single byte items
+    fn pop_count(&mut self) -> ScriptResult<usize> {
+        match self.pop()?[..] {
+            [n] if n as usize <= MAX_MULTISIG_PUBKEYS => Ok(n as usize),
+            _ => Err(ScriptError::InvalidThreshold),
+        }
     }
 
-    fn pushdata(&mut self, n: usize, bytes: Vec<u8>) {
-        assert!(bytes.len() == n as usize);
-        self.stack.push(bytes)
+    pub(crate) fn exec(&mut self, op: TgOpcode) -> ScriptResult<()> {
+        self.ops += 1;
+        if self.ops > self.limits.max_ops {
+            return Err(ScriptError::OpLimitExceeded)
+        }
+        let depth = self.eval_depth;
+        let index = match &mut self.trace {
+            Some(trace) => {
+                trace.push(TraceStep::new(op.clone(), depth, &self.stack));
+                Some(trace.len() - 1)
+            }
+            None => None,
+        };
+        let result = match op {
+            OP_0                                =>  self.op_0(),
+            OP_1                                =>  self.op_1(),
+            OP_DUP                              =>  self.op_dup(),
+            OP_2DUP                             =>  self.op_2dup(),
+            OP_DROP                             =>  self.op_drop(),
+            OP_EQUAL                            =>  self.op_equal(),
+            OP_EQUALVERIFY                      =>  self.op_equalverify(),
+            OP_VERIFYSIG                        =>  self.op_verifysig(),
+            OP_CHECKMULTISIG                    =>  self.op_checkmultisig(),
+            OP_SHA256                           =>  self.op_sha256(),
+            OP_HASH160                          =>  self.op_hash160(),
+            OP_PUSHTXID                         =>  self.op_pushtxid(),
+            OP_PUSHDATA1(n, bytes)              =>  self.op_pushdata1(n, bytes),
+            OP_PUSHDATA2(n, bytes)              =>  self.op_pushdata2(n, bytes),
+            OP_PUSHDATA4(n, bytes)              =>  self.op_pushdata4(n, bytes),
+            OP_IF(true_branch, false_branch)    =>  self.op_if(true_branch, false_branch),
+// we shouldn't directly encounter these so they are errors
+// OP_ELSE and OP_ENDIF get consumed while parsing OP_IF to avoid keeping track of conditional
+// state during evaluation
+// e.g. when the next op is OP_ELSE but shouldn't be executed, need to remember conditional state
+// instead, an else block is stored as an optional second field for OP_IF to evaluate directly
+            OP_ELSE(false_branch)               =>  self.op_else(false_branch),
+            OP_ENDIF                            =>  self.op_endif(),
+            OP_VALIDATE                         =>  self.op_validate(),
+        };
+        if let (Some(trace), Some(i)) = (&mut self.trace, index) {
+            if trace[i].branch.is_none() {
+                trace[i].stack_after = self.stack.clone();
+            }
+        }
+        result?;
+// no op pushes more than two items so checking afterwards is enough
+        if self.stack.len() > self.limits.max_stack_depth {
+            return Err(ScriptError::StackDepthExceeded)
+        }
+        Ok(())
     }
 }
 
@@ -89,195 +293,249 @@ impl Default for TgScriptEnv {
             stack: Vec::new(),
             eval_depth: 0,
             validity: None,
+            trace: None,
+            limits: ScriptLimits::default(),
+            ops: 0,
             secp: Secp256k1::new(),
         }
     }
 }
 
-// TODO: refactor ops to return results? could be easier to handle invalid scripts
 pub trait TgScriptInterpreter {
-    fn eval(&mut self, _script: TgScript) -> Result<()> { Err(Error::Adhoc("")) }
+    fn eval(&mut self, _script: TgScript) -> ScriptResult<()>;
 // NOTE: opcode functions - in own trait?
-    fn op_pushdata1(&mut self, _n: u8, _bytes: Vec<u8>);
-    fn op_pushdata2(&mut self, _n: u16, _bytes: Vec<u8>);
-    fn op_pushdata4(&mut self, _n: u32, _bytes: Vec<u8>);
-    fn op_0(&mut self);
-    fn op_1(&mut self);
-    fn op_dup(&mut self);
-    fn op_2dup(&mut self);
-    fn op_drop(&mut self);
-    fn op_if(&mut self, _true_branch: TgScript, _false_branch: Option<TgScript>);
-    fn op_validate(&mut self);
-    fn op_else(&mut self);
-    fn op_endif(&mut self);
-    fn op_equal(&mut self);
-    fn op_nequal(&mut self);
-    fn op_verifysig(&mut self);
-    fn op_sha256(&mut self);
-    fn op_pushtxid(&mut self);
+    fn op_pushdata1(&mut self, _n: u8, _bytes: Vec<u8>) -> ScriptResult<()>;
+    fn op_pushdata2(&mut self, _n: u16, _bytes: Vec<u8>) -> ScriptResult<()>;
+    fn op_pushdata4(&mut self, _n: u32, _bytes: Vec<u8>) -> ScriptResult<()>;
+    fn op_0(&mut self) -> ScriptResult<()>;
+    fn op_1(&mut self) -> ScriptResult<()>;
+    fn op_dup(&mut self) -> ScriptResult<()>;
+    fn op_2dup(&mut self) -> ScriptResult<()>;
+    fn op_drop(&mut self) -> ScriptResult<()>;
+    fn op_if(&mut self, _true_branch: TgScript, _false_branch: Option<TgScript>) -> ScriptResult<()>;
+    fn op_validate(&mut self) -> ScriptResult<()>;
+    fn op_else(&mut self, _false_branch: TgScript) -> ScriptResult<()>;
+    fn op_endif(&mut self) -> ScriptResult<()>;
+    fn op_equal(&mut self) -> ScriptResult<()>;
+    fn op_equalverify(&mut self) -> ScriptResult<()>;
+    fn op_nequal(&mut self) -> ScriptResult<()>;
+    fn op_verifysig(&mut self) -> ScriptResult<()>;
+    fn op_checkmultisig(&mut self) -> ScriptResult<()>;
+    fn op_sha256(&mut self) -> ScriptResult<()>;
+    fn op_hash160(&mut self) -> ScriptResult<()>;
+    fn op_pushtxid(&mut self) -> ScriptResult<()>;
 }
 
 impl TgScriptInterpreter for TgScriptEnv {
-    fn eval(&mut self, mut script: TgScript) -> Result<()> {
+    fn eval(&mut self, mut script: TgScript) -> ScriptResult<()> {
 
-        if self.eval_depth == EVAL_DEPTH_LIMIT {
-            panic!("eval depth limit reached");
+        if self.eval_depth == self.limits.max_eval_depth {
+            return Err(ScriptError::EvalDepthExceeded)
         }
-        else
-        {
-            println!("eval: {:?}", script);
-            self.eval_depth += 1;
+        if self.eval_depth == 0 {
+            let size = Vec::<u8>::from(script.clone()).len();
+            if size > self.limits.max_script_bytes {
+                return Err(ScriptError::ScriptTooLarge(size))
+            }
         }
+        self.eval_depth += 1;
 
         while script.0.len() > 0 {
-            let next = script.0.remove(0);    
-            println!("next op: {:?}", next);
-//            println!("preop stack: {:?}", self.stack);
-//            println!("preop stack depth: {:?}", self.stack.len());
-            match next {
-                OP_0                                =>  self.op_0(),
-                OP_1                                =>  self.op_1(),
-                OP_DUP                              =>  self.op_dup(),
-                OP_2DUP                             =>  self.op_2dup(),
-                OP_DROP                             =>  self.op_drop(),
-                OP_EQUAL                            =>  self.op_equal(),
-                OP_VERIFYSIG                        =>  self.op_verifysig(),
-                OP_SHA256                           =>  self.op_sha256(),
-                OP_PUSHTXID                         =>  self.op_pushtxid(),
-                OP_PUSHDATA1(n, bytes)              =>  self.op_pushdata1(n.try_into().unwrap(), bytes),
-                OP_PUSHDATA2(n, bytes)              =>  self.op_pushdata2(n.try_into().unwrap(), bytes),
-                OP_PUSHDATA4(n, bytes)              =>  self.op_pushdata4(n.try_into().unwrap(), bytes),
-                OP_IF(true_branch, false_branch)    =>  self.op_if(true_branch, false_branch),
-// we shouldn't directly encounter these so I guess they should panic
-// OP_ELSE and OP_ENDIF get consumed while parsing OP_IF to avoid keeping track of conditional
-// state during evaluation
-// e.g. when the next op is OP_ELSE but shouldn't be executed, need to remember conditional state
-// instead, an else block is stored as an optional second field for OP_IF to evaluate directly
-                OP_ELSE(_) => self.op_else(),
-                OP_ENDIF => self.op_endif(),
-                OP_VALIDATE => self.op_validate(),
+            let next = script.0.remove(0);
+            if let Err(e) = self.exec(next) {
+                self.eval_depth -= 1;
+                return Err(e)
             }
-//            println!("postop stack: {:?}", self.stack);
-//            println!("postop stack depth: {:?}", self.stack.len());
         }
         self.eval_depth -= 1;
 
-        println!("eval returning");
-
         Ok(())
     }
 
-    fn op_pushdata1(&mut self, n: u8, bytes: Vec<u8>) {
-        self.pushdata(n as usize, bytes);
+    fn op_pushdata1(&mut self, n: u8, bytes: Vec<u8>) -> ScriptResult<()> {
+        self.pushdata(n as usize, bytes)
     }
 
-    fn op_pushdata2(&mut self, n: u16, bytes: Vec<u8>) {
-        self.pushdata(n as usize, bytes);
+    fn op_pushdata2(&mut self, n: u16, bytes: Vec<u8>) -> ScriptResult<()> {
+        self.pushdata(n as usize, bytes)
     }
 
-    fn op_pushdata4(&mut self, n: u32, bytes: Vec<u8>) {
-        self.pushdata(n as usize, bytes);
+    fn op_pushdata4(&mut self, n: u32, bytes: Vec<u8>) -> ScriptResult<()> {
+        self.pushdata(n as usize, bytes)
     }
 
-    fn op_0(&mut self) {
-        self.stack.push(vec![OP_0.bytecode()])
+    fn op_0(&mut self) -> ScriptResult<()> {
+        self.stack.push(vec![OP_0.bytecode()]);
+        Ok(())
     }
 
-    fn op_1(&mut self) {
-        self.stack.push(vec![OP_1.bytecode()])
+    fn op_1(&mut self) -> ScriptResult<()> {
+        self.stack.push(vec![OP_1.bytecode()]);
+        Ok(())
     }
 
-    fn op_dup(&mut self) {
-        self.stack.push(self.stack.last().unwrap().clone());
+    fn op_dup(&mut self) -> ScriptResult<()> {
+        let top = self.stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+        self.stack.push(top);
+        Ok(())
     }
 
-    fn op_2dup(&mut self) {
+    fn op_2dup(&mut self) -> ScriptResult<()> {
         let len = self.stack.len();
+        if len < 2 {
+            return Err(ScriptError::StackUnderflow)
+        }
         self.stack.push(self.stack[len - 2].clone());
 // stack.len() increased by one but we want the last element of the stack before
         self.stack.push(self.stack[len - 1].clone());
+        Ok(())
     }
 
-    fn op_drop(&mut self) {
-        self.stack.pop().unwrap();
+    fn op_drop(&mut self) -> ScriptResult<()> {
+        self.pop()?;
+        Ok(())
     }
 
-    fn op_if(&mut self, true_branch: TgScript, false_branch: Option<TgScript>) {
-        if self.stack.pop().unwrap() != vec![OP_0.bytecode()] {
-            self.eval(true_branch).unwrap();
+    fn op_if(&mut self, true_branch: TgScript, false_branch: Option<TgScript>) -> ScriptResult<()> {
+        if self.pop()? != vec![OP_0.bytecode()] {
+            self.eval(true_branch)
         }
         else if let Some(false_branch) = false_branch {
-            self.eval(false_branch).unwrap();
+            self.eval(false_branch)
+        }
+        else {
+            Ok(())
         }
     }
 
-    fn op_else(&mut self) {
-        panic!("unexpected OP_ELSE")
+    fn op_else(&mut self, false_branch: TgScript) -> ScriptResult<()> {
+        Err(ScriptError::UnexpectedOpcode(OP_ELSE(false_branch)))
     }
 
-    fn op_endif(&mut self) {
-        panic!("unexpected OP_ENDIF")
+    fn op_endif(&mut self) -> ScriptResult<()> {
+        Err(ScriptError::UnexpectedOpcode(OP_ENDIF))
     }
 
-    fn op_validate(&mut self) {
+    fn op_validate(&mut self) -> ScriptResult<()> {
         self.validity = Some(
-            self.stack.pop().unwrap() == vec![OP_1.bytecode()]
+            self.pop()? == vec![OP_1.bytecode()]
         );
+        Ok(())
     }
 
-    fn op_equal(&mut self) {
-        if self.stack.pop().unwrap() == self.stack.pop().unwrap() {
-            self.op_1();
+    fn op_equal(&mut self) -> ScriptResult<()> {
+        if self.pop()? == self.pop()? {
+            self.op_1()
         }
         else {
-            self.op_0();
+            self.op_0()
         }
     }
 
-    fn op_nequal(&mut self) {
+// like OP_EQUAL but fails the script instead of pushing the result
+    fn op_equalverify(&mut self) -> ScriptResult<()> {
+        if self.pop()? == self.pop()? {
+            Ok(())
+        }
+        else {
+            Err(ScriptError::VerifyFailed)
+        }
+    }
+
+    fn op_nequal(&mut self) -> ScriptResult<()> {
         let len = self.stack.len();
+        if len < 2 {
+            return Err(ScriptError::StackUnderflow)
+        }
         if self.stack[len - 1] != self.stack[len - 2] {
-            self.op_1();
+            self.op_1()
         }
         else {
-            self.op_0();
+            self.op_0()
         }
     }
 
-    fn op_verifysig(&mut self) {
-        let msg_bytes = self.stack.pop().unwrap();
-        let pubkey_bytes = self.stack.pop().unwrap();
-        let sig_bytes = self.stack.pop().unwrap();
+    fn op_verifysig(&mut self) -> ScriptResult<()> {
+        let msg_bytes = self.pop()?;
+        let pubkey_bytes = self.pop()?;
+        let sig_bytes = self.pop()?;
         let msg = Message::from_slice(&msg_bytes);
         let pubkey = PublicKey::from_slice(&pubkey_bytes);
         let sig = Signature::from_der(&sig_bytes);
         if msg.is_err() || pubkey.is_err() || sig.is_err() {
-            self.op_0();
-            return
+            return self.op_0()
         }
         let msg = msg.unwrap();
         let pubkey = pubkey.unwrap();
         let sig = sig.unwrap();
         if self.secp.verify(&msg, &sig, &pubkey.key).is_ok() {
-            self.op_1();
+            self.op_1()
         }
         else {
-            self.op_0();
+            self.op_0()
         }
     }
 
-    fn op_sha256(&mut self) {
+// stack is [sig_1 .. sig_k, k, pubkey_1 .. pubkey_n, n, msg]
+// pushes 1 if every sig is valid for msg under a different one of the pubkeys
+    fn op_checkmultisig(&mut self) -> ScriptResult<()> {
+        let msg_bytes = self.pop()?;
+        let n = self.pop_count()?;
+        let mut pubkeys = Vec::with_capacity(n);
+        for _ in 0..n {
+            pubkeys.push(self.pop()?);
+        }
+        let k = self.pop_count()?;
+        if k == 0 || k > n {
+            return Err(ScriptError::InvalidThreshold)
+        }
+        let mut sigs = Vec::with_capacity(k);
+        for _ in 0..k {
+            sigs.push(self.pop()?);
+        }
+        let msg = match Message::from_slice(&msg_bytes) {
+            Ok(msg) => msg,
+            Err(_) => return self.op_0(),
+        };
+        let mut pubkeys: Vec<Option<PublicKey>> = pubkeys.iter().map(|pk| PublicKey::from_slice(pk).ok()).collect();
+        for sig_bytes in sigs {
+            let sig = match Signature::from_der(&sig_bytes) {
+                Ok(sig) => sig,
+                Err(_) => return self.op_0(),
+            };
+// each pubkey can only be used once
+            let secp = &self.secp;
+            let signer = pubkeys.iter().position(|pk| match pk {
+                Some(pk) => secp.verify(&msg, &sig, &pk.key).is_ok(),
+                None => false,
+            });
+            match signer {
+                Some(i) => pubkeys[i] = None,
+                None => return self.op_0(),
+            }
+        }
+        self.op_1()
+    }
 
+    fn op_sha256(&mut self) -> ScriptResult<()> {
+        let top = self.pop()?;
+        self.stack.push(sha256::Hash::hash(&top).to_vec());
+        Ok(())
     }
-    
-    fn op_pushtxid(&mut self) {
 
-        assert!(self.payout.is_some());
+    fn op_hash160(&mut self) -> ScriptResult<()> {
+        let top = self.pop()?;
+        self.stack.push(hash160::Hash::hash(&top).to_vec());
+        Ok(())
+    }
+    
+    fn op_pushtxid(&mut self) -> ScriptResult<()> {
 
-        let payout = self.payout.as_ref().unwrap();
+        let payout = self.payout.as_ref().ok_or(ScriptError::NoPayout)?;
         let payout_txid = &payout.psbt.clone().extract_tx().txid();
 
         self.stack.push(payout_txid.to_vec());
+        Ok(())
     }
 }
 
@@ -358,5 +616,200 @@ mod tests {
         assert_eq!(script, script2);
     }
 
-//    #[test]
+    #[test]
+    fn stack_underflow() {
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_DROP])), Err(ScriptError::StackUnderflow));
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_1, OP_2DUP])), Err(ScriptError::StackUnderflow));
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_1, OP_EQUAL])), Err(ScriptError::StackUnderflow));
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_IF(TgScript(vec![OP_1]), None)])), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn eval_depth_exceeded() {
+// deeper than the parsers accept, so it's built directly
+        let script = (0..3).fold(TgScript(vec![OP_1]), |inner, _| TgScript(vec![OP_1, OP_IF(inner, None)]));
+        assert_eq!(script.to_string(), "1 IF 1 IF 1 IF 1 ENDIF ENDIF ENDIF");
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(script), Err(ScriptError::EvalDepthExceeded));
+        let script: TgScript = "1 IF 1 IF 1 ENDIF ENDIF".parse().unwrap();
+        let mut env = TgScriptEnv::default();
+        assert!(env.eval(script).is_ok());
+    }
+
+    #[test]
+    fn no_payout() {
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_PUSHTXID])), Err(ScriptError::NoPayout));
+        let mut env = TgScriptEnv::default();
+        match env.validate_payout() {
+            Err(Error::Script(ScriptError::NoPayout)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn bad_pushdata_length() {
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_PUSHDATA1(2, vec![0xff])])), Err(ScriptError::BadPushdataLength { expected: 2, actual: 1 }));
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_PUSHDATA4(0, vec![0xff])])), Err(ScriptError::BadPushdataLength { expected: 0, actual: 1 }));
+    }
+
+    #[test]
+    fn unexpected_opcode() {
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_1, OP_ENDIF])), Err(ScriptError::UnexpectedOpcode(OP_ENDIF)));
+        let mut env = TgScriptEnv::default();
+        let else_op = OP_ELSE(TgScript(vec![OP_0]));
+        assert_eq!(env.eval(TgScript(vec![else_op.clone()])), Err(ScriptError::UnexpectedOpcode(else_op)));
+    }
+
+    #[test]
+    fn sha256() {
+        let mut env = TgScriptEnv::default();
+        env.push_input(b"abc".to_vec()).unwrap();
+        env.eval(TgScript(vec![OP_SHA256])).unwrap();
+        assert_eq!(hex::encode(env.pop().unwrap()), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_SHA256])), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn hash160() {
+        let mut env = TgScriptEnv::default();
+        env.push_input(b"abc".to_vec()).unwrap();
+        env.eval(TgScript(vec![OP_HASH160])).unwrap();
+        assert_eq!(hex::encode(env.pop().unwrap()), "bb1be98c142444d7a56aa3981c3942a978e4dc33");
+    }
+
+    #[test]
+    fn equalverify() {
+        let mut env = TgScriptEnv::default();
+        env.eval("PUSHDATA1 0xff PUSHDATA1 0xff EQUALVERIFY".parse().unwrap()).unwrap();
+        assert!(env.stack.is_empty());
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval("PUSHDATA1 0xff PUSHDATA1 0xfe EQUALVERIFY 1".parse().unwrap()), Err(ScriptError::VerifyFailed));
+    }
+
+    #[test]
+    fn preimage() {
+        let secret = b"the oracle's secret".to_vec();
+        let script: TgScript = format!("SHA256 PUSHDATA1 0x{} EQUAL VALIDATE", hex::encode(sha256::Hash::hash(&secret))).parse().unwrap();
+
+        let mut env = TgScriptEnv::default();
+        env.push_input(secret).unwrap();
+        env.eval(script.clone()).unwrap();
+        assert_eq!(env.validity, Some(true));
+
+        let mut env = TgScriptEnv::default();
+        env.push_input(b"wrong secret".to_vec()).unwrap();
+        env.eval(script).unwrap();
+        assert_eq!(env.validity, Some(false));
+    }
+
+    fn multisig_env(signers: &[u8], threshold: u8, sigs: &[u8], msg: &[u8]) -> (TgScriptEnv, TgScript) {
+        use bdk::bitcoin::secp256k1::SecretKey;
+        let secp = Secp256k1::new();
+        let keys: Vec<SecretKey> = signers.iter().map(|i| SecretKey::from_slice(&[*i; 32]).unwrap()).collect();
+        let mut env = TgScriptEnv::default();
+        for i in sigs {
+            let key = SecretKey::from_slice(&[*i; 32]).unwrap();
+            let sig = secp.sign(&Message::from_slice(msg).unwrap(), &key);
+            env.push_input(sig.serialize_der().to_vec()).unwrap();
+        }
+        let mut script = TgScript(vec![OP_PUSHDATA1(1, vec![threshold])]);
+        for key in keys {
+            let pubkey = PublicKey { compressed: true, key: bdk::bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &key) }.to_bytes();
+            script.0.push(OP_PUSHDATA1(pubkey.len() as u8, pubkey));
+        }
+        script.0.push(OP_PUSHDATA1(1, vec![signers.len() as u8]));
+        script.0.push(OP_PUSHDATA1(msg.len() as u8, msg.to_vec()));
+        script.0.push(OP_CHECKMULTISIG);
+        script.0.push(OP_VALIDATE);
+        (env, script)
+    }
+
+    #[test]
+    fn checkmultisig() {
+        let msg = [0xab; 32];
+        let (mut env, script) = multisig_env(&[1, 2, 3], 2, &[1, 3], &msg);
+        env.eval(script).unwrap();
+        assert_eq!(env.validity, Some(true));
+        assert!(env.stack.is_empty());
+
+// sigs don't have to be in pubkey order
+        let (mut env, script) = multisig_env(&[1, 2, 3], 2, &[3, 2], &msg);
+        env.eval(script).unwrap();
+        assert_eq!(env.validity, Some(true));
+
+// the same oracle can't sign twice
+        let (mut env, script) = multisig_env(&[1, 2, 3], 2, &[2, 2], &msg);
+        env.eval(script).unwrap();
+        assert_eq!(env.validity, Some(false));
+
+        let (mut env, script) = multisig_env(&[1, 2, 3], 2, &[1, 4], &msg);
+        env.eval(script).unwrap();
+        assert_eq!(env.validity, Some(false));
+
+        let (mut env, script) = multisig_env(&[1, 2, 3], 4, &[1, 2, 3], &msg);
+        assert_eq!(env.eval(script), Err(ScriptError::InvalidThreshold));
+
+        let (mut env, script) = multisig_env(&[1, 2, 3], 0, &[], &msg);
+        assert_eq!(env.eval(script), Err(ScriptError::InvalidThreshold));
+
+        let (mut env, script) = multisig_env(&[1, 2, 3], 2, &[1], &msg);
+        assert_eq!(env.eval(script), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn trace() {
+        let mut env = TgScriptEnv::default();
+        env.eval(TgScript(vec![OP_1])).unwrap();
+        assert!(env.trace().is_none());
+
+        let mut env = TgScriptEnv::default().with_trace();
+        env.eval("1 IF PUSHDATA1 0xff ENDIF DROP".parse().unwrap()).unwrap();
+        let trace = env.trace().unwrap();
+        assert_eq!(trace.len(), 4);
+        assert_eq!((trace[1].branch, trace[1].depth), (Some(true), 1));
+        assert_eq!(trace[1].stack_after, Vec::<Vec<u8>>::new());
+        assert_eq!(trace[2].depth, 2);
+        assert_eq!(trace[2].to_string(), "  PUSHDATA1 0xff [ff]");
+        assert_eq!(trace[3].stack_before, vec![vec![0xff]]);
+        assert!(trace[3].stack_after.is_empty());
+    }
+
+    #[test]
+    fn limits() {
+        let limits = ScriptLimits::default();
+
+        let mut env = TgScriptEnv::default().with_limits(ScriptLimits { max_ops: 3, ..limits });
+        assert_eq!(env.eval("1 1 DROP".parse().unwrap()), Ok(()));
+        let mut env = TgScriptEnv::default().with_limits(ScriptLimits { max_ops: 3, ..limits });
+        assert_eq!(env.eval("1 1 DROP DROP".parse().unwrap()), Err(ScriptError::OpLimitExceeded));
+// ops inside branches count too
+        let mut env = TgScriptEnv::default().with_limits(ScriptLimits { max_ops: 3, ..limits });
+        assert_eq!(env.eval("1 IF 1 DROP ENDIF".parse().unwrap()), Err(ScriptError::OpLimitExceeded));
+
+        let mut env = TgScriptEnv::default().with_limits(ScriptLimits { max_stack_depth: 2, ..limits });
+        assert_eq!(env.eval("1 DUP DUP".parse().unwrap()), Err(ScriptError::StackDepthExceeded));
+
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.push_input(vec![0; 521]), Err(ScriptError::ItemTooLarge(521)));
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_PUSHDATA2(600, vec![0; 600])])), Err(ScriptError::ItemTooLarge(600)));
+
+        let mut env = TgScriptEnv::default();
+        assert_eq!(env.eval(TgScript(vec![OP_1; 10_001])), Err(ScriptError::ScriptTooLarge(10_001)));
+
+// deeper than the parsers accept, so it's built directly
+        let script = (0..3).fold(TgScript(vec![OP_1]), |inner, _| TgScript(vec![OP_1, OP_IF(inner, None)]));
+        assert_eq!(script.to_string(), "1 IF 1 IF 1 IF 1 ENDIF ENDIF ENDIF");
+        let mut env = TgScriptEnv::default().with_limits(ScriptLimits { max_eval_depth: 4, ..limits });
+        assert!(env.eval(script).is_ok());
+    }
 }
@@ -1,6 +1,13 @@
-use std::fmt;
+use std::{
+    fmt,
+    str::FromStr,
+};
 use serde::{Serialize, Deserialize,};
 use byteorder::{BigEndian, WriteBytesExt};
+use crate::{
+    Error,
+    script::asm::tg_asm,
+};
 
 
 #[allow(non_camel_case_types)]
@@ -18,8 +25,11 @@ pub enum TgOpcode {
     OP_DUP,
     OP_2DUP,
     OP_EQUAL,
+    OP_EQUALVERIFY,
     OP_VERIFYSIG,
+    OP_CHECKMULTISIG,
     OP_SHA256,
+    OP_HASH160,
     OP_VALIDATE,
     OP_PUSHTXID,
 }
@@ -42,8 +52,40 @@ impl fmt::Debug for TgOpcode {
            OP_DUP                               =>  write!(f, "DUP"),
            OP_2DUP                              =>  write!(f, "2DUP"),
            OP_EQUAL                             =>  write!(f, "EQUAL"),
+           OP_EQUALVERIFY                       =>  write!(f, "EQUALVERIFY"),
            OP_VERIFYSIG                         =>  write!(f, "VERIFYSIG"),
+           OP_CHECKMULTISIG                     =>  write!(f, "CHECKMULTISIG"),
            OP_SHA256                            =>  write!(f, "SHA256"),
+           OP_HASH160                           =>  write!(f, "HASH160"),
+           OP_PUSHTXID                          =>  write!(f, "PUSHTXID"),
+        }
+    }
+}
+
+// assembly syntax, see script::asm
+impl fmt::Display for TgOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TgOpcode::*;
+        match self {
+           OP_0                                 =>  write!(f, "0"),
+           OP_1                                 =>  write!(f, "1"),
+           OP_PUSHDATA1(_, data)                =>  write!(f, "PUSHDATA1 0x{}", hex::encode(data)),
+           OP_PUSHDATA2(_, data)                =>  write!(f, "PUSHDATA2 0x{}", hex::encode(data)),
+           OP_PUSHDATA4(_, data)                =>  write!(f, "PUSHDATA4 0x{}", hex::encode(data)),
+           OP_IF(true_branch, None)             =>  write!(f, "IF {} ENDIF", true_branch),
+           OP_IF(true_branch, Some(false_branch)) => write!(f, "IF {} ELSE {} ENDIF", true_branch, false_branch),
+           OP_ELSE(false_branch)                =>  write!(f, "ELSE {}", false_branch),
+           OP_VALIDATE                          =>  write!(f, "VALIDATE"),
+           OP_ENDIF                             =>  write!(f, "ENDIF"),
+           OP_DROP                              =>  write!(f, "DROP"),
+           OP_DUP                               =>  write!(f, "DUP"),
+           OP_2DUP                              =>  write!(f, "2DUP"),
+           OP_EQUAL                             =>  write!(f, "EQUAL"),
+           OP_EQUALVERIFY                       =>  write!(f, "EQUALVERIFY"),
+           OP_VERIFYSIG                         =>  write!(f, "VERIFYSIG"),
+           OP_CHECKMULTISIG                     =>  write!(f, "CHECKMULTISIG"),
+           OP_SHA256                            =>  write!(f, "SHA256"),
+           OP_HASH160                           =>  write!(f, "HASH160"),
            OP_PUSHTXID                          =>  write!(f, "PUSHTXID"),
         }
     }
@@ -59,8 +101,8 @@ impl From<TgOpcode> for Vec<u8> {
             OP_PUSHDATA4(num_bytes, data)           =>  { v.write_u32::<BigEndian>(num_bytes).unwrap(); v.extend(Vec::from(data)); v } ,
             OP_IF(true_branch, None)                =>  { v.extend(Vec::from(true_branch)); v.push(OP_ENDIF.bytecode()); v },
             OP_IF(true_branch, Some(false_branch))  =>  { v.extend(Vec::from(true_branch)); v.push(OP_ELSE(TgScript::default()).bytecode()); v.extend(Vec::from(false_branch)); v.push(OP_ENDIF.bytecode()); v },
-//NOTE: don't think this should ever happen either ... hmmm
-            OP_ELSE(_false_branch)                  =>  { panic!("encountered OP_ELSE outside of OP_IF"); },
+// only valid inside OP_IF but serialize it the way the parser reads it rather than panic
+            OP_ELSE(false_branch)                   =>  { v.extend(Vec::from(false_branch)); v },
            _ => v,
         }
     }
@@ -84,8 +126,11 @@ impl TgOpcode {
             OP_DUP              =>  0x52,
             OP_2DUP             =>  0x53,
             OP_EQUAL            =>  0xE1,
+            OP_EQUALVERIFY      =>  0xE2,
             OP_VERIFYSIG        =>  0xC1,
+            OP_CHECKMULTISIG    =>  0xC4,
             OP_SHA256           =>  0xC2,
+            OP_HASH160          =>  0xC3,
         }
     }
 }
@@ -115,3 +160,21 @@ impl From<TgScript> for Vec<u8> {
         v
     }
 }
+
+impl fmt::Display for TgScript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ops: Vec<String> = self.0.iter().map(|op| op.to_string()).collect();
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+impl FromStr for TgScript {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match tg_asm(s) {
+            Ok((_, script)) => Ok(script),
+            Err(_) => Err(Error::Adhoc("couldn't assemble script")),
+        }
+    }
+}
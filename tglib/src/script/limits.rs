@@ -0,0 +1,37 @@
+use crate::script::interpreter::EVAL_DEPTH_LIMIT;
+
+// bounds on what parsing and evaluating a script may cost
+// payout scripts arrive from untrusted players so these are enforced by the parser,
+// the interpreter and the script analysis
+// they aren't configurable: a contract the players' analysis accepts has to evaluate the
+// same way under the arbiter's interpreter, so everyone uses the defaults
+
+pub const MAX_SCRIPT_BYTES: usize = 10_000;
+pub const MAX_OPS: usize = 201;
+pub const MAX_ITEM_BYTES: usize = 520;
+pub const MAX_STACK_DEPTH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScriptLimits {
+// serialized size of the whole script
+    pub max_script_bytes: usize,
+// opcodes executed in one evaluation, including those inside OP_IF branches
+    pub max_ops: usize,
+// size of a single pushed stack item
+    pub max_item_bytes: usize,
+    pub max_stack_depth: usize,
+// nested evaluation of OP_IF branches. the parser also rejects deeper nesting
+    pub max_eval_depth: u8,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        ScriptLimits {
+            max_script_bytes: MAX_SCRIPT_BYTES,
+            max_ops: MAX_OPS,
+            max_item_bytes: MAX_ITEM_BYTES,
+            max_stack_depth: MAX_STACK_DEPTH,
+            max_eval_depth: EVAL_DEPTH_LIMIT,
+        }
+    }
+}
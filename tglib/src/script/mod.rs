@@ -1,7 +1,18 @@
+pub mod analysis;
+pub mod asm;
 pub mod parser;
 pub mod interpreter;
+pub mod limits;
+pub mod debugger;
 pub mod lib;
 
 pub use lib::TgOpcode;
 pub use lib::TgScript;
-pub use interpreter::TgScriptEnv;
+pub use interpreter::{
+    ScriptError,
+    TgScriptEnv,
+    TraceStep,
+};
+pub use debugger::TgScriptDebugger;
+pub use analysis::ScriptAnalysis;
+pub use limits::ScriptLimits;
@@ -11,9 +11,12 @@ use nom::{
     sequence::{tuple, preceded, terminated},
 };
 use crate::{
-    script::lib::{
-        TgOpcode,
-        TgScript,
+    script::{
+        lib::{
+            TgOpcode,
+            TgScript,
+        },
+        limits::ScriptLimits,
     },
 };
 
@@ -60,26 +63,52 @@ fn op_equal(input: &[u8]) -> IResult<&[u8], TgOpcode> {
     op_bytecode(TgOpcode::OP_EQUAL)(input)
 }
 
+#[allow(dead_code)]
+fn op_equalverify(input: &[u8]) -> IResult<&[u8], TgOpcode> {
+    op_bytecode(TgOpcode::OP_EQUALVERIFY)(input)
+}
+
 #[allow(dead_code)]
 fn op_verifysig(input: &[u8]) -> IResult<&[u8], TgOpcode> {
     op_bytecode(TgOpcode::OP_VERIFYSIG)(input)
 }
 
+#[allow(dead_code)]
+fn op_checkmultisig(input: &[u8]) -> IResult<&[u8], TgOpcode> {
+    op_bytecode(TgOpcode::OP_CHECKMULTISIG)(input)
+}
+
+#[allow(dead_code)]
+fn op_sha256(input: &[u8]) -> IResult<&[u8], TgOpcode> {
+    op_bytecode(TgOpcode::OP_SHA256)(input)
+}
+
+#[allow(dead_code)]
+fn op_hash160(input: &[u8]) -> IResult<&[u8], TgOpcode> {
+    op_bytecode(TgOpcode::OP_HASH160)(input)
+}
+
 #[allow(dead_code)]
 fn op_validate(input: &[u8]) -> IResult<&[u8], TgOpcode> {
     op_bytecode(TgOpcode::OP_VALIDATE)(input)
 }
 
-#[allow(dead_code)]
-fn op_if(input: &[u8]) -> IResult<&[u8], TgOpcode> {
-    let (input, (true_branch, else_block)) = tuple((
-        preceded(
+fn too_large(input: &[u8]) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::TooLarge))
+}
+
+// depth is the nesting of the script containing this op, 0 for the top level script
+fn op_if<'a>(input: &'a [u8], limits: ScriptLimits, depth: u8) -> IResult<&'a [u8], TgOpcode> {
 // TODO: this is a bit of a wart, creating a script to get the bytecode
-            op_bytecode(TgOpcode::OP_IF(TgScript::default(),None)),
-            tg_script,
-        ),
+    let (input, _) = op_bytecode(TgOpcode::OP_IF(TgScript::default(),None))(input)?;
+// the branches would be evaluated at depth + 2, see TgScriptEnv::eval
+    if depth + 2 > limits.max_eval_depth {
+        return Err(too_large(input))
+    }
+    let (input, (true_branch, else_block)) = tuple((
+        |i| script(i, limits, depth + 1),
         terminated(
-            opt(op_else),
+            opt(|i| op_else(i, limits, depth + 1)),
             op_bytecode(TgOpcode::OP_ENDIF),
         ),
     ))(input)?;
@@ -96,10 +125,10 @@ fn op_if(input: &[u8]) -> IResult<&[u8], TgOpcode> {
     Ok((input, TgOpcode::OP_IF(true_branch,false_branch)))
 }
 
-fn op_else(input: &[u8]) -> IResult<&[u8], TgOpcode> {
+fn op_else<'a>(input: &'a [u8], limits: ScriptLimits, depth: u8) -> IResult<&'a [u8], TgOpcode> {
     let (input, else_script) = preceded(
         op_bytecode(TgOpcode::OP_ELSE(TgScript::default())),
-        tg_script,
+        |i| script(i, limits, depth),
     )(input)?;
     Ok((input, TgOpcode::OP_ELSE(else_script)))
 }
@@ -113,27 +142,37 @@ fn op_pushtxid(input: &[u8]) -> IResult<&[u8], TgOpcode> {
     op_bytecode(TgOpcode::OP_PUSHTXID)(input)
 }
 
-fn op_pushdata1(input: &[u8]) -> IResult<&[u8], TgOpcode> {
+// length_data only borrows from the input, so oversized items are rejected before anything is copied
+fn op_pushdata1(input: &[u8], limits: ScriptLimits) -> IResult<&[u8], TgOpcode> {
     let (input, data) = preceded(
         op_bytecode(TgOpcode::OP_PUSHDATA1(0,Vec::new())),
         length_data(be_u8),
     )(input)?;
+    if data.len() > limits.max_item_bytes {
+        return Err(too_large(input))
+    }
     Ok((input, TgOpcode::OP_PUSHDATA1(data.len().try_into().unwrap(),Vec::from(data))))
 }
 
-fn op_pushdata2(input: &[u8]) -> IResult<&[u8], TgOpcode> {
+fn op_pushdata2(input: &[u8], limits: ScriptLimits) -> IResult<&[u8], TgOpcode> {
     let (input, data) = preceded(
         op_bytecode(TgOpcode::OP_PUSHDATA2(0,Vec::new())),
         length_data(be_u16),
     )(input)?;
+    if data.len() > limits.max_item_bytes {
+        return Err(too_large(input))
+    }
     Ok((input, TgOpcode::OP_PUSHDATA2(data.len().try_into().unwrap(),Vec::from(data))))
 }
 
-fn op_pushdata4(input: &[u8]) -> IResult<&[u8], TgOpcode> {
+fn op_pushdata4(input: &[u8], limits: ScriptLimits) -> IResult<&[u8], TgOpcode> {
     let (input, data) = preceded(
         op_bytecode(TgOpcode::OP_PUSHDATA4(0,Vec::new())),
         length_data(be_u32),
     )(input)?;
+    if data.len() > limits.max_item_bytes {
+        return Err(too_large(input))
+    }
     Ok((input, TgOpcode::OP_PUSHDATA4(data.len().try_into().unwrap(),Vec::from(data))))
 }
 
@@ -156,28 +195,50 @@ fn op_pushdata4(input: &[u8]) -> IResult<&[u8], TgOpcode> {
 //    }
 //}
 
-pub fn tg_script(input: &[u8]) -> IResult<&[u8], TgScript> {
+fn script<'a>(input: &'a [u8], limits: ScriptLimits, depth: u8) -> IResult<&'a [u8], TgScript> {
     let (input, ops) = many1(
         alt((
             op_0,
             op_1,
-            op_if,
+            |i| op_if(i, limits, depth),
             op_drop,
             op_dup,
             op_2dup,
             op_equal,
+            op_equalverify,
             op_verifysig,
+            op_checkmultisig,
+            op_sha256,
+            op_hash160,
             op_validate,
             op_pushtxid,
-            op_pushdata1,
-            op_pushdata2,
-            op_pushdata4,
+            |i| op_pushdata1(i, limits),
+            |i| op_pushdata2(i, limits),
+            |i| op_pushdata4(i, limits),
         ))
     )(input)?; 
 
     Ok((input, TgScript(ops)))
 }
 
+pub fn tg_script(input: &[u8]) -> IResult<&[u8], TgScript> {
+    tg_script_with_limits(ScriptLimits::default())(input)
+}
+
+pub fn tg_script_with_limits(limits: ScriptLimits) -> impl Fn(&[u8]) -> IResult<&[u8], TgScript> {
+    move |input: &[u8]| {
+// nothing past the limit is parsed, one byte over is enough to tell the script is too large.
+// an op cut off there ends the script like any other bytes that don't parse, callers check rest
+        let window = &input[..input.len().min(limits.max_script_bytes.saturating_add(1))];
+        let (rest, script) = script(window, limits, 0)?;
+        let parsed = window.len() - rest.len();
+        if parsed > limits.max_script_bytes {
+            return Err(too_large(input))
+        }
+        Ok((&input[parsed..], script))
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -187,13 +248,109 @@ mod tests {
     const CONDITIONAL_SCRIPT_TRUE: &'static[u8] = &[0x01,0xF1,0x01,0xF2,0x00,0xF3,0xF1,0x00,0xF3];
     const _ERROR_SCRIPT: &'static[u8] = &[0xA1];
 
+    #[test]
+    fn opcode_round_trip() {
+        use TgOpcode::*;
+        let ops = vec![
+            OP_0,
+            OP_1,
+            OP_PUSHDATA1(1, vec![0xff]),
+            OP_PUSHDATA2(2, vec![0xff, 0xfe]),
+            OP_PUSHDATA4(3, vec![0xff, 0xfe, 0xfd]),
+            OP_IF(TgScript(vec![OP_1]), Some(TgScript(vec![OP_0]))),
+            OP_DROP,
+            OP_DUP,
+            OP_2DUP,
+            OP_EQUAL,
+            OP_EQUALVERIFY,
+            OP_VERIFYSIG,
+            OP_CHECKMULTISIG,
+            OP_SHA256,
+            OP_HASH160,
+            OP_VALIDATE,
+            OP_PUSHTXID,
+        ];
+        for op in ops {
+            let script = TgScript(vec![op]);
+            let bytes = Vec::<u8>::from(script.clone());
+            let (rest, parsed) = tg_script(&bytes).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed, script);
+        }
+    }
+
     #[test]
     fn parser () {
-        let (_input, script) = op_pushdata1(&PUSHDATA_SCRIPT).unwrap(); 
+        let (_input, script) = op_pushdata1(&PUSHDATA_SCRIPT, ScriptLimits::default()).unwrap(); 
         println!("{:?}", script);
 
         let script = tg_script(&CONDITIONAL_SCRIPT_TRUE); 
         println!("{:?}", script);
         assert!(script.is_ok());
     }
+
+    #[test]
+    fn limits() {
+        let limits = ScriptLimits::default();
+// length prefix claims 4GB but the item limit is checked before any copying
+        assert!(tg_script(&[0xD4, 0xff, 0xff, 0xff, 0xff]).is_err());
+        let mut big_item = vec![0xD2, 0x02, 0x09];
+        big_item.extend(vec![0u8; 521]);
+        assert!(matches!(tg_script(&big_item), Err(nom::Err::Failure(_))));
+        let mut item = vec![0xD2, 0x02, 0x08];
+        item.extend(vec![0u8; 520]);
+        assert!(tg_script(&item).is_ok());
+
+        let many_ops = vec![0x01; limits.max_script_bytes + 1];
+        assert!(tg_script(&many_ops).is_err());
+        assert!(tg_script_with_limits(ScriptLimits { max_script_bytes: many_ops.len(), ..limits })(&many_ops).is_ok());
+        assert!(matches!(tg_script(&vec![0x01; 10_000_000]), Err(nom::Err::Failure(_))));
+// a push running past the limit isn't read, what comes before it is the script
+        let mut cut = vec![0x01; limits.max_script_bytes - 1];
+        cut.extend(vec![0xD2, 0x02, 0x08]);
+        cut.extend(vec![0u8; 520]);
+        let (rest, script) = tg_script(&cut).unwrap();
+        assert_eq!(script.0.len(), limits.max_script_bytes - 1);
+        assert_eq!(rest, &cut[limits.max_script_bytes - 1..]);
+
+// nesting is bounded before recursing so this can't overflow the stack
+        assert!(tg_script(&vec![0xF1; 100_000]).is_err());
+        let nested = [0x01,0xF1,0x01,0xF1,0x01,0xF3,0xF3];
+        assert!(tg_script(&nested).is_ok());
+        let too_nested = [0x01,0xF1,0x01,0xF1,0x01,0xF1,0x01,0xF3,0xF3,0xF3];
+        assert!(tg_script(&too_nested).is_err());
+        assert!(tg_script_with_limits(ScriptLimits { max_eval_depth: 4, ..limits })(&too_nested).is_ok());
+    }
+
+// random byte strings, weighted towards valid opcodes so that some of them parse
+    #[test]
+    fn fuzz() {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+        use crate::script::interpreter::{TgScriptEnv, TgScriptInterpreter};
+        const BYTES: &[u8] = &[0x00,0x01,0x50,0x52,0x53,0xC1,0xC2,0xC3,0xC4,0xD0,0xD1,0xD2,0xD4,0xE1,0xE2,0xF1,0xF2,0xF3,0xF4,0xFF];
+// shown when the test fails, TG_FUZZ_SEED=<seed> reruns the same cases
+        let seed = std::env::var("TG_FUZZ_SEED").ok().and_then(|seed| seed.parse().ok()).unwrap_or_else(rand::random::<u64>);
+        println!("fuzz seed {}", seed);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut parsed = 0;
+        for _ in 0..20_000 {
+            let len = rng.gen_range(1..64);
+            let bytes: Vec<u8> = (0..len).map(|_| if rng.gen_bool(0.8) {
+                BYTES[rng.gen_range(0..BYTES.len())]
+            } else {
+                rng.gen()
+            }).collect();
+            if let Ok((rest, script)) = tg_script(&bytes) {
+                parsed += 1;
+                assert_eq!(Vec::<u8>::from(script.clone()), bytes[..bytes.len() - rest.len()].to_vec());
+// creating the secp context dominates so only evaluate some of them
+                if parsed <= 200 {
+                    let mut env = TgScriptEnv::default();
+                    let _ = env.eval(script);
+                    assert!(env.stack().len() <= ScriptLimits::default().max_stack_depth);
+                }
+            }
+        }
+        assert!(parsed > 0);
+    }
 }
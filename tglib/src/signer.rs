@@ -0,0 +1,485 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::Command,
+    str::FromStr,
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use serde::Deserialize;
+use secrecy::{
+    ExposeSecret,
+    Secret,
+};
+use bdk::{
+    bitcoin::{
+        Address,
+        Network,
+        PublicKey,
+        secp256k1::{
+            Secp256k1,
+            Message,
+            Signature,
+        },
+        util::{
+            bip32::{
+                ChildNumber,
+                DerivationPath,
+                ExtendedPrivKey,
+                ExtendedPubKey,
+                Fingerprint,
+            },
+            psbt::PartiallySignedTransaction,
+        },
+    },
+    signer::TransactionSigner,
+};
+use crate::{
+    Error,
+    Result,
+    wallet::{
+        SavedSeed,
+        SigningWallet,
+        BITCOIN_ACCOUNT_PATH,
+    },
+};
+
+// how long to wait on a person with an offline signer
+pub const FILE_SIGNER_TIMEOUT: Duration = Duration::from_secs(600);
+const FILE_SIGNER_POLL: Duration = Duration::from_millis(500);
+pub const REQUEST_FILE_NAME: &'static str = "sign-request.txt";
+pub const RESPONSE_FILE_NAME: &'static str = "sign-response.txt";
+
+// something holding the seed that isn't us, e.g. a hardware wallet or an offline machine
+// keys are found by their full path from the seed's master key, the same paths that go in psbt bip32 derivations
+pub trait ExternalSigner {
+// adds partial sigs to the inputs with bip32 derivations under its fingerprint
+    fn sign_psbt(&self, psbt: PartiallySignedTransaction) -> Result<PartiallySignedTransaction>;
+// signs the 32 bytes directly, contract and auth sigs aren't signed bitcoin messages
+    fn sign_message(&self, msg: &Message, path: &DerivationPath) -> Result<Signature>;
+}
+
+impl<S: ExternalSigner + ?Sized> ExternalSigner for Box<S> {
+    fn sign_psbt(&self, psbt: PartiallySignedTransaction) -> Result<PartiallySignedTransaction> {
+        (**self).sign_psbt(psbt)
+    }
+
+    fn sign_message(&self, msg: &Message, path: &DerivationPath) -> Result<Signature> {
+        (**self).sign_message(msg, path)
+    }
+}
+
+// a SigningWallet whose seed is somewhere else. only the fingerprint and account xpubkey are here,
+// so passwords are ignored and everything the signer returns is checked against the xpubkey
+pub struct ExternalSigningWallet<S> {
+    signer: S,
+    fingerprint: Fingerprint,
+    xpubkey: ExtendedPubKey,
+}
+
+impl<S: ExternalSigner> ExternalSigningWallet<S> {
+    pub fn new(signer: S, saved_seed: &SavedSeed) -> Self {
+        ExternalSigningWallet {
+            signer,
+            fingerprint: saved_seed.fingerprint,
+            xpubkey: saved_seed.xpubkey,
+        }
+    }
+
+// paths given to SigningWallet are relative to the account, like the rest of the wallet
+    fn full_path(&self, path: &DerivationPath) -> DerivationPath {
+        let account = DerivationPath::from_str(&format!("m/{}", BITCOIN_ACCOUNT_PATH)).unwrap();
+        let children: Vec<ChildNumber> = account.as_ref().iter().chain(path.as_ref().iter()).cloned().collect();
+        DerivationPath::from(children)
+    }
+
+    fn pubkey(&self, path: &DerivationPath) -> Result<PublicKey> {
+        let xpubkey = self.xpubkey.derive_pub(&Secp256k1::new(), path).map_err(|_| Error::Adhoc("can't derive pubkey for path"))?;
+        Ok(xpubkey.public_key)
+    }
+}
+
+impl<S: ExternalSigner> SigningWallet for ExternalSigningWallet<S> {
+    fn sign_tx(&self, mut psbt: PartiallySignedTransaction, path: Option<DerivationPath>, _pw: Secret<String>) -> Result<PartiallySignedTransaction> {
+// wallet psbts from bdk already carry derivations. a path means a single key, e.g. the escrow key,
+// so tell the signer which inputs it's for
+        if let Some(path) = path {
+            let pubkey = self.pubkey(&path)?;
+            let key_source = (self.fingerprint, self.full_path(&path));
+            let mut found = false;
+            for input in psbt.inputs.iter_mut() {
+                if input_uses_pubkey(input, &pubkey) {
+                    input.bip32_derivation.insert(pubkey, key_source.clone());
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(Error::Adhoc("psbt doesn't spend that key"))
+            }
+        }
+        let signed = self.signer.sign_psbt(psbt.clone())?;
+// the signer only gets to add sigs
+        psbt.merge(signed).map_err(|_| Error::Signer("signer returned a different tx".to_string()))?;
+        Ok(psbt)
+    }
+
+    fn sign_message(&self, msg: Message, path: DerivationPath, _pw: Secret<String>) -> Result<Signature> {
+        let sig = self.signer.sign_message(&msg, &self.full_path(&path))?;
+        Secp256k1::new().verify(&msg, &sig, &self.pubkey(&path)?.key)
+            .map_err(|_| Error::Signer("signature doesn't match our pubkey".to_string()))?;
+        Ok(sig)
+    }
+}
+
+fn input_uses_pubkey(input: &bdk::bitcoin::util::psbt::Input, pubkey: &PublicKey) -> bool {
+    let key = pubkey.to_bytes();
+    let in_witness_script = input.witness_script.as_ref()
+        .map(|script| script.as_bytes().windows(key.len()).any(|w| w == &key[..]))
+        .unwrap_or(false);
+    let is_p2wpkh = match (&input.witness_utxo, Address::p2wpkh(pubkey, Network::Bitcoin)) {
+        (Some(txout), Ok(address)) => txout.script_pubkey == address.script_pubkey(),
+        _ => false,
+    };
+    in_witness_script || is_p2wpkh
+}
+
+// signer from the config's signer field:
+//  empty for the wallet's own encrypted seed
+//  file:<dir> to pass payloads through files in dir, see FileSigner
+//  hwi:<command> for an hwi-compatible command, see ProcessSigner
+pub fn external_signing_wallet(spec: &str, saved_seed: &SavedSeed) -> Result<Option<ExternalSigningWallet<Box<dyn ExternalSigner>>>> {
+    let signer: Box<dyn ExternalSigner> = if spec.is_empty() {
+        return Ok(None)
+    } else if let Some(dir) = spec.strip_prefix("file:") {
+        Box::new(FileSigner::new(PathBuf::from(dir)))
+    } else if let Some(command) = spec.strip_prefix("hwi:") {
+        Box::new(ProcessSigner::new(command.to_string(), saved_seed.fingerprint, saved_seed.network))
+    } else {
+        return Err(Error::Config(format!("unknown signer {}", spec)))
+    };
+    Ok(Some(ExternalSigningWallet::new(signer, saved_seed)))
+}
+
+// one line of text so it can go through a file, a usb stick or a qr code:
+//  psbt <base64 psbt>
+//  message <path> <hex message>
+// answered by the base64 signed psbt or the hex der signature
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignRequest {
+    Psbt(PartiallySignedTransaction),
+    Message(Message, DerivationPath),
+}
+
+impl SignRequest {
+    pub fn to_payload(&self) -> String {
+        match self {
+            SignRequest::Psbt(psbt) => format!("psbt {}", psbt),
+            SignRequest::Message(msg, path) => format!("message {} {}", path, hex::encode(msg.as_ref())),
+        }
+    }
+
+    pub fn from_payload(payload: &str) -> Result<Self> {
+        let parts: Vec<&str> = payload.split_whitespace().collect();
+        match parts.as_slice() {
+            ["psbt", psbt] => Ok(SignRequest::Psbt(PartiallySignedTransaction::from_str(psbt).map_err(|_| Error::Adhoc("invalid psbt"))?)),
+            ["message", path, msg] => {
+                let path = DerivationPath::from_str(path).map_err(|_| Error::Adhoc("invalid derivation path"))?;
+                let msg = hex::decode(msg).ok().and_then(|msg| Message::from_slice(&msg).ok()).ok_or(Error::Adhoc("invalid message"))?;
+                Ok(SignRequest::Message(msg, path))
+            }
+            _ => Err(Error::Adhoc("invalid sign request")),
+        }
+    }
+}
+
+// the offline side: answer a request payload with whatever signer holds the seed there
+pub fn respond<S: ExternalSigner>(signer: &S, payload: &str) -> Result<String> {
+    match SignRequest::from_payload(payload)? {
+        SignRequest::Psbt(psbt) => Ok(signer.sign_psbt(psbt)?.to_string()),
+        SignRequest::Message(msg, path) => Ok(hex::encode(signer.sign_message(&msg, &path)?.serialize_der())),
+    }
+}
+
+// writes the request payload to dir and waits for someone to put the response next to it
+pub struct FileSigner {
+    dir: PathBuf,
+    timeout: Duration,
+}
+
+impl FileSigner {
+    pub fn new(dir: PathBuf) -> Self {
+        FileSigner {
+            dir,
+            timeout: FILE_SIGNER_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn exchange(&self, request: SignRequest) -> Result<String> {
+        let request_path = self.dir.join(REQUEST_FILE_NAME);
+        let response_path = self.dir.join(RESPONSE_FILE_NAME);
+// a leftover response belongs to some earlier request
+        let _r = fs::remove_file(&response_path);
+        fs::write(&request_path, request.to_payload()).map_err(|e| Error::Signer(format!("couldn't write {}: {}", request_path.display(), e)))?;
+        let start = Instant::now();
+        while !response_path.exists() {
+            if start.elapsed() > self.timeout {
+                let _r = fs::remove_file(&request_path);
+                return Err(Error::Signer("timed out waiting for signer".to_string()))
+            }
+            thread::sleep(FILE_SIGNER_POLL);
+        }
+        let response = fs::read_to_string(&response_path).map_err(|e| Error::Signer(format!("couldn't read {}: {}", response_path.display(), e)))?;
+        let _r = fs::remove_file(&request_path);
+        let _r = fs::remove_file(&response_path);
+        Ok(response.trim().to_string())
+    }
+}
+
+impl ExternalSigner for FileSigner {
+    fn sign_psbt(&self, psbt: PartiallySignedTransaction) -> Result<PartiallySignedTransaction> {
+        let response = self.exchange(SignRequest::Psbt(psbt))?;
+        PartiallySignedTransaction::from_str(&response).map_err(|_| Error::Signer("invalid psbt from signer".to_string()))
+    }
+
+    fn sign_message(&self, msg: &Message, path: &DerivationPath) -> Result<Signature> {
+        let response = self.exchange(SignRequest::Message(*msg, path.clone()))?;
+        hex::decode(&response).ok()
+            .and_then(|sig| Signature::from_der(&sig).ok())
+            .ok_or(Error::Signer("invalid signature from signer".to_string()))
+    }
+}
+
+// runs an hwi-compatible command, e.g. hwi itself:
+//  <command> --fingerprint <fingerprint> --chain <chain> signtx <base64 psbt>
+// and reads the json it prints
+// hwi's signmessage signs the message as prefixed text, which never verifies as a contract or auth sig,
+// so it only signs psbts. sign contracts with a file: signer instead
+pub struct ProcessSigner {
+    command: String,
+    fingerprint: Fingerprint,
+    network: Network,
+}
+
+#[derive(Deserialize)]
+struct HwiResponse {
+    psbt: Option<String>,
+    error: Option<String>,
+}
+
+impl ProcessSigner {
+    pub fn new(command: String, fingerprint: Fingerprint, network: Network) -> Self {
+        ProcessSigner {
+            command,
+            fingerprint,
+            network,
+        }
+    }
+
+    fn chain(&self) -> &'static str {
+        match self.network {
+            Network::Bitcoin => "main",
+            Network::Testnet => "test",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    fn run(&self, args: &[&str]) -> Result<HwiResponse> {
+        let output = Command::new(&self.command)
+            .args(&["--fingerprint", &self.fingerprint.to_string(), "--chain", self.chain()])
+            .args(args)
+            .output()
+            .map_err(|e| Error::Signer(format!("couldn't run {}: {}", self.command, e)))?;
+        if !output.status.success() && output.stdout.is_empty() {
+            return Err(Error::Signer(String::from_utf8_lossy(&output.stderr).trim().to_string()))
+        }
+        parse_hwi_response(&output.stdout)
+    }
+}
+
+fn parse_hwi_response(stdout: &[u8]) -> Result<HwiResponse> {
+    let response: HwiResponse = serde_json::from_slice(stdout).map_err(|e| Error::Signer(format!("invalid signer output: {}", e)))?;
+    match response.error {
+        Some(error) => Err(Error::Signer(error)),
+        None => Ok(response),
+    }
+}
+
+impl ExternalSigner for ProcessSigner {
+    fn sign_psbt(&self, psbt: PartiallySignedTransaction) -> Result<PartiallySignedTransaction> {
+        let response = self.run(&["signtx", &psbt.to_string()])?;
+        let psbt = response.psbt.ok_or(Error::Signer("signer returned no psbt".to_string()))?;
+        PartiallySignedTransaction::from_str(&psbt).map_err(|_| Error::Signer("invalid psbt from signer".to_string()))
+    }
+
+    fn sign_message(&self, _msg: &Message, _path: &DerivationPath) -> Result<Signature> {
+        Err(Error::Signer("hwi signers can't sign contracts or auth tokens, use a file: signer for those".to_string()))
+    }
+}
+
+// keys in memory, for tests and for answering FileSigner requests on an offline machine
+pub struct SoftwareSigner {
+    root_key: ExtendedPrivKey,
+}
+
+impl SoftwareSigner {
+    pub fn new(seed: Secret<Vec<u8>>, network: Network) -> Result<Self> {
+        let root_key = ExtendedPrivKey::new_master(network, seed.expose_secret()).map_err(|_| Error::Adhoc("invalid seed"))?;
+        Ok(SoftwareSigner { root_key })
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.root_key.fingerprint(&Secp256k1::new())
+    }
+
+    fn derive(&self, path: &DerivationPath) -> Result<ExtendedPrivKey> {
+        self.root_key.derive_priv(&Secp256k1::new(), path).map_err(|_| Error::Adhoc("can't derive key for path"))
+    }
+}
+
+impl ExternalSigner for SoftwareSigner {
+    fn sign_psbt(&self, mut psbt: PartiallySignedTransaction) -> Result<PartiallySignedTransaction> {
+        let secp = Secp256k1::new();
+        let fingerprint = self.fingerprint();
+        let mut paths: Vec<DerivationPath> = Vec::new();
+        for input in &psbt.inputs {
+            for (_pubkey, (key_fingerprint, path)) in &input.bip32_derivation {
+                if *key_fingerprint == fingerprint && !paths.contains(path) {
+                    paths.push(path.clone());
+                }
+            }
+        }
+        for path in paths {
+            self.derive(&path)?.private_key.sign_tx(&mut psbt, &secp)?;
+        }
+        Ok(psbt)
+    }
+
+    fn sign_message(&self, msg: &Message, path: &DerivationPath) -> Result<Signature> {
+        Ok(Secp256k1::new().sign(msg, &self.derive(path)?.private_key.key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use bip39::Mnemonic;
+    use bdk::bitcoin::{
+        OutPoint,
+        Script,
+        Transaction,
+        TxIn,
+        TxOut,
+    };
+    use crate::{
+        mock::{
+            NETWORK,
+            PLAYER_1_MNEMONIC,
+            PLAYER_2_MNEMONIC,
+        },
+        wallet::{
+            ESCROW_KIX,
+            ESCROW_SUBACCOUNT,
+        },
+    };
+
+    fn seed(mnemonic: &str) -> Secret<Vec<u8>> {
+        Secret::new(Mnemonic::from_str(mnemonic).unwrap().to_seed("").to_vec())
+    }
+
+    fn saved_seed(mnemonic: &str) -> SavedSeed {
+        SavedSeed::new(Secret::new("pw".to_string()), Some(Secret::new(mnemonic.to_string())), NETWORK).unwrap()
+    }
+
+    fn pw() -> Secret<String> {
+        Secret::new(String::new())
+    }
+
+    fn escrow_path() -> DerivationPath {
+        DerivationPath::from_str(&format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX)).unwrap()
+    }
+
+    #[test]
+    fn software_signer() {
+        let wallet = ExternalSigningWallet::new(SoftwareSigner::new(seed(PLAYER_1_MNEMONIC), NETWORK).unwrap(), &saved_seed(PLAYER_1_MNEMONIC));
+        let msg = Message::from_slice(&[1; 32]).unwrap();
+        let sig = wallet.sign_message(msg, escrow_path(), pw()).unwrap();
+        assert!(Secp256k1::new().verify(&msg, &sig, &wallet.pubkey(&escrow_path()).unwrap().key).is_ok());
+// a device with some other seed plugged in
+        let wallet = ExternalSigningWallet::new(SoftwareSigner::new(seed(PLAYER_2_MNEMONIC), NETWORK).unwrap(), &saved_seed(PLAYER_1_MNEMONIC));
+        assert!(matches!(wallet.sign_message(msg, escrow_path(), pw()), Err(Error::Signer(_))));
+    }
+
+    #[test]
+    fn file_signer() {
+        let dir = std::env::temp_dir().join(format!("tg-file-signer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wallet = ExternalSigningWallet::new(FileSigner::new(dir.clone()).with_timeout(Duration::from_secs(30)), &saved_seed(PLAYER_1_MNEMONIC));
+        let pubkey = wallet.pubkey(&escrow_path()).unwrap();
+// the offline machine
+        let device_dir = dir.clone();
+        let device = thread::spawn(move || {
+            let signer = SoftwareSigner::new(seed(PLAYER_1_MNEMONIC), NETWORK).unwrap();
+            for _ in 0..2 {
+                let request_path = device_dir.join(REQUEST_FILE_NAME);
+                while !request_path.exists() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                let request = fs::read_to_string(&request_path).unwrap();
+                fs::write(device_dir.join(RESPONSE_FILE_NAME), respond(&signer, &request).unwrap()).unwrap();
+                while request_path.exists() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        });
+
+        let msg = Message::from_slice(&[2; 32]).unwrap();
+        let sig = wallet.sign_message(msg, escrow_path(), pw()).unwrap();
+        assert!(Secp256k1::new().verify(&msg, &sig, &pubkey.key).is_ok());
+
+        let address = Address::p2wpkh(&pubkey, NETWORK).unwrap();
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xFFFFFFFF, witness: Vec::new() }],
+            output: vec![TxOut { value: 9000, script_pubkey: address.script_pubkey() }],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: 10000, script_pubkey: address.script_pubkey() });
+        let signed = wallet.sign_tx(psbt.clone(), Some(escrow_path()), pw()).unwrap();
+        assert_eq!(signed.global.unsigned_tx, psbt.global.unsigned_tx);
+        assert!(signed.inputs[0].partial_sigs.contains_key(&pubkey));
+
+        device.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sign_request_payload() {
+        let request = SignRequest::Message(Message::from_slice(&[3; 32]).unwrap(), escrow_path());
+        assert_eq!(SignRequest::from_payload(&request.to_payload()).unwrap(), request);
+        assert!(SignRequest::from_payload("message m/7/0").is_err());
+        assert!(SignRequest::from_payload("sign everything").is_err());
+    }
+
+    #[test]
+    fn hwi_response() {
+        assert!(matches!(parse_hwi_response(br#"{"error": "Could not find device with specified fingerprint", "code": -3}"#), Err(Error::Signer(_))));
+        assert!(matches!(parse_hwi_response(b"not json"), Err(Error::Signer(_))));
+        let response = parse_hwi_response(br#"{"psbt": "cHNidP8BAAoCAAAAAAAAAAAAAA==", "signed": true}"#).unwrap();
+        assert!(response.psbt.is_some());
+// refused before running anything, the command doesn't exist
+        let signer = ProcessSigner::new("no-such-hwi".to_string(), Fingerprint::default(), NETWORK);
+        let msg = Message::from_slice(&[4; 32]).unwrap();
+        assert!(matches!(signer.sign_message(&msg, &escrow_path()), Err(Error::Signer(_))));
+    }
+}
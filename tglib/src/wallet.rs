@@ -24,13 +24,14 @@ use bdk::bitcoin::{
     Network,
     PublicKey,
     Script,
+    SigHashType,
     Transaction,
     Txid,
     TxIn,
     TxOut,
     blockdata::{
+        opcodes,
         script::Builder,
-        opcodes::all as Opcodes,
         transaction::OutPoint,
     },
     hashes::{
@@ -52,32 +53,67 @@ use bdk::bitcoin::{
             DerivationPath,
             Fingerprint,
         },
+        key::PrivateKey,
         psbt::PartiallySignedTransaction,
     },
 };
+use bdk::{
+    blockchain::Blockchain,
+    database::MemoryDatabase,
+    miniscript::{
+        Descriptor,
+        DescriptorTrait,
+    },
+    Wallet,
+};
 use crate::{
     Error,
-    contract::Contract,
+    config::{
+        deserialize_network,
+        serialize_network,
+    },
+    contract::{
+        Contract,
+        ContractState,
+    },
     payout::Payout,
     script::{
         TgOpcode,
         TgScript,
         TgScriptEnv,
+        ScriptAnalysis,
     },
     Result,
-    mock::{
-        referee_pubkey,
-        ESCROW_KIX,
-        NETWORK,
-    }
 };
 
 pub const BITCOIN_ACCOUNT_PATH: &'static str = "44'/0'/0'";
 pub const NAMECOIN_ACCOUNT_PATH: &'static str = "44'/7'/0'";
 pub const ESCROW_SUBACCOUNT: &'static str = "7";
+// players always use this one, the arbiter rotates its escrow key, see escrow_kix
+pub const ESCROW_KIX: &'static str = "0";
+// far more rotations than the arbiter will ever do, and few enough to derive every key up to it.
+// also keeps the index below 2^31, where the path would turn hardened
+pub const MAX_ESCROW_KIX: u32 = 1000;
 pub const NAME_SUBACCOUNT: &'static str = "17";
 pub const NAME_KIX: &'static str = "0";
-pub const TX_FEE: u64 = 20000;
+// sat/vbyte. escrow txs are pre-signed, so contracts can't agree to less than the relay minimum
+// and the ceiling keeps a bad estimate from burning the escrow
+pub const MIN_FEE_RATE: u64 = 1;
+pub const MAX_FEE_RATE: u64 = 1000;
+// what version 1 and 2 contracts paid for every escrow tx, before contracts carried a fee rate
+pub const LEGACY_TX_FEE: u64 = 20000;
+// witness item count, signature with sighash flag and compressed pubkey, each length-prefixed
+pub const P2WPKH_SATISFACTION_WEIGHT: usize = 1 + 1 + 73 + 1 + 33;
+// version 3 and 4 payout txs carry an output anyone can spend for bumping them with a child tx
+// anyone can also pin them with it, so from version 5 players bump a payout by spending their own output
+pub const ANCHOR_VALUE: u64 = 330;
+// blocks after contract creation before the refund tx can be broadcast, about a week
+pub const REFUND_DELAY: u32 = 1008;
+// the least a refund can still be locked for when the other player or the arbiter signs it
+// leaves about a day between creating the contract and the last signature
+pub const MIN_REFUND_DELAY: u32 = REFUND_DELAY - 144;
+// contracts and seeds saved before they recorded their network were all made on regtest
+pub const LEGACY_NETWORK: Network = Network::Regtest;
 
 // mainnet
 //const NAMECOIN_VERSION_BYTE: u8 = 0x34;//52
@@ -92,10 +128,17 @@ pub struct SavedSeed {
     pub xpubkey: ExtendedPubKey,
     pub encrypted_seed: Vec<u8>,
     pub pw_hash: String,
+// xpubs serialize the same on every test network and come back as testnet, see SavedSeed::for_network
+    #[serde(default = "legacy_network", serialize_with = "serialize_network", deserialize_with = "deserialize_network")]
+    pub network: Network,
+}
+
+fn legacy_network() -> Network {
+    LEGACY_NETWORK
 }
 
 impl SavedSeed {
-    pub fn new(pw: Secret<String>, mnemonic: Option<Secret<String>>) -> Result<Self> {
+    pub fn new(pw: Secret<String>, mnemonic: Option<Secret<String>>, network: Network) -> Result<Self> {
 //  generate salt
         let pw_salt = rand::thread_rng().gen::<[u8; 32]>().to_vec();
         let config = Config::default();
@@ -122,20 +165,48 @@ impl SavedSeed {
         };
 
 //  compute root key fingerprint and bitcoin account xpubkey
-        let root_key = ExtendedPrivKey::new_master(NETWORK, seed.expose_secret()).unwrap();
+        let root_key = ExtendedPrivKey::new_master(network, seed.expose_secret()).unwrap();
         let secp = Secp256k1::new();
         let fingerprint = root_key.fingerprint(&secp);
-        let xpubkey = derive_account_xpubkey(seed, NETWORK);
+        let xpubkey = derive_account_xpubkey(seed, network);
 
         Ok(SavedSeed { 
             fingerprint,
             xpubkey,
             pw_hash,
             encrypted_seed,
+            network,
         })
     }
 
+// just the public parts, for wallets signing with an external signer. there's nothing to decrypt
+    pub fn watch_only(fingerprint: Fingerprint, xpubkey: ExtendedPubKey, network: Network) -> Self {
+        SavedSeed {
+            fingerprint,
+            xpubkey,
+            pw_hash: String::new(),
+            encrypted_seed: Vec::new(),
+            network,
+        }
+    }
+
+    pub fn is_watch_only(&self) -> bool {
+        self.encrypted_seed.is_empty()
+    }
+
+// call on every deserialized seed before using its xpubkey
+    pub fn for_network(mut self, network: Network) -> Result<Self> {
+        if self.network != network {
+            return Err(Error::Adhoc("seed is for a different network"))
+        }
+        self.xpubkey.network = network;
+        Ok(self)
+    }
+
     pub fn get_seed(&self, pw: Secret<String>) -> Result<Secret<Vec<u8>>> {
+        if self.is_watch_only() {
+            return Err(Error::Adhoc("watch-only seed, sign with an external signer"))
+        }
 // TODO: fix unwrap on wrong password
         if argon2::verify_encoded(&self.pw_hash, pw.expose_secret().as_bytes()).unwrap() {
             {
@@ -160,7 +231,7 @@ pub trait NameWallet {
     fn name_pubkey(&self) -> PublicKey;
 }
 
-// TODO: enable external signers e.g. hardware wallets
+// see signer::ExternalSigningWallet for keys kept on another device
 pub trait SigningWallet {
     fn sign_tx(&self, psbt: PartiallySignedTransaction, path: Option<DerivationPath>, pw: Secret<String>) -> Result<PartiallySignedTransaction>;
     fn sign_message(&self, msg: Message, path: DerivationPath, pw: Secret<String>) -> Result<Signature>;
@@ -170,19 +241,25 @@ pub trait EscrowWallet {
     fn get_escrow_pubkey(&self) -> PublicKey;
     fn validate_contract(&self, contract: &Contract) -> Result<()>;
     fn validate_payout(&self, payout: &Payout) -> Result<()> {
+        self.validate_payout_tx(payout)?;
+        validate_payout_script(payout)
+    }
+// everything but the payout script. a payout failing only that is a dispute, e.g. no token or the wrong one
+    fn validate_payout_tx(&self, payout: &Payout) -> Result<()> {
         self.validate_contract(&payout.contract)?;
 // payouts require fully signed contracts
-        let fully_signed = payout.contract.sigs.len() == 3 as usize;
-        if !fully_signed {
+        if payout.contract.state() != ContractState::ArbiterSigned {
             return Err(Error::InvalidPayout("contract is not fully signed"))
         }
-        let payout_address = &payout.address()?;
-// the payout tx must be an expected one
+// the payout tx must be one of the outcome txs committed to by the payout script
         let payout_tx = payout.psbt.clone().extract_tx();
-        let matching_tx = payout_tx.txid() == create_payout(&payout.contract, &payout_address).psbt.clone().extract_tx().txid();
-        if !matching_tx {
+        if ScriptAnalysis::new(&payout.contract.payout_script)?.branch_for(payout_tx.txid().as_ref()).is_none() {
             return Err(Error::InvalidPayout("invalid payout tx"))
         }
+// and has to pay at least the fee rate the players agreed to in the contract
+        if escrow_tx_fee_rate(&payout.contract, &payout_tx)? < payout.contract.fee_rate {
+            return Err(Error::InvalidPayout("payout tx fee rate is below the contract's"))
+        }
 //        let payout_address = payout.address()?;
 //        if payout_address != payout.contract.p1_payout_address &&
 //           payout_address != payout.contract.p2_payout_address {
@@ -199,18 +276,22 @@ pub trait EscrowWallet {
 //
             return Err(Error::InvalidPayout("payout tx signed incorrectly"))
         };
-        if payout.script_sig.is_none() {
-            return Err(Error::InvalidPayout("no script sig"))
-        }
-        let mut env = TgScriptEnv::new(payout.clone());
-        env.validate_payout()
+        Ok(())
     }
 }
 
+pub fn validate_payout_script(payout: &Payout) -> Result<()> {
+    if payout.script_sigs.is_empty() {
+        return Err(Error::InvalidPayout("no script sig"))
+    }
+    let mut env = TgScriptEnv::new(payout.clone());
+    env.validate_payout()
+}
+
 pub fn sign_contract<T>(wallet: &T, contract: &Contract, pw: Secret<String>) -> Result<Signature> 
 where T: EscrowWallet + SigningWallet {
-    Ok(wallet.sign_message(Message::from_slice(&contract.cxid()).unwrap(), 
-                DerivationPath::from_str(&format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX)).unwrap(), pw).unwrap())
+    wallet.sign_message(Message::from_slice(&contract.cxid()).unwrap(), 
+                DerivationPath::from_str(&format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX)).unwrap(), pw)
 }
 
 //pub fn sign_payout_psbt<T>(wallet: &T, psbt: PartiallySignedTransaction, pw: Secret<String>) -> Result<PartiallySignedTransaction> 
@@ -218,85 +299,142 @@ where T: EscrowWallet + SigningWallet {
 //    wallet.sign_tx(psbt, Some(DerivationPath::from_str(&format!("m/{}/{}", ESCROW_SUBACCOUNT, ESCROW_KIX)).unwrap()), pw)
 //}
 
+// the escrow is a 2 of 3 multisig in contract key order: p1, p2, arbiter
+// multi rather than sortedmulti keeps the same script, so existing escrow addresses and cxids don't change
+// TODO: taproot escrow with a musig2 key path for cooperative payouts and player+arbiter script leaves.
+// blocked on dependencies: bdk 0.9 pins bitcoin 0.26 and secp256k1 0.20, which have no taproot or schnorr
+// support, and there's no musig2 implementation for them. needs a bdk upgrade first, then a new contract
+// version carrying the escrow type and nonce exchange messages over the exchange service
+fn escrow_policy(keys: &[String]) -> String {
+    format!("wsh(multi(2,{}))", keys.join(","))
+}
+
+// addresses, psbt inputs, satisfaction weight and finalization of escrow txs all come from this
+pub fn escrow_descriptor(p1_pubkey: &PublicKey, p2_pubkey: &PublicKey, arbiter_pubkey: &PublicKey) -> Result<Descriptor<PublicKey>> {
+    let keys = [p1_pubkey, p2_pubkey, arbiter_pubkey].iter().map(|pubkey| pubkey.to_string()).collect::<Vec<String>>();
+    Ok(Descriptor::<PublicKey>::from_str(&escrow_policy(&keys)).map_err(bdk::Error::from)?)
+}
+
+// the escrow descriptor with one of the keys swapped for its private key, for a one-off wallet to sign escrow txs
+pub fn escrow_signing_descriptor(contract: &Contract, escrow_privkey: &PrivateKey) -> Result<String> {
+    let escrow_pubkey = PublicKey::from_private_key(&Secp256k1::new(), escrow_privkey);
+    let pubkeys = [contract.p1_pubkey, contract.p2_pubkey, contract.arbiter_pubkey];
+    if !pubkeys.contains(&escrow_pubkey) {
+        return Err(Error::Adhoc("can't sign - not party to this contract"))
+    }
+    let keys = pubkeys.iter().map(|pubkey| if *pubkey == escrow_pubkey { escrow_privkey.to_wif() } else { pubkey.to_string() }).collect::<Vec<String>>();
+    Ok(escrow_policy(&keys))
+}
+
+// psbts to sign come from players, and a sig that doesn't commit to every output would let them change it afterwards
+pub fn validate_sighash_all(psbt: &PartiallySignedTransaction) -> Result<()> {
+    if psbt.inputs.iter().any(|input| input.sighash_type.map_or(false, |sighash_type| sighash_type != SigHashType::All)) {
+        return Err(Error::Adhoc("escrow tx asks for a sighash other than SIGHASH_ALL"))
+    }
+    Ok(())
+}
+
+// watch-only wallet for the escrow, e.g. to see whether the escrow utxo is confirmed or spent
+pub fn escrow_wallet<B: Blockchain>(contract: &Contract, network: Network, blockchain: B) -> Result<Wallet<B, MemoryDatabase>> {
+    let descriptor = escrow_descriptor(&contract.p1_pubkey, &contract.p2_pubkey, &contract.arbiter_pubkey)?.to_string();
+    Ok(Wallet::new(descriptor.as_str(), None, network, MemoryDatabase::default(), blockchain)?)
+}
+
+// worst case witness weight for spending the escrow, for sizing escrow txs
+pub fn escrow_satisfaction_weight(contract: &Contract) -> Result<usize> {
+    Ok(escrow_descriptor(&contract.p1_pubkey, &contract.p2_pubkey, &contract.arbiter_pubkey)?.max_satisfaction_weight().map_err(bdk::Error::from)?)
+}
+
 pub fn create_escrow_address(p1_pubkey: &PublicKey, p2_pubkey: &PublicKey, arbiter_pubkey: &PublicKey, network: Network) -> Result<Address> {
-// TODO: could use descriptor here
-    let escrow_address = Address::p2wsh(
-        &create_escrow_script(p1_pubkey, p2_pubkey, arbiter_pubkey),
-        network,
-    );
-    Ok(escrow_address)
-}
-
-pub fn create_escrow_script(p1_pubkey: &PublicKey, p2_pubkey: &PublicKey, arbiter_pubkey: &PublicKey) -> Script {
-// standard multisig transaction script
-// https://en.bitcoin.it/wiki/BIP_0011
-    let b = Builder::new()
-        .push_opcode(Opcodes::OP_PUSHNUM_2)
-        .push_slice(&p1_pubkey.to_bytes())
-        .push_slice(&p2_pubkey.to_bytes())
-        .push_slice(&arbiter_pubkey.to_bytes())
-        .push_opcode(Opcodes::OP_PUSHNUM_3)
-        .push_opcode(Opcodes::OP_CHECKMULTISIG);
-    b.into_script()
+    Ok(escrow_descriptor(p1_pubkey, p2_pubkey, arbiter_pubkey)?.address(network).map_err(bdk::Error::from)?)
+}
+
+pub fn create_escrow_script(p1_pubkey: &PublicKey, p2_pubkey: &PublicKey, arbiter_pubkey: &PublicKey) -> Result<Script> {
+    Ok(escrow_descriptor(p1_pubkey, p2_pubkey, arbiter_pubkey)?.explicit_script())
 }
 
 pub fn create_payout(contract: &Contract, payout_address: &Address) -> Payout {
-    let escrow_address = create_escrow_address(
-        &contract.p1_pubkey,
-        &contract.p2_pubkey,
-        &contract.arbiter_pubkey,
-        payout_address.network,
-        ).unwrap();
+    let amount = contract.amount().unwrap().as_sat();
+    let payout_tx = contract_payout_tx(contract, payout_address, payout_address, amount, 0).unwrap();
+    Payout::new(contract.clone(), escrow_psbt(contract, payout_tx).unwrap())
+}
+
+// like create_payout, but the escrow is split between the players
+pub fn create_split_payout(contract: &Contract, p1_address: &Address, p2_address: &Address, p1_amount: u64, p2_amount: u64) -> Result<Payout> {
+    let payout_tx = contract_payout_tx(contract, p1_address, p2_address, p1_amount, p2_amount)?;
+    Ok(Payout::new(contract.clone(), escrow_psbt(contract, payout_tx)?))
+}
+
+// the payout tx the way the contract's version builds them, so older contracts still pay out
+// to the txids their payout scripts commit to
+pub fn contract_payout_tx(contract: &Contract, p1_address: &Address, p2_address: &Address, p1_amount: u64, p2_amount: u64) -> Result<Transaction> {
+    let escrow = escrow_descriptor(&contract.p1_pubkey, &contract.p2_pubkey, &contract.arbiter_pubkey)?;
     let funding_tx = contract.clone().funding_tx.extract_tx();
-    let mut escrow_txout = funding_tx.output.iter().filter(|txout| txout.script_pubkey == escrow_address.script_pubkey());
-    let mut psbt: PartiallySignedTransaction = PartiallySignedTransaction::from_unsigned_tx(create_payout_tx(&funding_tx, &escrow_address, &payout_address).unwrap()).unwrap();
-    if let Some(txout) = escrow_txout.next() {
-        psbt.inputs[0].witness_utxo = Some(txout.clone());
-        psbt.inputs[0].witness_script = Some(create_escrow_script(&contract.p1_pubkey, &contract.p2_pubkey, &contract.arbiter_pubkey));
+    if contract.version < 3 {
+        create_legacy_split_payout_tx(&funding_tx, &escrow, p1_address, p2_address, p1_amount, p2_amount)
+    } else {
+        split_payout_tx(&funding_tx, &escrow, p1_address, p2_address, p1_amount, p2_amount, contract.fee_rate, contract.version < 5)
     }
-    Payout::new(contract.clone(), psbt)
 }
 
-pub fn create_payout_script(escrow_address: &Address, p1_payout_address: &Address, p2_payout_address: &Address, funding_tx: &Transaction) -> TgScript {
+// what a child of the payout tx has to pay on top of its own fee for the two to pay fee_rate
+pub fn payout_fee_shortfall(payout: &Payout, fee_rate: u64) -> Result<u64> {
+    let payout_tx = &payout.psbt.global.unsigned_tx;
+    let payout_fee = payout.contract.amount()?.as_sat().checked_sub(payout_tx.output.iter().map(|txout| txout.value).sum()).ok_or(Error::Adhoc("payout tx pays out more than the escrow"))?;
+    let payout_vsize = estimated_vsize(payout_tx, &[escrow_satisfaction_weight(&payout.contract)?]);
+    Ok((fee_rate * payout_vsize).saturating_sub(payout_fee))
+}
+
+// vsize once the inputs are signed, given the witness weight of each input
+pub fn estimated_vsize(tx: &Transaction, satisfaction_weights: &[usize]) -> u64 {
+// 2 for the segwit marker and flag
+    let weight = tx.get_weight() + 2 + satisfaction_weights.iter().sum::<usize>();
+    ((weight + 3) / 4) as u64
+}
 
-    let p1_payout_tx = create_payout_tx(&funding_tx, &escrow_address, &p1_payout_address).unwrap();
-    let p2_payout_tx = create_payout_tx(&funding_tx, &escrow_address, &p2_payout_address).unwrap();
+// fee for a tx spending the escrow at fee_rate. output values don't change the size, so this works on unfinished txs
+fn escrow_tx_fee(tx: &Transaction, escrow: &Descriptor<PublicKey>, fee_rate: u64) -> Result<u64> {
+    let satisfaction_weight = escrow.max_satisfaction_weight().map_err(bdk::Error::from)?;
+    Ok(fee_rate * estimated_vsize(tx, &[satisfaction_weight]))
+}
 
-    let txid1: &[u8] = &p1_payout_tx.txid();
-    let txid2: &[u8] = &p2_payout_tx.txid();
+// the fee rate a payout or refund tx actually pays, rounded down
+pub fn escrow_tx_fee_rate(contract: &Contract, tx: &Transaction) -> Result<u64> {
+    let amount = contract.amount()?.as_sat();
+    let fee = amount.checked_sub(tx.output.iter().map(|txout| txout.value).sum()).ok_or(Error::Adhoc("tx pays out more than the escrow"))?;
+    Ok(fee / estimated_vsize(tx, &[escrow_satisfaction_weight(contract)?]))
+}
+
+// psbt for a tx spending the escrow output of the contract's funding tx
+pub fn escrow_psbt(contract: &Contract, tx: Transaction) -> Result<PartiallySignedTransaction> {
+    let funding_tx = contract.clone().funding_tx.extract_tx();
+    let (i, vout) = tx.input.iter()
+        .enumerate()
+        .find(|(_i, txin)| txin.previous_output.txid == funding_tx.txid())
+        .map(|(i, txin)| (i, txin.previous_output.vout as usize))
+        .ok_or(Error::Adhoc("tx doesn't spend the funding tx"))?;
+    let escrow_txout = funding_tx.output.get(vout).cloned().ok_or(Error::Adhoc("couldn't find escrow output in funding tx"))?;
+    let escrow_script = create_escrow_script(&contract.p1_pubkey, &contract.p2_pubkey, &contract.arbiter_pubkey)?;
+    if escrow_txout.script_pubkey != escrow_script.to_v0_p2wsh() {
+        return Err(Error::Adhoc("tx doesn't spend the escrow output"))
+    }
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).map_err(|_| Error::Adhoc("tx is already signed"))?;
+    psbt.inputs[i].witness_utxo = Some(escrow_txout);
+    psbt.inputs[i].witness_script = Some(escrow_script);
+    Ok(psbt)
+}
+
+pub fn create_payout_script(oracle_pubkey: &PublicKey, escrow: &Descriptor<PublicKey>, p1_payout_address: &Address, p2_payout_address: &Address, funding_tx: &Transaction, fee_rate: u64) -> TgScript {
+
+    let p1_payout_tx = create_payout_tx(&funding_tx, &escrow, &p1_payout_address, fee_rate).unwrap();
+    let p2_payout_tx = create_payout_tx(&funding_tx, &escrow, &p2_payout_address, fee_rate).unwrap();
 
 // TODO: should be a pubkeyhash instead of full pubkey, same reasons as bitcoin addresses
 // that requires the pubkey to also be given as input as in standard pay to pubkey hash
-    let pubkey_bytes = referee_pubkey().to_bytes();
-
-    use crate::script::TgOpcode::*;
-    TgScript(vec![         
-        OP_PUSHDATA1(pubkey_bytes.len().try_into().unwrap(), pubkey_bytes.clone()),
-// p1 payout
-        OP_PUSHDATA1(txid1.len().try_into().unwrap(), Vec::from(txid1)),
-        OP_PUSHTXID,
-        OP_EQUAL,
-        OP_IF(
-            TgScript(vec![
-// wasteful in this case (could use OP_DUP above) but this handles the case of the generic token in place of the payout txid
-                OP_PUSHDATA1(txid1.len().try_into().unwrap(), Vec::from(txid1)),
-            ]),
-            Some(TgScript(vec![
-// p2 payout
-                OP_PUSHDATA1(txid2.len().try_into().unwrap(), Vec::from(txid2)),
-                OP_PUSHTXID,
-                OP_EQUAL,
-                OP_IF(
-                    TgScript(vec![
-                        OP_PUSHDATA1(txid2.len().try_into().unwrap(), Vec::from(txid2)),
-                    ]),
-// unknown payout tx
-                    Some(TgScript(vec![OP_0])),
-                ),
-            ]))
-        ),
-        OP_VERIFYSIG,
-        OP_VALIDATE,
+// the payout txid stands in for the oracle token
+    create_token_pair_script(oracle_pubkey, vec![
+        (p1_payout_tx.txid(), p1_payout_tx.txid().to_vec()),
+        (p2_payout_tx.txid(), p2_payout_tx.txid().to_vec()),
     ])
 }
 
@@ -305,86 +443,286 @@ pub fn create_token_pair_script(oracle_pubkey: &PublicKey, pairs: Vec<(Txid, Vec
     let oracle_pubkey_bytes = oracle_pubkey.to_bytes();
     use crate::script::TgOpcode::*;
     script.0.push(OP_PUSHDATA1(oracle_pubkey_bytes.len().try_into().unwrap(), oracle_pubkey_bytes));
-    let fragments = pairs.iter().enumerate().map(|(i, (txid, token))| { 
-        let last = i == pairs.len() - 1;
-        token_branch_fragment(txid, token, last)
-    }).collect();
-    script.0.extend(nest_fragments(fragments));
+    script.0.extend(token_dispatch(&pairs));
     script.0.push(OP_VERIFYSIG);
     script.0.push(OP_VALIDATE);
     script
 }
 
-fn token_branch_fragment(txid: &Txid, token: &[u8], last: bool) -> Vec<crate::script::TgOpcode> {
+// like create_token_pair_script, but the oracle resolves the contract by publishing a secret
+// instead of a signature. each token is the sha256 hash of the secret for that outcome
+pub fn create_token_preimage_script(pairs: Vec<(Txid, Vec<u8>)>) -> TgScript {
+    let mut script = TgScript(vec![]);
+    use crate::script::TgOpcode::*;
+    script.0.push(OP_SHA256);
+    script.0.extend(token_dispatch(&pairs));
+    script.0.push(OP_EQUAL);
+    script.0.push(OP_VALIDATE);
+    script
+}
+
+// like create_token_pair_script, but threshold of the oracles have to sign the token
+// the payout carries one script sig per signing oracle
+pub fn create_threshold_token_script(oracle_pubkeys: &[PublicKey], threshold: u8, pairs: Vec<(Txid, Vec<u8>)>) -> TgScript {
+    let mut script = TgScript(vec![]);
     use crate::script::TgOpcode::*;
-    vec![
-        OP_PUSHDATA1(txid.len().try_into().unwrap(), Vec::from(txid.as_ref())),
-        OP_PUSHTXID,
-        OP_EQUAL,
-        OP_IF(
+    script.0.push(OP_PUSHDATA1(1, vec![threshold]));
+    for oracle_pubkey in oracle_pubkeys {
+        let oracle_pubkey_bytes = oracle_pubkey.to_bytes();
+        script.0.push(OP_PUSHDATA1(oracle_pubkey_bytes.len().try_into().unwrap(), oracle_pubkey_bytes));
+    }
+    script.0.push(OP_PUSHDATA1(1, vec![oracle_pubkeys.len().try_into().unwrap()]));
+    script.0.extend(token_dispatch(&pairs));
+    script.0.push(OP_CHECKMULTISIG);
+    script.0.push(OP_VALIDATE);
+    script
+}
+
+// pushes the token for the payout txid, or OP_0 for an unknown payout tx
+// each pair is checked in turn rather than nested in the previous OP_ELSE, so the eval
+// depth doesn't grow with the number of outcomes
+fn token_dispatch(pairs: &[(Txid, Vec<u8>)]) -> Vec<TgOpcode> {
+    use crate::script::TgOpcode::*;
+    let mut ops = vec![OP_0];
+    for (txid, token) in pairs {
+        ops.push(OP_PUSHDATA1(txid.len().try_into().unwrap(), Vec::from(txid.as_ref())));
+        ops.push(OP_PUSHTXID);
+        ops.push(OP_EQUAL);
+        ops.push(OP_IF(
             TgScript(vec![
-                OP_PUSHDATA1(token.len().try_into().unwrap(), Vec::from(token)),
+                OP_DROP,
+                OP_PUSHDATA1(token.len().try_into().unwrap(), token.clone()),
             ]),
-            if last { Some(TgScript(vec!(OP_0))) } else { None },
-        ),
-    ]
+            None,
+        ));
+    }
+    ops
 }
 
-fn nest_fragments(fragments: Vec<Vec<TgOpcode>>) -> Vec<TgOpcode> {
-    let (f, fs) = fragments.split_last().unwrap();
-    if fs.is_empty() {
-        f.to_owned()
-    } else {
-        let (f2, fs) = fs.split_last().unwrap();
-        let (op, ops) = f2.split_last().unwrap();
-        let nested = match op {
-            TgOpcode::OP_IF(true_branch, None) => TgOpcode::OP_IF(true_branch.to_owned(), Some(TgScript(f.to_owned()))),
-            _ => panic!("can't nest this script fragment: op is {:?}", op),
+// which of the create_*_script builders made a payout script, and with what
+#[derive(Clone, Debug, PartialEq)]
+pub enum PayoutTemplate {
+    TokenPair { oracle_pubkey: PublicKey, pairs: Vec<(Txid, Vec<u8>)> },
+    TokenPreimage { pairs: Vec<(Txid, Vec<u8>)> },
+    ThresholdToken { oracle_pubkeys: Vec<PublicKey>, threshold: u8, pairs: Vec<(Txid, Vec<u8>)> },
+}
+
+impl PayoutTemplate {
+// pulls the parameters out of the script and rebuilds it with them, so anything but an exact match is None
+    pub fn recognize(script: &TgScript) -> Option<Self> {
+        use crate::script::TgOpcode::*;
+        let ops = &script.0;
+        let template = match ops.first()? {
+            OP_SHA256 => PayoutTemplate::TokenPreimage { pairs: token_pairs(&ops[1..])? },
+// a threshold script starts with the threshold, a token pair script with the oracle pubkey
+            OP_PUSHDATA1(_, threshold) if threshold.len() == 1 => {
+                let mut oracle_pubkeys = Vec::new();
+                let mut i = 1;
+                loop {
+                    match ops.get(i)? {
+                        OP_PUSHDATA1(_, count) if count.len() == 1 => break,
+                        OP_PUSHDATA1(_, pubkey) => oracle_pubkeys.push(PublicKey::from_slice(pubkey).ok()?),
+                        _ => return None,
+                    }
+                    i += 1;
+                }
+                PayoutTemplate::ThresholdToken { oracle_pubkeys, threshold: threshold[0], pairs: token_pairs(&ops[i + 1..])? }
+            }
+            OP_PUSHDATA1(_, pubkey) => PayoutTemplate::TokenPair { oracle_pubkey: PublicKey::from_slice(pubkey).ok()?, pairs: token_pairs(&ops[1..])? },
+            _ => return None,
         };
-        let mut ops = ops.to_owned();
-        ops.push(nested);
-        let mut fs = fs.to_owned();
-        fs.push(ops);
-        nest_fragments(fs)
+        if &template.script() == script {
+            Some(template)
+        } else {
+            None
+        }
+    }
+
+    pub fn script(&self) -> TgScript {
+        match self {
+            PayoutTemplate::TokenPair { oracle_pubkey, pairs } => create_token_pair_script(oracle_pubkey, pairs.clone()),
+            PayoutTemplate::TokenPreimage { pairs } => create_token_preimage_script(pairs.clone()),
+            PayoutTemplate::ThresholdToken { oracle_pubkeys, threshold, pairs } => create_threshold_token_script(oracle_pubkeys, *threshold, pairs.clone()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PayoutTemplate::TokenPair { .. } => "token-pair",
+            PayoutTemplate::TokenPreimage { .. } => "token-preimage",
+            PayoutTemplate::ThresholdToken { .. } => "threshold-token",
+        }
+    }
+
+// empty for preimage scripts, the oracle there is whoever knows the secrets
+    pub fn oracle_pubkeys(&self) -> Vec<PublicKey> {
+        match self {
+            PayoutTemplate::TokenPair { oracle_pubkey, .. } => vec![*oracle_pubkey],
+            PayoutTemplate::TokenPreimage { .. } => vec![],
+            PayoutTemplate::ThresholdToken { oracle_pubkeys, .. } => oracle_pubkeys.clone(),
+        }
     }
 }
 
-pub fn create_payout_tx(funding_tx: &Transaction, escrow_address: &Address, payout_address: &Address) -> Result<Transaction> {
+// the pairs from token_dispatch at the start of ops, whatever follows is left to the caller
+fn token_pairs(ops: &[TgOpcode]) -> Option<Vec<(Txid, Vec<u8>)>> {
+    use crate::script::TgOpcode::*;
+    if ops.first()? != &OP_0 {
+        return None
+    }
+    let mut pairs = Vec::new();
+    for chunk in ops[1..].chunks_exact(4) {
+        match chunk {
+            [OP_PUSHDATA1(_, txid), OP_PUSHTXID, OP_EQUAL, OP_IF(TgScript(branch), None)] => match branch.as_slice() {
+                [OP_DROP, OP_PUSHDATA1(_, token)] => pairs.push((Txid::from_slice(txid).ok()?, token.clone())),
+                _ => break,
+            }
+            _ => break,
+        }
+    }
+    Some(pairs)
+}
+
+// p2wsh of this is the anchor output, spent with the script as the only witness item
+pub fn anchor_script() -> Script {
+    Builder::new().push_opcode(opcodes::OP_TRUE).into_script()
+}
 
-// TODO: need to standardize this with builder-implementation in player wallet
-    let (input, amount) = funding_tx.output
+// the escrow output of the funding tx as an input, with its value
+fn escrow_input(funding_tx: &Transaction, escrow: &Descriptor<PublicKey>, sequence: u32) -> Result<(TxIn, u64)> {
+    let (vout, txout) = funding_tx.output
         .iter()
         .enumerate()
-        .find_map(|(i, txout)| {
-            if txout.script_pubkey == escrow_address.script_pubkey() {
-                Some((
-                    vec!(TxIn {
-                        previous_output: OutPoint {
-                            txid: funding_tx.txid(),
-                            vout: i as u32,
-                        },
-                        script_sig: Script::new(),
+        .find(|(_vout, txout)| txout.script_pubkey == escrow.script_pubkey())
+        .ok_or(Error::Adhoc("couldn't find escrow output in funding tx"))?;
+    Ok((
+        TxIn {
+            previous_output: OutPoint {
+                txid: funding_tx.txid(),
+                vout: vout as u32,
+            },
+            script_sig: Script::new(),
+            sequence,
+            witness: Vec::new()
+        },
+        txout.value,
+    ))
+}
+
+// the whole escrow less the fee goes to payout_address
+pub fn create_payout_tx(funding_tx: &Transaction, escrow: &Descriptor<PublicKey>, payout_address: &Address, fee_rate: u64) -> Result<Transaction> {
+    let (_input, amount) = escrow_input(funding_tx, escrow, 0xFFFFFFFF)?;
+    create_split_payout_tx(funding_tx, escrow, payout_address, payout_address, amount, 0, fee_rate)
+}
+
+// pays p1_amount and p2_amount out of the escrow, which they have to add up to
+// each player pays the share of the tx fee in proportion to their share of the escrow
+// a player getting nothing gets no output, so winner takes all is the same tx as create_payout_tx
+pub fn create_split_payout_tx(funding_tx: &Transaction, escrow: &Descriptor<PublicKey>, p1_address: &Address, p2_address: &Address, p1_amount: u64, p2_amount: u64, fee_rate: u64) -> Result<Transaction> {
+    split_payout_tx(funding_tx, escrow, p1_address, p2_address, p1_amount, p2_amount, fee_rate, false)
+}
+
+// version 3 and 4 contracts add an anchor, which the players pay for like the fee
+fn split_payout_tx(funding_tx: &Transaction, escrow: &Descriptor<PublicKey>, p1_address: &Address, p2_address: &Address, p1_amount: u64, p2_amount: u64, fee_rate: u64, anchor: bool) -> Result<Transaction> {
 // see bdk::Wallet::create tx
 // i believe this disables RBF which is appropriate for now
-                        sequence: 0xFFFFFFFF,
-                        witness: Vec::new()
-                    }),
-                    txout.value,
-                ))
-            } else { None }
-    }).ok_or(Error::Adhoc("couldn't find escrow output in funding tx"))?;
+    let (input, amount) = escrow_input(funding_tx, escrow, 0xFFFFFFFF)?;
+    if amount == 0 || p1_amount.checked_add(p2_amount) != Some(amount) {
+        return Err(Error::Adhoc("payout amounts don't sum to contract amount"))
+    }
+    let mut tx = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec!(input),
+        output: vec![(p1_address, p1_amount), (p2_address, p2_amount)]
+            .into_iter()
+            .filter(|(_address, amount)| *amount > 0)
+            .map(|(address, amount)| TxOut {
+                value: amount,
+                script_pubkey: address.script_pubkey(),
+            })
+            .chain(Some(TxOut {
+                value: ANCHOR_VALUE,
+                script_pubkey: anchor_script().to_v0_p2wsh(),
+            }).filter(|_| anchor))
+            .collect(),
+    };
+    let fee = escrow_tx_fee(&tx, escrow, fee_rate)? + if anchor { ANCHOR_VALUE } else { 0 };
+    let p1_fee = (fee as u128 * p1_amount as u128 / amount as u128) as u64;
+    let shares = [(p1_amount, p1_fee), (p2_amount, fee - p1_fee)];
+    for (txout, (amount, fee)) in tx.output.iter_mut().zip(shares.iter().filter(|(amount, _fee)| *amount > 0)) {
+        if amount <= fee {
+            return Err(Error::Adhoc("payout amount doesn't cover its share of the tx fee"))
+        }
+        txout.value = amount - fee;
+    }
+    Ok(tx)
+}
 
+// like create_split_payout_tx, but with LEGACY_TX_FEE and no anchor, for version 1 and 2 contracts
+pub fn create_legacy_split_payout_tx(funding_tx: &Transaction, escrow: &Descriptor<PublicKey>, p1_address: &Address, p2_address: &Address, p1_amount: u64, p2_amount: u64) -> Result<Transaction> {
+    let (input, amount) = escrow_input(funding_tx, escrow, 0xFFFFFFFF)?;
+    if amount == 0 || p1_amount.checked_add(p2_amount) != Some(amount) {
+        return Err(Error::Adhoc("payout amounts don't sum to contract amount"))
+    }
+    let p1_fee = (LEGACY_TX_FEE as u128 * p1_amount as u128 / amount as u128) as u64;
+    let p2_fee = LEGACY_TX_FEE - p1_fee;
+    let mut output = Vec::new();
+    for (address, amount, fee) in vec![(p1_address, p1_amount, p1_fee), (p2_address, p2_amount, p2_fee)] {
+        if amount == 0 {
+            continue
+        }
+        if amount <= fee {
+            return Err(Error::Adhoc("payout amount doesn't cover its share of the tx fee"))
+        }
+        output.push(TxOut {
+            value: amount - fee,
+            script_pubkey: address.script_pubkey(),
+        });
+    }
     Ok(Transaction {
         version: 1,
         lock_time: 0,
-        input,
-        output: vec!(TxOut { 
-            value: amount - TX_FEE,
-            script_pubkey: payout_address.script_pubkey() 
-        })
+        input: vec!(input),
+        output,
     })
 }
 
+// returns each player's stake from the escrow once lock_time is reached
+// both players fund the escrow equally so the amount is split evenly, less the tx fee
+pub fn create_refund_tx(funding_tx: &Transaction, escrow: &Descriptor<PublicKey>, p1_address: &Address, p2_address: &Address, lock_time: u32, fee_rate: u64) -> Result<Transaction> {
+// lock_time is ignored if every input is final
+    let (input, amount) = escrow_input(funding_tx, escrow, 0xFFFFFFFE)?;
+    let mut tx = Transaction {
+        version: 1,
+        lock_time,
+        input: vec!(input),
+        output: vec!(
+            TxOut {
+                value: 0,
+                script_pubkey: p1_address.script_pubkey(),
+            },
+            TxOut {
+                value: 0,
+                script_pubkey: p2_address.script_pubkey(),
+            },
+        ),
+    };
+    let fee = escrow_tx_fee(&tx, escrow, fee_rate)?;
+    let stake = amount.checked_sub(fee).ok_or(Error::Adhoc("escrow amount too small for refund"))? / 2;
+    for txout in tx.output.iter_mut() {
+        txout.value = stake;
+    }
+    Ok(tx)
+}
+
+pub fn create_refund(contract: &Contract, p1_address: &Address, p2_address: &Address, lock_time: u32) -> Result<PartiallySignedTransaction> {
+    let escrow = escrow_descriptor(&contract.p1_pubkey, &contract.p2_pubkey, &contract.arbiter_pubkey)?;
+    let funding_tx = contract.clone().funding_tx.extract_tx();
+    let refund_tx = create_refund_tx(&funding_tx, &escrow, p1_address, p2_address, lock_time, contract.fee_rate)?;
+    escrow_psbt(contract, refund_tx)
+}
+
 pub fn derive_account_xprivkey(seed: Secret<Vec<u8>>, network: Network) -> ExtendedPrivKey {
         let root_key = ExtendedPrivKey::new_master(network, seed.expose_secret()).unwrap();
         let secp = Secp256k1::new();
@@ -397,6 +735,28 @@ pub fn derive_account_xpubkey(seed: Secret<Vec<u8>>, network: Network) -> Extend
         ExtendedPubKey::from_private(&secp, &derive_account_xprivkey(seed, network))
 }
 
+// kix has to be checked against MAX_ESCROW_KIX first, see validate_escrow_kix
+pub fn escrow_path(kix: u32) -> DerivationPath {
+    DerivationPath::from_str(&format!("m/{}/{}", ESCROW_SUBACCOUNT, kix)).unwrap()
+}
+
+// the escrow pubkey at kix under an account xpubkey
+pub fn escrow_pubkey(xpubkey: &ExtendedPubKey, kix: u32) -> PublicKey {
+    xpubkey.derive_pub(&Secp256k1::new(), &escrow_path(kix)).unwrap().public_key
+}
+
+pub fn validate_escrow_kix(kix: u32) -> Result<()> {
+    if kix > MAX_ESCROW_KIX {
+        return Err(Error::Config(format!("arbiter_escrow_kix {} is above the limit {}", kix, MAX_ESCROW_KIX)))
+    }
+    Ok(())
+}
+
+// which of the escrow keys up to current_kix made pubkey, newest first
+pub fn escrow_kix(xpubkey: &ExtendedPubKey, current_kix: u32, pubkey: &PublicKey) -> Option<u32> {
+    (0..=current_kix.min(MAX_ESCROW_KIX)).rev().find(|kix| escrow_pubkey(xpubkey, *kix) == *pubkey)
+}
+
 pub fn get_namecoin_address(pubkey: &PublicKey, network: Network) -> String {
     let mut sha256_engine = sha256::HashEngine::default();
     sha256_engine.input(&pubkey.key.serialize());
@@ -436,4 +796,275 @@ mod tests {
         assert_eq!(namecoin_address,TESTNET_ADDRESS_FROM_NAMECOIND)
     }
 
+    #[test]
+    fn test_estimated_vsize() {
+        let pubkey = PublicKey::from_slice(&hex::decode(PUBKEY).unwrap()).unwrap();
+        let address = Address::p2wpkh(&pubkey, Network::Regtest).unwrap();
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec!(TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xFFFFFFFF, witness: Vec::new() }),
+            output: vec!(TxOut { value: 1000, script_pubkey: address.script_pubkey() }),
+        };
+// the usual 1 in 1 out p2wpkh tx
+        assert_eq!(estimated_vsize(&tx, &[P2WPKH_SATISFACTION_WEIGHT]), 110);
+    }
+
+    #[test]
+    fn test_create_refund_tx() {
+        let keys = crate::codec::tests::keys();
+        let escrow = escrow_descriptor(&keys[0].1, &keys[1].1, &keys[2].1).unwrap();
+        let address = Address::p2wpkh(&keys[0].1, Network::Regtest).unwrap();
+        let funding_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: Vec::new(),
+            output: vec!(
+                TxOut { value: 1000, script_pubkey: address.script_pubkey() },
+                TxOut { value: 1_020_001, script_pubkey: escrow.script_pubkey() },
+            ),
+        };
+        let refund_tx = create_refund_tx(&funding_tx, &escrow, &address, &address, 700, 10).unwrap();
+        assert_eq!(refund_tx.lock_time, 700);
+        assert_eq!(refund_tx.input[0].previous_output, OutPoint { txid: funding_tx.txid(), vout: 1 });
+        assert!(refund_tx.input[0].sequence < 0xFFFFFFFF);
+        assert_eq!(refund_tx.output[0].value, refund_tx.output[1].value);
+// an odd sat left over from splitting the stakes goes to the fee
+        let fee = 1_020_001 - 2 * refund_tx.output[0].value;
+        let min_fee = 10 * estimated_vsize(&refund_tx, &[escrow.max_satisfaction_weight().unwrap()]);
+        assert!(fee == min_fee || fee == min_fee + 1);
+        let other_escrow = escrow_descriptor(&keys[1].1, &keys[0].1, &keys[2].1).unwrap();
+        assert!(create_refund_tx(&funding_tx, &other_escrow, &address, &address, 700, 10).is_err());
+    }
+
+    #[test]
+    fn test_escrow_descriptor() {
+        use bdk::bitcoin::blockdata::{
+            opcodes::all as Opcodes,
+            script::Builder,
+        };
+        use crate::codec::tests::{
+            keys,
+            test_contract,
+        };
+        let keys = keys();
+// same script as the bare multisig escrows were built with
+        let bip11_script = Builder::new()
+            .push_opcode(Opcodes::OP_PUSHNUM_2)
+            .push_slice(&keys[0].1.to_bytes())
+            .push_slice(&keys[1].1.to_bytes())
+            .push_slice(&keys[2].1.to_bytes())
+            .push_opcode(Opcodes::OP_PUSHNUM_3)
+            .push_opcode(Opcodes::OP_CHECKMULTISIG)
+            .into_script();
+        assert_eq!(create_escrow_script(&keys[0].1, &keys[1].1, &keys[2].1).unwrap(), bip11_script);
+        assert_eq!(create_escrow_address(&keys[0].1, &keys[1].1, &keys[2].1, Network::Regtest).unwrap(), Address::p2wsh(&bip11_script, Network::Regtest));
+
+        let contract = test_contract();
+        let arbiter_privkey = PrivateKey { compressed: true, network: Network::Regtest, key: keys[2].0 };
+        assert!(escrow_signing_descriptor(&contract, &arbiter_privkey).unwrap().ends_with(&format!("{}))", arbiter_privkey.to_wif())));
+        let other_privkey = PrivateKey { compressed: true, network: Network::Regtest, key: bdk::bitcoin::secp256k1::SecretKey::from_slice(&[4; 32]).unwrap() };
+        assert!(escrow_signing_descriptor(&contract, &other_privkey).is_err());
+
+        let refund = contract.refund_tx.unwrap();
+        assert_eq!(refund.inputs[0].witness_script, Some(bip11_script));
+    }
+
+    #[test]
+    fn test_create_split_payout_tx() {
+        let keys = crate::codec::tests::keys();
+        let escrow = escrow_descriptor(&keys[0].1, &keys[1].1, &keys[2].1).unwrap();
+        let p1_address = Address::p2wpkh(&keys[0].1, Network::Regtest).unwrap();
+        let p2_address = Address::p2pkh(&keys[1].1, Network::Regtest);
+        let funding_tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: Vec::new(),
+            output: vec!(
+                TxOut { value: 1_000_000, script_pubkey: escrow.script_pubkey() },
+            ),
+        };
+        let winner_takes_all = create_split_payout_tx(&funding_tx, &escrow, &p1_address, &p2_address, 1_000_000, 0, 20).unwrap();
+        assert_eq!(winner_takes_all.txid(), create_payout_tx(&funding_tx, &escrow, &p1_address, 20).unwrap().txid());
+        let weight = escrow.max_satisfaction_weight().unwrap();
+        assert_eq!(winner_takes_all.output.len(), 1);
+        assert_eq!(1_000_000 - winner_takes_all.output[0].value, 20 * estimated_vsize(&winner_takes_all, &[weight]));
+
+        let split = create_split_payout_tx(&funding_tx, &escrow, &p1_address, &p2_address, 750_000, 250_000, 20).unwrap();
+        let fee = 20 * estimated_vsize(&split, &[weight]);
+        assert_eq!(split.output.len(), 2);
+        assert_eq!(split.output.iter().map(|txout| txout.value).sum::<u64>(), 1_000_000 - fee);
+        assert_eq!(split.output[0].value, 750_000 - fee * 3 / 4);
+        assert_eq!(split.output[1].script_pubkey, p2_address.script_pubkey());
+
+// version 3 and 4 contracts built them with an anchor
+        let anchored = split_payout_tx(&funding_tx, &escrow, &p1_address, &p2_address, 750_000, 250_000, 20, true).unwrap();
+        let fee = 20 * estimated_vsize(&anchored, &[weight]);
+        assert_eq!(anchored.output[0].value, 750_000 - (fee + ANCHOR_VALUE) * 3 / 4);
+        assert_eq!(anchored.output[2], TxOut { value: ANCHOR_VALUE, script_pubkey: Address::p2wsh(&anchor_script(), Network::Regtest).script_pubkey() });
+// a higher fee rate is a different outcome tx
+        assert_ne!(create_split_payout_tx(&funding_tx, &escrow, &p1_address, &p2_address, 750_000, 250_000, 21).unwrap().txid(), split.txid());
+        assert!(create_split_payout_tx(&funding_tx, &escrow, &p1_address, &p2_address, 500_000, 400_000, 20).is_err());
+        assert!(create_split_payout_tx(&funding_tx, &escrow, &p1_address, &p2_address, 999_999, 1, 20).is_err());
+    }
+
+    #[test]
+    fn test_payout_fee_shortfall() {
+        let mut payout = crate::codec::tests::test_payout();
+        let payout_tx = payout.psbt.global.unsigned_tx.clone();
+        let vsize = estimated_vsize(&payout_tx, &[escrow_satisfaction_weight(&payout.contract).unwrap()]);
+// built at the contract's fee rate, so it's only short of a higher one
+        assert_eq!(payout_fee_shortfall(&payout, payout.contract.fee_rate).unwrap(), 0);
+        assert_eq!(payout_fee_shortfall(&payout, payout.contract.fee_rate + 10).unwrap(), 10 * vsize);
+        payout.psbt.global.unsigned_tx.output[0].value += 1;
+        assert_eq!(payout_fee_shortfall(&payout, payout.contract.fee_rate).unwrap(), 1);
+        payout.psbt.global.unsigned_tx.output[0].value = 2_000_000;
+        assert!(payout_fee_shortfall(&payout, payout.contract.fee_rate).is_err());
+    }
+
+    #[test]
+    fn test_legacy_payout_tx() {
+        let mut contract = crate::codec::tests::test_contract();
+        let p1_address = Address::p2wpkh(&contract.p1_pubkey, Network::Regtest).unwrap();
+        let p2_address = Address::p2wpkh(&contract.p2_pubkey, Network::Regtest).unwrap();
+        let current = contract_payout_tx(&contract, &p1_address, &p2_address, 750_000, 250_000).unwrap();
+        assert_eq!(current.output.len(), 2);
+        contract.version = 4;
+        let anchored = contract_payout_tx(&contract, &p1_address, &p2_address, 750_000, 250_000).unwrap();
+        assert_eq!(anchored.output[2].script_pubkey, anchor_script().to_v0_p2wsh());
+// version 2 contracts committed to fixed fee payout txs
+        contract.version = 2;
+        let payout_tx = contract_payout_tx(&contract, &p1_address, &p2_address, 750_000, 250_000).unwrap();
+        assert_ne!(payout_tx.txid(), current.txid());
+        assert_eq!(payout_tx.output, vec![
+            TxOut { value: 750_000 - LEGACY_TX_FEE * 3 / 4, script_pubkey: p1_address.script_pubkey() },
+            TxOut { value: 250_000 - LEGACY_TX_FEE / 4, script_pubkey: p2_address.script_pubkey() },
+        ]);
+        let payout = create_payout(&contract, &p1_address);
+        assert_eq!(payout.psbt.global.unsigned_tx.output, vec![TxOut { value: 1_000_000 - LEGACY_TX_FEE, script_pubkey: p1_address.script_pubkey() }]);
+    }
+
+    #[test]
+    fn test_token_pair_script_outcomes() {
+        let pubkey = PublicKey::from_slice(&hex::decode(PUBKEY).unwrap()).unwrap();
+        let pairs: Vec<(Txid, Vec<u8>)> = (1..=4u8).map(|i| (Txid::from_slice(&[i; 32]).unwrap(), vec![0xa0 + i; 32])).collect();
+        let analysis = ScriptAnalysis::new(&create_token_pair_script(&pubkey, pairs.clone())).unwrap();
+        assert!(analysis.validate().is_ok());
+        for (txid, token) in pairs {
+            assert_eq!(analysis.branch_for(txid.as_ref()).unwrap().message, Some(token));
+        }
+    }
+
+    #[test]
+    fn test_token_preimage_script() {
+        use crate::script::interpreter::TgScriptInterpreter;
+        let payout = crate::codec::tests::test_payout();
+        let payout_txid = payout.psbt.clone().extract_tx().txid();
+        let other_txid = Txid::from_slice(&[1; 32]).unwrap();
+        let (secret, other_secret) = (b"p2 wins".to_vec(), b"p1 wins".to_vec());
+        let script = create_token_preimage_script(vec![
+            (other_txid, sha256::Hash::hash(&other_secret).to_vec()),
+            (payout_txid, sha256::Hash::hash(&secret).to_vec()),
+        ]);
+
+        let analysis = ScriptAnalysis::new(&script).unwrap();
+        assert!(analysis.validate().is_ok());
+        assert_eq!(analysis.accepting().count(), 2);
+        assert!(analysis.branch_for(payout_txid.as_ref()).is_some());
+
+        let mut env = TgScriptEnv::new(payout.clone());
+        env.push_input(secret).unwrap();
+        env.eval(script.clone()).unwrap();
+        assert_eq!(env.validity(), Some(true));
+// the other outcome's secret doesn't pay out this tx
+        let mut env = TgScriptEnv::new(payout);
+        env.push_input(other_secret).unwrap();
+        env.eval(script).unwrap();
+        assert_eq!(env.validity(), Some(false));
+    }
+
+    #[test]
+    fn test_threshold_token_script() {
+        let keys = crate::codec::tests::keys();
+        let mut payout = crate::codec::tests::test_payout();
+        let payout_txid = payout.psbt.clone().extract_tx().txid();
+        let token = vec![0xa1; 32];
+        let script = create_threshold_token_script(&[keys[0].1, keys[1].1, keys[2].1], 2, vec![
+            (Txid::from_slice(&[1; 32]).unwrap(), vec![0xa2; 32]),
+            (payout_txid, token.clone()),
+        ]);
+        payout.contract.payout_script = script.clone();
+
+        let branch = ScriptAnalysis::new(&script).unwrap().branch_for(payout_txid.as_ref()).unwrap().clone();
+        assert_eq!(branch.signers, vec![keys[0].1.to_bytes(), keys[1].1.to_bytes(), keys[2].1.to_bytes()]);
+        assert_eq!(branch.threshold, 2);
+
+        let secp = Secp256k1::new();
+        let msg = Message::from_slice(&token).unwrap();
+        payout.script_sigs = vec![secp.sign(&msg, &keys[2].0), secp.sign(&msg, &keys[0].0)];
+        assert!(TgScriptEnv::new(payout.clone()).validate_payout().is_ok());
+// one oracle isn't enough, and neither is a sig on another outcome's token
+        payout.script_sigs = vec![secp.sign(&msg, &keys[1].0)];
+        assert!(TgScriptEnv::new(payout.clone()).validate_payout().is_err());
+        let other_msg = Message::from_slice(&[0xa2; 32]).unwrap();
+        payout.script_sigs = vec![secp.sign(&other_msg, &keys[0].0), secp.sign(&other_msg, &keys[1].0)];
+        assert!(TgScriptEnv::new(payout).validate_payout().is_err());
+    }
+
+    #[test]
+    fn test_validate_sighash_all() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xFFFFFFFF, witness: Vec::new() }],
+            output: vec![],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        assert!(validate_sighash_all(&psbt).is_ok());
+        psbt.inputs[0].sighash_type = Some(SigHashType::All);
+        assert!(validate_sighash_all(&psbt).is_ok());
+        for sighash_type in &[SigHashType::None, SigHashType::Single, SigHashType::AllPlusAnyoneCanPay] {
+            psbt.inputs[0].sighash_type = Some(*sighash_type);
+            assert!(validate_sighash_all(&psbt).is_err());
+        }
+    }
+
+    #[test]
+    fn test_escrow_kix() {
+        let xpubkey = derive_account_xpubkey(Secret::new(vec![1; 32]), Network::Regtest);
+        let old = escrow_pubkey(&xpubkey, 0);
+        let current = escrow_pubkey(&xpubkey, 2);
+        assert_ne!(old, current);
+        assert_eq!(escrow_kix(&xpubkey, 2, &current), Some(2));
+        assert_eq!(escrow_kix(&xpubkey, 2, &old), Some(0));
+// not rotated to yet
+        assert_eq!(escrow_kix(&xpubkey, 1, &current), None);
+        assert!(validate_escrow_kix(MAX_ESCROW_KIX).is_ok());
+        assert!(matches!(validate_escrow_kix(MAX_ESCROW_KIX + 1), Err(Error::Config(_))));
+        assert!(validate_escrow_kix(1 << 31).is_err());
+    }
+
+    #[test]
+    fn test_payout_templates() {
+        let pubkey = PublicKey::from_slice(&hex::decode(PUBKEY).unwrap()).unwrap();
+        let keys = crate::codec::tests::keys();
+        let pairs: Vec<(Txid, Vec<u8>)> = (1..=3u8).map(|i| (Txid::from_slice(&[i; 32]).unwrap(), vec![0xa0 + i; 32])).collect();
+        let templates = vec![
+            PayoutTemplate::TokenPair { oracle_pubkey: pubkey, pairs: pairs.clone() },
+            PayoutTemplate::TokenPreimage { pairs: pairs.clone() },
+            PayoutTemplate::ThresholdToken { oracle_pubkeys: vec![keys[0].1, keys[1].1, keys[2].1], threshold: 2, pairs: pairs.clone() },
+        ];
+        for template in templates {
+            assert_eq!(PayoutTemplate::recognize(&template.script()), Some(template.clone()));
+// anything extra or missing is a different script
+            let mut script = template.script();
+            script.0.insert(script.0.len() - 1, TgOpcode::OP_DROP);
+            assert_eq!(PayoutTemplate::recognize(&script), None);
+            let mut script = template.script();
+            script.0.remove(2);
+            assert_eq!(PayoutTemplate::recognize(&script), None);
+        }
+        assert_eq!(PayoutTemplate::recognize(&TgScript(vec![])), None);
+    }
+
 }
@@ -1,4 +1,5 @@
 use std::thread::sleep;
+use tglib::config::Config;
 use ump::{
     bitcoin::{PrivateKey, secp256k1},
     chrono::{offset::TimeZone, Date, Local, NaiveDate, Duration},
@@ -15,7 +16,6 @@ use ump::{
 };
 
 pub const UMP_PRIVKEY: &'static str = "L52hw8to1fdBj9eP8HESBNrfcbehxvKU1vsqWjmHJavxNEi9q91i";
-const PUBLISHER_URL: &'static str = "http://ump-publisher:60600";
 
 #[derive(Debug)]
 struct GameOutcome {
@@ -48,6 +48,7 @@ fn get_game_outcomes(schedule: MlbSchedule) -> Vec<GameOutcome> {
 }
 
 fn main() {
+    let config = Config::default().load().unwrap();
     sleep(Duration::seconds(2).to_std().unwrap());
     let today = Local::today();
     let yesterday = today - Duration::days(1);
@@ -65,7 +66,7 @@ fn main() {
     println!("Winners");
     println!("{:?}", game_outcomes);
 
-    let response = reqwest::blocking::get(format!("{}/game-info",PUBLISHER_URL)).unwrap().text().unwrap();
+    let response = reqwest::blocking::get(format!("{}/game-info", config.publisher_url)).unwrap().text().unwrap();
     let response: JsonResponse<Vec<GameInfo>> = serde_json::from_str(&response).unwrap();
 
     let game_info: Vec<GameInfo> = response.data.unwrap();
@@ -105,7 +106,7 @@ fn main() {
 
     let client = reqwest::blocking::Client::new();
     for (outcome_id, sig) in token_sigs {
-        client.post(format!("{}/signature",PUBLISHER_URL))
+        client.post(format!("{}/signature", config.publisher_url))
             .body(serde_json::to_string(&AddSignatureBody {
                 outcome_id,
                 sig_hex: hex::encode(sig.serialize_der().to_vec()),